@@ -0,0 +1,152 @@
+#![allow(clippy::needless_return)]
+
+use criterion::{criterion_group, criterion_main, Criterion};
+use rs_lox::{compile, Chunk, ObjString, Sandbox, Table, VMConfig, GC, VM};
+use std::io;
+
+// Each iteration compiles and runs the workload with a fresh GC, like
+// `rlox bench` does.
+//
+// To compare table probing strategies, run the benchmarks once as is and
+// once with `--features quadratic-probing`; Criterion reports the change.
+
+// Lox has no loops or functions yet, so workloads are unrolled into
+// straight-line code.
+
+fn fib() -> String {
+    let mut source = String::new();
+    for _ in 0..20 {
+        source.push_str("var a = 0;\nvar b = 1;\n");
+        for _ in 0..30 {
+            source.push_str("var t = a + b;\nvar a = b;\nvar b = t;\n");
+        }
+    }
+    return source;
+}
+
+fn string_churn() -> String {
+    let mut source = String::from("var s = \"\";\n");
+    for i in 0..500 {
+        source.push_str(&format!("var s = s + \"{}\";\n", i % 10));
+    }
+    return source;
+}
+
+fn table_churn() -> String {
+    let mut source = String::new();
+    for i in 0..1000 {
+        source.push_str(&format!("var g{} = {};\n", i, i));
+    }
+    for i in 0..999 {
+        source.push_str(&format!("g{} + g{};\n", i, i + 1));
+    }
+    return source;
+}
+
+fn loop_heavy() -> String {
+    let mut source = String::from("var x = 0;\n");
+    for _ in 0..500 {
+        source.push_str("var x = x + 1 * 2 - 3 / 4;\n!(x < 10) == (x > 10);\n");
+    }
+    return source;
+}
+
+// Reads a few globals from many sites, which is what the inline cache for
+// `Get` is for once a site can run more than once.
+fn global_reads() -> String {
+    let mut source = String::new();
+    for i in 0..50 {
+        source.push_str(&format!("var g{} = {};\n", i, i));
+    }
+    for i in 0..1000 {
+        source.push_str(&format!(
+            "g{} + g{} + g{};\n",
+            i % 50,
+            (i + 1) % 50,
+            (i + 2) % 50
+        ));
+    }
+    return source;
+}
+
+fn run(source: &String) {
+    let mut gc = GC::new();
+    let (chunk, _) = compile(source, &mut gc).expect("Benchmark failed to compile");
+    execute(chunk, &mut gc);
+}
+
+fn execute(chunk: Chunk, gc: &mut GC) {
+    let mut sink = io::sink();
+    let mut input = std::io::empty();
+    let mut vm = VM::new(
+        VMConfig {
+            trace_instructions: false,
+            trace_stack: false,
+            trace_globals: false,
+            stdout: &mut sink,
+            stdin: &mut input,
+            sandbox: Sandbox::default(),
+        },
+        gc,
+    );
+    vm.interpret_chunk(chunk).expect("Benchmark failed to run");
+}
+
+/// Looks up every key of a table filled to just under its maximum load,
+/// with similar keys like the globals of `table_churn`.
+fn table_lookups(c: &mut Criterion) {
+    let keys = (0..767)
+        .map(|i| ObjString::new(format!("g{}", i)))
+        .collect::<Vec<_>>();
+    let mut table = Table::new();
+    for (i, key) in keys.iter().enumerate() {
+        table.set(key, i);
+    }
+    c.bench_function("table_lookups", |b| {
+        b.iter(|| keys.iter().filter_map(|key| table.get(key)).sum::<usize>())
+    });
+}
+
+fn benchmarks(c: &mut Criterion) {
+    let workloads = [
+        ("fib", fib()),
+        ("string_churn", string_churn()),
+        ("table_churn", table_churn()),
+        ("loop_heavy", loop_heavy()),
+        ("global_reads", global_reads()),
+    ];
+    for (name, source) in workloads {
+        c.bench_function(name, |b| b.iter(|| run(&source)));
+    }
+}
+
+/// Runs already compiled workloads, to measure the dispatch loop without the
+/// compiler.
+///
+/// Instrumentation such as hooks, traces and `table-stats` must cost nothing
+/// here while it's off. To check a change, save a baseline before it with
+/// `cargo bench --bench interpreter -- dispatch --save-baseline before`, then
+/// run with `--baseline before` after it: Criterion should find no change
+/// beyond noise.
+fn dispatch(c: &mut Criterion) {
+    let workloads = [
+        ("dispatch_loop_heavy", loop_heavy()),
+        ("dispatch_global_reads", global_reads()),
+        ("dispatch_fib", fib()),
+    ];
+    for (name, source) in workloads {
+        let mut gc = GC::new();
+        let (chunk, _) = compile(&source, &mut gc).expect("Benchmark failed to compile");
+        let mut slots = chunk.clone();
+        slots
+            .resolve_global_slots()
+            .expect("Benchmark failed to resolve globals");
+        c.bench_function(name, |b| b.iter(|| execute(chunk.clone(), &mut gc)));
+        // As `rlox` runs scripts, with globals in slots rather than the table
+        let slots_name = format!("{}_slots", name);
+        c.bench_function(&slots_name, |b| b.iter(|| execute(slots.clone(), &mut gc)));
+    }
+}
+
+criterion_group!(benches, benchmarks, dispatch, table_lookups);
+criterion_main!(benches);
@@ -0,0 +1,39 @@
+//! Decodes arbitrary bytes as a compiled chunk and, if it passes the
+//! verifier, disassembles and runs it. None of that may panic.
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use rs_lox::debug::disassemble_chunk;
+use rs_lox::{stdlib, Sandbox, SharedChunk, VMConfig, GC, VM};
+
+fuzz_target!(|data: &[u8]| {
+    let shared = match SharedChunk::decode(data) {
+        Ok(shared) => shared,
+        Err(_) => return,
+    };
+    let mut gc = GC::new();
+    let chunk = shared.instantiate(&mut gc);
+    if chunk.verify().is_err() {
+        return;
+    }
+    disassemble_chunk(&chunk, "fuzz");
+    let mut output = std::io::sink();
+    let mut input = std::io::empty();
+    let mut vm = VM::new(
+        VMConfig {
+            trace_instructions: false,
+            trace_stack: false,
+            trace_globals: false,
+            stdout: &mut output,
+            stdin: &mut input,
+            sandbox: Sandbox {
+                max_memory: Some(1 << 24),
+                max_instructions: Some(100_000),
+                ..Sandbox::untrusted()
+            },
+        },
+        &mut gc,
+    );
+    stdlib::install(&mut vm);
+    let _ = vm.interpret_chunk(chunk);
+});
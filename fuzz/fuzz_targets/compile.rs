@@ -0,0 +1,62 @@
+//! Compiles arbitrary source with both compilers and runs it if it compiles,
+//! with its globals looked up by name or in slots. None of it may panic.
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use rs_lox::{compile, compile_two_pass, stdlib, Sandbox, VMConfig, GC, VM};
+
+/// Marks an input whose next two bytes pick how deep to nest the rest and
+/// with what, since mutating text alone rarely nests past the compilers'
+/// limit. Not valid UTF-8, so it doesn't start any plain script.
+const NESTED: u8 = 0xff;
+
+/// Openers and closers to nest with, each adding a level of nesting.
+const WRAPPERS: [(&str, &str); 5] = [("(", ")"), ("-", ""), ("!", ""), ("1 + ", ""), ("a(", ")")];
+
+fn source(data: &[u8]) -> String {
+    let (depth, (open, close), rest) = match data {
+        [NESTED, depth, wrapper, rest @ ..] => {
+            let wrapper = WRAPPERS[*wrapper as usize % WRAPPERS.len()];
+            // Up to about twice the limit
+            (*depth as usize * 8, wrapper, rest)
+        }
+        _ => (0, ("", ""), data),
+    };
+    let rest = String::from_utf8_lossy(rest);
+    format!("{}{}{}", open.repeat(depth), rest, close.repeat(depth))
+}
+
+fuzz_target!(|data: &[u8]| {
+    let source = source(data);
+    let mut gc = GC::new();
+    let _ = compile_two_pass(&source, &mut gc);
+    let mut chunk = match compile(&source, &mut gc) {
+        Ok((chunk, _)) => chunk,
+        Err(_) => return,
+    };
+    // Half the inputs run with globals in slots, as `rlox` runs scripts
+    if data.len() % 2 == 1 {
+        chunk
+            .resolve_global_slots()
+            .expect("Compiled chunks always resolve");
+    }
+    let mut output = std::io::sink();
+    let mut input = std::io::empty();
+    let mut vm = VM::new(
+        VMConfig {
+            trace_instructions: false,
+            trace_stack: false,
+            trace_globals: false,
+            stdout: &mut output,
+            stdin: &mut input,
+            sandbox: Sandbox {
+                max_memory: Some(1 << 24),
+                max_instructions: Some(100_000),
+                ..Sandbox::untrusted()
+            },
+        },
+        &mut gc,
+    );
+    stdlib::install(&mut vm);
+    let _ = vm.interpret_chunk(chunk);
+});
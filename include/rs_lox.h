@@ -0,0 +1,70 @@
+#ifndef RS_LOX_H
+#define RS_LOX_H
+
+/* Generated with cbindgen from src/ffi.rs. Do not edit by hand. */
+
+#include <stdarg.h>
+#include <stdbool.h>
+#include <stdint.h>
+#include <stdlib.h>
+
+typedef enum LoxStatus {
+  LOX_STATUS_OK = 0,
+  LOX_STATUS_COMPILE_ERROR = 1,
+  LOX_STATUS_RUNTIME_ERROR = 2,
+  /**
+   * A handle or string argument was NULL or not valid UTF-8.
+   */
+  LOX_STATUS_INVALID_ARGUMENT = 3,
+} LoxStatus;
+
+/**
+ * An interpreter session for embedding Lox in an application. Globals
+ * persist between calls to `eval`, and `print` writes to stdout unless
+ * redirected with `set_output`.
+ */
+typedef struct Lox Lox;
+
+/**
+ * Creates a new interpreter session. Free it with `lox_free`.
+ */
+Lox *lox_new(void);
+
+/**
+ * Frees a session created by `lox_new`. Does nothing for NULL.
+ *
+ * # Safety
+ * `lox` must be NULL or a handle from `lox_new` that hasn't been freed.
+ */
+void lox_free(Lox *lox);
+
+/**
+ * Runs `source` in the session. On success, `*result` is set to the value of
+ * the trailing expression statement as `print` would show it. On failure it
+ * is set to the error message. `result` may be NULL if neither is needed.
+ *
+ * # Safety
+ * `lox` must be a live handle from `lox_new`, `source` a NUL-terminated
+ * string, and `result` NULL or valid for writes.
+ */
+LoxStatus lox_eval(Lox *lox, const char *source, char **result);
+
+/**
+ * Returns the value of global `name` as `print` would show it, or NULL if it
+ * isn't defined.
+ *
+ * # Safety
+ * `lox` must be a live handle from `lox_new` and `name` a NUL-terminated
+ * string.
+ */
+char *lox_get_global(Lox *lox, const char *name);
+
+/**
+ * Frees a string returned by this API. Does nothing for NULL.
+ *
+ * # Safety
+ * `s` must be NULL or a string returned by this API that hasn't been freed.
+ */
+void lox_string_free(char *s);
+
+#endif /* RS_LOX_H */
@@ -0,0 +1,738 @@
+//! A typed syntax tree for Lox programs, for passes that need to see a whole
+//! program before emitting code. `parse` builds it with the same grammar,
+//! errors and warnings as the single-pass compiler, and [`lower`](crate::lower)
+//! turns it into the same bytecode.
+
+use crate::chunk::MAX_CONSTANTS;
+use crate::compiler::{
+    infix_precedence, Diagnostic, ParserErrorKind, Precedence, Severity, INTERNAL_ERROR,
+    MAX_NESTING,
+};
+use crate::scanner::{Dialect, Scanner, Token, TokenKind};
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Literal {
+    Number(f32),
+    Int(i64),
+    String(String),
+    True,
+    False,
+    Nil,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UnaryOp {
+    Negate,
+    Not,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BinaryOp {
+    Equal,
+    NotEqual,
+    Greater,
+    GreaterEqual,
+    Less,
+    LessEqual,
+    Add,
+    Subtract,
+    Multiply,
+    Divide,
+}
+
+/// An expression. Nodes keep the tokens they were parsed from, so passes can
+/// report positions and lowering can give instructions the same lines as the
+/// single-pass compiler does.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Expr {
+    Literal {
+        value: Literal,
+        token: Token,
+    },
+    Variable {
+        name: Token,
+    },
+    Grouping {
+        expr: Box<Expr>,
+        right_paren: Token,
+    },
+    Unary {
+        op: UnaryOp,
+        operator: Token,
+        operand: Box<Expr>,
+    },
+    Binary {
+        left: Box<Expr>,
+        op: BinaryOp,
+        operator: Token,
+        right: Box<Expr>,
+    },
+    Call {
+        callee: Box<Expr>,
+        arguments: Vec<Expr>,
+        right_paren: Token,
+    },
+    /// `receiver.name(arguments)`. There are no fields, so every `.` is a
+    /// method call.
+    Invoke {
+        receiver: Box<Expr>,
+        name: Token,
+        arguments: Vec<Expr>,
+        right_paren: Token,
+    },
+}
+
+impl Expr {
+    /// The last token of the expression's source. The single-pass compiler
+    /// emits an expression's own instructions once it has parsed that far,
+    /// so their positions come from here.
+    pub fn last_token(&self) -> &Token {
+        return match self {
+            Expr::Literal { token, .. } => token,
+            Expr::Variable { name } => name,
+            Expr::Grouping { right_paren, .. }
+            | Expr::Call { right_paren, .. }
+            | Expr::Invoke { right_paren, .. } => right_paren,
+            Expr::Unary { operand, .. } => operand.last_token(),
+            Expr::Binary { right, .. } => right.last_token(),
+        };
+    }
+
+    /// Whether evaluating the expression can do more than produce a value,
+    /// which for now means it calls something.
+    pub fn has_effects(&self) -> bool {
+        return match self {
+            Expr::Literal { .. } | Expr::Variable { .. } => false,
+            Expr::Grouping { expr, .. } => expr.has_effects(),
+            Expr::Unary { operand, .. } => operand.has_effects(),
+            Expr::Binary { left, right, .. } => left.has_effects() || right.has_effects(),
+            Expr::Call { .. } | Expr::Invoke { .. } => true,
+        };
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Stmt {
+    Var {
+        name: Token,
+        initializer: Option<Expr>,
+        semicolon: Token,
+    },
+    /// `import "path";` when `names` is empty, otherwise
+    /// `import { names } from "path";`.
+    Import {
+        names: Vec<Token>,
+        path: Token,
+        semicolon: Token,
+    },
+    Print {
+        expr: Expr,
+        semicolon: Token,
+    },
+    Expression {
+        expr: Expr,
+        semicolon: Token,
+    },
+    /// `defer statement;`, which runs `statement` at the end of the script.
+    Defer {
+        keyword: Token,
+        statement: Box<Stmt>,
+    },
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Program {
+    /// Statements that parsed without errors, in order.
+    pub statements: Vec<Stmt>,
+    /// The end of the source, where the final `Return` goes.
+    pub eof: Token,
+}
+
+/// Parses `source`, returning the program and every error and warning found.
+/// The program is only complete if there are no errors; statements with
+/// errors are left out.
+pub fn parse(source: &String) -> (Program, Vec<Diagnostic>) {
+    return parse_program(source, false, Dialect::default());
+}
+
+/// Like `parse`, but if `keep_result` is set, a trailing expression statement
+/// is the program's result and isn't reported as unused.
+pub(crate) fn parse_program(
+    source: &String,
+    keep_result: bool,
+    dialect: Dialect,
+) -> (Program, Vec<Diagnostic>) {
+    let mut parser = Parser::new(Scanner::with_dialect(source, dialect), keep_result);
+    let mut statements = Vec::new();
+    while !parser.aborted && !parser.r#match(TokenKind::Eof) {
+        let statement = parser.declaration();
+        if parser.panic_mode {
+            parser.synchronize();
+        } else if let Some(statement) = statement {
+            statements.push(statement);
+        }
+    }
+    let program = Program {
+        statements,
+        eof: parser.previous,
+    };
+    return (program, parser.diagnostics);
+}
+
+/// Builds the tree from tokens. Reports errors the way the single-pass
+/// compiler does, including its panic mode recovery, so both modes agree on
+/// diagnostics.
+struct Parser<'a> {
+    scanner: Scanner<'a>,
+    current: Token,
+    previous: Token,
+    diagnostics: Vec<Diagnostic>,
+    panic_mode: bool,
+    keep_result: bool,
+    /// Constants the chunk for the program will have so far. Every literal
+    /// and name gets its own, so they're counted as they're parsed to report
+    /// `MAX_CONSTANTS` where the single-pass compiler does.
+    constants: usize,
+    /// Set by an internal error, after which nothing more is parsed.
+    aborted: bool,
+    /// How many expressions the one being parsed is nested in, up to
+    /// `MAX_NESTING` as in the single-pass compiler.
+    depth: usize,
+}
+
+impl<'a> Parser<'a> {
+    fn new(scanner: Scanner<'a>, keep_result: bool) -> Parser<'a> {
+        let start = Token::new(TokenKind::Eof, "", 1, 1);
+        let mut parser = Parser {
+            scanner,
+            current: start.clone(),
+            previous: start,
+            diagnostics: Vec::new(),
+            panic_mode: false,
+            keep_result,
+            constants: 0,
+            aborted: false,
+            depth: 0,
+        };
+        parser.advance();
+        return parser;
+    }
+
+    fn report(&mut self, severity: Severity, token: Token, message: String) {
+        if self.panic_mode {
+            return;
+        }
+        if severity == Severity::Error {
+            self.panic_mode = true;
+        }
+        let kind = match severity {
+            Severity::Error => ParserErrorKind::at(&token),
+            Severity::Warning => ParserErrorKind::Other,
+        };
+        self.diagnostics.push(Diagnostic {
+            severity,
+            kind,
+            message,
+            token,
+        });
+    }
+
+    fn error(&mut self, message: &str) {
+        self.report(
+            Severity::Error,
+            self.previous.clone(),
+            String::from(message),
+        );
+    }
+
+    /// Reports a bug in the parser at the previous token, even in panic mode,
+    /// and stops parsing once the current declaration is done.
+    fn internal_error(&mut self, message: String) {
+        self.diagnostics.push(Diagnostic {
+            severity: Severity::Error,
+            kind: ParserErrorKind::Internal,
+            token: self.previous.clone(),
+            message: format!("{}{}", INTERNAL_ERROR, message),
+        });
+        self.panic_mode = true;
+        self.aborted = true;
+    }
+
+    fn error_at_current(&mut self, message: &str) {
+        self.report(Severity::Error, self.current.clone(), String::from(message));
+    }
+
+    fn synchronize(&mut self) {
+        self.panic_mode = false;
+        while self.current.kind != TokenKind::Eof {
+            if self.previous.kind == TokenKind::Semicolon {
+                return;
+            }
+            match self.current.kind {
+                TokenKind::Class
+                | TokenKind::Fun
+                | TokenKind::Var
+                | TokenKind::For
+                | TokenKind::If
+                | TokenKind::While
+                | TokenKind::Print
+                | TokenKind::Return => return,
+                _ => (),
+            }
+            self.advance();
+        }
+    }
+
+    /// Counts a constant for the token just consumed.
+    fn add_constant(&mut self) {
+        if self.constants == MAX_CONSTANTS {
+            return self.error("Too many constants in one chunk.");
+        }
+        self.constants += 1;
+    }
+
+    fn check(&self, kind: TokenKind) -> bool {
+        return self.current.kind == kind;
+    }
+
+    fn consume(&mut self, kind: TokenKind, message: &str) {
+        if self.check(kind) {
+            self.advance();
+        } else {
+            self.error_at_current(message);
+        }
+    }
+
+    fn r#match(&mut self, kind: TokenKind) -> bool {
+        if !self.check(kind) {
+            return false;
+        }
+        self.advance();
+        return true;
+    }
+
+    fn advance(&mut self) {
+        self.previous = self.current.clone();
+        loop {
+            self.current = self.scanner.scan();
+            if self.current.kind != TokenKind::Error {
+                break;
+            }
+            let message = self.current.lexeme.clone();
+            self.report(Severity::Error, self.current.clone(), message);
+        }
+    }
+
+    // Statements
+
+    fn declaration(&mut self) -> Option<Stmt> {
+        if self.r#match(TokenKind::Var) {
+            return Some(self.var_declaration());
+        } else if self.r#match(TokenKind::Import) {
+            return self.import_declaration();
+        } else if self.r#match(TokenKind::Defer) {
+            let keyword = self.previous.clone();
+            let keep_result = std::mem::replace(&mut self.keep_result, false);
+            let statement = self.statement();
+            self.keep_result = keep_result;
+            return Some(Stmt::Defer {
+                keyword,
+                statement: Box::new(statement),
+            });
+        }
+        return Some(self.statement());
+    }
+
+    fn var_declaration(&mut self) -> Stmt {
+        self.consume(TokenKind::Identifier, "Expected variable name.");
+        self.add_constant();
+        let name = self.previous.clone();
+        let initializer = if self.r#match(TokenKind::Equal) {
+            Some(self.expression())
+        } else {
+            None
+        };
+        self.consume(
+            TokenKind::Semicolon,
+            "Expected ';' after variable declaration.",
+        );
+        return Stmt::Var {
+            name,
+            initializer,
+            semicolon: self.previous.clone(),
+        };
+    }
+
+    fn import_declaration(&mut self) -> Option<Stmt> {
+        let mut names = Vec::new();
+        if self.r#match(TokenKind::LeftBrace) {
+            loop {
+                self.consume(TokenKind::Identifier, "Expected name to import.");
+                self.add_constant();
+                names.push(self.previous.clone());
+                if !self.r#match(TokenKind::Comma) {
+                    break;
+                }
+            }
+            self.consume(TokenKind::RightBrace, "Expected '}' after imported names.");
+            if !(self.check(TokenKind::Identifier) && self.current.lexeme == "from") {
+                self.error_at_current("Expected 'from' after imported names.");
+                return None;
+            }
+            self.advance();
+        }
+        self.consume(TokenKind::Str, "Expected module path.");
+        self.add_constant();
+        let path = self.previous.clone();
+        self.consume(TokenKind::Semicolon, "Expected ';' after import.");
+        return Some(Stmt::Import {
+            names,
+            path,
+            semicolon: self.previous.clone(),
+        });
+    }
+
+    fn statement(&mut self) -> Stmt {
+        if self.r#match(TokenKind::Print) {
+            let expr = self.expression();
+            self.consume(TokenKind::Semicolon, "Expected ';' after value.");
+            return Stmt::Print {
+                expr,
+                semicolon: self.previous.clone(),
+            };
+        }
+        let start = self.current.clone();
+        let expr = self.expression();
+        self.consume(TokenKind::Semicolon, "Expected ';' after value.");
+        let is_result = self.keep_result && self.check(TokenKind::Eof);
+        if !is_result && !expr.has_effects() {
+            let message = String::from("Expression result is unused.");
+            self.report(Severity::Warning, start, message);
+        }
+        return Stmt::Expression {
+            expr,
+            semicolon: self.previous.clone(),
+        };
+    }
+
+    // Expressions
+
+    fn expression(&mut self) -> Expr {
+        return self.parse_precedence(Precedence::Assignment);
+    }
+
+    fn parse_precedence(&mut self, precedence: Precedence) -> Expr {
+        let depth = self.depth;
+        let expr = self.parse_operators(precedence);
+        self.depth = depth;
+        return expr;
+    }
+
+    /// Counts a level of nesting, where the single-pass compiler does, or
+    /// reports it's too deep and returns false.
+    fn nest(&mut self) -> bool {
+        if self.depth == MAX_NESTING {
+            self.error_at_current("Expression nesting too deep.");
+            return false;
+        }
+        self.depth += 1;
+        return true;
+    }
+
+    fn parse_operators(&mut self, precedence: Precedence) -> Expr {
+        if !self.nest() {
+            return self.placeholder();
+        }
+        self.advance();
+        let mut expr = match self.prefix() {
+            Some(expr) => expr,
+            None => {
+                self.error("Expected expression.");
+                return self.placeholder();
+            }
+        };
+        // Stops at an error, as the single-pass compiler does
+        while !self.panic_mode && precedence as u8 <= infix_precedence(self.current.kind) as u8 {
+            if !self.nest() {
+                break;
+            }
+            self.advance();
+            expr = self.infix(expr);
+        }
+        return expr;
+    }
+
+    /// Stands in for an expression that failed to parse. It's never lowered,
+    /// since the statement it's in has an error.
+    fn placeholder(&self) -> Expr {
+        return Expr::Literal {
+            value: Literal::Nil,
+            token: self.previous.clone(),
+        };
+    }
+
+    fn prefix(&mut self) -> Option<Expr> {
+        let token = self.previous.clone();
+        let literal = |value| Expr::Literal {
+            value,
+            token: token.clone(),
+        };
+        return Some(match token.kind {
+            TokenKind::LeftParen => {
+                let expr = self.expression();
+                self.consume(TokenKind::RightParen, "Expect ')' after expression.");
+                Expr::Grouping {
+                    expr: Box::new(expr),
+                    right_paren: self.previous.clone(),
+                }
+            }
+            TokenKind::Minus | TokenKind::Bang => {
+                let operand = self.parse_precedence(Precedence::Unary);
+                let op = if token.kind == TokenKind::Minus {
+                    UnaryOp::Negate
+                } else {
+                    UnaryOp::Not
+                };
+                Expr::Unary {
+                    op,
+                    operator: token,
+                    operand: Box::new(operand),
+                }
+            }
+            // Literals without a decimal point are integers, unless they
+            // don't fit
+            TokenKind::Number => match token.lexeme.parse::<i64>() {
+                Ok(value) => {
+                    self.add_constant();
+                    literal(Literal::Int(value))
+                }
+                Err(_) => match token.lexeme.parse::<f32>() {
+                    Ok(value) => {
+                        self.add_constant();
+                        literal(Literal::Number(value))
+                    }
+                    Err(_) => {
+                        self.error("Invalid number.");
+                        self.placeholder()
+                    }
+                },
+            },
+            TokenKind::Str => {
+                self.add_constant();
+                let value = token.lexeme[1..token.lexeme.len() - 1].to_string();
+                literal(Literal::String(value))
+            }
+            TokenKind::True => literal(Literal::True),
+            TokenKind::False => literal(Literal::False),
+            TokenKind::Nil => literal(Literal::Nil),
+            TokenKind::Identifier => {
+                self.add_constant();
+                Expr::Variable { name: token }
+            }
+            _ => return None,
+        });
+    }
+
+    fn infix(&mut self, left: Expr) -> Expr {
+        let operator = self.previous.clone();
+        let op = match operator.kind {
+            TokenKind::LeftParen => {
+                let arguments = self.argument_list();
+                return Expr::Call {
+                    callee: Box::new(left),
+                    arguments,
+                    right_paren: self.previous.clone(),
+                };
+            }
+            TokenKind::Dot => return self.invoke(left),
+            TokenKind::BangEqual => BinaryOp::NotEqual,
+            TokenKind::EqualEqual => BinaryOp::Equal,
+            TokenKind::Greater => BinaryOp::Greater,
+            TokenKind::GreaterEqual => BinaryOp::GreaterEqual,
+            TokenKind::Less => BinaryOp::Less,
+            TokenKind::LessEqual => BinaryOp::LessEqual,
+            TokenKind::Plus => BinaryOp::Add,
+            TokenKind::Minus => BinaryOp::Subtract,
+            TokenKind::Star => BinaryOp::Multiply,
+            TokenKind::Slash => BinaryOp::Divide,
+            kind => {
+                self.internal_error(format!("Invalid binary token kind: {:?}", kind));
+                return left;
+            }
+        };
+        let operator_precedence = infix_precedence(operator.kind);
+        let precedence = match operator_precedence.next() {
+            Some(precedence) => precedence,
+            None => {
+                let message = format!("No precedence above {:?}", operator_precedence);
+                self.internal_error(message);
+                return left;
+            }
+        };
+        let right = self.parse_precedence(precedence);
+        return Expr::Binary {
+            left: Box::new(left),
+            op,
+            operator,
+            right: Box::new(right),
+        };
+    }
+
+    fn invoke(&mut self, receiver: Expr) -> Expr {
+        self.consume(TokenKind::Identifier, "Expected method name after '.'.");
+        self.add_constant();
+        let name = self.previous.clone();
+        if !self.r#match(TokenKind::LeftParen) {
+            self.error("Expected '(' after method name.");
+            return receiver;
+        }
+        let arguments = self.argument_list();
+        return Expr::Invoke {
+            receiver: Box::new(receiver),
+            name,
+            arguments,
+            right_paren: self.previous.clone(),
+        };
+    }
+
+    fn argument_list(&mut self) -> Vec<Expr> {
+        let mut arguments = Vec::new();
+        if !self.check(TokenKind::RightParen) {
+            loop {
+                arguments.push(self.expression());
+                if arguments.len() > usize::from(u8::MAX) {
+                    self.error("Can't have more than 255 arguments.");
+                }
+                if !self.r#match(TokenKind::Comma) {
+                    break;
+                }
+            }
+        }
+        self.consume(TokenKind::RightParen, "Expected ')' after arguments.");
+        return arguments;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse_str(source: &str) -> (Program, Vec<Diagnostic>) {
+        return parse(&String::from(source));
+    }
+
+    #[test]
+    fn reports_internal_errors() {
+        let source = String::from("1 ; print 2;");
+        let mut parser = Parser::new(Scanner::new(&source), false);
+        let left = parser.expression();
+        // Only operators are parsed as infix, so force another token
+        parser.advance();
+        assert_eq!(parser.infix(left.clone()), left);
+        assert!(parser.aborted);
+        assert_eq!(
+            crate::compiler::format_diagnostics(&parser.diagnostics),
+            "[line 1] Error at ';': Internal compiler error: Invalid binary token kind: Semicolon"
+        );
+    }
+
+    #[test]
+    fn reports_too_many_constants() {
+        // As in the single-pass compiler's test, start with the pool almost
+        // full
+        let source = String::from("var a = \"b\";\nprint nil;\nprint c;");
+        let mut parser = Parser::new(Scanner::new(&source), false);
+        parser.constants = MAX_CONSTANTS - 1;
+        while !parser.r#match(TokenKind::Eof) {
+            parser.declaration();
+            if parser.panic_mode {
+                parser.synchronize();
+            }
+        }
+        assert_eq!(
+            crate::compiler::format_diagnostics(&parser.diagnostics),
+            "[line 1] Error at '\"b\"': Too many constants in one chunk.\n\
+             [line 3] Error at 'c': Too many constants in one chunk."
+        );
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore = "scans long sources")]
+    fn reports_too_deep_nesting() {
+        let (_, diagnostics) = parse_str(&format!("print {}1;", "-".repeat(MAX_NESTING - 1)));
+        assert!(diagnostics.is_empty());
+        let nested = format!(
+            "{}1{};",
+            "(".repeat(MAX_NESTING + 1),
+            ")".repeat(MAX_NESTING + 1)
+        );
+        let chained = format!("a{};", ".b()".repeat(MAX_NESTING));
+        let (program, diagnostics) = parse_str(&format!("{}\nprint 2;\n{}", nested, chained));
+        assert_eq!(
+            crate::compiler::format_diagnostics(&diagnostics),
+            "[line 1] Error at '(': Expression nesting too deep.\n\
+             [line 3] Error at '.': Expression nesting too deep."
+        );
+        assert_eq!(program.statements.len(), 1);
+    }
+
+    #[test]
+    fn parses_by_precedence() {
+        let (program, diagnostics) = parse_str("print -1 + 2 * a.b(3);");
+        assert!(diagnostics.is_empty());
+        let expr = match &program.statements[..] {
+            [Stmt::Print { expr, .. }] => expr,
+            statements => panic!("Expected a print statement, got {:?}", statements),
+        };
+        let (left, right) = match expr {
+            Expr::Binary {
+                left,
+                op: BinaryOp::Add,
+                right,
+                ..
+            } => (left, right),
+            expr => panic!("Expected an addition, got {:?}", expr),
+        };
+        assert!(matches!(
+            **left,
+            Expr::Unary {
+                op: UnaryOp::Negate,
+                ..
+            }
+        ));
+        match &**right {
+            Expr::Binary {
+                op: BinaryOp::Multiply,
+                right,
+                ..
+            } => {
+                assert!(matches!(&**right, Expr::Invoke { arguments, .. } if arguments.len() == 1))
+            }
+            expr => panic!("Expected a multiplication, got {:?}", expr),
+        }
+        assert_eq!(expr.last_token().lexeme, ")");
+        assert!(expr.has_effects());
+    }
+
+    #[test]
+    fn leaves_out_statements_with_errors() {
+        let (program, diagnostics) = parse_str("var a = 1;\nprint;\nimport { b } from \"c.lox\";");
+        assert_eq!(program.statements.len(), 2);
+        assert!(matches!(&program.statements[1], Stmt::Import { names, .. } if names.len() == 1));
+        assert_eq!(diagnostics.len(), 1);
+        assert_eq!(
+            diagnostics[0].to_string(),
+            "[line 2] Error at ';': Expected expression."
+        );
+    }
+
+    #[test]
+    fn warns_about_unused_results() {
+        let (_, diagnostics) = parse_str("1 + 2;\nclock();");
+        assert_eq!(diagnostics.len(), 1);
+        assert_eq!(diagnostics[0].severity, Severity::Warning);
+        let (_, diagnostics) =
+            parse_program(&String::from("clock();\n1 + 2;"), true, Dialect::default());
+        assert!(diagnostics.is_empty());
+    }
+}
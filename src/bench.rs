@@ -0,0 +1,104 @@
+use rs_lox::{compile, stdlib, InterpreterError, Sandbox, VMConfig, GC, VM};
+use std::fmt;
+use std::io;
+use std::time::{Duration, Instant};
+
+/// Timings and counters collected by running a script several times.
+/// Instruction and allocation counts are per run, since every run executes
+/// the same program with a fresh GC and VM.
+#[derive(Debug)]
+pub struct BenchReport {
+    pub iterations: u32,
+    pub total: Duration,
+    pub min: Duration,
+    pub max: Duration,
+    pub instructions: u64,
+    pub allocations: usize,
+}
+
+fn millis(duration: Duration) -> f64 {
+    duration.as_secs_f64() * 1000.0
+}
+
+impl fmt::Display for BenchReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mean = self.total / self.iterations.max(1);
+        writeln!(f, "iterations:   {}", self.iterations)?;
+        writeln!(f, "total time:   {:.6} ms", millis(self.total))?;
+        writeln!(f, "mean time:    {:.6} ms", millis(mean))?;
+        writeln!(f, "min time:     {:.6} ms", millis(self.min))?;
+        writeln!(f, "max time:     {:.6} ms", millis(self.max))?;
+        writeln!(f, "instructions: {} per run", self.instructions)?;
+        return writeln!(f, "allocations:  {} per run", self.allocations);
+    }
+}
+
+/// Compiles and runs `source` `iterations` times, discarding its output, the
+/// way `rlox` runs a script. Compilation is included in the measured time.
+pub fn bench(source: &String, iterations: u32) -> Result<BenchReport, InterpreterError> {
+    let mut report = BenchReport {
+        iterations,
+        total: Duration::ZERO,
+        min: Duration::MAX,
+        max: Duration::ZERO,
+        instructions: 0,
+        allocations: 0,
+    };
+    for _ in 0..iterations {
+        let mut sink = io::sink();
+        let mut gc = GC::new();
+        let mut input = io::empty();
+        let mut vm = VM::new(
+            VMConfig {
+                trace_instructions: false,
+                trace_stack: false,
+                trace_globals: false,
+                stdout: &mut sink,
+                stdin: &mut input,
+                sandbox: Sandbox::default(),
+            },
+            &mut gc,
+        );
+        stdlib::install(&mut vm);
+        let baseline = vm.with_gc(|gc| gc.allocation_count());
+        let start = Instant::now();
+        let (mut chunk, _) = vm.with_gc(|gc| compile(source, gc))?;
+        chunk
+            .resolve_global_slots()
+            .map_err(InterpreterError::RuntimeError)?;
+        vm.interpret_chunk(chunk)?;
+        let elapsed = start.elapsed();
+        report.instructions = vm.instruction_count();
+        report.allocations = vm.with_gc(|gc| gc.allocation_count()) - baseline;
+        report.total += elapsed;
+        report.min = report.min.min(elapsed);
+        report.max = report.max.max(elapsed);
+    }
+    if iterations == 0 {
+        report.min = Duration::ZERO;
+    }
+    return Ok(report);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn counts_instructions_and_allocations() {
+        let source = String::from("var a = \"x\"; print a + \"y\";");
+        let report = bench(&source, 3).unwrap();
+        assert_eq!(report.iterations, 3);
+        // Constant, DefineGlobal, Get, Constant, Add, Print, Return
+        assert_eq!(report.instructions, 7);
+        // "a", "x", "y" and "xy"
+        assert_eq!(report.allocations, 4);
+        assert!(report.min <= report.max);
+    }
+
+    #[test]
+    fn reports_runtime_errors() {
+        let source = String::from("print -nil;");
+        assert!(bench(&source, 1).is_err());
+    }
+}
@@ -0,0 +1,172 @@
+use lsp_types::{Diagnostic, DiagnosticSeverity, Position, Range};
+use rs_lox::{compile, InterpreterError, Scanner, Severity, Token, TokenKind, GC};
+use std::convert::TryFrom;
+
+/// Where a token is in the source, in LSP's zero-based lines and UTF-16
+/// columns. The scanner counts columns in characters, from one.
+fn token_range(source: &str, token: &Token) -> Range {
+    let line = u32::try_from(token.line.max(1) - 1).unwrap_or(0);
+    let text = source.lines().nth(line as usize).unwrap_or("");
+    let utf16_len = |s: &str| s.encode_utf16().count() as u32;
+    let before: String = text
+        .chars()
+        .take(usize::from(token.column.max(1)) - 1)
+        .collect();
+    let start = utf16_len(&before);
+    let end = match token.kind {
+        // The lexeme of an error token is its message, not source text
+        TokenKind::Error | TokenKind::Eof => start,
+        _ => start + utf16_len(&token.lexeme),
+    };
+    return Range::new(Position::new(line, start), Position::new(line, end));
+}
+
+fn contains(range: &Range, position: Position) -> bool {
+    return range.start <= position && position <= range.end;
+}
+
+/// Compiles `source` and reports its errors and warnings.
+pub fn diagnostics(source: &str) -> Vec<Diagnostic> {
+    let mut gc = GC::new();
+    let found = match compile(&String::from(source), &mut gc) {
+        Ok((_, warnings)) => warnings,
+        Err(InterpreterError::CompileError(errors)) => errors,
+        Err(_) => Vec::new(),
+    };
+    return found
+        .iter()
+        .map(|diagnostic| Diagnostic {
+            range: token_range(source, &diagnostic.token),
+            severity: Some(match diagnostic.severity {
+                Severity::Warning => DiagnosticSeverity::WARNING,
+                Severity::Error => DiagnosticSeverity::ERROR,
+            }),
+            source: Some(String::from("rlox")),
+            message: diagnostic.message.clone(),
+            ..Diagnostic::default()
+        })
+        .collect();
+}
+
+fn tokens(source: &str) -> Vec<Token> {
+    let source = String::from(source);
+    let mut scanner = Scanner::new(&source);
+    let mut tokens = Vec::new();
+    loop {
+        let token = scanner.scan();
+        if token.kind == TokenKind::Eof {
+            return tokens;
+        }
+        tokens.push(token);
+    }
+}
+
+/// A global declared by `var` or brought in by `import { name }`.
+#[derive(Debug, PartialEq)]
+pub struct Declaration {
+    pub name: String,
+    pub imported: bool,
+    /// The name where it's declared.
+    pub range: Range,
+}
+
+/// Every declaration in `source`, in order. Scripts have no functions or
+/// blocks yet, so these are all globals.
+pub fn declarations(source: &str) -> Vec<Declaration> {
+    let tokens = tokens(source);
+    let mut declarations = Vec::new();
+    let mut declare = |token: &Token, imported| {
+        declarations.push(Declaration {
+            name: token.lexeme.clone(),
+            imported,
+            range: token_range(source, token),
+        });
+    };
+    let mut index = 0;
+    while index < tokens.len() {
+        match tokens[index].kind {
+            TokenKind::Var => {
+                if let Some(name) = tokens.get(index + 1) {
+                    if name.kind == TokenKind::Identifier {
+                        declare(name, false);
+                    }
+                }
+            }
+            TokenKind::Import
+                if tokens.get(index + 1).map(|t| t.kind) == Some(TokenKind::LeftBrace) =>
+            {
+                index += 2;
+                while let Some(token) = tokens.get(index) {
+                    match token.kind {
+                        TokenKind::Identifier => declare(token, true),
+                        TokenKind::Comma => {}
+                        _ => break,
+                    }
+                    index += 1;
+                }
+                continue;
+            }
+            _ => {}
+        }
+        index += 1;
+    }
+    return declarations;
+}
+
+/// Where the global named at `position` is declared, if it's an identifier
+/// with a declaration in `source`.
+pub fn definition(source: &str, position: Position) -> Option<Range> {
+    let name = tokens(source).into_iter().find(|token| {
+        token.kind == TokenKind::Identifier && contains(&token_range(source, token), position)
+    })?;
+    return declarations(source)
+        .into_iter()
+        .find(|declaration| declaration.name == name.lexeme)
+        .map(|declaration| declaration.range);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn range(line: u32, start: u32, end: u32) -> Range {
+        return Range::new(Position::new(line, start), Position::new(line, end));
+    }
+
+    #[test]
+    fn reports_compile_errors_with_ranges() {
+        let found = diagnostics("var a = 1;\nprint a +;");
+        assert_eq!(found.len(), 1);
+        assert_eq!(found[0].range, range(1, 9, 10));
+        assert_eq!(found[0].severity, Some(DiagnosticSeverity::ERROR));
+        assert!(diagnostics("print 1;").is_empty());
+    }
+
+    #[test]
+    fn finds_declarations() {
+        let source = "import { area, pi } from \"geometry.lox\";\nvar r = 2;\nprint area(r);";
+        let found = declarations(source)
+            .into_iter()
+            .map(|d| (d.name, d.imported, d.range))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            found,
+            vec![
+                (String::from("area"), true, range(0, 9, 13)),
+                (String::from("pi"), true, range(0, 15, 17)),
+                (String::from("r"), false, range(1, 4, 5)),
+            ]
+        );
+    }
+
+    #[test]
+    fn goes_to_definition() {
+        let source = "var total = 1;\nprint total + total;";
+        assert_eq!(
+            definition(source, Position::new(1, 15)),
+            Some(range(0, 4, 9))
+        );
+        assert_eq!(definition(source, Position::new(1, 0)), None);
+        assert_eq!(definition("print missing;", Position::new(0, 8)), None);
+    }
+}
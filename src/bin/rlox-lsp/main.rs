@@ -0,0 +1,161 @@
+#![allow(clippy::needless_return)]
+
+//! A language server for Lox over stdio. It reports compile errors and
+//! warnings as documents change, lists their declarations as document
+//! symbols, and finds where globals are declared.
+
+mod analysis;
+
+use lsp_server::{Connection, Message, Notification, Request, RequestId, Response};
+use lsp_types::notification::{
+    DidChangeTextDocument, DidCloseTextDocument, DidOpenTextDocument,
+    Notification as NotificationTrait, PublishDiagnostics,
+};
+use lsp_types::request::{DocumentSymbolRequest, GotoDefinition, Request as RequestTrait};
+use lsp_types::{
+    DocumentSymbol, DocumentSymbolResponse, GotoDefinitionResponse, Location, OneOf,
+    PublishDiagnosticsParams, ServerCapabilities, SymbolKind, TextDocumentSyncCapability,
+    TextDocumentSyncKind, Uri,
+};
+use std::collections::HashMap;
+use std::error::Error;
+
+/// Open documents by URI. `Uri` has interior mutability, so its string is
+/// the key.
+type Documents = HashMap<String, String>;
+
+fn capabilities() -> ServerCapabilities {
+    return ServerCapabilities {
+        text_document_sync: Some(TextDocumentSyncCapability::Kind(TextDocumentSyncKind::FULL)),
+        document_symbol_provider: Some(OneOf::Left(true)),
+        definition_provider: Some(OneOf::Left(true)),
+        ..ServerCapabilities::default()
+    };
+}
+
+fn publish_diagnostics(
+    connection: &Connection,
+    uri: Uri,
+    source: &str,
+) -> Result<(), Box<dyn Error>> {
+    let params = PublishDiagnosticsParams::new(uri, analysis::diagnostics(source), None);
+    let notification = Notification::new(PublishDiagnostics::METHOD.to_string(), params);
+    connection
+        .sender
+        .send(Message::Notification(notification))?;
+    return Ok(());
+}
+
+fn handle_notification(
+    connection: &Connection,
+    documents: &mut Documents,
+    notification: Notification,
+) -> Result<(), Box<dyn Error>> {
+    match notification.method.as_str() {
+        DidOpenTextDocument::METHOD => {
+            let params: <DidOpenTextDocument as NotificationTrait>::Params =
+                serde_json::from_value(notification.params)?;
+            let document = params.text_document;
+            publish_diagnostics(connection, document.uri.clone(), &document.text)?;
+            documents.insert(document.uri.to_string(), document.text);
+        }
+        DidChangeTextDocument::METHOD => {
+            let params: <DidChangeTextDocument as NotificationTrait>::Params =
+                serde_json::from_value(notification.params)?;
+            // Only full syncs are advertised, so the last change is the
+            // whole document
+            if let Some(change) = params.content_changes.into_iter().last() {
+                let uri = params.text_document.uri;
+                publish_diagnostics(connection, uri.clone(), &change.text)?;
+                documents.insert(uri.to_string(), change.text);
+            }
+        }
+        DidCloseTextDocument::METHOD => {
+            let params: <DidCloseTextDocument as NotificationTrait>::Params =
+                serde_json::from_value(notification.params)?;
+            let uri = params.text_document.uri;
+            documents.remove(uri.as_str());
+            // Clear the diagnostics of a closed document
+            publish_diagnostics(connection, uri, "")?;
+        }
+        _ => {}
+    }
+    return Ok(());
+}
+
+#[allow(deprecated)]
+fn document_symbols(source: &str) -> DocumentSymbolResponse {
+    let symbols = analysis::declarations(source)
+        .into_iter()
+        .map(|declaration| DocumentSymbol {
+            name: declaration.name,
+            detail: declaration.imported.then(|| String::from("imported")),
+            kind: SymbolKind::VARIABLE,
+            tags: None,
+            deprecated: None,
+            range: declaration.range,
+            selection_range: declaration.range,
+            children: None,
+        })
+        .collect();
+    return DocumentSymbolResponse::Nested(symbols);
+}
+
+/// Answers `request`, with a null result for documents that aren't open.
+fn handle_request(documents: &Documents, request: Request) -> Result<Response, Box<dyn Error>> {
+    let id: RequestId = request.id.clone();
+    let result = match request.method.as_str() {
+        DocumentSymbolRequest::METHOD => {
+            let params: <DocumentSymbolRequest as RequestTrait>::Params =
+                serde_json::from_value(request.params)?;
+            let symbols = documents
+                .get(params.text_document.uri.as_str())
+                .map(|source| document_symbols(source));
+            serde_json::to_value(symbols)?
+        }
+        GotoDefinition::METHOD => {
+            let params: <GotoDefinition as RequestTrait>::Params =
+                serde_json::from_value(request.params)?;
+            let uri = params.text_document_position_params.text_document.uri;
+            let position = params.text_document_position_params.position;
+            let location = documents
+                .get(uri.as_str())
+                .and_then(|source| analysis::definition(source, position))
+                .map(|range| GotoDefinitionResponse::Scalar(Location::new(uri.clone(), range)));
+            serde_json::to_value(location)?
+        }
+        method => {
+            return Ok(Response::new_err(
+                id,
+                lsp_server::ErrorCode::MethodNotFound as i32,
+                format!("Unsupported request '{}'", method),
+            ))
+        }
+    };
+    return Ok(Response::new_ok(id, result));
+}
+
+fn main() -> Result<(), Box<dyn Error>> {
+    let (connection, io_threads) = Connection::stdio();
+    connection.initialize(serde_json::to_value(capabilities())?)?;
+    let mut documents = Documents::new();
+    for message in &connection.receiver {
+        match message {
+            Message::Request(request) => {
+                if connection.handle_shutdown(&request)? {
+                    break;
+                }
+                let response = handle_request(&documents, request)?;
+                connection.sender.send(Message::Response(response))?;
+            }
+            Message::Notification(notification) => {
+                handle_notification(&connection, &mut documents, notification)?;
+            }
+            Message::Response(_) => {}
+        }
+    }
+    // The writer thread stops once every sender is gone
+    drop(connection);
+    io_threads.join()?;
+    return Ok(());
+}
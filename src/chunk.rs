@@ -1,65 +1,329 @@
+use crate::debug::{decode_instruction, Instruction, Operand};
+use crate::gc::GC;
 use crate::rle::*;
 use crate::value::*;
 use num_derive::FromPrimitive;
 use num_traits::FromPrimitive;
+use std::collections::HashMap;
 use std::convert::TryFrom;
 use std::fmt;
+use std::sync::Arc;
 
+/// Instructions of the bytecode. The numbers are part of the `.loxc` format,
+/// so existing opcodes keep theirs: new ones take the next free number, and
+/// `ENCODED_VERSION` is bumped if a number ever has to change.
 #[derive(Debug, Clone, Copy, PartialEq, FromPrimitive)]
+#[non_exhaustive]
+#[repr(u8)]
 pub enum OpCode {
-    Return,
-    Constant,
-    ConstantLong,
-    Nil,
-    True,
-    False,
-    Pop,
+    Return = 0,
+    Constant = 1,
+    ConstantLong = 2,
+    Nil = 3,
+    True = 4,
+    False = 5,
+    Pop = 6,
+    Get = 7,
+    GetLong = 8,
+    DefineGlobal = 9,
+    DefineGlobalLong = 10,
+    Equal = 11,
+    Greater = 12,
+    Less = 13,
+    Add = 14,
+    Subtract = 15,
+    Multiply = 16,
+    Divide = 17,
+    Not = 18,
+    Negate = 19,
+    Print = 20,
+    Call = 21,
+    Invoke = 22,
+    InvokeLong = 23,
+    Import = 24,
+    ImportAll = 25,
+    ImportName = 26,
+    ImportNameLong = 27,
+    GetSlot = 28,
+    GetSlotLong = 29,
+    DefineSlot = 30,
+    DefineSlotLong = 31,
+}
+
+/// How an opcode is laid out and what it does to the stack.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct OpInfo {
+    /// Bytes of operands after the opcode.
+    pub operand_bytes: usize,
+    /// Values popped. Calls also pop their arguments, which
+    /// `Instruction::stack_effect` adds.
+    pub pops: usize,
+    pub pushes: usize,
+}
+
+impl OpCode {
+    pub fn info(self) -> OpInfo {
+        use OpCode::*;
+        let (operand_bytes, pops, pushes) = match self {
+            // Returns the value on top of the stack if there is one
+            Return => (0, 0, 0),
+            Constant | Get | GetSlot => (1, 0, 1),
+            ConstantLong | GetLong | GetSlotLong => (2, 0, 1),
+            Nil | True | False => (0, 0, 1),
+            Pop | Print => (0, 1, 0),
+            DefineGlobal | DefineSlot => (1, 1, 0),
+            DefineGlobalLong | DefineSlotLong => (2, 1, 0),
+            Equal | Greater | Less | Add | Subtract | Multiply | Divide => (0, 2, 1),
+            Not | Negate => (0, 1, 1),
+            // The callee or receiver, then the argument count
+            Call => (1, 1, 1),
+            Invoke => (2, 1, 1),
+            InvokeLong => (3, 1, 1),
+            // The module path is replaced by the module's key, which the
+            // following instructions read in place
+            Import | ImportAll => (0, 1, 1),
+            ImportName => (1, 1, 1),
+            ImportNameLong => (2, 1, 1),
+        };
+        return OpInfo {
+            operand_bytes,
+            pops,
+            pushes,
+        };
+    }
+}
+
+/// Width of a constant index operand.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OperandWidth {
+    Byte,
+    Short,
+}
+
+impl OperandWidth {
+    /// Number of bytes the operand takes.
+    pub fn size(self) -> usize {
+        return match self {
+            OperandWidth::Byte => 1,
+            OperandWidth::Short => 2,
+        };
+    }
+}
+
+macro_rules! const_ops {
+    ($($op:ident => $byte_op:ident, $long_op:ident;)*) => {
+        /// An instruction that refers to a constant, whatever the width of
+        /// its index. Each has a one byte opcode and a `...Long` one with a
+        /// two byte index.
+        #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+        pub enum ConstOp {
+            $($op,)*
+        }
+
+        impl ConstOp {
+            pub const ALL: &'static [ConstOp] = &[$(ConstOp::$op,)*];
+
+            /// The opcode for this instruction with an index of `width`.
+            pub fn opcode(self, width: OperandWidth) -> OpCode {
+                return match (self, width) {
+                    $(
+                        (ConstOp::$op, OperandWidth::Byte) => OpCode::$byte_op,
+                        (ConstOp::$op, OperandWidth::Short) => OpCode::$long_op,
+                    )*
+                };
+            }
+
+            /// Splits an opcode into the instruction and the width of its
+            /// index, or returns `None` if it doesn't refer to a constant.
+            pub fn from_opcode(opcode: OpCode) -> Option<(ConstOp, OperandWidth)> {
+                return match opcode {
+                    $(
+                        OpCode::$byte_op => Some((ConstOp::$op, OperandWidth::Byte)),
+                        OpCode::$long_op => Some((ConstOp::$op, OperandWidth::Short)),
+                    )*
+                    _ => None,
+                };
+            }
+        }
+    };
+}
+
+// Every opcode with a constant operand, in both widths. New ones only need
+// a line here for the compiler, verifier and disassembler to handle them.
+const_ops! {
+    Constant => Constant, ConstantLong;
+    Get => Get, GetLong;
+    DefineGlobal => DefineGlobal, DefineGlobalLong;
+    Invoke => Invoke, InvokeLong;
+    ImportName => ImportName, ImportNameLong;
+}
+
+/// An instruction on a global slot, which `Chunk::resolve_global_slots`
+/// puts in place of the instruction on a global's name.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SlotOp {
     Get,
-    GetLong,
-    DefineGlobal,
-    DefineGlobalLong,
-    Equal,
-    Greater,
-    Less,
-    Add,
-    Subtract,
-    Multiply,
-    Divide,
-    Not,
-    Negate,
-    Print,
+    Define,
+}
+
+impl SlotOp {
+    /// The opcode for this instruction with a slot of `width`.
+    pub fn opcode(self, width: OperandWidth) -> OpCode {
+        return match (self, width) {
+            (SlotOp::Get, OperandWidth::Byte) => OpCode::GetSlot,
+            (SlotOp::Get, OperandWidth::Short) => OpCode::GetSlotLong,
+            (SlotOp::Define, OperandWidth::Byte) => OpCode::DefineSlot,
+            (SlotOp::Define, OperandWidth::Short) => OpCode::DefineSlotLong,
+        };
+    }
+
+    /// Splits an opcode into the instruction and the width of its slot, or
+    /// returns `None` if it doesn't refer to a slot.
+    pub fn from_opcode(opcode: OpCode) -> Option<(SlotOp, OperandWidth)> {
+        return match opcode {
+            OpCode::GetSlot => Some((SlotOp::Get, OperandWidth::Byte)),
+            OpCode::GetSlotLong => Some((SlotOp::Get, OperandWidth::Short)),
+            OpCode::DefineSlot => Some((SlotOp::Define, OperandWidth::Byte)),
+            OpCode::DefineSlotLong => Some((SlotOp::Define, OperandWidth::Short)),
+            _ => None,
+        };
+    }
+
+    /// The instruction on a global's name that this one replaces.
+    fn from_const_op(op: ConstOp) -> Option<SlotOp> {
+        return match op {
+            ConstOp::Get => Some(SlotOp::Get),
+            ConstOp::DefineGlobal => Some(SlotOp::Define),
+            _ => None,
+        };
+    }
 }
 
 pub type LineNumber = i16;
+/// Column of the token an instruction came from, starting from 1. Zero means
+/// the column isn't known.
+pub type ColumnNumber = u16;
 
+/// Most constants a chunk can refer to, since the widest operand is two
+/// bytes.
+pub const MAX_CONSTANTS: usize = u16::MAX as usize + 1;
+
+/// Bytecode taken out of a chunk, with the position of each byte.
+pub(crate) type PositionedCode = Vec<(u8, LineNumber, ColumnNumber)>;
+
+/// Starts every chunk written by `SharedChunk::encode`.
+const ENCODED_MAGIC: &[u8; 4] = b"LOXC";
+/// Version of the encoding, which covers the opcode numbering. Bump it when
+/// either changes so stale files are rejected instead of misread; the
+/// `opcode_numbers_are_stable` test fails as a reminder.
+const ENCODED_VERSION: u8 = 3;
+
+#[derive(Clone)]
 pub struct Chunk {
-    code: Vec<u8>,
+    // Code and debug info are shared with `SharedChunk`s made from this
+    // chunk and copied on write.
+    code: Arc<Vec<u8>>,
     constants: ValueArray,
-    lines: Rle<LineNumber>,
+    lines: Arc<Rle<LineNumber>>,
+    columns: Arc<Rle<ColumnNumber>>,
+    /// Stack slots the code needs, recorded by `record_max_stack` and
+    /// cleared by any write.
+    max_stack: Option<usize>,
+    /// The constant naming each global slot, set by `resolve_global_slots`.
+    global_slots: Arc<[usize]>,
+}
+
+/// How much space a chunk's debug info takes. Lines and columns are stored
+/// for each byte, but operands share their instruction's position, so each
+/// run covers at least a whole instruction.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DebugInfoSize {
+    pub code_bytes: usize,
+    pub line_runs: usize,
+    pub column_runs: usize,
+    /// Size of both tables in the `.loxc` encoding.
+    pub encoded_bytes: usize,
 }
 
 impl fmt::Debug for Chunk {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> std::fmt::Result {
         f.debug_struct("Chunk")
-            .field("code", &format_args!("{:#?}", self.code.iter().map(|b| OpCode::from_u8(*b).unwrap()).collect::<Vec<OpCode>>()))
+            .field(
+                "code",
+                &format_args!(
+                    "{:#?}",
+                    self.code
+                        .iter()
+                        .map(|b| OpCode::from_u8(*b).unwrap())
+                        .collect::<Vec<OpCode>>()
+                ),
+            )
             .field("constants", &self.constants)
-            .field("lines", &self.lines)
+            .field("lines", &self.lines.iter().collect::<Vec<_>>())
+            .field("columns", &self.columns.iter().collect::<Vec<_>>())
+            .field("global_slots", &self.global_slots)
             .finish()
     }
 }
 
+impl Default for Chunk {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl Chunk {
     pub fn new() -> Chunk {
         Chunk {
-            code: Vec::new(),
+            code: Arc::new(Vec::new()),
             constants: ValueArray::new(),
-            lines: Rle::new(),
+            lines: Arc::new(Rle::new()),
+            columns: Arc::new(Rle::new()),
+            max_stack: None,
+            global_slots: Arc::from([]),
         }
     }
 
+    /// Writes a byte without a known column.
     pub fn write_byte(&mut self, op: u8, line: LineNumber) {
-        self.code.push(op);
-        self.lines.push(line);
+        self.write_byte_at(op, line, 0);
+    }
+
+    pub fn write_byte_at(&mut self, op: u8, line: LineNumber, column: ColumnNumber) {
+        Arc::make_mut(&mut self.code).push(op);
+        Arc::make_mut(&mut self.lines).push(line);
+        Arc::make_mut(&mut self.columns).push(column);
+        self.max_stack = None;
+    }
+
+    /// Removes the code from `offset` on and returns each byte with its line
+    /// and column, to be put back later with `append_code`. Constants the
+    /// code refers to stay in the pool.
+    pub(crate) fn split_off_code(&mut self, offset: usize) -> PositionedCode {
+        let code = Arc::make_mut(&mut self.code).split_off(offset);
+        let positions = code
+            .iter()
+            .enumerate()
+            .map(|(index, byte)| {
+                let line = self.lines.get(offset + index).copied().unwrap_or_default();
+                let column = self
+                    .columns
+                    .get(offset + index)
+                    .copied()
+                    .unwrap_or_default();
+                (*byte, line, column)
+            })
+            .collect();
+        Arc::make_mut(&mut self.lines).truncate(offset);
+        Arc::make_mut(&mut self.columns).truncate(offset);
+        self.max_stack = None;
+        return positions;
+    }
+
+    pub(crate) fn append_code(&mut self, code: PositionedCode) {
+        for (byte, line, column) in code {
+            self.write_byte_at(byte, line, column);
+        }
     }
 
     /// Helper method to write an OpCode, functionally equal to `write_byte`
@@ -68,12 +332,14 @@ impl Chunk {
     }
 
     pub fn write_short(&mut self, value: u16, line: LineNumber) {
+        self.write_short_at(value, line, 0);
+    }
+
+    pub fn write_short_at(&mut self, value: u16, line: LineNumber, column: ColumnNumber) {
         // LITTLE ENDIAN
         let [a, b] = value.to_be_bytes();
-        self.code.push(a);
-        self.code.push(b);
-        self.lines.push(line);
-        self.lines.push(line);
+        self.write_byte_at(a, line, column);
+        self.write_byte_at(b, line, column);
     }
 
     pub fn read_byte(&self, index: usize) -> Option<u8> {
@@ -95,34 +361,577 @@ impl Chunk {
         self.lines.get(offset)
     }
 
+    pub fn get_column(&self, offset: usize) -> Option<&ColumnNumber> {
+        self.columns.get(offset)
+    }
+
+    pub fn debug_info_size(&self) -> DebugInfoSize {
+        let mut encoded = Vec::new();
+        write_runs(&mut encoded, &self.lines);
+        write_runs(&mut encoded, &self.columns);
+        return DebugInfoSize {
+            code_bytes: self.code.len(),
+            line_runs: self.lines.iter_runs().count(),
+            column_runs: self.columns.iter_runs().count(),
+            encoded_bytes: encoded.len(),
+        };
+    }
+
     pub fn add_const(&mut self, value: Value) -> usize {
         self.constants.push(value);
         return self.constants.len() - 1;
     }
 
-    pub fn ref_const(
+    pub fn ref_const(&mut self, const_ref: usize, op: ConstOp, line: LineNumber) {
+        self.ref_const_at(const_ref, op, line, 0);
+    }
+
+    /// Writes `op` with the narrowest opcode that can hold `const_ref`.
+    pub fn ref_const_at(
         &mut self,
         const_ref: usize,
-        byte_op: OpCode,
-        long_op: OpCode,
+        op: ConstOp,
         line: LineNumber,
+        column: ColumnNumber,
     ) {
         if let Ok(const_byte) = u8::try_from(const_ref) {
-            self.write_opcode(byte_op, line);
-            self.write_byte(const_byte, line);
+            self.write_byte_at(op.opcode(OperandWidth::Byte) as u8, line, column);
+            self.write_byte_at(const_byte, line, column);
         } else if let Ok(const_long) = u16::try_from(const_ref) {
-            self.write_opcode(long_op, line);
-            self.write_short(const_long, line);
+            self.write_byte_at(op.opcode(OperandWidth::Short) as u8, line, column);
+            self.write_short_at(const_long, line, column);
         } else {
             panic!("Invalid constant reference");
         }
     }
 
-    pub fn get_constant(&self, offset: usize) -> Value {
-        match self.constants.get(offset) {
-            Some(value) => value.clone(),
-            None => panic!("Invalid constant reference: {}", offset),
+    pub fn constant_count(&self) -> usize {
+        self.constants.len()
+    }
+
+    pub fn get_constants(&self) -> &[Value] {
+        &self.constants
+    }
+
+    pub fn get_constant(&self, offset: usize) -> Option<Value> {
+        return self.constants.get(offset).copied();
+    }
+
+    /// The constant naming each global slot, by slot. Empty unless the
+    /// globals were resolved with `resolve_global_slots`.
+    pub fn global_slots(&self) -> &[usize] {
+        &self.global_slots
+    }
+
+    /// The name of global `slot`, if there is such a slot.
+    pub fn global_slot_name(&self, slot: usize) -> Option<Value> {
+        let index = *self.global_slots.get(slot)?;
+        return self.get_constant(index);
+    }
+
+    /// Decodes each instruction in turn with its offset. Stops early at an
+    /// instruction that doesn't decode, which `Instructions::check_end`
+    /// reports.
+    pub fn instructions(&self) -> Instructions<'_> {
+        return Instructions {
+            chunk: self,
+            offset: 0,
+        };
+    }
+
+    /// Finds the most values the code has on the stack at once, failing if
+    /// an instruction would pop more than there are. The code runs straight
+    /// through, as there are no jumps.
+    pub fn stack_depth(&self) -> Result<usize, String> {
+        let (max, result) = self.walk_stack();
+        result?;
+        return Ok(max);
+    }
+
+    /// The most values the code can have on the stack at once when run from
+    /// an empty stack, even if it's invalid. That's the depth up to the first
+    /// instruction that `stack_depth` fails at, where the VM stops too.
+    pub fn stack_bound(&self) -> usize {
+        return self.walk_stack().0;
+    }
+
+    /// Walks the code as `stack_depth` does, returning the deepest stack
+    /// before it ended or failed.
+    fn walk_stack(&self) -> (usize, Result<(), String>) {
+        let mut depth: usize = 0;
+        let mut max = 0;
+        let mut instructions = self.instructions();
+        for (offset, instruction) in &mut instructions {
+            let (pops, pushes) = instruction.stack_effect();
+            depth = match depth.checked_sub(pops) {
+                Some(depth) => depth,
+                None => return (max, Err(format!("Stack underflow at offset {}", offset))),
+            };
+            depth += pushes;
+            max = max.max(depth);
+        }
+        return (max, instructions.check_end());
+    }
+
+    /// Records `stack_depth` so the VM can check that the chunk fits on the
+    /// stack before running it.
+    pub fn record_max_stack(&mut self) -> Result<usize, String> {
+        let depth = self.stack_depth()?;
+        self.max_stack = Some(depth);
+        return Ok(depth);
+    }
+
+    /// The depth saved by `record_max_stack`, unless the chunk was written
+    /// to since.
+    pub fn max_stack(&self) -> Option<usize> {
+        return self.max_stack;
+    }
+
+    /// Gives every global the code names a dense slot, and turns each `Get`
+    /// and `DefineGlobal` into `GetSlot` and `DefineSlot` on it, so the VM
+    /// indexes an array instead of looking names up. Slots are numbered in
+    /// the order names first appear among the constants, so a slot is never
+    /// wider than a constant it replaces and the code keeps its layout.
+    ///
+    /// A slot is bound to the global of its name when the chunk is loaded.
+    /// One the script defines later, or that a module it imports defines, is
+    /// bound when it's defined. Fails if the code doesn't decode or the
+    /// globals were already resolved.
+    pub fn resolve_global_slots(&mut self) -> Result<(), String> {
+        if !self.global_slots.is_empty() {
+            return Err(String::from("Globals are already resolved to slots"));
+        }
+        let mut sites = Vec::new();
+        let mut instructions = self.instructions();
+        for (offset, instruction) in &mut instructions {
+            let const_op = ConstOp::from_opcode(instruction.opcode);
+            let slot_op =
+                const_op.and_then(|(op, width)| Some((SlotOp::from_const_op(op)?, width)));
+            if let (Some((op, width)), Some(Operand::Constant(index))) =
+                (slot_op, instruction.operands.first())
+            {
+                sites.push((offset, op, width, *index));
+            }
+        }
+        instructions.check_end()?;
+        // Each site has its own constant, so sites share a slot by name
+        let mut indices = sites
+            .iter()
+            .map(|(_, _, _, index)| *index)
+            .collect::<Vec<_>>();
+        indices.sort_unstable();
+        indices.dedup();
+        let mut names = Vec::new();
+        let mut slots_by_name = HashMap::new();
+        let mut slots_by_index = HashMap::new();
+        for index in indices {
+            let name = self.get_constant(index);
+            let slot = match name.as_ref().and_then(Value::as_string) {
+                Some(name) => *slots_by_name
+                    .entry(name.get_value().clone())
+                    .or_insert(names.len()),
+                None => names.len(),
+            };
+            if slot == names.len() {
+                names.push(index);
+            }
+            slots_by_index.insert(index, slot);
+        }
+        let code = Arc::make_mut(&mut self.code);
+        for (offset, op, width, index) in sites {
+            // Slots are numbered in the order of their names' first
+            // constants, so a slot is at most the index it replaces
+            let slot = slots_by_index[&index];
+            code[offset] = op.opcode(width) as u8;
+            match width {
+                OperandWidth::Byte => code[offset + 1] = slot as u8,
+                OperandWidth::Short => {
+                    code[offset + 1..offset + 3].copy_from_slice(&(slot as u16).to_be_bytes())
+                }
+            }
+        }
+        self.global_slots = Arc::from(names);
+        return Ok(());
+    }
+
+    /// Checks that every instruction decodes, refers to constants that
+    /// exist, and that the code ends with `OP_RETURN`, so the VM can't run
+    /// past it. Compiled chunks always pass; this is for ones read from
+    /// elsewhere, such as a `.loxc` file.
+    pub fn verify(&self) -> Result<(), String> {
+        self.stack_depth()?;
+        let mut instructions = self.instructions();
+        let mut last = None;
+        for (offset, instruction) in &mut instructions {
+            for operand in &instruction.operands {
+                match operand {
+                    Operand::Constant(index) if *index >= self.constants.len() => {
+                        return Err(format!(
+                            "Invalid constant reference {} at offset {}",
+                            index, offset
+                        ));
+                    }
+                    Operand::Slot(slot) if *slot >= self.global_slots.len() => {
+                        return Err(format!("Invalid global slot {} at offset {}", slot, offset));
+                    }
+                    _ => {}
+                }
+            }
+            last = Some(instruction.opcode);
+        }
+        instructions.check_end()?;
+        if last != Some(OpCode::Return) {
+            return Err(String::from("Chunk doesn't end with OP_RETURN"));
+        }
+        for index in self.global_slots.iter() {
+            let name = self.get_constant(*index);
+            if name.as_ref().and_then(Value::as_string).is_none() {
+                return Err(format!("Global slot named by invalid constant {}", index));
+            }
+        }
+        return Ok(());
+    }
+
+    /// Appends the code and constants of `other`, which must use the same GC
+    /// as this chunk. Constant operands in `other` are renumbered to follow
+    /// this chunk's constants, switching to the long form of an instruction
+    /// when the new index doesn't fit in a byte. Nothing is appended if
+    /// `other` doesn't decode or has too many constants to address, or if
+    /// either chunk has global slots.
+    pub fn append(&mut self, other: &Chunk) -> Result<(), String> {
+        if !self.global_slots.is_empty() || !other.global_slots.is_empty() {
+            return Err(String::from("Can't append chunks with global slots"));
+        }
+        let base = self.constants.len();
+        if base + other.constants.len() > usize::from(u16::MAX) + 1 {
+            return Err(String::from("Too many constants in appended chunk"));
+        }
+        let mut decoded = other.instructions();
+        let instructions = (&mut decoded)
+            .map(|(offset, instruction)| (instruction, *other.get_column(offset).unwrap()))
+            .collect::<Vec<_>>();
+        decoded.check_end()?;
+        for value in other.constants.iter() {
+            self.add_const(*value);
+        }
+        for (instruction, column) in instructions {
+            let line = instruction.line;
+            if let Some(Operand::Constant(index)) = instruction.operands.first() {
+                let (op, _) = ConstOp::from_opcode(instruction.opcode).unwrap();
+                self.ref_const_at(base + index, op, line, column);
+            } else {
+                self.write_byte_at(instruction.opcode as u8, line, column);
+            }
+            for operand in &instruction.operands {
+                if let Operand::Byte(byte) = operand {
+                    self.write_byte_at(*byte, line, column);
+                }
+            }
+        }
+        return Ok(());
+    }
+
+    /// Makes a copy of this chunk that doesn't depend on the GC it was
+    /// compiled with. Fails if a constant is an object other than a string.
+    pub fn share(&self) -> Result<SharedChunk, String> {
+        let constants = self
+            .constants
+            .iter()
+            .map(|value| SharedConstant::from_value(*value))
+            .collect::<Result<Vec<_>, _>>()?;
+        return Ok(SharedChunk {
+            code: Arc::clone(&self.code),
+            constants: Arc::from(constants),
+            lines: Arc::clone(&self.lines),
+            columns: Arc::clone(&self.columns),
+            max_stack: self.max_stack,
+            global_slots: Arc::clone(&self.global_slots),
+        });
+    }
+}
+
+/// Iterator over a chunk's instructions, from `Chunk::instructions`.
+pub struct Instructions<'a> {
+    chunk: &'a Chunk,
+    offset: usize,
+}
+
+impl<'a> Instructions<'a> {
+    /// Where the next instruction starts. If iteration stopped early, this
+    /// is the instruction that failed to decode.
+    pub fn offset(&self) -> usize {
+        return self.offset;
+    }
+
+    /// Fails if iteration stopped before the end of the code.
+    pub fn check_end(&self) -> Result<(), String> {
+        if self.offset < self.chunk.code.len() {
+            return Err(format!("Invalid instruction at offset {}", self.offset));
+        }
+        return Ok(());
+    }
+}
+
+impl<'a> Iterator for Instructions<'a> {
+    type Item = (usize, Instruction);
+
+    fn next(&mut self) -> Option<(usize, Instruction)> {
+        let offset = self.offset;
+        let instruction = decode_instruction(self.chunk, offset)?;
+        self.offset += instruction.byte_len;
+        return Some((offset, instruction));
+    }
+}
+
+/// A constant that isn't tied to a GC.
+#[derive(Debug, Clone, PartialEq)]
+pub enum SharedConstant {
+    Nil,
+    Boolean(bool),
+    Number(f32),
+    Int(i64),
+    String(String),
+}
+
+impl SharedConstant {
+    fn from_value(value: Value) -> Result<SharedConstant, String> {
+        return match value {
+            Value::Nil => Ok(SharedConstant::Nil),
+            Value::Boolean(b) => Ok(SharedConstant::Boolean(b)),
+            Value::Number(n) => Ok(SharedConstant::Number(n)),
+            Value::Int(n) => Ok(SharedConstant::Int(n)),
+            Value::Object(obj) => match obj.as_string() {
+                Some(s) => Ok(SharedConstant::String(s.get_value().clone())),
+                None => Err(format!("Constant {} can't be shared", value)),
+            },
+        };
+    }
+
+    fn to_value(&self, gc: &mut GC) -> Value {
+        return match self {
+            SharedConstant::Nil => Value::Nil,
+            SharedConstant::Boolean(b) => Value::Boolean(*b),
+            SharedConstant::Number(n) => Value::Number(*n),
+            SharedConstant::Int(n) => Value::Int(*n),
+            SharedConstant::String(s) => Value::Object(gc.alloc_string(s.clone())),
+        };
+    }
+}
+
+/// An immutable compiled chunk that can be sent to other threads and run by
+/// any number of VMs. Each VM gets its own `Chunk` from `instantiate`, which
+/// shares the bytecode and only allocates the constants in the VM's GC.
+#[derive(Debug, Clone)]
+pub struct SharedChunk {
+    code: Arc<Vec<u8>>,
+    constants: Arc<[SharedConstant]>,
+    lines: Arc<Rle<LineNumber>>,
+    columns: Arc<Rle<ColumnNumber>>,
+    max_stack: Option<usize>,
+    global_slots: Arc<[usize]>,
+}
+
+impl SharedChunk {
+    pub fn instantiate(&self, gc: &mut GC) -> Chunk {
+        Chunk {
+            code: Arc::clone(&self.code),
+            constants: self.constants.iter().map(|c| c.to_value(gc)).collect(),
+            lines: Arc::clone(&self.lines),
+            columns: Arc::clone(&self.columns),
+            max_stack: self.max_stack,
+            global_slots: Arc::clone(&self.global_slots),
+        }
+    }
+
+    pub fn get_code(&self) -> &[u8] {
+        &self.code
+    }
+
+    pub fn get_constants(&self) -> &[SharedConstant] {
+        &self.constants
+    }
+
+    /// Serializes the chunk, for example to cache it in a `.loxc` file.
+    pub fn encode(&self) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        bytes.extend_from_slice(ENCODED_MAGIC);
+        bytes.push(ENCODED_VERSION);
+        write_u32(&mut bytes, self.code.len());
+        bytes.extend_from_slice(&self.code);
+        write_u32(&mut bytes, self.constants.len());
+        for constant in self.constants.iter() {
+            match constant {
+                SharedConstant::Nil => bytes.push(0),
+                SharedConstant::Boolean(b) => bytes.extend_from_slice(&[1, *b as u8]),
+                SharedConstant::Number(n) => {
+                    bytes.push(2);
+                    bytes.extend_from_slice(&n.to_le_bytes());
+                }
+                SharedConstant::String(s) => {
+                    bytes.push(3);
+                    write_u32(&mut bytes, s.len());
+                    bytes.extend_from_slice(s.as_bytes());
+                }
+                SharedConstant::Int(n) => {
+                    bytes.push(4);
+                    bytes.extend_from_slice(&n.to_le_bytes());
+                }
+            }
+        }
+        write_runs(&mut bytes, &self.lines);
+        write_runs(&mut bytes, &self.columns);
+        write_varint(&mut bytes, self.global_slots.len() as u64);
+        for index in self.global_slots.iter() {
+            write_varint(&mut bytes, *index as u64);
+        }
+        return bytes;
+    }
+
+    /// Reads a chunk written by `encode`.
+    pub fn decode(bytes: &[u8]) -> Result<SharedChunk, String> {
+        let mut reader = Reader { bytes, offset: 0 };
+        if reader.take(4)? != ENCODED_MAGIC {
+            return Err(String::from("Not a compiled Lox chunk"));
+        }
+        let version = reader.take(1)?[0];
+        if version != ENCODED_VERSION {
+            return Err(format!("Unsupported chunk version {}", version));
+        }
+        let code_len = reader.read_u32()?;
+        let code = reader.take(code_len)?.to_vec();
+        let mut constants = Vec::new();
+        for _ in 0..reader.read_u32()? {
+            let constant = match reader.take(1)?[0] {
+                0 => SharedConstant::Nil,
+                1 => SharedConstant::Boolean(reader.take(1)?[0] != 0),
+                2 => {
+                    let n = <[u8; 4]>::try_from(reader.take(4)?).unwrap();
+                    SharedConstant::Number(f32::from_le_bytes(n))
+                }
+                3 => {
+                    let len = reader.read_u32()?;
+                    let s = std::str::from_utf8(reader.take(len)?)
+                        .map_err(|_| String::from("Invalid string constant"))?;
+                    SharedConstant::String(String::from(s))
+                }
+                4 => {
+                    let n = <[u8; 8]>::try_from(reader.take(8)?).unwrap();
+                    SharedConstant::Int(i64::from_le_bytes(n))
+                }
+                tag => return Err(format!("Unknown constant tag {}", tag)),
+            };
+            constants.push(constant);
+        }
+        let lines = reader.read_runs(code_len, "Line")?;
+        let columns = reader.read_runs(code_len, "Column")?;
+        let mut global_slots = Vec::new();
+        for _ in 0..reader.read_varint()? {
+            let index = usize::try_from(reader.read_varint()?)
+                .map_err(|_| String::from("Invalid global slot"))?;
+            global_slots.push(index);
+        }
+        if reader.offset != bytes.len() {
+            return Err(String::from("Unexpected data after chunk"));
         }
+        return Ok(SharedChunk {
+            code: Arc::new(code),
+            constants: Arc::from(constants),
+            lines: Arc::new(lines),
+            columns: Arc::new(columns),
+            max_stack: None,
+            global_slots: Arc::from(global_slots),
+        });
+    }
+}
+
+fn write_u32(bytes: &mut Vec<u8>, value: usize) {
+    bytes.extend_from_slice(&(value as u32).to_le_bytes());
+}
+
+/// Writes `value` seven bits at a time, low bits first, with the high bit of
+/// each byte set if more follow.
+fn write_varint(bytes: &mut Vec<u8>, mut value: u64) {
+    while value >= 0x80 {
+        bytes.push(value as u8 | 0x80);
+        value >>= 7;
+    }
+    bytes.push(value as u8);
+}
+
+/// Writes a line or column table as the number of runs, then each run's
+/// difference from the previous run's value and its length. Nearby
+/// instructions have nearby positions, so most differences fit in a byte.
+fn write_runs<T: Eq + fmt::Debug + Copy + Into<i64>>(bytes: &mut Vec<u8>, rle: &Rle<T>) {
+    write_varint(bytes, rle.iter_runs().count() as u64);
+    let mut previous = 0;
+    for (value, count) in rle.iter_runs() {
+        let value = value.into();
+        let delta = value - previous;
+        // Zigzag, so small negative differences are small too
+        write_varint(bytes, ((delta << 1) ^ (delta >> 63)) as u64);
+        write_varint(bytes, count as u64);
+        previous = value;
+    }
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+    offset: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, len: usize) -> Result<&'a [u8], String> {
+        let end = self.offset + len;
+        if end > self.bytes.len() {
+            return Err(String::from("Compiled chunk is truncated"));
+        }
+        let bytes = &self.bytes[self.offset..end];
+        self.offset = end;
+        return Ok(bytes);
+    }
+
+    fn read_u32(&mut self) -> Result<usize, String> {
+        let bytes = <[u8; 4]>::try_from(self.take(4)?).unwrap();
+        return Ok(u32::from_le_bytes(bytes) as usize);
+    }
+
+    fn read_varint(&mut self) -> Result<u64, String> {
+        let mut value = 0;
+        for shift in (0..64).step_by(7) {
+            let byte = self.take(1)?[0];
+            value |= u64::from(byte & 0x7f) << shift;
+            if byte & 0x80 == 0 {
+                return Ok(value);
+            }
+        }
+        return Err(String::from("Invalid integer in compiled chunk"));
+    }
+
+    /// Reads a table written by `write_runs`, which must cover `len` bytes
+    /// of code. `name` says which table it is in errors.
+    fn read_runs<T: Eq + fmt::Debug + Copy + TryFrom<i64>>(
+        &mut self,
+        len: usize,
+        name: &str,
+    ) -> Result<Rle<T>, String> {
+        let mismatch = || format!("{} numbers don't match the code", name);
+        let mut rle = Rle::new();
+        let mut previous: i64 = 0;
+        for _ in 0..self.read_varint()? {
+            let zigzag = self.read_varint()?;
+            let delta = (zigzag >> 1) as i64 ^ -((zigzag & 1) as i64);
+            let value = previous.checked_add(delta).ok_or_else(mismatch)?;
+            let count = self.read_varint()?;
+            if count > (len - rle.len()) as u64 {
+                return Err(mismatch());
+            }
+            rle.push_n(T::try_from(value).map_err(|_| mismatch())?, count as usize);
+            previous = value;
+        }
+        if rle.len() != len {
+            return Err(mismatch());
+        }
+        return Ok(rle);
     }
 }
 
@@ -130,6 +939,7 @@ impl Chunk {
 mod tests {
 
     use super::*;
+    use crate::native::ObjNative;
 
     #[test]
     fn returns_correct_line() {
@@ -163,16 +973,16 @@ mod tests {
     fn adds_correct_constant() {
         let mut chunk = Chunk::new();
         chunk.add_const(Value::Number(1.2));
-        assert_eq!(chunk.get_constant(0), Value::Number(1.2));
+        assert_eq!(chunk.get_constant(0), Some(Value::Number(1.2)));
     }
 
     #[test]
     fn writes_constant() {
         let mut chunk = Chunk::new();
         let const_ref = chunk.add_const(Value::Number(1.2));
-        chunk.ref_const(const_ref, OpCode::Constant, OpCode::ConstantLong, 1);
+        chunk.ref_const(const_ref, ConstOp::Constant, 1);
         assert_eq!(chunk.get_code(), &[OpCode::Constant as u8, 0]);
-        assert_eq!(chunk.get_constant(0), Value::Number(1.2));
+        assert_eq!(chunk.get_constant(0), Some(Value::Number(1.2)));
     }
 
     #[test]
@@ -182,7 +992,392 @@ mod tests {
             chunk.add_const(Value::Number(i as f32));
         }
         for i in 0..300 {
-            assert_eq!(chunk.get_constant(i), Value::Number(i as f32));
+            assert_eq!(chunk.get_constant(i), Some(Value::Number(i as f32)));
+        }
+    }
+
+    #[test]
+    fn shares_and_instantiates() {
+        let mut gc = GC::new();
+        let mut chunk = Chunk::new();
+        let const_ref = chunk.add_const(Value::Object(gc.alloc_string(String::from("hi"))));
+        chunk.ref_const(const_ref, ConstOp::Constant, 1);
+        chunk.add_const(Value::Number(2.0));
+        let shared = chunk.share().unwrap();
+        assert_eq!(
+            shared.get_constants(),
+            &[
+                SharedConstant::String(String::from("hi")),
+                SharedConstant::Number(2.0)
+            ]
+        );
+
+        let mut other_gc = GC::new();
+        let copy = shared.instantiate(&mut other_gc);
+        assert_eq!(copy.get_code(), chunk.get_code());
+        assert_eq!(copy.get_line(1), Some(&1));
+        assert_eq!(copy.get_constant(0).unwrap().to_string(), "\"hi\"");
+
+        let native = gc.alloc_native(ObjNative::new(
+            String::from("f"),
+            0,
+            Box::new(|_, _| Ok(Value::Nil)),
+        ));
+        chunk.add_const(Value::Object(native));
+        assert!(chunk.share().is_err());
+    }
+
+    #[test]
+    fn encodes_and_decodes() {
+        let mut gc = GC::new();
+        let mut chunk = Chunk::new();
+        let const_ref = chunk.add_const(Value::Object(gc.alloc_string(String::from("hi"))));
+        chunk.ref_const(const_ref, ConstOp::Constant, 1);
+        chunk.add_const(Value::Number(2.5));
+        chunk.add_const(Value::Boolean(true));
+        chunk.add_const(Value::Nil);
+        chunk.add_const(Value::Int(-3));
+        chunk.write_byte_at(OpCode::Print as u8, 1, 7);
+        chunk.write_byte_at(OpCode::Return as u8, 3, 1);
+        let shared = chunk.share().unwrap();
+
+        let bytes = shared.encode();
+        let decoded = SharedChunk::decode(&bytes).unwrap();
+        assert_eq!(decoded.get_code(), shared.get_code());
+        assert_eq!(decoded.get_constants(), shared.get_constants());
+        let copy = decoded.instantiate(&mut gc);
+        assert_eq!(copy.get_line(2), Some(&1));
+        assert_eq!(copy.get_line(3), Some(&3));
+        assert_eq!(copy.get_column(1), Some(&0));
+        assert_eq!(copy.get_column(2), Some(&7));
+        assert_eq!(copy.get_column(3), Some(&1));
+
+        assert!(SharedChunk::decode(&bytes[..bytes.len() - 1]).is_err());
+        assert!(SharedChunk::decode(b"print 1;").is_err());
+    }
+
+    #[test]
+    fn resolves_global_slots() {
+        let mut gc = GC::new();
+        let mut name = |chunk: &mut Chunk, name: &str| {
+            chunk.add_const(Value::Object(gc.alloc_string(String::from(name))))
+        };
+        // Each site has its own constant, as the compiler writes them
+        let mut chunk = Chunk::new();
+        let a = name(&mut chunk, "a");
+        chunk.write_opcode(OpCode::Nil, 1);
+        chunk.ref_const(a, ConstOp::DefineGlobal, 1);
+        let b = name(&mut chunk, "b");
+        chunk.ref_const(b, ConstOp::Get, 1);
+        chunk.write_opcode(OpCode::Pop, 1);
+        for _ in 0..300 {
+            chunk.add_const(Value::Nil);
+        }
+        let a = name(&mut chunk, "a");
+        chunk.ref_const(a, ConstOp::Get, 2);
+        let b = name(&mut chunk, "b");
+        chunk.ref_const(b, ConstOp::Get, 2);
+        chunk.write_opcode(OpCode::Add, 2);
+        chunk.write_opcode(OpCode::Print, 2);
+        chunk.write_opcode(OpCode::Return, 2);
+        chunk.record_max_stack().unwrap();
+
+        chunk.resolve_global_slots().unwrap();
+        assert_eq!(
+            chunk.get_code(),
+            &[
+                OpCode::Nil as u8,
+                OpCode::DefineSlot as u8,
+                0,
+                OpCode::GetSlot as u8,
+                1,
+                OpCode::Pop as u8,
+                // Still long, with room to spare
+                OpCode::GetSlotLong as u8,
+                0,
+                0,
+                OpCode::GetSlotLong as u8,
+                0,
+                1,
+                OpCode::Add as u8,
+                OpCode::Print as u8,
+                OpCode::Return as u8,
+            ]
+        );
+        assert_eq!(chunk.global_slots(), &[0, 1]);
+        assert_eq!(
+            chunk
+                .global_slot_name(1)
+                .unwrap()
+                .as_string()
+                .unwrap()
+                .get_value(),
+            "b"
+        );
+        assert_eq!(chunk.max_stack(), Some(2));
+        assert_eq!(chunk.verify(), Ok(()));
+        assert!(chunk.resolve_global_slots().is_err());
+        assert!(chunk.clone().append(&chunk).is_err());
+
+        let bytes = chunk.share().unwrap().encode();
+        let copy = SharedChunk::decode(&bytes).unwrap().instantiate(&mut gc);
+        assert_eq!(copy.global_slots(), &[0, 1]);
+        assert_eq!(copy.get_code(), chunk.get_code());
+
+        let mut invalid = Chunk::new();
+        invalid.write_opcode(OpCode::GetSlot, 1);
+        invalid.write_byte(0, 1);
+        invalid.write_opcode(OpCode::Return, 1);
+        assert_eq!(
+            invalid.verify(),
+            Err(String::from("Invalid global slot 0 at offset 0"))
+        );
+    }
+
+    #[test]
+    fn verifies_chunks() {
+        let mut chunk = Chunk::new();
+        let const_ref = chunk.add_const(Value::Number(1.0));
+        chunk.ref_const(const_ref, ConstOp::Constant, 1);
+        assert_eq!(
+            chunk.verify(),
+            Err(String::from("Chunk doesn't end with OP_RETURN"))
+        );
+        chunk.write_opcode(OpCode::Return, 1);
+        assert_eq!(chunk.verify(), Ok(()));
+
+        let mut missing = Chunk::new();
+        missing.write_opcode(OpCode::Constant, 1);
+        missing.write_byte(3, 1);
+        missing.write_opcode(OpCode::Return, 1);
+        assert_eq!(
+            missing.verify(),
+            Err(String::from("Invalid constant reference 3 at offset 0"))
+        );
+
+        let mut truncated = Chunk::new();
+        truncated.write_opcode(OpCode::Return, 1);
+        truncated.write_opcode(OpCode::Call, 1);
+        assert_eq!(
+            truncated.verify(),
+            Err(String::from("Invalid instruction at offset 1"))
+        );
+    }
+
+    #[test]
+    fn rejects_mismatched_lines() {
+        let mut chunk = Chunk::new();
+        chunk.write_opcode(OpCode::Return, 1);
+        let mut bytes = chunk.share().unwrap().encode();
+        // The single line run covers one byte; claim it covers two. The
+        // column run and the count of global slots follow it
+        let run_len = bytes.len() - 5;
+        bytes[run_len] = 2;
+        assert_eq!(
+            SharedChunk::decode(&bytes).unwrap_err(),
+            "Line numbers don't match the code"
+        );
+    }
+
+    #[test]
+    fn measures_debug_info() {
+        let mut chunk = Chunk::new();
+        for line in 1..=100 {
+            for column in 1..=5 {
+                let const_ref = chunk.add_const(Value::Nil);
+                chunk.ref_const_at(const_ref % 256, ConstOp::Constant, line, column * 4);
+            }
+        }
+        chunk.write_opcode(OpCode::Return, 100);
+        assert_eq!(
+            chunk.debug_info_size(),
+            DebugInfoSize {
+                code_bytes: 1001,
+                line_runs: 100,
+                column_runs: 501,
+                // Each table starts with its run count, then every run is a
+                // one byte difference and a one byte length
+                encoded_bytes: 1 + 100 * 2 + 2 + 501 * 2,
+            }
+        );
+    }
+
+    #[test]
+    fn appends_chunks() {
+        let mut chunk = Chunk::new();
+        for i in 0..255 {
+            chunk.add_const(Value::Number(i as f32));
+        }
+        chunk.ref_const(0, ConstOp::Constant, 1);
+        chunk.write_opcode(OpCode::Print, 1);
+
+        let mut other = Chunk::new();
+        other.add_const(Value::Number(-1.0));
+        other.add_const(Value::Number(-2.0));
+        other.ref_const_at(0, ConstOp::Constant, 2, 3);
+        other.ref_const_at(1, ConstOp::Invoke, 3, 5);
+        other.write_byte_at(0, 3, 5);
+        other.write_opcode(OpCode::Return, 4);
+        chunk.append(&other).unwrap();
+
+        assert_eq!(chunk.constant_count(), 257);
+        assert_eq!(chunk.get_constant(256), Some(Value::Number(-2.0)));
+        let [high, low] = 256u16.to_be_bytes();
+        assert_eq!(
+            chunk.get_code(),
+            &[
+                OpCode::Constant as u8,
+                0,
+                OpCode::Print as u8,
+                OpCode::Constant as u8,
+                255,
+                // The method name moves to 256, past what a byte can hold
+                OpCode::InvokeLong as u8,
+                high,
+                low,
+                0,
+                OpCode::Return as u8,
+            ]
+        );
+        assert_eq!(chunk.get_line(5), Some(&3));
+        assert_eq!(chunk.get_column(8), Some(&5));
+        assert_eq!(chunk.verify(), Ok(()));
+
+        let mut broken = Chunk::new();
+        broken.write_opcode(OpCode::Call, 1);
+        assert_eq!(
+            chunk.append(&broken),
+            Err(String::from("Invalid instruction at offset 0"))
+        );
+        assert_eq!(chunk.get_code().len(), 10);
+    }
+
+    #[test]
+    fn iterates_instructions() {
+        let mut chunk = Chunk::new();
+        let const_ref = chunk.add_const(Value::Number(1.0));
+        chunk.ref_const(const_ref, ConstOp::Constant, 1);
+        chunk.write_opcode(OpCode::Call, 1);
+        chunk.write_byte(0, 1);
+        chunk.write_opcode(OpCode::Return, 2);
+        let decoded = chunk
+            .instructions()
+            .map(|(offset, instruction)| (offset, instruction.opcode))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            decoded,
+            [
+                (0, OpCode::Constant),
+                (2, OpCode::Call),
+                (4, OpCode::Return)
+            ]
+        );
+
+        chunk.write_byte(0xff, 2);
+        chunk.write_opcode(OpCode::Return, 2);
+        let mut instructions = chunk.instructions();
+        assert_eq!(instructions.by_ref().count(), 3);
+        assert_eq!(instructions.offset(), 5);
+        assert_eq!(
+            instructions.check_end(),
+            Err(String::from("Invalid instruction at offset 5"))
+        );
+    }
+
+    #[test]
+    fn maps_const_ops_to_opcodes() {
+        for op in ConstOp::ALL {
+            for width in [OperandWidth::Byte, OperandWidth::Short] {
+                assert_eq!(ConstOp::from_opcode(op.opcode(width)), Some((*op, width)));
+            }
+        }
+        assert_eq!(ConstOp::from_opcode(OpCode::Call), None);
+
+        let mut chunk = Chunk::new();
+        chunk.ref_const(7, ConstOp::DefineGlobal, 1);
+        chunk.ref_const(300, ConstOp::DefineGlobal, 1);
+        let [high, low] = 300u16.to_be_bytes();
+        assert_eq!(
+            chunk.get_code(),
+            &[
+                OpCode::DefineGlobal as u8,
+                7,
+                OpCode::DefineGlobalLong as u8,
+                high,
+                low
+            ]
+        );
+    }
+
+    #[test]
+    fn measures_stack_depth() {
+        let mut chunk = Chunk::new();
+        let callee = chunk.add_const(Value::Nil);
+        chunk.ref_const(callee, ConstOp::Get, 1);
+        chunk.write_opcode(OpCode::True, 1);
+        chunk.write_opcode(OpCode::False, 1);
+        chunk.write_opcode(OpCode::Call, 1);
+        chunk.write_byte(2, 1);
+        chunk.write_opcode(OpCode::Print, 1);
+        chunk.write_opcode(OpCode::Return, 1);
+        assert_eq!(chunk.stack_depth(), Ok(3));
+        assert_eq!(chunk.max_stack(), None);
+        assert_eq!(chunk.record_max_stack(), Ok(3));
+        assert_eq!(chunk.max_stack(), Some(3));
+        chunk.write_opcode(OpCode::Add, 2);
+        assert_eq!(chunk.max_stack(), None);
+        assert_eq!(
+            chunk.stack_depth(),
+            Err(String::from("Stack underflow at offset 8"))
+        );
+        assert_eq!(
+            chunk.verify(),
+            Err(String::from("Stack underflow at offset 8"))
+        );
+        assert_eq!(chunk.stack_bound(), 3);
+    }
+
+    #[test]
+    fn opcode_numbers_are_stable() {
+        // Changing these breaks existing `.loxc` files; bump ENCODED_VERSION
+        // along with them
+        let numbers = [
+            (OpCode::Return, 0),
+            (OpCode::Constant, 1),
+            (OpCode::ConstantLong, 2),
+            (OpCode::Nil, 3),
+            (OpCode::True, 4),
+            (OpCode::False, 5),
+            (OpCode::Pop, 6),
+            (OpCode::Get, 7),
+            (OpCode::GetLong, 8),
+            (OpCode::DefineGlobal, 9),
+            (OpCode::DefineGlobalLong, 10),
+            (OpCode::Equal, 11),
+            (OpCode::Greater, 12),
+            (OpCode::Less, 13),
+            (OpCode::Add, 14),
+            (OpCode::Subtract, 15),
+            (OpCode::Multiply, 16),
+            (OpCode::Divide, 17),
+            (OpCode::Not, 18),
+            (OpCode::Negate, 19),
+            (OpCode::Print, 20),
+            (OpCode::Call, 21),
+            (OpCode::Invoke, 22),
+            (OpCode::InvokeLong, 23),
+            (OpCode::Import, 24),
+            (OpCode::ImportAll, 25),
+            (OpCode::ImportName, 26),
+            (OpCode::ImportNameLong, 27),
+            (OpCode::GetSlot, 28),
+            (OpCode::GetSlotLong, 29),
+            (OpCode::DefineSlot, 30),
+            (OpCode::DefineSlotLong, 31),
+        ];
+        for (opcode, number) in numbers {
+            assert_eq!(opcode as u8, number);
+            assert_eq!(OpCode::from_u8(number), Some(opcode));
         }
     }
 }
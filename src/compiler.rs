@@ -1,15 +1,16 @@
 use crate::chunk::OpCode::*;
 use crate::chunk::*;
-use crate::gc::{Obj, GC};
+use crate::gc::GC;
 use crate::scanner::TokenKind::*;
 use crate::scanner::*;
 use crate::value::Value;
 use crate::vm::*;
 use num_derive::FromPrimitive;
 use num_traits::FromPrimitive;
+use std::fmt;
 
 #[derive(Debug, FromPrimitive, Clone, Copy)]
-enum Precedence {
+pub(crate) enum Precedence {
     None,
     Assignment,
     Or,
@@ -23,6 +24,24 @@ enum Precedence {
     Primary,
 }
 
+impl Precedence {
+    /// The next higher precedence, which the right operand of a
+    /// left-associative operator is parsed with. `None` for `Primary`.
+    pub(crate) fn next(self) -> Option<Precedence> {
+        return FromPrimitive::from_u8(self as u8 + 1);
+    }
+}
+
+/// Prefix of the message of a diagnostic for a bug in the compiler itself,
+/// which stops compilation instead of panicking.
+pub(crate) const INTERNAL_ERROR: &str = "Internal compiler error: ";
+
+/// Deepest that expressions can nest before it's a compile error. Every
+/// grouping, unary operator and operand of a binary operator, call or method
+/// is a level. The compilers, and passes over the syntax tree, recurse once
+/// per level, and would overflow the host's stack on deeper input.
+pub(crate) const MAX_NESTING: usize = 1024;
+
 type ParseFn = for<'a> fn(compiler: &mut Compiler<'a>);
 
 struct ParseRule {
@@ -35,8 +54,8 @@ fn get_rule(token: TokenKind) -> ParseRule {
     return match token {
         LeftParen => ParseRule {
             prefix: Some(grouping),
-            infix: None,
-            precedence: Precedence::None,
+            infix: Some(call),
+            precedence: Precedence::Call,
         },
         RightParen => ParseRule {
             prefix: None,
@@ -60,8 +79,8 @@ fn get_rule(token: TokenKind) -> ParseRule {
         },
         Dot => ParseRule {
             prefix: None,
-            infix: None,
-            precedence: Precedence::None,
+            infix: Some(dot),
+            precedence: Precedence::Call,
         },
         Minus => ParseRule {
             prefix: Some(unary),
@@ -178,6 +197,16 @@ fn get_rule(token: TokenKind) -> ParseRule {
             infix: None,
             precedence: Precedence::None,
         },
+        TokenKind::Defer => ParseRule {
+            prefix: None,
+            infix: None,
+            precedence: Precedence::None,
+        },
+        TokenKind::Import => ParseRule {
+            prefix: None,
+            infix: None,
+            precedence: Precedence::None,
+        },
         TokenKind::Nil => ParseRule {
             prefix: Some(literal),
             infix: None,
@@ -236,34 +265,152 @@ fn get_rule(token: TokenKind) -> ParseRule {
     };
 }
 
+/// How tightly `kind` binds as an infix operator, or `Precedence::None` if
+/// it isn't one.
+pub(crate) fn infix_precedence(kind: TokenKind) -> Precedence {
+    return get_rule(kind).precedence;
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Severity {
+    /// Reported, but the program still compiles.
+    Warning,
+    /// The program can't be compiled.
+    Error,
+}
+
+impl fmt::Display for Severity {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Severity::Warning => write!(f, "Warning"),
+            Severity::Error => write!(f, "Error"),
+        }
+    }
+}
+
+/// What caused an error, so that callers don't have to match messages.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ParserErrorKind {
+    /// The input ended where more was expected.
+    UnexpectedEof,
+    /// A string was still open at the end of the input.
+    UnterminatedString,
+    /// A bug in the compiler rather than in the source.
+    Internal,
+    /// Any other error, and every warning.
+    Other,
+}
+
+impl ParserErrorKind {
+    /// The kind of an error reported at `token`.
+    pub(crate) fn at(token: &Token) -> ParserErrorKind {
+        return match token.kind {
+            TokenKind::Eof => ParserErrorKind::UnexpectedEof,
+            TokenKind::Error if token.lexeme == UNTERMINATED_STRING => {
+                ParserErrorKind::UnterminatedString
+            }
+            _ => ParserErrorKind::Other,
+        };
+    }
+
+    /// Whether more input could fix the error.
+    pub fn is_incomplete(self) -> bool {
+        return matches!(
+            self,
+            ParserErrorKind::UnexpectedEof | ParserErrorKind::UnterminatedString
+        );
+    }
+}
+
+/// A problem found while compiling, at `token`.
 #[derive(Debug, PartialEq, Eq)]
-pub struct ParserError {
+pub struct Diagnostic {
+    pub severity: Severity,
+    pub kind: ParserErrorKind,
     pub message: String,
     pub token: Token,
 }
 
+impl Diagnostic {
+    /// Whether this reports a bug in the compiler rather than in the source.
+    pub fn is_internal(&self) -> bool {
+        return self.kind == ParserErrorKind::Internal;
+    }
+}
+
+/// Whether `diagnostics` has errors and every one of them is because the
+/// input ended too soon, such as an unterminated string. The REPL reads
+/// another line in that case instead of reporting them.
+pub fn is_incomplete(diagnostics: &[Diagnostic]) -> bool {
+    let mut errors = diagnostics
+        .iter()
+        .filter(|diagnostic| diagnostic.severity == Severity::Error)
+        .peekable();
+    return errors.peek().is_some() && errors.all(|error| error.kind.is_incomplete());
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "[line {}] {}", self.token.line, self.severity)?;
+        match self.token.kind {
+            // The message of a scanner error is the token's lexeme
+            TokenKind::Error => {}
+            TokenKind::Eof => write!(f, " at end")?,
+            _ => write!(f, " at '{}'", self.token.lexeme)?,
+        }
+        return write!(f, ": {}", self.message);
+    }
+}
+
+/// Renders diagnostics one per line, as the CLI reports them.
+pub fn format_diagnostics(diagnostics: &[Diagnostic]) -> String {
+    return diagnostics
+        .iter()
+        .map(|diagnostic| diagnostic.to_string())
+        .collect::<Vec<_>>()
+        .join("\n");
+}
+
 struct Compiler<'a> {
     gc: &'a mut GC,
     scanner: Scanner<'a>,
     current: Token,
     previous: Token,
-    errors: Vec<ParserError>,
+    diagnostics: Vec<Diagnostic>,
     panic_mode: bool,
+    /// Whether the expression being compiled can do more than produce a
+    /// value, such as calling a function.
+    has_effects: bool,
     current_chunk: Chunk,
+    keep_result: bool,
+    /// Code of each `defer` so far, which runs at the end of the script.
+    deferred: Vec<PositionedCode>,
+    /// Set by an internal error, after which nothing more is compiled.
+    aborted: bool,
+    /// How many expressions the one being compiled is nested in.
+    depth: usize,
 }
 
 impl<'a> Compiler<'a> {
-    fn new(mut scanner: Scanner<'a>, gc: &'a mut GC) -> Compiler<'a> {
-        let first = scanner.scan();
-        Compiler {
+    fn new(scanner: Scanner<'a>, gc: &'a mut GC, keep_result: bool) -> Compiler<'a> {
+        let start = Token::new(TokenKind::Eof, "", 1, 1);
+        let mut compiler = Compiler {
             gc,
-            current: first.clone(),
-            previous: first,
+            current: start.clone(),
+            previous: start,
             scanner,
             panic_mode: false,
-            errors: Vec::new(),
+            diagnostics: Vec::new(),
+            has_effects: false,
             current_chunk: Chunk::new(),
-        }
+            keep_result,
+            deferred: Vec::new(),
+            aborted: false,
+            depth: 0,
+        };
+        // Reads the first token, reporting it if it's a scanner error
+        compiler.advance();
+        return compiler;
     }
     // Error handling
     fn error_at(&mut self, token: Token, message: String) {
@@ -271,11 +418,39 @@ impl<'a> Compiler<'a> {
             return;
         }
         self.panic_mode = true;
-        self.errors.push(ParserError { token, message })
+        self.diagnostics.push(Diagnostic {
+            severity: Severity::Error,
+            kind: ParserErrorKind::at(&token),
+            token,
+            message,
+        })
+    }
+    fn warning_at(&mut self, token: Token, message: String) {
+        if self.panic_mode {
+            return;
+        }
+        self.diagnostics.push(Diagnostic {
+            severity: Severity::Warning,
+            kind: ParserErrorKind::Other,
+            token,
+            message,
+        })
     }
     fn error_at_current(&mut self, message: String) {
         self.error_at(self.current.clone(), message)
     }
+    /// Reports a bug in the compiler at the previous token, even in panic
+    /// mode, and stops compiling once the current declaration is done.
+    fn internal_error(&mut self, message: String) {
+        self.diagnostics.push(Diagnostic {
+            severity: Severity::Error,
+            kind: ParserErrorKind::Internal,
+            token: self.previous.clone(),
+            message: format!("{}{}", INTERNAL_ERROR, message),
+        });
+        self.panic_mode = true;
+        self.aborted = true;
+    }
     fn error(&mut self, message: String) {
         self.error_at(self.previous.clone(), message)
     }
@@ -330,20 +505,49 @@ impl<'a> Compiler<'a> {
         }
     }
     fn parse_precedence(&mut self, precedence: Precedence) {
+        let depth = self.depth;
+        self.parse_operators(precedence);
+        self.depth = depth;
+    }
+    /// Counts a level of nesting for the expression starting at the current
+    /// token, or reports it's too deep and returns false.
+    fn nest(&mut self) -> bool {
+        if self.depth == MAX_NESTING {
+            self.error_at_current(String::from("Expression nesting too deep."));
+            return false;
+        }
+        self.depth += 1;
+        return true;
+    }
+    fn parse_operators(&mut self, precedence: Precedence) {
+        if !self.nest() {
+            return;
+        }
         self.advance();
         let prefix_rule = get_rule(self.previous.kind).prefix;
         match prefix_rule {
             None => {
-                self.error_at_current(String::from("Expected expression."));
+                self.error(String::from("Expected expression."));
             }
             Some(rule) => {
                 rule(self);
-                while precedence as u8 <= get_rule(self.current.kind).precedence as u8 {
+                // After an error the rest of the statement is skipped, rather
+                // than parsed as operators of what's left of the expression
+                while !self.panic_mode
+                    && precedence as u8 <= get_rule(self.current.kind).precedence as u8
+                {
+                    // The expression so far becomes an operand
+                    if !self.nest() {
+                        return;
+                    }
                     self.advance();
-                    if let Some(infix_rule) = get_rule(self.previous.kind).infix {
-                        infix_rule(self);
+                    match get_rule(self.previous.kind).infix {
+                        Some(infix_rule) => infix_rule(self),
+                        None => {
+                            let kind = self.previous.kind;
+                            return self.internal_error(format!("No infix rule for {:?}", kind));
+                        }
                     }
-                    // TODO report error?
                 }
             }
         }
@@ -351,18 +555,46 @@ impl<'a> Compiler<'a> {
     fn identifier_constant(&mut self) -> usize {
         let value = self.previous.lexeme.to_string();
         let obj = self.gc.alloc_string(value);
-        return self.current_chunk.add_const(Value::Object(obj));
+        return self.make_constant(Value::Object(obj));
+    }
+    fn make_constant(&mut self, value: Value) -> usize {
+        if self.current_chunk.constant_count() == MAX_CONSTANTS {
+            self.error(String::from("Too many constants in one chunk."));
+            return 0;
+        }
+        return self.current_chunk.add_const(value);
     }
     fn parse_variable(&mut self, message: String) -> usize {
         self.consume(TokenKind::Identifier, message);
         return self.identifier_constant();
     }
+    fn argument_list(&mut self) -> u8 {
+        let mut arg_count: u8 = 0;
+        if !self.check(TokenKind::RightParen) {
+            loop {
+                self.expression();
+                if arg_count == u8::MAX {
+                    self.error(String::from("Can't have more than 255 arguments."));
+                } else {
+                    arg_count += 1;
+                }
+                if !self.r#match(TokenKind::Comma) {
+                    break;
+                }
+            }
+        }
+        self.consume(
+            TokenKind::RightParen,
+            String::from("Expected ')' after arguments."),
+        );
+        return arg_count;
+    }
     fn define_variable(&mut self, name_ref: usize) {
-        self.current_chunk.ref_const(
+        self.current_chunk.ref_const_at(
             name_ref,
-            OpCode::DefineGlobal,
-            OpCode::DefineGlobalLong,
+            ConstOp::DefineGlobal,
             self.previous.line,
+            self.previous.column,
         );
     }
     // Expressions
@@ -372,6 +604,10 @@ impl<'a> Compiler<'a> {
     fn declaration(&mut self) {
         if self.r#match(TokenKind::Var) {
             self.var_declaration();
+        } else if self.r#match(TokenKind::Import) {
+            self.import_declaration();
+        } else if self.r#match(TokenKind::Defer) {
+            self.defer_statement();
         } else {
             self.statement();
         }
@@ -393,6 +629,55 @@ impl<'a> Compiler<'a> {
         );
         self.define_variable(name_ref);
     }
+    // import "path";
+    // import { name, other } from "path";
+    fn import_declaration(&mut self) {
+        let mut names = Vec::new();
+        if self.r#match(LeftBrace) {
+            loop {
+                names.push(self.parse_variable(String::from("Expected name to import.")));
+                if !self.r#match(Comma) {
+                    break;
+                }
+            }
+            self.consume(
+                RightBrace,
+                String::from("Expected '}' after imported names."),
+            );
+            if !(self.check(Identifier) && self.current.lexeme == "from") {
+                self.error_at_current(String::from("Expected 'from' after imported names."));
+                return;
+            }
+            self.advance();
+        }
+        self.consume(Str, String::from("Expected module path."));
+        string(self);
+        self.consume(Semicolon, String::from("Expected ';' after import."));
+        // The path stays on the stack until every name is imported
+        self.emit_opcode(OpCode::Import);
+        if names.is_empty() {
+            self.emit_opcode(OpCode::ImportAll);
+        }
+        for name_ref in names {
+            self.current_chunk.ref_const_at(
+                name_ref,
+                ConstOp::ImportName,
+                self.previous.line,
+                self.previous.column,
+            );
+        }
+        self.emit_opcode(OpCode::Pop);
+    }
+    // defer statement;
+    fn defer_statement(&mut self) {
+        let start = self.current_chunk.get_code().len();
+        // The script's result comes before the deferred code runs
+        let keep_result = std::mem::replace(&mut self.keep_result, false);
+        self.statement();
+        self.keep_result = keep_result;
+        let code = self.current_chunk.split_off_code(start);
+        self.deferred.push(code);
+    }
     fn statement(&mut self) {
         if self.r#match(TokenKind::Print) {
             self.print_statement();
@@ -406,14 +691,26 @@ impl<'a> Compiler<'a> {
         self.emit_opcode(OpCode::Print);
     }
     fn expression_statement(&mut self) {
+        let start = self.current.clone();
+        self.has_effects = false;
         self.expression();
         self.consume(Semicolon, String::from("Expected ';' after value."));
+        if self.keep_result && self.check(TokenKind::Eof) {
+            // Leave the value on the stack for the final `Return`
+            return;
+        }
+        if !self.has_effects {
+            self.warning_at(start, String::from("Expression result is unused."));
+        }
         self.emit_opcode(OpCode::Pop);
     }
     // Emitting
     fn emit_opcode(&mut self, opcode: OpCode) {
+        self.emit_byte(opcode as u8)
+    }
+    fn emit_byte(&mut self, byte: u8) {
         self.current_chunk
-            .write_byte(opcode as u8, self.previous.line)
+            .write_byte_at(byte, self.previous.line, self.previous.column)
     }
     fn emit_opcodes(&mut self, opcodes: &[OpCode]) {
         for opcode in opcodes {
@@ -424,57 +721,77 @@ impl<'a> Compiler<'a> {
         self.emit_opcode(OpCode::Return)
     }
     fn emit_constant(&mut self, value: Value) {
-        let const_ref = self.current_chunk.add_const(value);
-        self.current_chunk.ref_const(
+        let const_ref = self.make_constant(value);
+        self.current_chunk.ref_const_at(
             const_ref,
-            OpCode::Constant,
-            OpCode::ConstantLong,
+            ConstOp::Constant,
             self.previous.line,
+            self.previous.column,
         );
     }
     fn end(mut self) -> Chunk {
+        // Deferred code runs last first, once everything else is done
+        while let Some(code) = self.deferred.pop() {
+            self.current_chunk.append_code(code);
+        }
         self.emit_return();
+        // Code with syntax errors may not balance, but then it's discarded
+        let _ = self.current_chunk.record_max_stack();
         self.current_chunk
     }
 }
 
+/// Literals without a decimal point are integers, unless they don't fit.
 fn number(compiler: &mut Compiler<'_>) {
-    let value = compiler.previous.lexeme.parse::<f32>().unwrap();
-    compiler.emit_constant(Value::Number(value));
+    if let Ok(value) = compiler.previous.lexeme.parse::<i64>() {
+        return compiler.emit_constant(Value::Int(value));
+    }
+    match compiler.previous.lexeme.parse::<f32>() {
+        Ok(value) => compiler.emit_constant(Value::Number(value)),
+        Err(_) => compiler.error(String::from("Invalid number.")),
+    }
 }
 
 fn unary<'a>(compiler: &mut Compiler<'a>) {
     let op_kind = compiler.previous.kind;
-    compiler.parse_precedence(Precedence::Unary);
-    match op_kind {
-        Minus => {
-            compiler.emit_opcode(Negate);
+    let opcode = match op_kind {
+        Minus => Negate,
+        Bang => Not,
+        _ => {
+            return compiler.internal_error(format!("Invalid unary token kind: {:?}", op_kind));
         }
-        Bang => {
-            compiler.emit_opcode(Not);
-        }
-        _ => panic!("Invalid unary token kind: {:?}", op_kind),
     };
+    compiler.parse_precedence(Precedence::Unary);
+    compiler.emit_opcode(opcode);
 }
 
 fn binary<'a>(compiler: &mut Compiler<'a>) {
     let op_kind = compiler.previous.kind;
-    let rule = get_rule(op_kind);
-    let precedence = FromPrimitive::from_u8((rule.precedence as u8) + 1).unwrap();
+    let opcodes: &[OpCode] = match op_kind {
+        BangEqual => &[OpCode::Equal, Not],
+        EqualEqual => &[OpCode::Equal],
+        TokenKind::Greater => &[OpCode::Greater],
+        GreaterEqual => &[OpCode::Less, OpCode::Not],
+        TokenKind::Less => &[OpCode::Less],
+        LessEqual => &[OpCode::Greater, OpCode::Not],
+        Plus => &[Add],
+        Minus => &[Subtract],
+        Star => &[Multiply],
+        Slash => &[Divide],
+        _ => {
+            return compiler.internal_error(format!("Invalid binary token kind: {:?}", op_kind));
+        }
+    };
+    let rule_precedence = get_rule(op_kind).precedence;
+    let precedence = match rule_precedence.next() {
+        Some(precedence) => precedence,
+        None => {
+            let message = format!("No precedence above {:?}", rule_precedence);
+            return compiler.internal_error(message);
+        }
+    };
     compiler.parse_precedence(precedence);
-    match op_kind {
-        BangEqual => compiler.emit_opcodes(&[OpCode::Equal, Not]),
-        EqualEqual => compiler.emit_opcode(OpCode::Equal),
-        TokenKind::Greater => compiler.emit_opcode(OpCode::Greater),
-        GreaterEqual => compiler.emit_opcodes(&[OpCode::Less, OpCode::Not]),
-        TokenKind::Less => compiler.emit_opcode(OpCode::Less),
-        LessEqual => compiler.emit_opcodes(&[OpCode::Greater, OpCode::Not]),
-        Plus => compiler.emit_opcode(Add),
-        Minus => compiler.emit_opcode(Subtract),
-        Star => compiler.emit_opcode(Multiply),
-        Slash => compiler.emit_opcode(Divide),
-        _ => panic!("Invalid binary token kind: {:?}", op_kind),
-    }
+    compiler.emit_opcodes(opcodes);
 }
 
 fn literal<'a>(compiler: &mut Compiler<'a>) {
@@ -482,7 +799,7 @@ fn literal<'a>(compiler: &mut Compiler<'a>) {
         TokenKind::True => compiler.emit_opcode(OpCode::True),
         TokenKind::False => compiler.emit_opcode(OpCode::False),
         TokenKind::Nil => compiler.emit_opcode(OpCode::Nil),
-        _ => panic!("Invalid literal token kind: {:?}", compiler.previous.kind),
+        kind => compiler.internal_error(format!("Invalid literal token kind: {:?}", kind)),
     }
 }
 
@@ -494,6 +811,35 @@ fn grouping<'a>(compiler: &mut Compiler<'a>) {
     );
 }
 
+fn call<'a>(compiler: &mut Compiler<'a>) {
+    compiler.has_effects = true;
+    let arg_count = compiler.argument_list();
+    compiler.emit_opcode(OpCode::Call);
+    compiler.emit_byte(arg_count);
+}
+
+fn dot<'a>(compiler: &mut Compiler<'a>) {
+    compiler.consume(
+        TokenKind::Identifier,
+        String::from("Expected method name after '.'."),
+    );
+    let name_ref = compiler.identifier_constant();
+    if !compiler.r#match(LeftParen) {
+        // There are no fields yet, so a name after '.' must be a method call
+        compiler.error(String::from("Expected '(' after method name."));
+        return;
+    }
+    compiler.has_effects = true;
+    let arg_count = compiler.argument_list();
+    compiler.current_chunk.ref_const_at(
+        name_ref,
+        ConstOp::Invoke,
+        compiler.previous.line,
+        compiler.previous.column,
+    );
+    compiler.emit_byte(arg_count);
+}
+
 fn string<'a>(compiler: &mut Compiler<'a>) {
     let lexeme = &compiler.previous.lexeme;
     let value = lexeme[1..lexeme.len() - 1].to_string();
@@ -503,11 +849,11 @@ fn string<'a>(compiler: &mut Compiler<'a>) {
 
 fn named_variable<'a>(compiler: &mut Compiler<'a>) {
     let name_ref = compiler.identifier_constant();
-    compiler.current_chunk.ref_const(
+    compiler.current_chunk.ref_const_at(
         name_ref,
-        OpCode::Get,
-        OpCode::GetLong,
+        ConstOp::Get,
         compiler.previous.line,
+        compiler.previous.column,
     );
 }
 
@@ -515,23 +861,91 @@ fn variable<'a>(compiler: &mut Compiler<'a>) {
     named_variable(compiler);
 }
 
-pub fn compile<'a>(source: &'a String, gc: &mut GC) -> Result<Chunk, InterpreterError> {
-    let scanner = Scanner::new(&source);
-    let mut compiler = Compiler::new(scanner, gc);
-    while !compiler.r#match(TokenKind::Eof) {
+/// A compiled chunk and the warnings found while compiling it.
+pub type Compiled = (Chunk, Vec<Diagnostic>);
+
+/// Compiles `source`. Warnings don't stop compilation and are returned with
+/// the chunk; if there are any errors, every diagnostic is returned in a
+/// `CompileError` instead.
+pub fn compile(source: &String, gc: &mut GC) -> Result<Compiled, InterpreterError> {
+    return compile_chunk(source, gc, false, Dialect::default());
+}
+
+/// Like `compile`, for the language of `dialect` rather than the default.
+pub fn compile_with_dialect(
+    source: &String,
+    gc: &mut GC,
+    dialect: Dialect,
+) -> Result<Compiled, InterpreterError> {
+    return compile_chunk(source, gc, false, dialect);
+}
+
+/// Like `compile`, but if the program ends with an expression statement, the
+/// chunk returns that expression's value instead of `nil`.
+pub fn compile_with_result(source: &String, gc: &mut GC) -> Result<Compiled, InterpreterError> {
+    return compile_chunk(source, gc, true, Dialect::default());
+}
+
+pub(crate) fn compile_chunk(
+    source: &String,
+    gc: &mut GC,
+    keep_result: bool,
+    dialect: Dialect,
+) -> Result<Compiled, InterpreterError> {
+    let scanner = Scanner::with_dialect(source, dialect);
+    let mut compiler = Compiler::new(scanner, gc, keep_result);
+    while !compiler.aborted && !compiler.r#match(TokenKind::Eof) {
         compiler.declaration();
     }
-    compiler.consume(TokenKind::Eof, String::from("Expect end of expression."));
-    match compiler.errors.len() {
-        0 => Ok(compiler.end()),
-        _ => Err(InterpreterError::CompileError(compiler.errors)),
+    if !compiler.aborted {
+        compiler.consume(TokenKind::Eof, String::from("Expect end of expression."));
+    }
+    let has_errors = compiler
+        .diagnostics
+        .iter()
+        .any(|d| d.severity == Severity::Error);
+    if has_errors {
+        return Err(InterpreterError::CompileError(compiler.diagnostics));
+    }
+    let diagnostics = std::mem::take(&mut compiler.diagnostics);
+    return Ok((compiler.end(), diagnostics));
+}
+
+/// Like `compile`, but parses the whole program into an `ast::Program` first
+/// and then lowers it to bytecode. Slower, but produces the same chunk and
+/// diagnostics.
+pub fn compile_two_pass(source: &String, gc: &mut GC) -> Result<Compiled, InterpreterError> {
+    return compile_two_pass_chunk(source, gc, false, Dialect::default());
+}
+
+/// Like `compile_two_pass`, for the language of `dialect` rather than the
+/// default.
+pub fn compile_two_pass_with_dialect(
+    source: &String,
+    gc: &mut GC,
+    dialect: Dialect,
+) -> Result<Compiled, InterpreterError> {
+    return compile_two_pass_chunk(source, gc, false, dialect);
+}
+
+pub(crate) fn compile_two_pass_chunk(
+    source: &String,
+    gc: &mut GC,
+    keep_result: bool,
+    dialect: Dialect,
+) -> Result<Compiled, InterpreterError> {
+    let (program, diagnostics) = crate::ast::parse_program(source, keep_result, dialect);
+    if diagnostics.iter().any(|d| d.severity == Severity::Error) {
+        return Err(InterpreterError::CompileError(diagnostics));
     }
+    return Ok((crate::lower::lower(&program, gc, keep_result), diagnostics));
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
     use crate::assert_eq_str;
+    use crate::chunk::OpCode::{Call, Equal, False, Greater, Nil, Print, Return, True};
 
     macro_rules! test_compile {
         ($program:expr) => {{
@@ -546,7 +960,7 @@ mod tests {
         ($program:expr) => {{
             let (result, gc) = test_compile!($program);
             assert!(result.is_ok());
-            (result.unwrap(), gc)
+            (result.unwrap().0, gc)
         }};
     }
 
@@ -557,36 +971,206 @@ mod tests {
         gc.alloc_string("Hello".to_string());
     }
 
+    #[test]
+    fn renders_errors() {
+        let (result, _gc) = test_compile!("print;\nvar = 1;\n1 +");
+        let error = result.unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "[line 1] Error at ';': Expected expression.\n\
+             [line 2] Error at '=': Expected variable name.\n\
+             [line 3] Error at end: Expected expression."
+        );
+        let (result, _gc) = test_compile!("\"open");
+        assert_eq!(
+            result.unwrap_err().to_string(),
+            "[line 1] Error: Unterminated string."
+        );
+    }
+
+    #[test]
+    fn classifies_errors() {
+        let kinds = |source: &str| match test_compile!(source).0 {
+            Err(InterpreterError::CompileError(diagnostics)) => diagnostics
+                .iter()
+                .map(|diagnostic| diagnostic.kind)
+                .collect::<Vec<_>>(),
+            _ => Vec::new(),
+        };
+        assert_eq!(kinds("print 1 +"), [ParserErrorKind::UnexpectedEof]);
+        assert_eq!(kinds("print \"a;"), [ParserErrorKind::UnterminatedString]);
+        assert_eq!(
+            kinds("print );\nvar a = 1"),
+            [ParserErrorKind::Other, ParserErrorKind::UnexpectedEof]
+        );
+        let (result, _gc) = test_compile!("print );\nvar a = 1");
+        match result {
+            Err(InterpreterError::CompileError(diagnostics)) => {
+                assert!(!is_incomplete(&diagnostics));
+                assert!(is_incomplete(&diagnostics[1..]));
+                // The error keeps where the input ended
+                assert_eq!(diagnostics[1].token.line, 2);
+                assert_eq!(diagnostics[1].token.column, 10);
+            }
+            _ => panic!("expected a compile error"),
+        }
+        assert!(!is_incomplete(&[]));
+    }
+
+    #[test]
+    fn reports_internal_errors() {
+        // The parse rules only call these for the tokens they handle, so
+        // call them directly with others
+        let rules: [(ParseFn, &str); 3] = [
+            (unary, "Invalid unary token kind: Semicolon"),
+            (binary, "Invalid binary token kind: Semicolon"),
+            (literal, "Invalid literal token kind: Semicolon"),
+        ];
+        for (rule, message) in rules {
+            let mut gc = GC::new();
+            let source = String::from("; print 1;");
+            let mut compiler = Compiler::new(Scanner::new(&source), &mut gc, false);
+            compiler.advance();
+            rule(&mut compiler);
+            assert!(compiler.aborted);
+            assert!(compiler.diagnostics[0].is_internal());
+            assert_eq!(
+                format_diagnostics(&compiler.diagnostics),
+                format!(
+                    "[line 1] Error at ';': Internal compiler error: {}",
+                    message
+                )
+            );
+        }
+        let (result, _gc) = test_compile!("print -1;");
+        assert!(!result.unwrap().1.iter().any(Diagnostic::is_internal));
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore = "scans long sources")]
+    fn reports_too_deep_nesting() {
+        // Every parenthesis, operator and operand is a level, as is the
+        // outermost expression
+        let deepest = MAX_NESTING - 1;
+        let (parens, closing) = ("(".repeat(deepest), ")".repeat(deepest));
+        let sources = [
+            format!("{}1{};", parens, closing),
+            format!("print {}1;", "-".repeat(deepest)),
+            format!("print 1{};", " + 1".repeat(deepest - 1)),
+            format!("a{};", ".b()".repeat(deepest)),
+        ];
+        // Not `test_compile!`, whose debug output of long chunks is too much
+        let mut gc = GC::new();
+        for source in &sources {
+            assert!(compile(source, &mut gc).is_ok(), "{}", source);
+        }
+        let sources = [
+            format!("(({}1{}));", parens, closing),
+            format!("print --{}1;", "-".repeat(deepest)),
+            format!("print 1{};", " + 1".repeat(deepest)),
+            format!("a{};", ".b()".repeat(MAX_NESTING)),
+        ];
+        for (source, at) in sources.iter().zip(["(", "-", "1", "."]) {
+            let result = compile(&format!("{}\nprint 2;", source), &mut gc);
+            assert_eq!(
+                result.unwrap_err().to_string(),
+                format!("[line 1] Error at '{}': Expression nesting too deep.", at)
+            );
+        }
+    }
+
+    #[test]
+    fn reports_too_many_constants() {
+        // Every literal and name has its own constant, so a script this long
+        // would run out; start with the pool almost full instead
+        let mut gc = GC::new();
+        let source = String::from("var a = \"b\";\nprint nil;\nprint c;");
+        let mut compiler = Compiler::new(Scanner::new(&source), &mut gc, false);
+        for _ in 1..MAX_CONSTANTS {
+            compiler.current_chunk.add_const(Value::Nil);
+        }
+        while !compiler.r#match(TokenKind::Eof) {
+            compiler.declaration();
+        }
+        assert_eq!(
+            format_diagnostics(&compiler.diagnostics),
+            "[line 1] Error at '\"b\"': Too many constants in one chunk.\n\
+             [line 3] Error at 'c': Too many constants in one chunk."
+        );
+    }
+
+    #[test]
+    fn warns_about_unused_results() {
+        let (result, _gc) = test_compile!("print 1;\n1 + 2;\nclock();\n\"a\".len();");
+        let (_, warnings) = result.unwrap();
+        assert_eq!(
+            format_diagnostics(&warnings),
+            "[line 2] Warning at '1': Expression result is unused."
+        );
+        assert_eq!(warnings[0].severity, Severity::Warning);
+
+        // The trailing expression is the chunk's result, so it is used
+        let mut gc = GC::new();
+        let (_, warnings) = compile_with_result(&String::from("1 + 2;"), &mut gc).unwrap();
+        assert!(warnings.is_empty());
+
+        // With errors, warnings are reported alongside them
+        let (result, _gc) = test_compile!("1;\nprint;");
+        assert_eq!(
+            result.unwrap_err().to_string(),
+            "[line 1] Warning at '1': Expression result is unused.\n\
+             [line 2] Error at ';': Expected expression."
+        );
+    }
+
     mod literals {
         use super::*;
         use crate::assert_eq_str;
-        use crate::gc::ObjString;
 
         #[test]
         fn number_literal() {
-            let (chunk, _) = test_compile_ok!("123;");
-            assert_eq!(chunk.get_constant(0), Value::Number(123.0));
+            let (chunk, _gc) = test_compile_ok!("123;");
+            assert_eq!(chunk.get_constant(0), Some(Value::Int(123)));
             let expect_code = [Constant as u8, 0, Pop as u8, Return as u8];
             assert_eq!(chunk.get_code(), expect_code);
         }
 
+        #[test]
+        fn extreme_number_literals() {
+            // Literals too large for an i64 and an f32 saturate to infinity
+            let huge = format!("1{};", "0".repeat(400));
+            let (chunk, _gc) = test_compile_ok!(huge);
+            assert_eq!(chunk.get_constant(0), Some(Value::Number(f32::INFINITY)));
+            let tiny = format!("0.{}1;", "0".repeat(400));
+            let (chunk, _gc) = test_compile_ok!(tiny);
+            assert_eq!(chunk.get_constant(0), Some(Value::Number(0.0)));
+            let (chunk, _gc) = test_compile_ok!("340282350000000000000000000000000000000.5;");
+            assert_eq!(chunk.get_constant(0), Some(Value::Number(f32::MAX)));
+            // There is no exponent syntax, so this is a number then a name
+            let (result, _gc) = test_compile!("1e99999;");
+            assert_eq!(
+                result.unwrap_err().to_string(),
+                "[line 1] Error at 'e99999': Expected ';' after value."
+            );
+        }
+
         #[test]
         fn true_literal() {
-            let (chunk, _) = test_compile_ok!("true;");
+            let (chunk, _gc) = test_compile_ok!("true;");
             let expect_code = [True as u8, Pop as u8, Return as u8];
             assert_eq!(chunk.get_code(), expect_code);
         }
 
         #[test]
         fn false_literal() {
-            let (chunk, _) = test_compile_ok!("false;");
+            let (chunk, _gc) = test_compile_ok!("false;");
             let expect_code = [False as u8, Pop as u8, Return as u8];
             assert_eq!(chunk.get_code(), expect_code);
         }
 
         #[test]
         fn nil_literal() {
-            let (chunk, _) = test_compile_ok!("nil;");
+            let (chunk, _gc) = test_compile_ok!("nil;");
             let expect_code = [Nil as u8, Pop as u8, Return as u8];
             assert_eq!(chunk.get_code(), expect_code);
         }
@@ -595,7 +1179,7 @@ mod tests {
             let (chunk, gc) = test_compile_ok!(r#""hello world";"#);
             let expect_code = [Constant as u8, 0, Pop as u8, Return as u8];
             assert_eq!(chunk.get_code(), expect_code);
-            match chunk.get_constant(0) {
+            match chunk.get_constant(0).unwrap() {
                 Value::Object(o) => assert_eq_str!(o, "hello world"),
                 _ => panic!("Expect string object"),
             }
@@ -605,24 +1189,24 @@ mod tests {
 
     #[test]
     fn negate() {
-        let (chunk, _) = test_compile_ok!("-123;");
-        assert_eq!(chunk.get_constant(0), Value::Number(123.0));
+        let (chunk, _gc) = test_compile_ok!("-123;");
+        assert_eq!(chunk.get_constant(0), Some(Value::Int(123)));
         let expect_code = [Constant as u8, 0, Negate as u8, Pop as u8, Return as u8];
         assert_eq!(chunk.get_code(), expect_code);
     }
 
     #[test]
     fn not() {
-        let (chunk, _) = test_compile_ok!("!true;");
+        let (chunk, _gc) = test_compile_ok!("!true;");
         let expect_code = [True as u8, Not as u8, Pop as u8, Return as u8];
         assert_eq!(chunk.get_code(), expect_code);
     }
 
     #[test]
     fn equal_equal() {
-        let (chunk, _) = test_compile_ok!("123 == 123;");
-        assert_eq!(chunk.get_constant(0), Value::Number(123.0));
-        assert_eq!(chunk.get_constant(1), Value::Number(123.0));
+        let (chunk, _gc) = test_compile_ok!("123 == 123;");
+        assert_eq!(chunk.get_constant(0), Some(Value::Int(123)));
+        assert_eq!(chunk.get_constant(1), Some(Value::Int(123)));
         let expect_code = [
             Constant as u8,
             0,
@@ -637,9 +1221,9 @@ mod tests {
 
     #[test]
     fn bang_equal() {
-        let (chunk, _) = test_compile_ok!("123 != 123;");
-        assert_eq!(chunk.get_constant(0), Value::Number(123.0));
-        assert_eq!(chunk.get_constant(1), Value::Number(123.0));
+        let (chunk, _gc) = test_compile_ok!("123 != 123;");
+        assert_eq!(chunk.get_constant(0), Some(Value::Int(123)));
+        assert_eq!(chunk.get_constant(1), Some(Value::Int(123)));
         let expect_code = [
             Constant as u8,
             0,
@@ -655,9 +1239,9 @@ mod tests {
 
     #[test]
     fn greater() {
-        let (chunk, _) = test_compile_ok!("123 > 123;");
-        assert_eq!(chunk.get_constant(0), Value::Number(123.0));
-        assert_eq!(chunk.get_constant(1), Value::Number(123.0));
+        let (chunk, _gc) = test_compile_ok!("123 > 123;");
+        assert_eq!(chunk.get_constant(0), Some(Value::Int(123)));
+        assert_eq!(chunk.get_constant(1), Some(Value::Int(123)));
         let expect_code = [
             Constant as u8,
             0,
@@ -670,29 +1254,71 @@ mod tests {
         assert_eq!(chunk.get_code(), expect_code);
     }
 
+    #[test]
+    fn call() {
+        let (chunk, _gc) = test_compile_ok!("f(1, 2)();");
+        let expect_code = [
+            Get as u8,
+            0,
+            Constant as u8,
+            1,
+            Constant as u8,
+            2,
+            Call as u8,
+            2,
+            Call as u8,
+            0,
+            Pop as u8,
+            Return as u8,
+        ];
+        assert_eq!(chunk.get_code(), expect_code);
+    }
+
+    #[test]
+    fn call_missing_paren() {
+        let (result, _) = test_compile!("f(1, 2;");
+        assert!(result.is_err());
+    }
+
     mod statements {
         use super::*;
 
         #[test]
         fn expression_statement() {
-            let (chunk, _) = test_compile_ok!("123;");
-            assert_eq!(chunk.get_constant(0), Value::Number(123.0));
+            let (chunk, _gc) = test_compile_ok!("123;");
+            assert_eq!(chunk.get_constant(0), Some(Value::Int(123)));
             let expect_code = [Constant as u8, 0, Pop as u8, Return as u8];
             assert_eq!(chunk.get_code(), expect_code);
         }
 
+        #[test]
+        fn expression_statement_with_result() {
+            let mut gc = GC::new();
+            let source = String::from("1; 2;");
+            let (chunk, _) = compile_with_result(&source, &mut gc).unwrap();
+            let expect_code = [
+                Constant as u8,
+                0,
+                Pop as u8,
+                Constant as u8,
+                1,
+                Return as u8,
+            ];
+            assert_eq!(chunk.get_code(), expect_code);
+        }
+
         #[test]
         fn print_statement() {
-            let (chunk, _) = test_compile_ok!("print 123;");
-            assert_eq!(chunk.get_constant(0), Value::Number(123.0));
+            let (chunk, _gc) = test_compile_ok!("print 123;");
+            assert_eq!(chunk.get_constant(0), Some(Value::Int(123)));
             let expect_code = [Constant as u8, 0, Print as u8, Return as u8];
             assert_eq!(chunk.get_code(), expect_code);
         }
 
         #[test]
         fn global_var_declaration_wo_initializer() {
-            let (chunk, _) = test_compile_ok!("var x;");
-            match chunk.get_constant(0) {
+            let (chunk, _gc) = test_compile_ok!("var x;");
+            match chunk.get_constant(0).unwrap() {
                 Value::Object(o) => assert_eq_str!(o, "x"),
                 _ => panic!("Expect string object"),
             }
@@ -702,25 +1328,25 @@ mod tests {
 
         #[test]
         fn global_var_declaration_with_init() {
-            let (chunk, _) = test_compile_ok!("var x = 123;");
-            match chunk.get_constant(0) {
+            let (chunk, _gc) = test_compile_ok!("var x = 123;");
+            match chunk.get_constant(0).unwrap() {
                 Value::Object(o) => assert_eq_str!(o, "x"),
                 _ => panic!("Expect string object"),
             }
-            assert_eq!(chunk.get_constant(1), Value::Number(123.0));
+            assert_eq!(chunk.get_constant(1), Some(Value::Int(123)));
             let expect_code = [Constant as u8, 1, DefineGlobal as u8, 0, Return as u8];
             assert_eq!(chunk.get_code(), expect_code);
         }
 
         #[test]
         fn global_var_read() {
-            let (chunk, _) = test_compile_ok!("var x = 123; x;");
-            match chunk.get_constant(0) {
+            let (chunk, _gc) = test_compile_ok!("var x = 123; x;");
+            match chunk.get_constant(0).unwrap() {
                 Value::Object(o) => assert_eq_str!(o, "x"),
                 _ => panic!("Expect string object"),
             }
-            assert_eq!(chunk.get_constant(1), Value::Number(123.0));
-            match chunk.get_constant(2) {
+            assert_eq!(chunk.get_constant(1), Some(Value::Int(123)));
+            match chunk.get_constant(2).unwrap() {
                 Value::Object(o) => assert_eq_str!(o, "x"),
                 _ => panic!("Expect string object"),
             }
@@ -0,0 +1,186 @@
+use rs_lox::{Chunk, LineNumber, VmHook};
+use std::collections::BTreeMap;
+use std::convert::TryFrom;
+use std::io;
+use std::path::{Path, PathBuf};
+
+/// How many times each line of a file with code on it ran.
+#[derive(Debug, Default, PartialEq)]
+pub struct FileCoverage {
+    /// Every line with an instruction on it, including those that never ran.
+    pub hits: BTreeMap<LineNumber, u64>,
+}
+
+impl FileCoverage {
+    pub fn lines_found(&self) -> usize {
+        return self.hits.len();
+    }
+
+    pub fn lines_hit(&self) -> usize {
+        return self.hits.values().filter(|&&count| count > 0).count();
+    }
+}
+
+/// Lines executed in every file a VM ran, collected by installing it with
+/// `VM::set_hook`. Chunks without a script path aren't recorded, since there
+/// is no source to report them against.
+#[derive(Debug, Default)]
+pub struct Coverage {
+    pub files: BTreeMap<PathBuf, FileCoverage>,
+}
+
+/// The compiler ends every chunk with a `Return` on the line of the end of
+/// the file, which often has no source on it, so that isn't counted as code.
+fn is_implicit_return(chunk: &Chunk, offset: usize) -> bool {
+    return offset + 1 == chunk.get_code().len();
+}
+
+impl VmHook for Coverage {
+    fn before_instruction(&mut self, script: Option<&Path>, chunk: &Chunk, offset: usize) {
+        let script = match script {
+            Some(script) if !is_implicit_return(chunk, offset) => script,
+            _ => return,
+        };
+        if !self.files.contains_key(script) {
+            let mut file = FileCoverage::default();
+            for (offset, _) in chunk.instructions() {
+                if is_implicit_return(chunk, offset) {
+                    continue;
+                }
+                if let Some(&line) = chunk.get_line(offset) {
+                    file.hits.insert(line, 0);
+                }
+            }
+            self.files.insert(script.to_path_buf(), file);
+        }
+        // A line counts as run each time execution enters it, not once per
+        // instruction on it
+        let line = chunk.get_line(offset);
+        let entered = offset == 0 || chunk.get_line(offset - 1) != line;
+        if let (true, Some(line)) = (entered, line) {
+            let file = self.files.get_mut(script).unwrap();
+            *file.hits.entry(*line).or_insert(0) += 1;
+        }
+    }
+}
+
+/// Writes `source` with each line prefixed by how many times it ran, `-` for
+/// lines without code and `#####` for lines that never ran, as gcov does.
+pub fn annotate(out: &mut dyn io::Write, source: &str, file: &FileCoverage) -> io::Result<()> {
+    for (index, text) in source.lines().enumerate() {
+        let hits = LineNumber::try_from(index + 1)
+            .ok()
+            .and_then(|line| file.hits.get(&line));
+        let count = match hits {
+            None => String::from("-"),
+            Some(0) => String::from("#####"),
+            Some(count) => count.to_string(),
+        };
+        writeln!(out, "{:>6} | {}", count, text)?;
+    }
+    return Ok(());
+}
+
+/// Writes a summary line per file and the total, as `path: hit/found (N%)`.
+pub fn summarize(out: &mut dyn io::Write, coverage: &Coverage) -> io::Result<()> {
+    let (mut hit, mut found) = (0, 0);
+    for (path, file) in &coverage.files {
+        write_summary(
+            out,
+            &path.display().to_string(),
+            file.lines_hit(),
+            file.lines_found(),
+        )?;
+        hit += file.lines_hit();
+        found += file.lines_found();
+    }
+    return write_summary(out, "total", hit, found);
+}
+
+fn write_summary(out: &mut dyn io::Write, name: &str, hit: usize, found: usize) -> io::Result<()> {
+    let percent = if found == 0 {
+        100.0
+    } else {
+        hit as f64 * 100.0 / found as f64
+    };
+    return writeln!(out, "{}: {}/{} lines ({:.1}%)", name, hit, found, percent);
+}
+
+/// Writes `coverage` as an LCOV tracefile, for tools such as `genhtml`.
+pub fn write_lcov(out: &mut dyn io::Write, coverage: &Coverage) -> io::Result<()> {
+    for (path, file) in &coverage.files {
+        writeln!(out, "TN:")?;
+        writeln!(out, "SF:{}", path.display())?;
+        for (line, count) in &file.hits {
+            writeln!(out, "DA:{},{}", line, count)?;
+        }
+        writeln!(out, "LF:{}", file.lines_found())?;
+        writeln!(out, "LH:{}", file.lines_hit())?;
+        writeln!(out, "end_of_record")?;
+    }
+    return Ok(());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rs_lox::{compile, GC};
+
+    /// Feeds every instruction of `source` to a `Coverage`, as if it ran
+    /// straight through.
+    fn cover(source: &str) -> Coverage {
+        let mut gc = GC::new();
+        let (chunk, _) = compile(&String::from(source), &mut gc).unwrap();
+        let mut coverage = Coverage::default();
+        for (offset, _) in chunk.instructions() {
+            coverage.before_instruction(Some(Path::new("main.lox")), &chunk, offset);
+        }
+        return coverage;
+    }
+
+    #[test]
+    fn counts_lines_entered() {
+        let coverage = cover("var a = 1;\n\nprint a + 2;\n");
+        let file = &coverage.files[Path::new("main.lox")];
+        assert_eq!(file.hits, BTreeMap::from([(1, 1), (3, 1)]));
+
+        let mut output = Vec::new();
+        annotate(&mut output, "var a = 1;\n\nprint a + 2;", file).unwrap();
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "     1 | var a = 1;\n     - | \n     1 | print a + 2;\n"
+        );
+    }
+
+    #[test]
+    fn reports_lines_never_run() {
+        let mut coverage = Coverage::default();
+        coverage.files.insert(
+            PathBuf::from("lib.lox"),
+            FileCoverage {
+                hits: BTreeMap::from([(1, 2), (2, 0)]),
+            },
+        );
+
+        let mut output = Vec::new();
+        annotate(&mut output, "a;\nb;", &coverage.files[Path::new("lib.lox")]).unwrap();
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "     2 | a;\n ##### | b;\n"
+        );
+
+        let mut output = Vec::new();
+        summarize(&mut output, &coverage).unwrap();
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "lib.lox: 1/2 lines (50.0%)\ntotal: 1/2 lines (50.0%)\n"
+        );
+
+        let mut output = Vec::new();
+        write_lcov(&mut output, &coverage).unwrap();
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "TN:\nSF:lib.lox\nDA:1,2\nDA:2,0\nLF:2\nLH:1\nend_of_record\n"
+        );
+    }
+}
@@ -1,106 +1,215 @@
 use crate::chunk::OpCode::*;
 use crate::chunk::*;
-use crate::value::Value::Number;
-use crate::value::*;
+use crate::table::Table;
+use crate::value::Value;
 use num_traits::FromPrimitive;
-use std::convert::TryInto;
 
 pub fn disassemble_chunk(chunk: &Chunk, name: &str) -> String {
     let mut result = String::new();
     result.push_str(&format!("== {} ==\n", name));
-    let mut offset = 0;
-    while offset < chunk.get_code().len() {
-        let instr_result = disassemble_instruction(chunk, offset);
-        match instr_result {
-            None => break,
-            Some((next_offset, description)) => {
-                result.push_str(&description);
-                result.push_str("\n");
-                offset = next_offset
-            }
-        }
+    let mut instructions = chunk.instructions();
+    for (offset, instruction) in &mut instructions {
+        result.push_str(&describe_instruction(chunk, offset, &instruction));
+        result.push('\n');
+    }
+    // Nothing after an unknown opcode can be decoded reliably, so it ends the
+    // listing
+    if let Some((_, description)) = disassemble_instruction(chunk, instructions.offset()) {
+        result.push_str(&description);
+        result.push('\n');
+    }
+    return result;
+}
+
+/// Lists global variables as `name = value` lines, sorted by name.
+pub fn dump_globals(globals: &Table<Value>) -> String {
+    let mut entries = globals
+        .iter()
+        .map(|(name, value)| (name.get_value().clone(), *value))
+        .collect::<Vec<_>>();
+    if entries.is_empty() {
+        return String::from("No globals defined.\n");
+    }
+    entries.sort_by(|(a, _), (b, _)| a.cmp(b));
+    let mut result = String::new();
+    for (name, value) in entries {
+        result.push_str(&format!("{} = {}\n", name, value));
     }
     return result;
 }
 
+/// An operand of a decoded instruction.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Operand {
+    /// Index into the chunk's constants.
+    Constant(usize),
+    /// Index into the chunk's global slots.
+    Slot(usize),
+    /// A plain byte, such as an argument count.
+    Byte(u8),
+}
+
+/// An instruction decoded from a chunk by `decode_instruction`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Instruction {
+    pub opcode: OpCode,
+    pub operands: Vec<Operand>,
+    pub line: LineNumber,
+    /// Size of the instruction with its operands; the next one starts this
+    /// many bytes after it.
+    pub byte_len: usize,
+}
+
+impl Instruction {
+    /// Values the instruction pops and pushes, including call arguments.
+    pub fn stack_effect(&self) -> (usize, usize) {
+        let info = self.opcode.info();
+        let args = match (self.opcode, self.operands.last()) {
+            (Call | Invoke | InvokeLong, Some(Operand::Byte(arg_count))) => usize::from(*arg_count),
+            _ => 0,
+        };
+        return (info.pops + args, info.pushes);
+    }
+}
+
+/// Decodes the instruction at `offset`. Returns `None` past the end of the
+/// code, for an unknown opcode, or if the operands are cut off.
+pub fn decode_instruction(chunk: &Chunk, offset: usize) -> Option<Instruction> {
+    let opcode: OpCode = FromPrimitive::from_u8(chunk.read_byte(offset)?)?;
+    let line = *chunk.get_line(offset)?;
+    let byte_len = 1 + opcode.info().operand_bytes;
+    if chunk.get_code().len() < offset + byte_len {
+        return None;
+    }
+    let mut operands = Vec::new();
+    let mut next = offset + 1;
+    if let Some((_, width)) = ConstOp::from_opcode(opcode) {
+        let index = match width {
+            OperandWidth::Byte => usize::from(chunk.read_byte(next)?),
+            OperandWidth::Short => usize::from(chunk.read_short(next)?),
+        };
+        operands.push(Operand::Constant(index));
+        next += width.size();
+    } else if let Some((_, width)) = SlotOp::from_opcode(opcode) {
+        let slot = match width {
+            OperandWidth::Byte => usize::from(chunk.read_byte(next)?),
+            OperandWidth::Short => usize::from(chunk.read_short(next)?),
+        };
+        operands.push(Operand::Slot(slot));
+        next += width.size();
+    }
+    // Whatever follows a constant index, such as an argument count
+    while next < offset + byte_len {
+        operands.push(Operand::Byte(chunk.read_byte(next)?));
+        next += 1;
+    }
+    return Some(Instruction {
+        opcode,
+        operands,
+        line,
+        byte_len,
+    });
+}
+
+/// The name the disassembler shows `opcode` with, such as `OP_CONSTANT`.
+pub fn opcode_name(opcode: OpCode) -> &'static str {
+    return match opcode {
+        Return => "OP_RETURN",
+        Constant => "OP_CONSTANT",
+        ConstantLong => "OP_CONSTANT_LONG",
+        OpCode::Nil => "OP_NIL",
+        True => "OP_TRUE",
+        False => "OP_FALSE",
+        Pop => "OP_POP",
+        Get => "OP_GET_GLOBAL",
+        GetLong => "OP_GET_GLOBAL_LONG",
+        DefineGlobal => "OP_DEFINE_GLOBAL",
+        DefineGlobalLong => "OP_DEFINE_GLOBAL_LONG",
+        Equal => "OP_EQUAL",
+        Greater => "OP_GREATER",
+        Less => "OP_LESS",
+        Add => "OP_ADD",
+        Subtract => "OP_SUBTRACT",
+        Multiply => "OP_MULTIPLY",
+        Divide => "OP_DIVIDE",
+        Negate => "OP_NEGATE",
+        Not => "OP_NOT",
+        Print => "OP_PRINT",
+        Call => "OP_CALL",
+        Invoke => "OP_INVOKE",
+        InvokeLong => "OP_INVOKE_LONG",
+        Import => "OP_IMPORT",
+        ImportAll => "OP_IMPORT_ALL",
+        ImportName => "OP_IMPORT_NAME",
+        ImportNameLong => "OP_IMPORT_NAME_LONG",
+        GetSlot => "OP_GET_SLOT",
+        GetSlotLong => "OP_GET_SLOT_LONG",
+        DefineSlot => "OP_DEFINE_SLOT",
+        DefineSlotLong => "OP_DEFINE_SLOT_LONG",
+    };
+}
+
 pub fn disassemble_instruction(chunk: &Chunk, offset: usize) -> Option<(usize, String)> {
     let byte = chunk.read_byte(offset)?;
-    let instruction: Option<OpCode> = FromPrimitive::from_u8(byte);
-    let (new_offset, instr_description): (usize, String) = match instruction {
-        Some(Return) => simple_instruction("OP_RETURN", offset),
-        Some(Constant) => constant_instruction("OP_CONSTANT", chunk, offset)?,
-        Some(ConstantLong) => constant_long_instruction("OP_CONSTANT_LONG", chunk, offset)?,
-        Some(OpCode::Nil) => simple_instruction("OP_NIL", offset),
-        Some(True) => simple_instruction("OP_TRUE", offset),
-        Some(False) => simple_instruction("OP_FALSE", offset),
-        Some(Pop) => simple_instruction("OP_POP", offset),
-        Some(Get) => constant_instruction("OP_GET_GLOBAL", chunk, offset)?,
-        Some(GetLong) => constant_long_instruction("OP_GET_GLOBAL_LONG", chunk, offset)?,
-        Some(DefineGlobal) => constant_instruction("OP_DEFINE_GLOBAL", chunk, offset)?,
-        Some(DefineGlobalLong) => {
-            constant_long_instruction("OP_DEFINE_GLOBAL_LONG", chunk, offset)?
-        }
-        Some(Equal) => simple_instruction("OP_EQUAL", offset),
-        Some(Greater) => simple_instruction("OP_GREATER", offset),
-        Some(Less) => simple_instruction("OP_LESS", offset),
-        Some(Add) => simple_instruction("OP_ADD", offset),
-        Some(Subtract) => simple_instruction("OP_SUBTRACT", offset),
-        Some(Multiply) => simple_instruction("OP_MULTIPLY", offset),
-        Some(Divide) => simple_instruction("OP_DIVIDE", offset),
-        Some(Negate) => simple_instruction("OP_NEGATE", offset),
-        Some(Not) => simple_instruction("OP_NOT", offset),
-        Some(Print) => simple_instruction("OP_PRINT", offset),
-        None => {
-            return Some((
-                offset + 1,
-                format!("[{}] Unknown opcode {:02x}", offset, byte),
-            ))
-        }
-    };
+    if OpCode::from_u8(byte).is_none() {
+        return Some((
+            offset + 1,
+            format!("[{}] Unknown opcode {:02x}", offset, byte),
+        ));
+    }
+    let instruction = decode_instruction(chunk, offset)?;
     return Some((
-        new_offset,
-        format!(
-            "{:04} {}{}",
-            offset,
-            line_info(chunk, offset),
-            instr_description
-        ),
+        offset + instruction.byte_len,
+        describe_instruction(chunk, offset, &instruction),
     ));
 }
 
+/// Formats an instruction already decoded from `chunk` at `offset`, as
+/// `disassemble_instruction` does.
+pub fn describe_instruction(chunk: &Chunk, offset: usize, instruction: &Instruction) -> String {
+    let name = opcode_name(instruction.opcode);
+    let description = match instruction.operands.as_slice() {
+        [] => name.to_string(),
+        [Operand::Byte(operand)] => format!("{} {}", name, operand),
+        [Operand::Constant(constant)] => {
+            format!("{} {}", name, describe_constant(chunk, *constant))
+        }
+        [Operand::Slot(slot)] => match chunk.global_slot_name(*slot) {
+            Some(global) => format!("{} {} '{}'", name, slot, global),
+            None => format!("{} {} <invalid>", name, slot),
+        },
+        [Operand::Constant(constant), Operand::Byte(arg_count)] => format!(
+            "{} ({} args) {}",
+            name,
+            arg_count,
+            describe_constant(chunk, *constant)
+        ),
+        operands => format!("{} {:?}", name, operands),
+    };
+    return format!("{:04} {}{}", offset, line_info(chunk, offset), description);
+}
+
+fn describe_constant(chunk: &Chunk, index: usize) -> String {
+    return match chunk.get_constant(index) {
+        Some(value) => format!("{} '{}'", index, value),
+        None => format!("{} <invalid>", index),
+    };
+}
+
 fn line_info(chunk: &Chunk, offset: usize) -> String {
     let cur_line = chunk.get_line(offset).unwrap();
-    if offset > 0 && cur_line == chunk.get_line(offset - 1).unwrap() {
+    if offset > 0 && chunk.get_line(offset - 1) == Some(cur_line) {
         return String::from("   | ");
     } else {
         return format!("{:4} ", cur_line);
     }
 }
 
-fn simple_instruction(name: &str, offset: usize) -> (usize, String) {
-    return (offset + 1, format!("{}", name));
-}
-
-fn constant_instruction(name: &str, chunk: &Chunk, offset: usize) -> Option<(usize, String)> {
-    let constant = chunk.get_code()[offset + 1];
-    let index: usize = constant.try_into().ok()?;
-    let value = chunk.get_constant(index);
-    let description = format!("{} {} '{}'", name, constant, value);
-    return Some((offset + 2, description));
-}
-
-fn constant_long_instruction(name: &str, chunk: &Chunk, offset: usize) -> Option<(usize, String)> {
-    let constant = chunk.read_short(offset + 1)?;
-    let index: usize = constant.try_into().ok()?;
-    let value = chunk.get_constant(index);
-    let description = format!("{} {} '{}'", name, constant, value);
-    return Some((offset + 3, description));
-}
-
 #[cfg(test)]
 mod tests {
 
     use super::*;
+    use crate::value::Value::Number;
 
     #[test]
     fn retrn() {
@@ -120,7 +229,7 @@ mod tests {
     fn constant() {
         let mut chunk = Chunk::new();
         let const_ref = chunk.add_const(Number(1.2));
-        chunk.ref_const(const_ref, OpCode::Constant, OpCode::ConstantLong, 1);
+        chunk.ref_const(const_ref, ConstOp::Constant, 1);
         let result = disassemble_chunk(&chunk, "test chunk");
         assert_eq!(
             result,
@@ -136,7 +245,7 @@ mod tests {
         let mut chunk = Chunk::new();
         for i in 0..300 {
             let const_ref = chunk.add_const(Number(i as f32));
-            chunk.ref_const(const_ref, OpCode::Constant, OpCode::ConstantLong, i);
+            chunk.ref_const(const_ref, ConstOp::Constant, i);
         }
         let mut target_result = String::from("== test chunk ==\n");
         for i in 0..256 {
@@ -278,7 +387,7 @@ mod tests {
     fn line_numbers() {
         let mut chunk = Chunk::new();
         let const_ref = chunk.add_const(Number(1.2));
-        chunk.ref_const(const_ref, OpCode::Constant, OpCode::ConstantLong, 123);
+        chunk.ref_const(const_ref, ConstOp::Constant, 123);
         chunk.write_opcode(Return, 123);
         let result = disassemble_chunk(&chunk, "test chunk");
         assert_eq!(
@@ -318,4 +427,81 @@ mod tests {
             )
         );
     }
+
+    #[test]
+    fn call_and_invoke() {
+        let mut chunk = Chunk::new();
+        chunk.write_opcode(Call, 1);
+        chunk.write_byte(2, 1);
+        let const_ref = chunk.add_const(Number(1.0));
+        chunk.ref_const(const_ref, ConstOp::Invoke, 1);
+        chunk.write_byte(3, 1);
+        let result = disassemble_chunk(&chunk, "test chunk");
+        assert_eq!(
+            result,
+            String::from(
+                "== test chunk ==\n\
+                0000    1 OP_CALL 2\n\
+                0002    | OP_INVOKE (3 args) 0 '1'\n"
+            )
+        );
+    }
+
+    #[test]
+    fn global_slots() {
+        let mut gc = crate::gc::GC::new();
+        let mut chunk = Chunk::new();
+        let name = chunk.add_const(Value::Object(gc.alloc_string(String::from("x"))));
+        chunk.write_opcode(OpCode::Nil, 1);
+        chunk.ref_const(name, ConstOp::DefineGlobal, 1);
+        chunk.ref_const(name, ConstOp::Get, 1);
+        chunk.resolve_global_slots().unwrap();
+        chunk.write_opcode(GetSlot, 1);
+        chunk.write_byte(1, 1);
+        let result = disassemble_chunk(&chunk, "test chunk");
+        assert_eq!(
+            result,
+            String::from(
+                "== test chunk ==\n\
+                0000    1 OP_NIL\n\
+                0001    | OP_DEFINE_SLOT 0 '\"x\"'\n\
+                0003    | OP_GET_SLOT 0 '\"x\"'\n\
+                0005    | OP_GET_SLOT 1 <invalid>\n"
+            )
+        );
+    }
+
+    #[test]
+    fn decodes_instructions() {
+        let mut chunk = Chunk::new();
+        let const_ref = chunk.add_const(Number(1.0));
+        chunk.ref_const(const_ref, ConstOp::Invoke, 7);
+        chunk.write_byte(3, 7);
+        chunk.write_opcode(Return, 8);
+        assert_eq!(
+            decode_instruction(&chunk, 0),
+            Some(Instruction {
+                opcode: Invoke,
+                operands: vec![Operand::Constant(0), Operand::Byte(3)],
+                line: 7,
+                byte_len: 3,
+            })
+        );
+        assert_eq!(
+            decode_instruction(&chunk, 3),
+            Some(Instruction {
+                opcode: Return,
+                operands: Vec::new(),
+                line: 8,
+                byte_len: 1,
+            })
+        );
+        assert_eq!(decode_instruction(&chunk, 4), None);
+
+        // Operands cut off by the end of the code
+        let mut truncated = Chunk::new();
+        truncated.write_opcode(ConstantLong, 1);
+        truncated.write_byte(0, 1);
+        assert_eq!(decode_instruction(&truncated, 0), None);
+    }
 }
@@ -0,0 +1,270 @@
+use rs_lox::debug::{describe_instruction, disassemble_instruction, dump_globals};
+use rs_lox::{
+    compile, search_path_from_env, stdlib, InterpreterError, LineNumber, RunOutcome, Sandbox,
+    VMConfig, Value, GC, VM,
+};
+use std::io::{self, BufRead, Write};
+
+const HELP: &str = "\
+Commands:
+  help                 Show this message
+  break [line]         Set a breakpoint, or list breakpoints without an argument
+  delete <line>        Remove a breakpoint
+  step                 Execute a single instruction
+  next                 Execute until the next source line
+  continue             Run until the next breakpoint or the end of the script
+  stack                Show the value stack
+  globals              List global variables and their values
+  dis                  Disassemble the script, marking the next instruction
+  quit                 Stop debugging
+";
+
+#[derive(Debug, PartialEq)]
+enum Command {
+    Help,
+    Break(Option<LineNumber>),
+    Delete(LineNumber),
+    Step,
+    Next,
+    Continue,
+    Stack,
+    Globals,
+    Dis,
+    Quit,
+}
+
+fn parse_line_number(word: Option<&str>) -> Result<LineNumber, String> {
+    match word.map(str::parse::<LineNumber>) {
+        Some(Ok(line)) if line > 0 => Ok(line),
+        _ => Err(String::from("Expected a line number.")),
+    }
+}
+
+fn parse_command(line: &str) -> Result<Command, String> {
+    let mut words = line.split_whitespace();
+    let name = words.next().unwrap_or("");
+    let command = match name {
+        "help" | "h" => Command::Help,
+        "break" | "b" => match words.next() {
+            None => Command::Break(None),
+            word => Command::Break(Some(parse_line_number(word)?)),
+        },
+        "delete" | "d" => Command::Delete(parse_line_number(words.next())?),
+        "step" | "s" => Command::Step,
+        "next" | "n" => Command::Next,
+        "continue" | "c" => Command::Continue,
+        "stack" => Command::Stack,
+        "globals" => Command::Globals,
+        "dis" => Command::Dis,
+        "quit" | "q" => Command::Quit,
+        _ => {
+            return Err(format!(
+                "Unknown command '{}'. Type help for a list of commands.",
+                name
+            ))
+        }
+    };
+    if words.next().is_some() {
+        return Err(format!("Too many arguments for '{}'.", name));
+    }
+    Ok(command)
+}
+
+fn show_location(vm: &VM, lines: &[&str]) {
+    match vm.current_line() {
+        Some(line) => {
+            let text = lines.get(line as usize - 1).map_or("", |text| text.trim());
+            println!("{:4} | {}", line, text);
+            if let Some((_, description)) = disassemble_instruction(vm.chunk(), vm.ip()) {
+                println!("     -> {}", description);
+            }
+        }
+        None => println!("At end of script."),
+    }
+}
+
+fn show_disassembly(vm: &VM) {
+    for (offset, instruction) in vm.chunk().instructions() {
+        let marker = if offset == vm.ip() { "=>" } else { "  " };
+        let description = describe_instruction(vm.chunk(), offset, &instruction);
+        println!("{} {}", marker, description);
+    }
+}
+
+fn show_stack(vm: &VM) {
+    if vm.stack().is_empty() {
+        println!("Stack is empty.");
+        return;
+    }
+    for value in vm.stack() {
+        print!("[{}]", value);
+    }
+    println!();
+}
+
+/// Executes instructions until the VM reaches a different source line.
+fn next_line(vm: &mut VM) -> Result<Option<Value>, InterpreterError> {
+    let line = vm.current_line();
+    loop {
+        if let Some(value) = vm.step()? {
+            return Ok(Some(value));
+        }
+        if vm.current_line() != line {
+            return Ok(None);
+        }
+    }
+}
+
+/// Runs `source` under an interactive debugger that reads commands from
+/// stdin. Runtime errors in the script are returned to the caller.
+pub fn debug(source: &String, path: &str) -> Result<(), InterpreterError> {
+    let lines = source.lines().collect::<Vec<_>>();
+    let mut stdout = io::stdout();
+    let mut gc = GC::new();
+    let (chunk, warnings) = compile(source, &mut gc)?;
+    for warning in &warnings {
+        eprintln!("{}", warning);
+    }
+    let mut script_input = io::BufReader::new(io::stdin());
+    let mut vm = VM::new(
+        VMConfig {
+            trace_instructions: false,
+            trace_stack: false,
+            trace_globals: false,
+            stdout: &mut stdout,
+            stdin: &mut script_input,
+            sandbox: Sandbox::default(),
+        },
+        &mut gc,
+    );
+    stdlib::install(&mut vm);
+    vm.set_search_path(search_path_from_env());
+    vm.set_script_path(path);
+    vm.load_chunk(chunk)?;
+    println!("Paused at the start of the script. Type help for a list of commands.");
+    show_location(&vm, &lines);
+    let stdin = io::stdin();
+    let mut input = String::new();
+    loop {
+        print!("(rlox) ");
+        let _ = io::stdout().flush();
+        input.clear();
+        match stdin.lock().read_line(&mut input) {
+            Ok(0) | Err(_) => {
+                println!();
+                return Ok(());
+            }
+            Ok(_) => {}
+        }
+        if input.trim().is_empty() {
+            continue;
+        }
+        let command = match parse_command(&input) {
+            Ok(command) => command,
+            Err(message) => {
+                eprintln!("{}", message);
+                continue;
+            }
+        };
+        let finished = match command {
+            Command::Help => {
+                print!("{}", HELP);
+                None
+            }
+            Command::Break(Some(line)) => {
+                vm.set_breakpoint(line);
+                println!("Breakpoint set at line {}.", line);
+                None
+            }
+            Command::Break(None) => {
+                if vm.breakpoints().is_empty() {
+                    println!("No breakpoints set.");
+                }
+                for line in vm.breakpoints() {
+                    println!("Breakpoint at line {}.", line);
+                }
+                None
+            }
+            Command::Delete(line) => {
+                if vm.clear_breakpoint(line) {
+                    println!("Breakpoint at line {} removed.", line);
+                } else {
+                    println!("No breakpoint at line {}.", line);
+                }
+                None
+            }
+            Command::Step => {
+                let finished = vm.step()?;
+                if finished.is_none() {
+                    show_location(&vm, &lines);
+                }
+                finished
+            }
+            Command::Next => {
+                let finished = next_line(&mut vm)?;
+                if finished.is_none() {
+                    show_location(&vm, &lines);
+                }
+                finished
+            }
+            Command::Continue => match vm.resume()? {
+                RunOutcome::Finished(value) => Some(value),
+                RunOutcome::Breakpoint(line) => {
+                    println!("Breakpoint hit at line {}.", line);
+                    show_location(&vm, &lines);
+                    None
+                }
+                RunOutcome::Suspended => {
+                    println!("Suspended by a native; continuing returns nil.");
+                    show_location(&vm, &lines);
+                    None
+                }
+            },
+            Command::Stack => {
+                show_stack(&vm);
+                None
+            }
+            Command::Globals => {
+                print!("{}", dump_globals(vm.globals()));
+                None
+            }
+            Command::Dis => {
+                show_disassembly(&vm);
+                None
+            }
+            Command::Quit => return Ok(()),
+        };
+        if let Some(value) = finished {
+            println!("Script finished with {}.", value);
+            return Ok(());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_commands() {
+        assert_eq!(parse_command("step\n"), Ok(Command::Step));
+        assert_eq!(parse_command("n"), Ok(Command::Next));
+        assert_eq!(parse_command("  continue "), Ok(Command::Continue));
+        assert_eq!(parse_command("stack"), Ok(Command::Stack));
+        assert_eq!(parse_command("globals"), Ok(Command::Globals));
+        assert_eq!(parse_command("dis"), Ok(Command::Dis));
+        assert_eq!(parse_command("q"), Ok(Command::Quit));
+        assert!(parse_command("stack 1").is_err());
+        assert!(parse_command("frobnicate").is_err());
+    }
+
+    #[test]
+    fn parses_breakpoints() {
+        assert_eq!(parse_command("break 12"), Ok(Command::Break(Some(12))));
+        assert_eq!(parse_command("b"), Ok(Command::Break(None)));
+        assert_eq!(parse_command("delete 3"), Ok(Command::Delete(3)));
+        assert!(parse_command("break zero").is_err());
+        assert!(parse_command("break 0").is_err());
+        assert!(parse_command("delete").is_err());
+    }
+}
@@ -0,0 +1,339 @@
+use rs_lox::ast::{Expr, Program, Stmt};
+use rs_lox::{LineNumber, Scanner, Token, TokenKind};
+use std::io;
+
+/// How the `--dump-*` modes print what they dump.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    /// One line per item, for reading.
+    Text,
+    /// A single JSON value, for other tools.
+    Json,
+}
+
+impl Format {
+    /// Reads the format from what follows a flag such as `--dump-tokens`:
+    /// nothing for text, or `=json`.
+    pub fn from_suffix(suffix: &str) -> Option<Format> {
+        return match suffix {
+            "" => Some(Format::Text),
+            "=json" => Some(Format::Json),
+            _ => None,
+        };
+    }
+}
+
+/// Quotes `s` as a JSON string.
+pub fn json_string(s: &str) -> String {
+    let mut quoted = String::from("\"");
+    for c in s.chars() {
+        match c {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            '\n' => quoted.push_str("\\n"),
+            '\r' => quoted.push_str("\\r"),
+            '\t' => quoted.push_str("\\t"),
+            c if c.is_control() => quoted.push_str(&format!("\\u{:04x}", c as u32)),
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    return quoted;
+}
+
+fn scan_all(source: &String) -> Vec<Token> {
+    let mut scanner = Scanner::new(source);
+    let mut tokens = Vec::new();
+    loop {
+        let token = scanner.scan();
+        let done = token.kind == TokenKind::Eof;
+        tokens.push(token);
+        if done {
+            return tokens;
+        }
+    }
+}
+
+/// Writes every token in `source`, up to and including the end of the file.
+/// Scanner errors are tokens too, with the message as their lexeme.
+///
+/// As text, each token is `line:column Kind 'lexeme'`. As JSON, it's an
+/// array of objects with `kind`, `lexeme`, `line` and `column`.
+pub fn write_tokens(out: &mut dyn io::Write, source: &String, format: Format) -> io::Result<()> {
+    let tokens = scan_all(source);
+    if format == Format::Text {
+        for token in &tokens {
+            let position = format!("{}:{}", token.line, token.column);
+            let kind = format!("{:?}", token.kind);
+            writeln!(out, "{:<8} {:<12} '{}'", position, kind, token.lexeme)?;
+        }
+        return Ok(());
+    }
+    writeln!(out, "[")?;
+    for (index, token) in tokens.iter().enumerate() {
+        let separator = if index + 1 < tokens.len() { "," } else { "" };
+        writeln!(
+            out,
+            "  {{\"kind\": {}, \"lexeme\": {}, \"line\": {}, \"column\": {}}}{}",
+            json_string(&format!("{:?}", token.kind)),
+            json_string(&token.lexeme),
+            token.line,
+            token.column,
+            separator
+        )?;
+    }
+    return writeln!(out, "]");
+}
+
+/// A syntax tree node, reduced to what's printed.
+struct Node {
+    kind: &'static str,
+    line: LineNumber,
+    attributes: Vec<(&'static str, String)>,
+    children: Vec<Node>,
+}
+
+impl Node {
+    fn new(kind: &'static str, at: &Token) -> Node {
+        return Node {
+            kind,
+            line: at.line,
+            attributes: Vec::new(),
+            children: Vec::new(),
+        };
+    }
+
+    fn attribute(mut self, name: &'static str, value: &str) -> Node {
+        self.attributes.push((name, String::from(value)));
+        return self;
+    }
+
+    fn child(mut self, child: Node) -> Node {
+        self.children.push(child);
+        return self;
+    }
+
+    fn write_text(&self, out: &mut dyn io::Write, depth: usize) -> io::Result<()> {
+        write!(out, "{:indent$}{}", "", self.kind, indent = depth * 2)?;
+        for (name, value) in &self.attributes {
+            write!(out, " {}={}", name, value)?;
+        }
+        writeln!(out, " (line {})", self.line)?;
+        return self
+            .children
+            .iter()
+            .try_for_each(|child| child.write_text(out, depth + 1));
+    }
+
+    fn write_json(&self, out: &mut dyn io::Write, depth: usize) -> io::Result<()> {
+        write!(
+            out,
+            "{{\"kind\": {}, \"line\": {}",
+            json_string(self.kind),
+            self.line
+        )?;
+        for (name, value) in &self.attributes {
+            write!(out, ", {}: {}", json_string(name), json_string(value))?;
+        }
+        if self.children.is_empty() {
+            return write!(out, "}}");
+        }
+        write!(out, ", \"children\": [")?;
+        for (index, child) in self.children.iter().enumerate() {
+            let separator = if index == 0 { "" } else { "," };
+            write!(
+                out,
+                "{}\n{:indent$}",
+                separator,
+                "",
+                indent = (depth + 1) * 2
+            )?;
+            child.write_json(out, depth + 1)?;
+        }
+        return write!(out, "\n{:indent$}]}}", "", indent = depth * 2);
+    }
+}
+
+fn expr_node(expr: &Expr) -> Node {
+    return match expr {
+        Expr::Literal { token, .. } => {
+            Node::new("Literal", token).attribute("value", &token.lexeme)
+        }
+        Expr::Variable { name } => Node::new("Variable", name).attribute("name", &name.lexeme),
+        Expr::Grouping { expr, right_paren } => {
+            Node::new("Grouping", right_paren).child(expr_node(expr))
+        }
+        Expr::Unary {
+            operator, operand, ..
+        } => Node::new("Unary", operator)
+            .attribute("operator", &operator.lexeme)
+            .child(expr_node(operand)),
+        Expr::Binary {
+            left,
+            operator,
+            right,
+            ..
+        } => Node::new("Binary", operator)
+            .attribute("operator", &operator.lexeme)
+            .child(expr_node(left))
+            .child(expr_node(right)),
+        Expr::Call {
+            callee,
+            arguments,
+            right_paren,
+        } => arguments.iter().fold(
+            Node::new("Call", right_paren).child(expr_node(callee)),
+            |node, argument| node.child(expr_node(argument)),
+        ),
+        Expr::Invoke {
+            receiver,
+            name,
+            arguments,
+            ..
+        } => {
+            let node = Node::new("Invoke", name)
+                .attribute("name", &name.lexeme)
+                .child(expr_node(receiver));
+            arguments
+                .iter()
+                .fold(node, |node, argument| node.child(expr_node(argument)))
+        }
+    };
+}
+
+fn stmt_node(stmt: &Stmt) -> Node {
+    return match stmt {
+        Stmt::Var {
+            name, initializer, ..
+        } => {
+            let node = Node::new("Var", name).attribute("name", &name.lexeme);
+            match initializer {
+                Some(initializer) => node.child(expr_node(initializer)),
+                None => node,
+            }
+        }
+        Stmt::Import { names, path, .. } => {
+            let node = Node::new("Import", path).attribute("path", &path.lexeme);
+            if names.is_empty() {
+                return node;
+            }
+            let names = names
+                .iter()
+                .map(|name| name.lexeme.as_str())
+                .collect::<Vec<_>>();
+            node.attribute("names", &names.join(", "))
+        }
+        Stmt::Print { expr, semicolon } => Node::new("Print", semicolon).child(expr_node(expr)),
+        Stmt::Expression { expr, semicolon } => {
+            Node::new("Expression", semicolon).child(expr_node(expr))
+        }
+        Stmt::Defer { keyword, statement } => {
+            Node::new("Defer", keyword).child(stmt_node(statement))
+        }
+    };
+}
+
+/// Writes the syntax tree of `program`. As text, each node is a line with
+/// its kind, attributes such as names and operators, and line, followed by
+/// its children indented. As JSON, it's an array of the statements, each an
+/// object with `kind`, `line`, the attributes and any `children`.
+pub fn write_ast(out: &mut dyn io::Write, program: &Program, format: Format) -> io::Result<()> {
+    let nodes = program.statements.iter().map(stmt_node).collect::<Vec<_>>();
+    if format == Format::Text {
+        return nodes.iter().try_for_each(|node| node.write_text(out, 0));
+    }
+    write!(out, "[")?;
+    for (index, node) in nodes.iter().enumerate() {
+        let separator = if index == 0 { "" } else { "," };
+        write!(out, "{}\n  ", separator)?;
+        node.write_json(out, 1)?;
+    }
+    return writeln!(out, "\n]");
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn dump_tokens(source: &str, format: Format) -> String {
+        let mut out = Vec::new();
+        write_tokens(&mut out, &String::from(source), format).unwrap();
+        return String::from_utf8(out).unwrap();
+    }
+
+    #[test]
+    fn dumps_tokens_as_text() {
+        assert_eq!(
+            dump_tokens("var a = \"b\";\n@", Format::Text),
+            "1:1      Var          'var'\n\
+             1:5      Identifier   'a'\n\
+             1:7      Equal        '='\n\
+             1:9      Str          '\"b\"'\n\
+             1:12     Semicolon    ';'\n\
+             2:1      Error        'Unexpected character.'\n\
+             2:2      Eof          ''\n"
+        );
+    }
+
+    #[test]
+    fn dumps_tokens_as_json() {
+        assert_eq!(
+            dump_tokens("print \"a\\\\\";", Format::Json),
+            "[\n  \
+             {\"kind\": \"Print\", \"lexeme\": \"print\", \"line\": 1, \"column\": 1},\n  \
+             {\"kind\": \"Str\", \"lexeme\": \"\\\"a\\\\\\\\\\\"\", \"line\": 1, \"column\": 7},\n  \
+             {\"kind\": \"Semicolon\", \"lexeme\": \";\", \"line\": 1, \"column\": 12},\n  \
+             {\"kind\": \"Eof\", \"lexeme\": \"\", \"line\": 1, \"column\": 13}\n\
+             ]\n"
+        );
+        assert_eq!(json_string("\u{1}\t"), "\"\\u0001\\t\"");
+    }
+
+    fn dump_ast(source: &str, format: Format) -> String {
+        let (program, diagnostics) = rs_lox::ast::parse(&String::from(source));
+        assert!(diagnostics.is_empty(), "{:?}", diagnostics);
+        let mut out = Vec::new();
+        write_ast(&mut out, &program, format).unwrap();
+        return String::from_utf8(out).unwrap();
+    }
+
+    #[test]
+    fn dumps_ast_as_text() {
+        assert_eq!(
+            dump_ast(
+                "import { a } from \"m.lox\";\nvar b;\nprint -a + (2);\ndefer a.f(b, nil);",
+                Format::Text
+            ),
+            "Import path=\"m.lox\" names=a (line 1)\n\
+             Var name=b (line 2)\n\
+             Print (line 3)\n\
+             \x20 Binary operator=+ (line 3)\n\
+             \x20   Unary operator=- (line 3)\n\
+             \x20     Variable name=a (line 3)\n\
+             \x20   Grouping (line 3)\n\
+             \x20     Literal value=2 (line 3)\n\
+             Defer (line 4)\n\
+             \x20 Expression (line 4)\n\
+             \x20   Invoke name=f (line 4)\n\
+             \x20     Variable name=a (line 4)\n\
+             \x20     Variable name=b (line 4)\n\
+             \x20     Literal value=nil (line 4)\n"
+        );
+    }
+
+    #[test]
+    fn dumps_ast_as_json() {
+        assert_eq!(
+            dump_ast("print f(\"x\");", Format::Json),
+            "[\n  \
+             {\"kind\": \"Print\", \"line\": 1, \"children\": [\n    \
+             {\"kind\": \"Call\", \"line\": 1, \"children\": [\n      \
+             {\"kind\": \"Variable\", \"line\": 1, \"name\": \"f\"},\n      \
+             {\"kind\": \"Literal\", \"line\": 1, \"value\": \"\\\"x\\\"\"}\n    \
+             ]}\n  \
+             ]}\n\
+             ]\n"
+        );
+        assert_eq!(dump_ast("", Format::Json), "[\n]\n");
+    }
+}
@@ -0,0 +1,103 @@
+use crate::dump::json_string;
+use rs_lox::debug::{decode_instruction, opcode_name};
+use rs_lox::{Chunk, Value, VmHook};
+use std::io;
+use std::path::Path;
+
+/// Longest preview of the value on top of the stack, in characters.
+const PREVIEW_LEN: usize = 40;
+
+/// Writes a line of JSON for every instruction a VM runs, collected by
+/// installing it with `VM::set_hook`. Each has the `script` and `line` it
+/// came from, its `offset` and `opcode`, the `stack_depth` it starts with
+/// and a preview of the value on `top` of the stack, or `null` for what
+/// isn't known.
+pub struct EventLog<W> {
+    out: W,
+    /// The first error writing, after which nothing more is written.
+    error: Option<io::Error>,
+}
+
+impl<W: io::Write> EventLog<W> {
+    pub fn new(out: W) -> EventLog<W> {
+        return EventLog { out, error: None };
+    }
+
+    /// Flushes the log, or returns the first error writing to it.
+    pub fn finish(mut self) -> io::Result<()> {
+        return match self.error.take() {
+            Some(error) => Err(error),
+            None => self.out.flush(),
+        };
+    }
+}
+
+fn preview(value: &Value) -> String {
+    let text = value.to_string();
+    if text.chars().count() <= PREVIEW_LEN {
+        return text;
+    }
+    let mut preview = text.chars().take(PREVIEW_LEN).collect::<String>();
+    preview.push_str("...");
+    return preview;
+}
+
+fn json_or_null<T>(value: Option<T>, json: impl FnOnce(T) -> String) -> String {
+    return value.map_or_else(|| String::from("null"), json);
+}
+
+impl<W: io::Write + Send> VmHook for EventLog<W> {
+    fn before_instruction(&mut self, _: Option<&Path>, _: &Chunk, _: usize) {}
+
+    fn before_instruction_with_stack(
+        &mut self,
+        script: Option<&Path>,
+        chunk: &Chunk,
+        offset: usize,
+        stack: &[Value],
+    ) {
+        if self.error.is_some() {
+            return;
+        }
+        let opcode = decode_instruction(chunk, offset).map(|instruction| instruction.opcode);
+        let written = writeln!(
+            self.out,
+            "{{\"script\": {}, \"line\": {}, \"offset\": {}, \"opcode\": {}, \"stack_depth\": {}, \"top\": {}}}",
+            json_or_null(script, |script| json_string(&script.display().to_string())),
+            json_or_null(chunk.get_line(offset), |line| line.to_string()),
+            offset,
+            json_or_null(opcode, |opcode| json_string(opcode_name(opcode))),
+            stack.len(),
+            json_or_null(stack.last(), |top| json_string(&preview(top))),
+        );
+        self.error = written.err();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rs_lox::{compile, GC};
+
+    #[test]
+    fn logs_each_instruction() {
+        let mut gc = GC::new();
+        let (chunk, _) = compile(&String::from("print 1;"), &mut gc).unwrap();
+        let long = gc.alloc_string("a".repeat(50));
+        let mut log = EventLog::new(Vec::new());
+        log.before_instruction_with_stack(Some(Path::new("main.lox")), &chunk, 0, &[]);
+        log.before_instruction_with_stack(None, &chunk, 2, &[Value::Object(long)]);
+        log.before_instruction_with_stack(None, &chunk, 9, &[]);
+        let output = String::from_utf8(log.out).unwrap();
+        assert_eq!(
+            output,
+            format!(
+                "{{\"script\": \"main.lox\", \"line\": 1, \"offset\": 0, \"opcode\": \"OP_CONSTANT\", \"stack_depth\": 0, \"top\": null}}\n\
+                 {{\"script\": null, \"line\": 1, \"offset\": 2, \"opcode\": \"OP_PRINT\", \"stack_depth\": 1, \"top\": \"\\\"{}...\"}}\n\
+                 {{\"script\": null, \"line\": null, \"offset\": 9, \"opcode\": null, \"stack_depth\": 0, \"top\": null}}\n",
+                // Strings show quoted, and the quote counts towards the length
+                "a".repeat(PREVIEW_LEN - 1)
+            )
+        );
+    }
+}
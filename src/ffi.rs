@@ -0,0 +1,160 @@
+//! C API for embedding the interpreter, enabled with the `ffi` feature.
+//! `include/rs_lox.h` declares these functions and is generated with
+//! `cbindgen --config cbindgen.toml --output include/rs_lox.h`.
+//!
+//! A session is an opaque `Lox` handle. Strings passed in must be valid,
+//! NUL-terminated UTF-8. Strings returned are owned by the caller and must be
+//! released with `lox_string_free`.
+
+use crate::lox::{Lox, LoxError};
+use std::ffi::{CStr, CString};
+use std::os::raw::c_char;
+use std::ptr;
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LoxStatus {
+    Ok = 0,
+    CompileError = 1,
+    RuntimeError = 2,
+    /// A handle or string argument was NULL or not valid UTF-8.
+    InvalidArgument = 3,
+}
+
+unsafe fn read_str<'a>(s: *const c_char) -> Option<&'a str> {
+    if s.is_null() {
+        return None;
+    }
+    return CStr::from_ptr(s).to_str().ok();
+}
+
+fn into_c_string(s: String) -> *mut c_char {
+    // Interior NULs can only come from Lox strings, so cut the string there
+    let bytes = s.into_bytes();
+    let end = bytes.iter().position(|b| *b == 0).unwrap_or(bytes.len());
+    return CString::new(&bytes[..end]).unwrap().into_raw();
+}
+
+/// Creates a new interpreter session. Free it with `lox_free`.
+#[no_mangle]
+pub extern "C" fn lox_new() -> *mut Lox {
+    return Box::into_raw(Box::new(Lox::new()));
+}
+
+/// Frees a session created by `lox_new`. Does nothing for NULL.
+///
+/// # Safety
+/// `lox` must be NULL or a handle from `lox_new` that hasn't been freed.
+#[no_mangle]
+pub unsafe extern "C" fn lox_free(lox: *mut Lox) {
+    if !lox.is_null() {
+        drop(Box::from_raw(lox));
+    }
+}
+
+/// Runs `source` in the session. On success, `*result` is set to the value of
+/// the trailing expression statement as `print` would show it. On failure it
+/// is set to the error message. `result` may be NULL if neither is needed.
+///
+/// # Safety
+/// `lox` must be a live handle from `lox_new`, `source` a NUL-terminated
+/// string, and `result` NULL or valid for writes.
+#[no_mangle]
+pub unsafe extern "C" fn lox_eval(
+    lox: *mut Lox,
+    source: *const c_char,
+    result: *mut *mut c_char,
+) -> LoxStatus {
+    let (lox, source) = match (lox.as_mut(), read_str(source)) {
+        (Some(lox), Some(source)) => (lox, source),
+        _ => return LoxStatus::InvalidArgument,
+    };
+    let (status, message) = match lox.eval(source) {
+        Ok(value) => (LoxStatus::Ok, value.to_string()),
+        Err(error @ LoxError::CompileError(_)) => (LoxStatus::CompileError, error.to_string()),
+        Err(error) => (LoxStatus::RuntimeError, error.to_string()),
+    };
+    if !result.is_null() {
+        *result = into_c_string(message);
+    }
+    return status;
+}
+
+/// Returns the value of global `name` as `print` would show it, or NULL if it
+/// isn't defined.
+///
+/// # Safety
+/// `lox` must be a live handle from `lox_new` and `name` a NUL-terminated
+/// string.
+#[no_mangle]
+pub unsafe extern "C" fn lox_get_global(lox: *mut Lox, name: *const c_char) -> *mut c_char {
+    let (lox, name) = match (lox.as_mut(), read_str(name)) {
+        (Some(lox), Some(name)) => (lox, name),
+        _ => return ptr::null_mut(),
+    };
+    return match lox.get_global(name) {
+        Some(value) => into_c_string(value.to_string()),
+        None => ptr::null_mut(),
+    };
+}
+
+/// Frees a string returned by this API. Does nothing for NULL.
+///
+/// # Safety
+/// `s` must be NULL or a string returned by this API that hasn't been freed.
+#[no_mangle]
+pub unsafe extern "C" fn lox_string_free(s: *mut c_char) {
+    if !s.is_null() {
+        drop(CString::from_raw(s));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    unsafe fn eval(lox: *mut Lox, source: &str) -> (LoxStatus, String) {
+        let source = CString::new(source).unwrap();
+        let mut result = ptr::null_mut();
+        let status = lox_eval(lox, source.as_ptr(), &mut result);
+        let message = CStr::from_ptr(result).to_str().unwrap().to_owned();
+        lox_string_free(result);
+        return (status, message);
+    }
+
+    #[test]
+    fn evaluates_through_handles() {
+        unsafe {
+            let lox = lox_new();
+            assert_eq!(
+                eval(lox, "var a = \"x\"; a + \"y\";"),
+                (LoxStatus::Ok, String::from("\"xy\""))
+            );
+            assert_eq!(
+                eval(lox, "-a;"),
+                (
+                    LoxStatus::RuntimeError,
+                    String::from("Runtime error: [line 1] Invalid type for negation: \"x\"")
+                )
+            );
+            assert_eq!(eval(lox, "print").0, LoxStatus::CompileError);
+
+            let name = CString::new("a").unwrap();
+            let value = lox_get_global(lox, name.as_ptr());
+            assert_eq!(CStr::from_ptr(value).to_str(), Ok("\"x\""));
+            lox_string_free(value);
+            let missing = CString::new("b").unwrap();
+            assert!(lox_get_global(lox, missing.as_ptr()).is_null());
+
+            assert_eq!(
+                lox_eval(ptr::null_mut(), name.as_ptr(), ptr::null_mut()),
+                LoxStatus::InvalidArgument
+            );
+            assert_eq!(
+                lox_eval(lox, ptr::null(), ptr::null_mut()),
+                LoxStatus::InvalidArgument
+            );
+            lox_free(lox);
+        }
+    }
+}
@@ -1,9 +1,15 @@
-use crate::table::Table;
-use crate::value::Value;
+use crate::native::ObjNative;
+use crate::table::{Table, TableStats};
+use crate::userdata::{ObjUserData, UserDataType};
 use core::fmt::{Display, Error, Formatter};
+use std::any::Any;
+use std::collections::BTreeMap;
+use std::fmt;
 use std::marker::PhantomData;
 use std::ops::Deref;
+use std::panic::Location;
 use std::ptr::null_mut;
+use std::sync::Arc;
 
 #[derive(PartialEq, Debug)]
 pub struct ObjString {
@@ -31,15 +37,54 @@ impl Display for ObjString {
 }
 
 #[derive(PartialEq, Debug)]
+#[non_exhaustive]
 pub enum Obj {
     String(ObjString),
+    Native(ObjNative),
+    UserData(ObjUserData),
 }
 
 impl Obj {
     pub fn unwrap_string(&self) -> &ObjString {
         match self {
             Obj::String(s) => s,
-            _ => panic!("Expected Obj::String"),
+            _ => panic!("Expected string, got {:?}", self),
+        }
+    }
+
+    pub fn as_string(&self) -> Option<&ObjString> {
+        match self {
+            Obj::String(s) => Some(s),
+            _ => None,
+        }
+    }
+
+    pub fn as_native(&self) -> Option<&ObjNative> {
+        match self {
+            Obj::Native(native) => Some(native),
+            _ => None,
+        }
+    }
+
+    pub fn as_userdata(&self) -> Option<&ObjUserData> {
+        match self {
+            Obj::UserData(userdata) => Some(userdata),
+            _ => None,
+        }
+    }
+
+    /// Bytes the object counts for in `GC::bytes_allocated`.
+    pub fn size(&self) -> usize {
+        return object_size(self);
+    }
+
+    /// What a heap dump groups the object under: `string`, `native`, or the
+    /// name of a userdata's type.
+    pub fn kind(&self) -> &str {
+        match self {
+            Obj::String(_) => "string",
+            Obj::Native(_) => "native",
+            Obj::UserData(userdata) => userdata.get_type().get_name(),
         }
     }
 }
@@ -47,8 +92,25 @@ impl Obj {
 struct ObjRefInner {
     value: Obj,
     next: *mut ObjRefInner,
+    /// Where the object was allocated from, for heap dumps. Only kept in
+    /// debug builds, since it makes every object a pointer bigger.
+    #[cfg(debug_assertions)]
+    site: &'static Location<'static>,
 }
 
+/// Bytes an object counts for in `GC::bytes_allocated`.
+fn object_size(value: &Obj) -> usize {
+    let contents = match value {
+        Obj::String(s) => s.get_value().len(),
+        _ => 0,
+    };
+    return std::mem::size_of::<ObjRefInner>() + contents;
+}
+
+/// A reference to an object owned by a `GC`. It is only valid while that GC
+/// is alive, and must only be used by whoever currently owns the GC. To keep
+/// the second rule, `ObjRef` (and so `Value`) is neither `Send` nor `Sync`:
+/// objects can only move to another thread together with their GC.
 #[derive(Debug, PartialEq, PartialOrd, Copy, Clone)]
 pub struct ObjRef {
     ptr: *mut ObjRefInner,
@@ -72,13 +134,77 @@ impl Display for ObjRef {
     fn fmt(&self, f: &mut Formatter) -> Result<(), Error> {
         match self.deref() {
             Obj::String(obj_string) => obj_string.fmt(f),
+            Obj::Native(native) => native.fmt(f),
+            Obj::UserData(userdata) => userdata.fmt(f),
         }
     }
 }
 
+/// Live objects of one kind, see `HeapDump`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HeapGroup {
+    pub kind: String,
+    pub count: usize,
+    pub bytes: usize,
+    /// Objects per allocation site, as `file:line:column`. Always empty in
+    /// release builds, which don't record sites.
+    pub sites: BTreeMap<String, usize>,
+}
+
+/// Every live object in a GC, grouped by kind, see `GC::heap_dump`.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct HeapDump {
+    /// Sorted by kind.
+    pub groups: Vec<HeapGroup>,
+}
+
+impl HeapDump {
+    pub fn count(&self) -> usize {
+        return self.groups.iter().map(|group| group.count).sum();
+    }
+
+    pub fn bytes(&self) -> usize {
+        return self.groups.iter().map(|group| group.bytes).sum();
+    }
+}
+
+impl Display for HeapDump {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        writeln!(f, "{:<24} {:>8} {:>10}", "kind", "count", "bytes")?;
+        for group in &self.groups {
+            writeln!(
+                f,
+                "{:<24} {:>8} {:>10}",
+                group.kind, group.count, group.bytes
+            )?;
+            for (site, count) in &group.sites {
+                writeln!(f, "  {:<22} {:>8}", site, count)?;
+            }
+        }
+        return writeln!(
+            f,
+            "{:<24} {:>8} {:>10}",
+            "total",
+            self.count(),
+            self.bytes()
+        );
+    }
+}
+
+/// A snapshot of the GC's counters, see `GC::stats`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct GCStats {
+    pub allocations: usize,
+    pub bytes_allocated: usize,
+    /// The table of interned strings.
+    pub strings: TableStats,
+}
+
 pub struct GC {
     strings: Table<*mut ObjRefInner>,
     refs: *mut ObjRefInner,
+    allocations: usize,
+    bytes_allocated: usize,
 }
 
 fn hash_string(s: &str) -> u32 {
@@ -91,22 +217,100 @@ fn hash_string(s: &str) -> u32 {
     return h;
 }
 
+impl Default for GC {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl GC {
     pub fn new() -> GC {
         GC {
             refs: null_mut(),
             strings: Table::new(),
+            allocations: 0,
+            bytes_allocated: 0,
+        }
+    }
+
+    /// Number of objects allocated since the GC was created. Interned strings
+    /// that were already present are not counted.
+    pub fn allocation_count(&self) -> usize {
+        self.allocations
+    }
+
+    /// Approximate size of all objects allocated since the GC was created,
+    /// counting string contents but not host data inside userdata, unless
+    /// reported with `add_external_bytes`.
+    pub fn bytes_allocated(&self) -> usize {
+        self.bytes_allocated
+    }
+
+    pub fn stats(&self) -> GCStats {
+        GCStats {
+            allocations: self.allocations,
+            bytes_allocated: self.bytes_allocated,
+            strings: self.strings.stats(),
         }
     }
 
+    /// Every object the GC owns, newest first. Objects are only freed with
+    /// the GC, so these are all the objects allocated so far.
+    pub fn objects(&self) -> impl Iterator<Item = &Obj> + '_ {
+        return self.inners().map(|inner| &inner.value);
+    }
+
+    fn inners(&self) -> impl Iterator<Item = &ObjRefInner> + '_ {
+        // SAFETY: every pointer in the list is a live object owned by the GC,
+        // which can't be freed while `self` is borrowed
+        let first = unsafe { self.refs.as_ref() };
+        return std::iter::successors(first, |inner| unsafe { inner.next.as_ref() });
+    }
+
+    /// Counts and sizes of live objects by kind, with their allocation sites
+    /// in debug builds, to find out what a script is holding on to.
+    pub fn heap_dump(&self) -> HeapDump {
+        let mut groups: BTreeMap<&str, HeapGroup> = BTreeMap::new();
+        for inner in self.inners() {
+            let kind = inner.value.kind();
+            let group = groups.entry(kind).or_insert_with(|| HeapGroup {
+                kind: String::from(kind),
+                count: 0,
+                bytes: 0,
+                sites: BTreeMap::new(),
+            });
+            group.count += 1;
+            group.bytes += object_size(&inner.value);
+            #[cfg(debug_assertions)]
+            {
+                *group.sites.entry(inner.site.to_string()).or_insert(0) += 1;
+            }
+        }
+        return HeapDump {
+            groups: groups.into_values().collect(),
+        };
+    }
+
+    /// Counts memory that an object holds outside the GC, such as a growing
+    /// buffer inside userdata, towards `bytes_allocated`.
+    pub fn add_external_bytes(&mut self, bytes: usize) {
+        self.bytes_allocated += bytes;
+    }
+
+    #[track_caller]
     fn alloc_inner(&mut self, value: Obj) -> *mut ObjRefInner {
+        self.allocations += 1;
+        self.bytes_allocated += object_size(&value);
         self.refs = Box::into_raw(Box::new(ObjRefInner {
             value,
             next: self.refs,
+            #[cfg(debug_assertions)]
+            site: Location::caller(),
         }));
         self.refs
     }
 
+    #[track_caller]
     pub fn alloc_string(&mut self, value: String) -> ObjRef {
         let obj_string = ObjString::new(value);
         return if let Some(interned) = self.strings.find(&obj_string) {
@@ -118,9 +322,7 @@ impl GC {
             let new_inner = self.alloc_inner(Obj::String(obj_string));
             unsafe {
                 if let Obj::String(inner_string) = &self.refs.as_ref().unwrap().value {
-                    self.strings.set(&inner_string, new_inner);
-                } else {
-                    panic!("Expected Obj::String");
+                    self.strings.set(inner_string, new_inner);
                 }
             }
             ObjRef {
@@ -130,14 +332,43 @@ impl GC {
         };
     }
 
+    #[track_caller]
+    pub fn alloc_native(&mut self, native: ObjNative) -> ObjRef {
+        return ObjRef {
+            ptr: self.alloc_inner(Obj::Native(native)),
+            _marker: PhantomData,
+        };
+    }
+
+    #[track_caller]
+    pub fn alloc_userdata(
+        &mut self,
+        user_type: &Arc<UserDataType>,
+        data: Box<dyn Any + Send>,
+    ) -> ObjRef {
+        let userdata = ObjUserData::new(Arc::clone(user_type), data);
+        return ObjRef {
+            ptr: self.alloc_inner(Obj::UserData(userdata)),
+            _marker: PhantomData,
+        };
+    }
+
     unsafe fn free_obj(&mut self, ptr: *mut ObjRefInner) {
-        match &(*ptr).value {
-            Obj::String(ObjString { value, hash: _ }) => drop(value),
-        }
         drop(Box::from_raw(ptr))
     }
 }
 
+// SAFETY: the GC uniquely owns every object in its list and its intern
+// table, and nothing else frees or mutates them. Object payloads are `Send`
+// themselves (checked below), so moving the whole heap to another thread is
+// sound. `ObjRef`s into the heap stay behind unless their owner is sent too.
+unsafe impl Send for GC {}
+
+const _: fn() = || {
+    fn assert_send<T: Send>() {}
+    assert_send::<Obj>();
+};
+
 impl Drop for GC {
     fn drop(&mut self) {
         let mut cur = self.refs;
@@ -151,11 +382,15 @@ impl Drop for GC {
     }
 }
 
+#[cfg(test)]
 #[macro_export]
 macro_rules! assert_eq_str {
     ($ref: expr, $str: expr) => {
         match &*$ref {
-            Obj::String(obj_string) => assert_eq!(obj_string.get_value(), &$str.to_string()),
+            $crate::gc::Obj::String(obj_string) => {
+                assert_eq!(obj_string.get_value(), &$str.to_string())
+            }
+            #[allow(unreachable_patterns)]
             _ => panic!("Expected string"),
         }
     };
@@ -164,7 +399,6 @@ macro_rules! assert_eq_str {
 #[cfg(test)]
 mod test {
 
-    #[macro_use]
     use super::*;
 
     #[test]
@@ -182,6 +416,97 @@ mod test {
         let s2 = gc.alloc_string("hello world".to_string());
         assert_eq_str!(s1, "hello world");
         assert_eq_str!(s2, "hello world");
+        assert_eq!(gc.allocation_count(), 1);
+        drop(gc);
+    }
+
+    #[test]
+    fn accesses_objects_by_kind() {
+        let user_type = Arc::new(UserDataType::new("Point"));
+        let mut gc = GC::new();
+        let string = gc.alloc_string(String::from("a"));
+        let userdata = gc.alloc_userdata(&user_type, Box::new(()));
+        assert!(string.as_string().is_some());
+        assert!(string.as_native().is_none() && string.as_userdata().is_none());
+        assert_eq!(
+            userdata.as_userdata().unwrap().get_type().get_name(),
+            "Point"
+        );
+        assert!(userdata.as_string().is_none() && userdata.as_native().is_none());
+    }
+
+    #[test]
+    fn reports_stats() {
+        let mut gc = GC::new();
+        gc.alloc_string("a".to_string());
+        gc.alloc_string("b".to_string());
+        gc.alloc_string("a".to_string());
+        let stats = gc.stats();
+        assert_eq!(stats.allocations, 2);
+        assert_eq!(stats.bytes_allocated, gc.bytes_allocated());
+        assert_eq!(stats.strings.len, 2);
+    }
+
+    #[test]
+    fn dumps_objects_by_kind() {
+        let user_type = Arc::new(UserDataType::new("Point"));
+        let mut gc = GC::new();
+        gc.alloc_string(String::from("ab"));
+        gc.alloc_string(String::from("cde"));
+        gc.alloc_string(String::from("ab"));
+        gc.alloc_userdata(&user_type, Box::new(()));
+        assert_eq!(gc.objects().count(), 3);
+
+        let dump = gc.heap_dump();
+        let kinds = dump
+            .groups
+            .iter()
+            .map(|group| (group.kind.as_str(), group.count))
+            .collect::<Vec<_>>();
+        assert_eq!(kinds, vec![("Point", 1), ("string", 2)]);
+        assert_eq!(dump.bytes(), gc.bytes_allocated());
+        let strings = &dump.groups[1];
+        if cfg!(debug_assertions) {
+            // Both strings were allocated from this file
+            assert_eq!(strings.sites.len(), 2);
+            assert!(strings
+                .sites
+                .keys()
+                .all(|site| site.starts_with("src/gc.rs:")));
+        } else {
+            assert!(strings.sites.is_empty());
+        }
+        assert!(dump.to_string().ends_with(&format!(
+            "{:<24} {:>8} {:>10}\n",
+            "total",
+            3,
+            gc.bytes_allocated()
+        )));
+    }
+
+    /// Records its name in a shared log when dropped.
+    struct DropLog(&'static str, Arc<std::sync::Mutex<Vec<&'static str>>>);
+
+    impl Drop for DropLog {
+        fn drop(&mut self) {
+            self.1.lock().unwrap().push(self.0);
+        }
+    }
+
+    #[test]
+    fn frees_every_object_once() {
+        let log = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let user_type = Arc::new(UserDataType::new("Log"));
+        let mut gc = GC::new();
+        gc.alloc_userdata(&user_type, Box::new(DropLog("first", Arc::clone(&log))));
+        gc.alloc_string(String::from("between"));
+        gc.alloc_userdata(&user_type, Box::new(DropLog("second", Arc::clone(&log))));
+        // Interning returns the existing object, which must not be freed twice
+        gc.alloc_string(String::from("between"));
+        gc.alloc_userdata(&user_type, Box::new(DropLog("third", Arc::clone(&log))));
+        assert!(log.lock().unwrap().is_empty());
         drop(gc);
+        // Objects are freed newest first
+        assert_eq!(*log.lock().unwrap(), vec!["third", "second", "first"]);
     }
 }
@@ -0,0 +1,92 @@
+//! A bytecode virtual machine for the Lox language from
+//! [Crafting Interpreters](https://craftinginterpreters.com).
+//!
+//! Applications that just want to run Lox code should use [`Lox`], which
+//! owns everything an interpreter session needs:
+//!
+//! ```
+//! let mut lox = rs_lox::Lox::new();
+//! assert_eq!(lox.eval("1 + 2;"), Ok(rs_lox::LoxValue::Int(3)));
+//! ```
+//!
+//! Underneath, source code goes through the [`Scanner`] and is compiled in a
+//! single pass by [`compile`] into a [`Chunk`] of bytecode, which the [`VM`]
+//! executes.
+//! Strings and other heap objects are owned by a [`GC`], which has to outlive
+//! every chunk and VM that refers to its objects.
+//!
+//! ```
+//! use rs_lox::{compile, Sandbox, Value, VMConfig, GC, VM};
+//!
+//! let mut output = Vec::new();
+//! let mut gc = GC::new();
+//! let (chunk, _) = compile(&String::from("print 1 + 2;"), &mut gc).unwrap();
+//! let mut input = std::io::empty();
+//! let mut vm = VM::new(
+//!     VMConfig {
+//!         trace_instructions: false,
+//!         trace_stack: false,
+//!         trace_globals: false,
+//!         stdout: &mut output,
+//!         stdin: &mut input,
+//!         sandbox: Sandbox::default(),
+//!     },
+//!     &mut gc,
+//! );
+//! assert_eq!(vm.interpret_chunk(chunk), Ok(Value::Nil));
+//! assert_eq!(output, b"3\n");
+//! ```
+//!
+//! Everything the crate supports is re-exported from the root; the
+//! [`debug`] module holds the disassembler used by the CLI tools, and
+//! [`stdlib`] the natives that `Lox` sessions get by default.
+
+#![allow(clippy::needless_return, clippy::bool_assert_comparison)]
+
+pub mod ast;
+mod chunk;
+mod compiler;
+pub mod debug;
+#[cfg(feature = "ffi")]
+pub mod ffi;
+mod lint;
+mod lower;
+mod lox;
+mod module;
+mod native;
+mod rle;
+mod sandbox;
+mod scanner;
+pub mod stdlib;
+mod value;
+mod vm;
+#[macro_use]
+mod gc;
+mod table;
+mod userdata;
+#[cfg(feature = "wasm")]
+pub mod wasm;
+
+pub use crate::chunk::{
+    Chunk, ColumnNumber, ConstOp, DebugInfoSize, Instructions, LineNumber, OpCode, OperandWidth,
+    SharedChunk, SharedConstant,
+};
+pub use crate::compiler::{
+    compile, compile_two_pass, compile_two_pass_with_dialect, compile_with_dialect,
+    compile_with_result, is_incomplete, Compiled, Diagnostic, ParserErrorKind, Severity,
+};
+pub use crate::gc::{GCStats, HeapDump, HeapGroup, Obj, ObjRef, ObjString, GC};
+pub use crate::lint::lint;
+pub use crate::lower::lower;
+pub use crate::lox::{Lox, LoxError, LoxValue};
+pub use crate::module::{search_path_from_env, SEARCH_PATH_VAR};
+pub use crate::native::{IntoNative, NativeFn, NativeReturn, ObjNative, Pending};
+pub use crate::sandbox::{Capability, Sandbox, MAX_IMPORT_DEPTH};
+pub use crate::scanner::{Dialect, Scanner, Token, TokenKind};
+pub use crate::table::{Table, TableStats};
+pub use crate::userdata::{with_userdata, ObjUserData, UserDataType};
+pub use crate::value::{FromLox, ToLox, Value};
+pub use crate::vm::{
+    BuiltinType, Frame, IntegerOverflow, InterpreterError, RunOutcome, VMConfig, VmHook, STACK_MAX,
+    VM,
+};
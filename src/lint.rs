@@ -0,0 +1,289 @@
+//! Checks a parsed program for code that is probably a mistake, even though
+//! it compiles.
+
+use crate::ast::{BinaryOp, Expr, Literal, Program, Stmt};
+use crate::chunk::LineNumber;
+use crate::compiler::{Diagnostic, ParserErrorKind, Severity};
+use crate::scanner::Token;
+use std::collections::{HashMap, HashSet};
+
+struct Linter<'a> {
+    /// Globals that exist before the program runs, such as natives.
+    predefined: &'a HashSet<String>,
+    /// Where each global the program declares is first declared.
+    declarations: HashMap<&'a str, LineNumber>,
+    /// Globals declared by the statements checked so far.
+    defined: HashSet<&'a str>,
+    /// Set after an `import "path";`, which defines names that can't be known
+    /// without loading the module.
+    imported_all: bool,
+    /// Statements of each `defer`, checked once the rest of the program is,
+    /// since that's when they run.
+    deferred: Vec<&'a Stmt>,
+    diagnostics: Vec<Diagnostic>,
+}
+
+fn literal_of(expr: &Expr) -> Option<&Literal> {
+    return match expr {
+        Expr::Literal { value, .. } => Some(value),
+        Expr::Grouping { expr, .. } => literal_of(expr),
+        _ => None,
+    };
+}
+
+fn type_name(literal: &Literal) -> &'static str {
+    return match literal {
+        Literal::Number(_) | Literal::Int(_) => "number",
+        Literal::String(_) => "string",
+        Literal::True | Literal::False => "boolean",
+        Literal::Nil => "nil",
+    };
+}
+
+/// The result of comparing two literals, if it's known without running the
+/// program.
+fn compare(left: &Literal, op: BinaryOp, right: &Literal) -> Option<bool> {
+    let number = |literal: &Literal| match literal {
+        Literal::Number(n) => Some(*n as f64),
+        Literal::Int(n) => Some(*n as f64),
+        _ => None,
+    };
+    if let (Some(a), Some(b)) = (number(left), number(right)) {
+        // As in the VM, `<=` and `>=` are the negations of `>` and `<`
+        #[allow(clippy::neg_cmp_op_on_partial_ord)]
+        return match op {
+            BinaryOp::Equal => Some(a == b),
+            BinaryOp::NotEqual => Some(a != b),
+            BinaryOp::Greater => Some(a > b),
+            BinaryOp::GreaterEqual => Some(!(a < b)),
+            BinaryOp::Less => Some(a < b),
+            BinaryOp::LessEqual => Some(!(a > b)),
+            _ => None,
+        };
+    }
+    return match op {
+        BinaryOp::Equal => Some(left == right),
+        BinaryOp::NotEqual => Some(left != right),
+        _ => None,
+    };
+}
+
+impl<'a> Linter<'a> {
+    fn warn(&mut self, token: &Token, message: String) {
+        self.diagnostics.push(Diagnostic {
+            severity: Severity::Warning,
+            kind: ParserErrorKind::Other,
+            message,
+            token: token.clone(),
+        });
+    }
+
+    fn statement(&mut self, statement: &'a Stmt) {
+        match statement {
+            Stmt::Var {
+                name, initializer, ..
+            } => {
+                if let Some(initializer) = initializer {
+                    let is_self = match initializer {
+                        Expr::Variable { name: other } => other.lexeme == name.lexeme,
+                        _ => false,
+                    };
+                    if is_self && self.is_defined(&name.lexeme) {
+                        self.warn(
+                            name,
+                            format!("Variable '{}' is assigned to itself.", name.lexeme),
+                        );
+                    } else {
+                        self.expression(initializer);
+                    }
+                }
+                self.defined.insert(&name.lexeme);
+            }
+            Stmt::Import { names, .. } => {
+                if names.is_empty() {
+                    self.imported_all = true;
+                }
+                for name in names {
+                    self.defined.insert(&name.lexeme);
+                }
+            }
+            Stmt::Print { expr, .. } | Stmt::Expression { expr, .. } => self.expression(expr),
+            Stmt::Defer { statement, .. } => self.deferred.push(statement),
+        }
+    }
+
+    fn is_defined(&self, name: &str) -> bool {
+        return self.imported_all || self.defined.contains(name) || self.predefined.contains(name);
+    }
+
+    fn expression(&mut self, expr: &'a Expr) {
+        match expr {
+            Expr::Literal { .. } => {}
+            Expr::Variable { name } => self.variable(name),
+            Expr::Grouping { expr, .. } => self.expression(expr),
+            Expr::Unary { operand, .. } => self.expression(operand),
+            Expr::Binary {
+                left,
+                op,
+                operator,
+                right,
+            } => {
+                self.expression(left);
+                self.expression(right);
+                if let (Some(a), Some(b)) = (literal_of(left), literal_of(right)) {
+                    self.comparison(a, *op, operator, b);
+                }
+            }
+            Expr::Call {
+                callee, arguments, ..
+            } => {
+                self.expression(callee);
+                arguments
+                    .iter()
+                    .for_each(|argument| self.expression(argument));
+            }
+            Expr::Invoke {
+                receiver,
+                arguments,
+                ..
+            } => {
+                self.expression(receiver);
+                arguments
+                    .iter()
+                    .for_each(|argument| self.expression(argument));
+            }
+        }
+    }
+
+    fn variable(&mut self, name: &Token) {
+        if self.is_defined(&name.lexeme) {
+            return;
+        }
+        let message = match self.declarations.get(name.lexeme.as_str()) {
+            Some(line) => format!(
+                "Variable '{}' is used before its definition on line {}.",
+                name.lexeme, line
+            ),
+            None => format!("Variable '{}' is never defined.", name.lexeme),
+        };
+        self.warn(name, message);
+    }
+
+    fn comparison(&mut self, left: &Literal, op: BinaryOp, operator: &Token, right: &Literal) {
+        let result = match compare(left, op, right) {
+            Some(result) => result,
+            None => return,
+        };
+        let message = if type_name(left) != type_name(right) {
+            format!(
+                "Comparing a {} with a {} is always {}.",
+                type_name(left),
+                type_name(right),
+                result
+            )
+        } else {
+            format!("Comparison is always {}.", result)
+        };
+        self.warn(operator, message);
+    }
+}
+
+/// Finds probable mistakes in `program`:
+///
+/// - reading a global before it's defined, or one that's never defined,
+/// - declaring a global with its own value, as in `var a = a;`,
+/// - comparing literals, whose result is always the same, and `==` or `!=`
+///   between literals of different types in particular.
+///
+/// `predefined` are the globals that exist before the program runs, such as
+/// the standard library.
+pub fn lint(program: &Program, predefined: &HashSet<String>) -> Vec<Diagnostic> {
+    let mut declarations = HashMap::new();
+    for statement in &program.statements {
+        let names = match statement {
+            Stmt::Var { name, .. } => std::slice::from_ref(name),
+            Stmt::Import { names, .. } => &names[..],
+            _ => &[],
+        };
+        for name in names {
+            declarations
+                .entry(name.lexeme.as_str())
+                .or_insert(name.line);
+        }
+    }
+    let mut linter = Linter {
+        predefined,
+        declarations,
+        defined: HashSet::new(),
+        imported_all: false,
+        deferred: Vec::new(),
+        diagnostics: Vec::new(),
+    };
+    for statement in &program.statements {
+        linter.statement(statement);
+    }
+    while let Some(statement) = linter.deferred.pop() {
+        linter.statement(statement);
+    }
+    return linter.diagnostics;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ast::parse;
+    use crate::compiler::format_diagnostics;
+
+    fn lint_str(source: &str) -> String {
+        let (program, diagnostics) = parse(&String::from(source));
+        assert!(diagnostics.is_empty(), "{:?}", diagnostics);
+        let predefined = HashSet::from([String::from("clock")]);
+        return format_diagnostics(&lint(&program, &predefined));
+    }
+
+    #[test]
+    fn finds_globals_used_before_definition() {
+        assert_eq!(
+            lint_str("print a;\nvar a = 1;\nprint a + b;\nprint clock();"),
+            "[line 1] Warning at 'a': Variable 'a' is used before its definition on line 2.\n\
+             [line 3] Warning at 'b': Variable 'b' is never defined."
+        );
+        assert_eq!(
+            lint_str("import { pi } from \"m.lox\";\nprint pi;\nimport \"n.lox\";\nprint e;"),
+            ""
+        );
+    }
+
+    #[test]
+    fn checks_deferred_statements_last() {
+        assert_eq!(
+            lint_str("defer print a;\nvar a = 1;\ndefer print b;"),
+            "[line 3] Warning at 'b': Variable 'b' is never defined."
+        );
+    }
+
+    #[test]
+    fn finds_self_assignment() {
+        assert_eq!(
+            lint_str("var a = 1;\nvar a = a;\nvar b = (a);"),
+            "[line 2] Warning at 'a': Variable 'a' is assigned to itself."
+        );
+    }
+
+    #[test]
+    fn finds_constant_comparisons() {
+        assert_eq!(
+            lint_str(
+                "print 1 == \"1\";\nprint nil != false;\nprint (2) < 1;\nprint \"a\" == \"a\";"
+            ),
+            "[line 1] Warning at '==': Comparing a number with a string is always false.\n\
+             [line 2] Warning at '!=': Comparing a nil with a boolean is always true.\n\
+             [line 3] Warning at '<': Comparison is always false.\n\
+             [line 4] Warning at '==': Comparison is always true."
+        );
+        assert_eq!(
+            lint_str("var a = 1;\nprint a == 1;\nprint \"a\" < \"b\";"),
+            ""
+        );
+    }
+}
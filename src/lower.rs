@@ -0,0 +1,278 @@
+//! Turns a syntax tree into bytecode. The result is the same chunk, down to
+//! the order of constants and the position of every instruction, as the
+//! single-pass compiler makes from the same source.
+
+use crate::ast::{BinaryOp, Expr, Literal, Program, Stmt, UnaryOp};
+use crate::chunk::{Chunk, ConstOp, OpCode, PositionedCode};
+use crate::gc::GC;
+use crate::scanner::Token;
+use crate::value::Value;
+
+struct Lowering<'a> {
+    gc: &'a mut GC,
+    chunk: Chunk,
+    /// Code of each `defer` so far, which runs at the end of the script.
+    deferred: Vec<PositionedCode>,
+}
+
+impl<'a> Lowering<'a> {
+    fn emit_opcode(&mut self, opcode: OpCode, at: &Token) {
+        self.emit_byte(opcode as u8, at);
+    }
+
+    fn emit_byte(&mut self, byte: u8, at: &Token) {
+        self.chunk.write_byte_at(byte, at.line, at.column);
+    }
+
+    fn name_constant(&mut self, name: &Token) -> usize {
+        let obj = self.gc.alloc_string(name.lexeme.clone());
+        return self.chunk.add_const(Value::Object(obj));
+    }
+
+    fn emit_constant(&mut self, value: Value, at: &Token) {
+        let const_ref = self.chunk.add_const(value);
+        self.chunk
+            .ref_const_at(const_ref, ConstOp::Constant, at.line, at.column);
+    }
+
+    fn statement(&mut self, statement: &Stmt, keep_result: bool) {
+        match statement {
+            Stmt::Var {
+                name,
+                initializer,
+                semicolon,
+            } => {
+                let name_ref = self.name_constant(name);
+                match initializer {
+                    Some(initializer) => self.expression(initializer),
+                    None => self.emit_opcode(OpCode::Nil, name),
+                }
+                self.chunk.ref_const_at(
+                    name_ref,
+                    ConstOp::DefineGlobal,
+                    semicolon.line,
+                    semicolon.column,
+                );
+            }
+            Stmt::Import {
+                names,
+                path,
+                semicolon,
+            } => {
+                let name_refs = names
+                    .iter()
+                    .map(|name| self.name_constant(name))
+                    .collect::<Vec<_>>();
+                let value = path.lexeme[1..path.lexeme.len() - 1].to_string();
+                let obj = self.gc.alloc_string(value);
+                self.emit_constant(Value::Object(obj), path);
+                self.emit_opcode(OpCode::Import, semicolon);
+                if names.is_empty() {
+                    self.emit_opcode(OpCode::ImportAll, semicolon);
+                }
+                for name_ref in name_refs {
+                    self.chunk.ref_const_at(
+                        name_ref,
+                        ConstOp::ImportName,
+                        semicolon.line,
+                        semicolon.column,
+                    );
+                }
+                self.emit_opcode(OpCode::Pop, semicolon);
+            }
+            Stmt::Print { expr, semicolon } => {
+                self.expression(expr);
+                self.emit_opcode(OpCode::Print, semicolon);
+            }
+            Stmt::Expression { expr, semicolon } => {
+                self.expression(expr);
+                if !keep_result {
+                    self.emit_opcode(OpCode::Pop, semicolon);
+                }
+            }
+            Stmt::Defer { statement, .. } => {
+                // Lowered in place, so constants are added in the same order
+                // as by the single-pass compiler
+                let start = self.chunk.get_code().len();
+                self.statement(statement, false);
+                let code = self.chunk.split_off_code(start);
+                self.deferred.push(code);
+            }
+        }
+    }
+
+    fn expression(&mut self, expr: &Expr) {
+        let at = expr.last_token();
+        match expr {
+            Expr::Literal { value, token } => match value {
+                Literal::Number(n) => self.emit_constant(Value::Number(*n), token),
+                Literal::Int(n) => self.emit_constant(Value::Int(*n), token),
+                Literal::String(s) => {
+                    let obj = self.gc.alloc_string(s.clone());
+                    self.emit_constant(Value::Object(obj), token);
+                }
+                Literal::True => self.emit_opcode(OpCode::True, token),
+                Literal::False => self.emit_opcode(OpCode::False, token),
+                Literal::Nil => self.emit_opcode(OpCode::Nil, token),
+            },
+            Expr::Variable { name } => {
+                let name_ref = self.name_constant(name);
+                self.chunk
+                    .ref_const_at(name_ref, ConstOp::Get, name.line, name.column);
+            }
+            Expr::Grouping { expr, .. } => self.expression(expr),
+            Expr::Unary { op, operand, .. } => {
+                self.expression(operand);
+                let opcode = match op {
+                    UnaryOp::Negate => OpCode::Negate,
+                    UnaryOp::Not => OpCode::Not,
+                };
+                self.emit_opcode(opcode, at);
+            }
+            Expr::Binary {
+                left, op, right, ..
+            } => {
+                self.expression(left);
+                self.expression(right);
+                let opcodes: &[OpCode] = match op {
+                    BinaryOp::NotEqual => &[OpCode::Equal, OpCode::Not],
+                    BinaryOp::Equal => &[OpCode::Equal],
+                    BinaryOp::Greater => &[OpCode::Greater],
+                    BinaryOp::GreaterEqual => &[OpCode::Less, OpCode::Not],
+                    BinaryOp::Less => &[OpCode::Less],
+                    BinaryOp::LessEqual => &[OpCode::Greater, OpCode::Not],
+                    BinaryOp::Add => &[OpCode::Add],
+                    BinaryOp::Subtract => &[OpCode::Subtract],
+                    BinaryOp::Multiply => &[OpCode::Multiply],
+                    BinaryOp::Divide => &[OpCode::Divide],
+                };
+                for opcode in opcodes {
+                    self.emit_opcode(*opcode, at);
+                }
+            }
+            Expr::Call {
+                callee, arguments, ..
+            } => {
+                self.expression(callee);
+                for argument in arguments {
+                    self.expression(argument);
+                }
+                self.emit_opcode(OpCode::Call, at);
+                self.emit_byte(arguments.len() as u8, at);
+            }
+            Expr::Invoke {
+                receiver,
+                name,
+                arguments,
+                ..
+            } => {
+                self.expression(receiver);
+                let name_ref = self.name_constant(name);
+                for argument in arguments {
+                    self.expression(argument);
+                }
+                self.chunk
+                    .ref_const_at(name_ref, ConstOp::Invoke, at.line, at.column);
+                self.emit_byte(arguments.len() as u8, at);
+            }
+        }
+    }
+}
+
+/// Emits bytecode for `program`, which must have parsed without errors. If
+/// `keep_result` is set and the program ends with an expression statement,
+/// the chunk returns its value.
+pub fn lower(program: &Program, gc: &mut GC, keep_result: bool) -> Chunk {
+    let mut lowering = Lowering {
+        gc,
+        chunk: Chunk::new(),
+        deferred: Vec::new(),
+    };
+    let count = program.statements.len();
+    for (index, statement) in program.statements.iter().enumerate() {
+        lowering.statement(statement, keep_result && index + 1 == count);
+    }
+    while let Some(code) = lowering.deferred.pop() {
+        lowering.chunk.append_code(code);
+    }
+    lowering.emit_opcode(OpCode::Return, &program.eof);
+    // A program that parsed without errors always balances
+    let _ = lowering.chunk.record_max_stack();
+    return lowering.chunk;
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::compiler::{compile_chunk, compile_two_pass_chunk};
+    use crate::debug::disassemble_chunk;
+    use crate::gc::GC;
+    use crate::scanner::Dialect;
+
+    /// Both compilers must produce the same chunk, with the same positions,
+    /// or the same diagnostics.
+    fn assert_same(source: &str, keep_result: bool, dialect: Dialect) {
+        let source = String::from(source);
+        let mut gc = GC::new();
+        let single = compile_chunk(&source, &mut gc, keep_result, dialect);
+        let two_pass = compile_two_pass_chunk(&source, &mut gc, keep_result, dialect);
+        match (single, two_pass) {
+            (Ok((single, single_warnings)), Ok((two_pass, two_pass_warnings))) => {
+                assert_eq!(single.get_code(), two_pass.get_code(), "{}", source);
+                assert_eq!(
+                    disassemble_chunk(&single, "single"),
+                    disassemble_chunk(&two_pass, "single"),
+                    "{}",
+                    source
+                );
+                for offset in 0..single.get_code().len() {
+                    assert_eq!(single.get_column(offset), two_pass.get_column(offset));
+                }
+                assert_eq!(single.max_stack(), two_pass.max_stack());
+                assert_eq!(single_warnings, two_pass_warnings, "{}", source);
+            }
+            (single, two_pass) => assert_eq!(single.err(), two_pass.err(), "{}", source),
+        }
+    }
+
+    #[test]
+    fn matches_single_pass_compiler() {
+        let sources = [
+            "print 1 + 2 * 3 - -4 / (5);",
+            "var a;\nvar b = \"text\";\nprint a == b != !(1 >= 2);",
+            "print 1 <= 2;\nprint\n  3\n  >\n  4;",
+            "clock();\n\"a\".len(1, \"b\");\nvar x = input(nil) + \"!\";",
+            "import \"geometry.lox\";\nimport { area, pi } from \"geometry.lox\";",
+            "1 + 2;\nnil;",
+            "true; false;",
+            "print;\nvar = 1;\n1 +",
+            "1;\nprint;",
+            "import { a } \"b\";\nprint 1;",
+            "a.b;",
+            "defer print \"a\";\nprint 1;\ndefer clock();\n2;",
+            "defer 1;\ndefer var a;",
+            "\"open",
+            "",
+        ];
+        for source in sources {
+            assert_same(source, false, Dialect::default());
+            assert_same(source, true, Dialect::default());
+        }
+    }
+
+    #[test]
+    fn matches_single_pass_compiler_in_dialects() {
+        let strict = ["var import = 1;\ndefer;", "import \"geometry.lox\";"];
+        for source in strict {
+            assert_same(source, false, Dialect::strict());
+        }
+        let case_insensitive = Dialect {
+            case_insensitive_keywords: true,
+            ..Dialect::extended()
+        };
+        assert_same(
+            "VAR a = TRUE;\nPrint a Or Nil;\nDEFER print a;",
+            false,
+            case_insensitive,
+        );
+    }
+}
@@ -0,0 +1,472 @@
+use crate::chunk::{Chunk, SharedChunk};
+use crate::compiler::{compile_with_result, format_diagnostics, Diagnostic};
+use crate::gc::GC;
+use crate::native::{IntoNative, ObjNative};
+use crate::sandbox::{Capability, Sandbox};
+use crate::stdlib;
+use crate::table::Table;
+use crate::userdata::UserDataType;
+use crate::value::{format_number, Value};
+use crate::vm::{BuiltinMethods, IntegerOverflow, InterpreterError, VMConfig, VM};
+use std::any::Any;
+use std::fmt;
+use std::io;
+use std::sync::Arc;
+
+/// A Lox value that can outlive the interpreter it came from. Strings are
+/// copied out of the GC.
+#[derive(Debug, Clone, PartialEq)]
+#[non_exhaustive]
+pub enum LoxValue {
+    Nil,
+    Boolean(bool),
+    Number(f32),
+    Int(i64),
+    String(String),
+    /// A value that only makes sense inside the interpreter, such as a
+    /// native function, described by how `print` would show it. Converts
+    /// back to `nil`.
+    Opaque(String),
+}
+
+impl fmt::Display for LoxValue {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            LoxValue::Nil => write!(f, "nil"),
+            LoxValue::Boolean(b) => b.fmt(f),
+            LoxValue::Number(n) => write!(f, "{}", format_number(*n)),
+            LoxValue::Int(n) => n.fmt(f),
+            LoxValue::String(s) => write!(f, "\"{}\"", s),
+            LoxValue::Opaque(description) => write!(f, "{}", description),
+        }
+    }
+}
+
+impl LoxValue {
+    fn from_value(value: Value) -> LoxValue {
+        match value {
+            Value::Nil => LoxValue::Nil,
+            Value::Boolean(b) => LoxValue::Boolean(b),
+            Value::Number(n) => LoxValue::Number(n),
+            Value::Int(n) => LoxValue::Int(n),
+            Value::Object(obj) => match obj.as_string() {
+                Some(s) => LoxValue::String(s.get_value().clone()),
+                None => LoxValue::Opaque(obj.to_string()),
+            },
+        }
+    }
+
+    fn into_value(self, gc: &mut GC) -> Value {
+        match self {
+            LoxValue::Nil => Value::Nil,
+            LoxValue::Boolean(b) => Value::Boolean(b),
+            LoxValue::Number(n) => Value::Number(n),
+            LoxValue::Int(n) => Value::Int(n),
+            LoxValue::String(s) => Value::Object(gc.alloc_string(s)),
+            LoxValue::Opaque(_) => Value::Nil,
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum LoxError {
+    CompileError(Vec<Diagnostic>),
+    RuntimeError(String),
+}
+
+impl fmt::Display for LoxError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            LoxError::CompileError(diagnostics) => write!(f, "{}", format_diagnostics(diagnostics)),
+            LoxError::RuntimeError(message) => write!(f, "Runtime error: {}", message),
+        }
+    }
+}
+
+impl std::error::Error for LoxError {}
+
+impl From<InterpreterError> for LoxError {
+    fn from(error: InterpreterError) -> Self {
+        match error {
+            InterpreterError::CompileError(errors) => LoxError::CompileError(errors),
+            InterpreterError::RuntimeError(message) => LoxError::RuntimeError(message),
+        }
+    }
+}
+
+/// An interpreter session for embedding Lox in an application. Globals
+/// persist between calls to `eval`, and `print` writes to stdout unless
+/// redirected with `set_output`. The standard library is installed when the
+/// session is created.
+///
+/// ```
+/// use rs_lox::{Lox, LoxValue};
+///
+/// let mut lox = Lox::new();
+/// lox.set_global("width", LoxValue::Number(4.0));
+/// lox.eval("var area = width * width;").unwrap();
+/// assert_eq!(lox.get_global("area"), Some(LoxValue::Number(16.0)));
+/// assert_eq!(lox.eval("area + 1;"), Ok(LoxValue::Number(17.0)));
+/// ```
+pub struct Lox {
+    globals: Table<Value>,
+    builtin_methods: BuiltinMethods,
+    output: Box<dyn io::Write + Send>,
+    input: Box<dyn io::BufRead + Send>,
+    sandbox: Sandbox,
+    integer_overflow: IntegerOverflow,
+    number_precision: Option<usize>,
+    gc: GC,
+}
+
+// SAFETY: the globals only refer to objects in `self.gc`, and values leave
+// the session as owned `LoxValue`s, so no `ObjRef` outlives a move.
+unsafe impl Send for Lox {}
+
+impl Default for Lox {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Lox {
+    pub fn new() -> Lox {
+        let mut lox = Lox {
+            globals: Table::with_insertion_order(),
+            builtin_methods: BuiltinMethods::new(),
+            output: Box::new(io::stdout()),
+            input: Box::new(io::BufReader::new(io::stdin())),
+            sandbox: Sandbox::default(),
+            integer_overflow: IntegerOverflow::default(),
+            number_precision: None,
+            gc: GC::new(),
+        };
+        lox.with_vm(stdlib::install);
+        return lox;
+    }
+
+    /// Redirects the output of `print` statements.
+    pub fn set_output(&mut self, output: Box<dyn io::Write + Send>) {
+        self.output = output;
+    }
+
+    /// Replaces stdin as the source of lines for `input()`.
+    pub fn set_input(&mut self, input: Box<dyn io::BufRead + Send>) {
+        self.input = input;
+    }
+
+    /// Restricts what scripts run in this session can do. Natives already
+    /// registered with a capability the sandbox denies fail when called.
+    pub fn set_sandbox(&mut self, sandbox: Sandbox) {
+        self.sandbox = sandbox;
+    }
+
+    /// Sets what integer arithmetic does when the result doesn't fit in an
+    /// `i64`. It's a runtime error by default.
+    pub fn set_integer_overflow(&mut self, overflow: IntegerOverflow) {
+        self.integer_overflow = overflow;
+    }
+
+    /// Makes `print` and `format` show floats with `digits` significant
+    /// digits, instead of the shortest form that reads back as the same
+    /// float.
+    pub fn set_number_precision(&mut self, digits: Option<usize>) {
+        self.number_precision = digits;
+    }
+
+    /// Runs `source`. If it ends with an expression statement, returns that
+    /// expression's value, otherwise returns `nil`.
+    pub fn eval(&mut self, source: &str) -> Result<LoxValue, LoxError> {
+        let (chunk, _) = compile_with_result(&String::from(source), &mut self.gc)?;
+        return self.run_chunk(chunk);
+    }
+
+    /// Compiles `source` once into a chunk that any number of sessions, on
+    /// any thread, can `run` without recompiling. Like `eval`, the chunk
+    /// returns the value of a trailing expression statement.
+    pub fn compile(source: &str) -> Result<SharedChunk, LoxError> {
+        let mut gc = GC::new();
+        let (chunk, _) = compile_with_result(&String::from(source), &mut gc)?;
+        return chunk.share().map_err(LoxError::RuntimeError);
+    }
+
+    /// Runs a chunk made by `Lox::compile` against this session's globals.
+    pub fn run(&mut self, chunk: &SharedChunk) -> Result<LoxValue, LoxError> {
+        let chunk = chunk.instantiate(&mut self.gc);
+        return self.run_chunk(chunk);
+    }
+
+    fn run_chunk(&mut self, chunk: Chunk) -> Result<LoxValue, LoxError> {
+        let result = self.with_vm(|vm| vm.interpret_chunk(chunk));
+        return Ok(LoxValue::from_value(result?));
+    }
+
+    /// Runs `f` on a VM that uses this session's globals, methods of
+    /// built-in types, output and sandbox.
+    fn with_vm<T>(&mut self, f: impl FnOnce(&mut VM) -> T) -> T {
+        let mut vm = VM::new(
+            VMConfig {
+                trace_instructions: false,
+                trace_stack: false,
+                trace_globals: false,
+                stdout: &mut *self.output,
+                stdin: &mut *self.input,
+                sandbox: self.sandbox.clone(),
+            },
+            &mut self.gc,
+        );
+        vm.set_integer_overflow(self.integer_overflow);
+        vm.set_number_precision(self.number_precision);
+        std::mem::swap(vm.globals_mut(), &mut self.globals);
+        std::mem::swap(vm.builtin_methods_mut(), &mut self.builtin_methods);
+        let result = f(&mut vm);
+        std::mem::swap(vm.globals_mut(), &mut self.globals);
+        std::mem::swap(vm.builtin_methods_mut(), &mut self.builtin_methods);
+        return result;
+    }
+
+    /// Defines a global native function. See `VM::register_native`.
+    pub fn register_native(
+        &mut self,
+        name: &str,
+        arity: u8,
+        function: impl Fn(&mut VM, &[Value]) -> Result<Value, String> + Send + Sync + 'static,
+    ) {
+        self.define_native(ObjNative::new(
+            String::from(name),
+            arity,
+            Box::new(function),
+        ));
+    }
+
+    /// Defines a global native that needs `capability`. See
+    /// `VM::register_native_requiring`.
+    pub fn register_native_requiring(
+        &mut self,
+        capability: Capability,
+        name: &str,
+        arity: u8,
+        function: impl Fn(&mut VM, &[Value]) -> Result<Value, String> + Send + Sync + 'static,
+    ) -> bool {
+        if !self.sandbox.allows(capability) {
+            return false;
+        }
+        let native = ObjNative::new(String::from(name), arity, Box::new(function));
+        self.define_native(native.requiring(capability));
+        return true;
+    }
+
+    /// Defines a global native function from a plain Rust closure. See
+    /// `VM::register_fn`.
+    pub fn register_fn<Args, F: IntoNative<Args>>(&mut self, name: &str, function: F) {
+        self.define_native(ObjNative::new(
+            String::from(name),
+            F::ARITY,
+            function.into_native(),
+        ));
+    }
+
+    fn define_native(&mut self, native: ObjNative) {
+        let name = self.gc.alloc_string(native.get_name().clone());
+        let native = self.gc.alloc_native(native);
+        self.globals
+            .set(name.unwrap_string(), Value::Object(native));
+    }
+
+    /// Defines a global `name` holding host data wrapped as userdata.
+    pub fn set_userdata(
+        &mut self,
+        name: &str,
+        user_type: &Arc<UserDataType>,
+        data: impl Any + Send,
+    ) {
+        let userdata = self.gc.alloc_userdata(user_type, Box::new(data));
+        let name = self.gc.alloc_string(String::from(name));
+        self.globals
+            .set(name.unwrap_string(), Value::Object(userdata));
+    }
+
+    pub fn set_global(&mut self, name: &str, value: LoxValue) {
+        let name = self.gc.alloc_string(String::from(name));
+        let value = value.into_value(&mut self.gc);
+        self.globals.set(name.unwrap_string(), value);
+    }
+
+    pub fn get_global(&mut self, name: &str) -> Option<LoxValue> {
+        let name = self.gc.alloc_string(String::from(name));
+        return self
+            .globals
+            .get(name.unwrap_string())
+            .map(|v| LoxValue::from_value(*v));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{Arc, Mutex};
+
+    #[derive(Clone, Default)]
+    struct SharedBuffer(Arc<Mutex<Vec<u8>>>);
+
+    impl io::Write for SharedBuffer {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn evaluates_expressions() {
+        let mut lox = Lox::new();
+        assert_eq!(lox.eval("1 + 2;"), Ok(LoxValue::Int(3)));
+        assert_eq!(lox.eval("1 + 0.5;"), Ok(LoxValue::Number(1.5)));
+        assert_eq!(lox.eval("7 / 2;"), Ok(LoxValue::Number(3.5)));
+        assert_eq!(lox.eval("16777217 + 1;"), Ok(LoxValue::Int(16777218)));
+        assert_eq!(lox.eval("2 == 2.0;"), Ok(LoxValue::Boolean(true)));
+        assert_eq!(lox.eval("2 < 2.5;"), Ok(LoxValue::Boolean(true)));
+        assert_eq!(
+            lox.eval("\"a\" + \"b\";"),
+            Ok(LoxValue::String(String::from("ab")))
+        );
+        assert_eq!(lox.eval("var a = 1;"), Ok(LoxValue::Nil));
+    }
+
+    #[test]
+    fn keeps_globals_between_evals() {
+        let mut lox = Lox::new();
+        lox.eval("var greeting = \"hello\";").unwrap();
+        assert_eq!(
+            lox.eval("greeting + \" world\";"),
+            Ok(LoxValue::String(String::from("hello world")))
+        );
+        assert_eq!(
+            lox.get_global("greeting"),
+            Some(LoxValue::String(String::from("hello")))
+        );
+        assert_eq!(lox.get_global("missing"), None);
+    }
+
+    #[test]
+    fn sets_globals() {
+        let mut lox = Lox::new();
+        lox.set_global("name", LoxValue::String(String::from("lox")));
+        lox.set_global("flag", LoxValue::Boolean(true));
+        assert_eq!(lox.eval("name == \"lox\";"), Ok(LoxValue::Boolean(true)));
+        assert_eq!(lox.eval("!flag;"), Ok(LoxValue::Boolean(false)));
+    }
+
+    #[test]
+    fn redirects_output() {
+        let buffer = SharedBuffer::default();
+        let mut lox = Lox::new();
+        lox.set_output(Box::new(buffer.clone()));
+        lox.eval("print 1; print \"two\";").unwrap();
+        assert_eq!(&*buffer.0.lock().unwrap(), b"1\n\"two\"\n");
+    }
+
+    #[test]
+    fn shows_numbers_with_precision() {
+        let buffer = SharedBuffer::default();
+        let mut lox = Lox::new();
+        lox.set_output(Box::new(buffer.clone()));
+        lox.set_number_precision(Some(3));
+        lox.eval("print 2 / 3; print 12345;").unwrap();
+        assert_eq!(&*buffer.0.lock().unwrap(), b"0.667\n12345\n");
+        assert_eq!(
+            lox.eval("format(\"{}!\", 1 / 8);"),
+            Ok(LoxValue::String(String::from("0.125!")))
+        );
+    }
+
+    #[test]
+    fn calls_natives() {
+        let mut lox = Lox::new();
+        lox.register_fn("sqrt", |x: f64| x.sqrt());
+        lox.register_fn("greet", |name: String| format!("hello {}", name));
+        lox.register_native("count", 2, |_, args| Ok(Value::from(args.len() as f64)));
+        assert_eq!(lox.eval("sqrt(16);"), Ok(LoxValue::Number(4.0)));
+        assert_eq!(
+            lox.eval("greet(\"lox\");"),
+            Ok(LoxValue::String(String::from("hello lox")))
+        );
+        assert_eq!(lox.eval("count(nil, nil);"), Ok(LoxValue::Number(2.0)));
+        assert_eq!(
+            lox.eval("sqrt;"),
+            Ok(LoxValue::Opaque(String::from("<native fn sqrt>")))
+        );
+    }
+
+    #[test]
+    fn reports_errors() {
+        let mut lox = Lox::new();
+        assert!(matches!(lox.eval("print"), Err(LoxError::CompileError(_))));
+        assert_eq!(
+            lox.eval("-nil;"),
+            Err(LoxError::RuntimeError(String::from(
+                "[line 1] Invalid type for negation: nil"
+            )))
+        );
+        // Globals defined before an error are kept
+        assert!(lox.eval("var a = 1; -nil;").is_err());
+        assert_eq!(lox.get_global("a"), Some(LoxValue::Int(1)));
+    }
+
+    #[test]
+    fn checks_integer_overflow() {
+        let mut lox = Lox::new();
+        lox.eval("var max = 9223372036854775807;").unwrap();
+        assert_eq!(
+            lox.eval("max + 1;"),
+            Err(LoxError::RuntimeError(String::from(
+                "[line 1] Integer overflow"
+            )))
+        );
+        lox.set_integer_overflow(IntegerOverflow::Wrap);
+        assert_eq!(lox.eval("max + 1;"), Ok(LoxValue::Int(i64::MIN)));
+        assert_eq!(lox.eval("-(max + 1);"), Ok(LoxValue::Int(i64::MIN)));
+    }
+
+    #[test]
+    fn runs_on_worker_threads() {
+        let handles = (0..4)
+            .map(|i| {
+                let mut lox = Lox::new();
+                lox.set_global("i", LoxValue::Number(i as f32));
+                std::thread::spawn(move || lox.eval("i * 2;"))
+            })
+            .collect::<Vec<_>>();
+        for (i, handle) in handles.into_iter().enumerate() {
+            let expected = LoxValue::Number(i as f32 * 2.0);
+            assert_eq!(handle.join().unwrap(), Ok(expected));
+        }
+    }
+
+    #[test]
+    fn runs_shared_chunks() {
+        let chunk = Arc::new(Lox::compile("var greeting = \"hello \" + name; greeting;").unwrap());
+        let handles = ["ann", "bob", "cid"]
+            .iter()
+            .map(|name| {
+                let chunk = Arc::clone(&chunk);
+                let mut lox = Lox::new();
+                lox.set_global("name", LoxValue::String(String::from(*name)));
+                std::thread::spawn(move || (lox.run(&chunk), lox.get_global("greeting")))
+            })
+            .collect::<Vec<_>>();
+        for (name, handle) in ["ann", "bob", "cid"].iter().zip(handles) {
+            let expected = LoxValue::String(format!("hello {}", name));
+            let (result, global) = handle.join().unwrap();
+            assert_eq!(result, Ok(expected.clone()));
+            assert_eq!(global, Some(expected));
+        }
+        assert!(matches!(
+            Lox::compile("print"),
+            Err(LoxError::CompileError(_))
+        ));
+    }
+}
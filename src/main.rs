@@ -1,96 +1,494 @@
-mod chunk;
-mod compiler;
-mod debug;
-mod rle;
-mod scanner;
-mod value;
-mod vm;
-#[macro_use]
-mod gc;
-mod table;
-
-use crate::chunk::Chunk;
-use crate::compiler::compile;
-use crate::gc::GC;
-use crate::vm::*;
+#![allow(clippy::needless_return, clippy::bool_assert_comparison)]
+
+mod bench;
+mod coverage;
+mod debugger;
+mod dump;
+mod event_log;
+mod profile;
+mod repl;
+mod tester;
+
+use rs_lox::{
+    ast, compile_two_pass_with_dialect, compile_with_dialect, lint, search_path_from_env, stdlib,
+    Dialect, InterpreterError, Sandbox, Severity, VMConfig, VmHook, GC, VM,
+};
 use std::env;
-use std::error::Error;
+use std::process::ExitCode;
+
+// Exit codes follow the conventions from `sysexits.h`, as in the book.
+const EX_USAGE: u8 = 64;
+const EX_DATAERR: u8 = 65;
+const EX_SOFTWARE: u8 = 70;
+const EX_IOERR: u8 = 74;
+
+fn exit_code(error: &InterpreterError) -> u8 {
+    match error {
+        InterpreterError::CompileError(_) => EX_DATAERR,
+        _ => EX_SOFTWARE,
+    }
+}
 
-fn repl() -> Result<(), Box<dyn Error>> {
-    println!("LOX interpreter. Press Ctrl+C to exit.");
-    loop {
-        use std::io::Write;
-        use text_io::read;
+/// Flag that makes compile warnings fail the script like errors.
+const DENY_WARNINGS: &str = "--deny-warnings";
+/// Flag that compiles through the syntax tree instead of in a single pass.
+const TWO_PASS: &str = "--two-pass";
+/// Flag that accepts only the Lox of Crafting Interpreters, without the
+/// extensions, see `Dialect::strict`.
+const STRICT: &str = "--strict";
+/// Flag that accepts keywords in any case.
+const CASE_INSENSITIVE_KEYWORDS: &str = "--case-insensitive-keywords";
+/// Flag that prints a script's tokens instead of running it, followed by
+/// `=json` for JSON.
+const DUMP_TOKENS: &str = "--dump-tokens";
+/// Flag that prints a script's syntax tree instead of running it, followed
+/// by `=json` for JSON.
+const DUMP_AST: &str = "--dump-ast";
 
-        let mut stdout = std::io::stdout();
-        let empty_chunk = Chunk::new();
-        let mut gc = GC::new();
+/// Flag followed by a file to write a line of JSON to for every instruction
+/// the script runs.
+const TRACE_JSON: &str = "--trace-json";
+
+/// How to compile and run scripts, from the flags that can go before them.
+#[derive(Debug, Clone, Copy)]
+struct Flags<'a> {
+    deny_warnings: bool,
+    two_pass: bool,
+    trace_json: Option<&'a str>,
+    dialect: Dialect,
+}
+
+/// Runs a script. `path` is the file it came from, if any, which its
+/// imports are resolved against.
+fn run_source(source: &String, path: Option<&str>, flags: Flags) -> u8 {
+    let mut event_log = match flags.trace_json {
+        Some(log_path) => match std::fs::File::create(log_path) {
+            Ok(file) => Some(event_log::EventLog::new(std::io::BufWriter::new(file))),
+            Err(error) => {
+                eprintln!("Could not create '{}': {}", log_path, error);
+                return EX_IOERR;
+            }
+        },
+        None => None,
+    };
+    let mut stdout = std::io::stdout();
+    let mut gc = GC::new();
+    let compiled = if flags.two_pass {
+        compile_two_pass_with_dialect(source, &mut gc, flags.dialect)
+    } else {
+        compile_with_dialect(source, &mut gc, flags.dialect)
+    };
+    let result = compiled.and_then(|(mut chunk, warnings)| {
+        if flags.deny_warnings && !warnings.is_empty() {
+            return Err(InterpreterError::CompileError(warnings));
+        }
+        for warning in &warnings {
+            eprintln!("{}", warning);
+        }
+        // A script runs once, so its globals can be slots, unlike the
+        // REPL's which each line adds to
+        chunk
+            .resolve_global_slots()
+            .map_err(InterpreterError::RuntimeError)?;
+        let mut stdin = std::io::BufReader::new(std::io::stdin());
         let mut vm = VM::new(
             VMConfig {
-                trace_instructions: true,
-                trace_stack: true,
-                trace_globals: true,
+                trace_instructions: false,
+                trace_stack: false,
+                trace_globals: false,
                 stdout: &mut stdout,
+                stdin: &mut stdin,
+                sandbox: Sandbox::default(),
             },
-            &empty_chunk,
             &mut gc,
         );
-        print!("> ");
-        std::io::stdout().flush()?;
-        let input: String = read!("{}\n");
-        match vm.with_gc(|gc| compile(&input, gc)) {
-            Ok(chunk) => match vm.interpret_chunk(&chunk) {
-                Ok(_) => {}
-                Err(error) => println!("{}", error),
-            },
-            Err(e) => println!("{}", e),
+        stdlib::install(&mut vm);
+        vm.set_search_path(search_path_from_env());
+        if let Some(path) = path {
+            vm.set_script_path(path);
+        }
+        if let Some(log) = event_log.as_mut() {
+            vm.set_hook(log);
+        }
+        vm.interpret_chunk(chunk)
+    });
+    let code = match result {
+        Ok(_) => 0,
+        Err(error) => {
+            eprintln!("{}", error);
+            exit_code(&error)
+        }
+    };
+    if let (Some(log), Some(log_path)) = (event_log, flags.trace_json) {
+        if let Err(error) = log.finish() {
+            eprintln!("Could not write '{}': {}", log_path, error);
+            return EX_IOERR;
+        }
+    }
+    return code;
+}
+
+fn run_file(path: &str, flags: Flags) -> u8 {
+    return match std::fs::read_to_string(path) {
+        Ok(contents) => run_source(&contents, Some(path), flags),
+        Err(error) => {
+            eprintln!("Could not read '{}': {}", path, error);
+            EX_IOERR
+        }
+    };
+}
+
+fn run_debugger(path: &str) -> u8 {
+    let source = match std::fs::read_to_string(path) {
+        Ok(source) => source,
+        Err(error) => {
+            eprintln!("Could not read '{}': {}", path, error);
+            return EX_IOERR;
+        }
+    };
+    return match debugger::debug(&source, path) {
+        Ok(()) => 0,
+        Err(error) => {
+            eprintln!("{}", error);
+            exit_code(&error)
+        }
+    };
+}
+
+fn run_bench(path: &str, args: &[String]) -> u8 {
+    let iterations = match args {
+        [] => 10,
+        [flag, count] if flag == "--iterations" => match count.parse::<u32>() {
+            Ok(count) if count > 0 => count,
+            _ => {
+                eprintln!("Expected a positive number of iterations, got '{}'.", count);
+                return EX_USAGE;
+            }
+        },
+        _ => {
+            eprintln!("Usage: rlox bench <script> [--iterations N]");
+            return EX_USAGE;
+        }
+    };
+    let source = match std::fs::read_to_string(path) {
+        Ok(source) => source,
+        Err(error) => {
+            eprintln!("Could not read '{}': {}", path, error);
+            return EX_IOERR;
+        }
+    };
+    return match bench::bench(&source, iterations) {
+        Ok(report) => {
+            print!("{}", report);
+            0
+        }
+        Err(error) => {
+            eprintln!("{}", error);
+            exit_code(&error)
+        }
+    };
+}
+
+fn run_tests(dir: &str) -> u8 {
+    return match tester::run_tests(std::path::Path::new(dir), &mut std::io::stdout(), None) {
+        Ok(report) if report.failed == 0 => 0,
+        Ok(_) => EX_SOFTWARE,
+        Err(error) => {
+            eprintln!("Could not run tests in '{}': {}", dir, error);
+            EX_IOERR
+        }
+    };
+}
+
+/// The globals every script starts with, which the linter shouldn't report
+/// as undefined.
+fn predefined_globals() -> std::collections::HashSet<String> {
+    let mut stdout = std::io::sink();
+    let mut stdin = std::io::empty();
+    let mut gc = GC::new();
+    let mut vm = VM::new(
+        VMConfig {
+            trace_instructions: false,
+            trace_stack: false,
+            trace_globals: false,
+            stdout: &mut stdout,
+            stdin: &mut stdin,
+            sandbox: Sandbox::default(),
+        },
+        &mut gc,
+    );
+    stdlib::install(&mut vm);
+    return vm
+        .globals()
+        .iter()
+        .map(|(name, _)| name.get_value().clone())
+        .collect();
+}
+
+/// Checks a script for probable mistakes without running it. Findings fail
+/// the command only with `--deny-warnings`.
+fn run_lint(path: &str, flags: Flags) -> u8 {
+    let source = match std::fs::read_to_string(path) {
+        Ok(source) => source,
+        Err(error) => {
+            eprintln!("Could not read '{}': {}", path, error);
+            return EX_IOERR;
+        }
+    };
+    let (program, mut diagnostics) = ast::parse(&source);
+    let failed = diagnostics.iter().any(|d| d.severity == Severity::Error);
+    if !failed {
+        diagnostics.extend(lint(&program, &predefined_globals()));
+    }
+    for diagnostic in &diagnostics {
+        eprintln!("{}", diagnostic);
+    }
+    if failed || (flags.deny_warnings && !diagnostics.is_empty()) {
+        return EX_DATAERR;
+    }
+    return 0;
+}
+
+/// Prints the tokens the scanner finds in a script, without parsing it.
+fn run_dump_tokens(path: &str, format: dump::Format) -> u8 {
+    let source = match std::fs::read_to_string(path) {
+        Ok(source) => source,
+        Err(error) => {
+            eprintln!("Could not read '{}': {}", path, error);
+            return EX_IOERR;
+        }
+    };
+    if let Err(error) = dump::write_tokens(&mut std::io::stdout(), &source, format) {
+        eprintln!("Could not write tokens: {}", error);
+        return EX_IOERR;
+    }
+    return 0;
+}
+
+/// Prints the syntax tree of a script, without compiling it. Parse errors
+/// are reported instead, and fail the command.
+fn run_dump_ast(path: &str, format: dump::Format) -> u8 {
+    let source = match std::fs::read_to_string(path) {
+        Ok(source) => source,
+        Err(error) => {
+            eprintln!("Could not read '{}': {}", path, error);
+            return EX_IOERR;
+        }
+    };
+    let (program, diagnostics) = ast::parse(&source);
+    for diagnostic in &diagnostics {
+        eprintln!("{}", diagnostic);
+    }
+    if diagnostics.iter().any(|d| d.severity == Severity::Error) {
+        return EX_DATAERR;
+    }
+    if let Err(error) = dump::write_ast(&mut std::io::stdout(), &program, format) {
+        eprintln!("Could not write syntax tree: {}", error);
+        return EX_IOERR;
+    }
+    return 0;
+}
+
+/// Runs the `--dump-*` mode `name` with the format that follows it in `flag`.
+fn run_dump(flag: &str, name: &str, path: &str, run: fn(&str, dump::Format) -> u8) -> u8 {
+    return match dump::Format::from_suffix(&flag[name.len()..]) {
+        Some(format) => run(path, format),
+        None => {
+            eprintln!("Unknown format in '{}', expected {}=json.", flag, name);
+            EX_USAGE
+        }
+    };
+}
+
+/// Runs a script, or every test script under a directory, and reports which
+/// lines ran: annotated sources by default, or an LCOV tracefile with
+/// `--lcov`. A failing script still gets a report, but fails the command.
+fn run_coverage(path: &str, args: &[String]) -> u8 {
+    let lcov = match args {
+        [] => false,
+        [flag] if flag == "--lcov" => true,
+        _ => {
+            eprintln!("Usage: rlox coverage <script | dir> [--lcov]");
+            return EX_USAGE;
+        }
+    };
+    let path = std::path::Path::new(path);
+    let mut coverage = coverage::Coverage::default();
+    let code = if path.is_dir() {
+        // Test results go to stderr, to keep stdout for the report
+        match tester::run_tests(path, &mut std::io::stderr(), Some(&mut coverage)) {
+            Ok(report) if report.failed == 0 => 0,
+            Ok(_) => EX_SOFTWARE,
+            Err(error) => {
+                eprintln!("Could not run tests in '{}': {}", path.display(), error);
+                return EX_IOERR;
+            }
+        }
+    } else {
+        let source = match std::fs::read_to_string(path) {
+            Ok(source) => source,
+            Err(error) => {
+                eprintln!("Could not read '{}': {}", path.display(), error);
+                return EX_IOERR;
+            }
         };
+        match tester::run_test(&source, path, Some(&mut coverage)) {
+            Ok(()) => 0,
+            Err(error) => {
+                eprintln!("{}", error);
+                exit_code(&error)
+            }
+        }
+    };
+
+    let mut stdout = std::io::stdout();
+    let written = if lcov {
+        coverage::write_lcov(&mut stdout, &coverage)
+    } else {
+        coverage
+            .files
+            .iter()
+            .try_for_each(|(file, lines)| {
+                use std::io::Write;
+                let source = std::fs::read_to_string(file)?;
+                writeln!(stdout, "{}:", file.display())?;
+                coverage::annotate(&mut stdout, &source, lines)?;
+                return writeln!(stdout);
+            })
+            .and_then(|()| coverage::summarize(&mut stdout, &coverage))
+    };
+    if let Err(error) = written {
+        eprintln!("Could not write coverage report: {}", error);
+        return EX_IOERR;
+    }
+    return code;
+}
+
+/// Runs a script, discarding its output, and writes where it spent its time
+/// as folded stacks, sampled every `--interval` instructions. With
+/// `--memory`, writes which lines allocated the most instead.
+fn run_profile(path: &str, args: &[String]) -> u8 {
+    // No interval profiles allocations
+    let interval = match args {
+        [] => Some(100),
+        [flag] if flag == "--memory" => None,
+        [flag, count] if flag == "--interval" => match count.parse::<u64>() {
+            Ok(count) if count > 0 => Some(count),
+            _ => {
+                eprintln!(
+                    "Expected a positive number of instructions, got '{}'.",
+                    count
+                );
+                return EX_USAGE;
+            }
+        },
+        _ => {
+            eprintln!("Usage: rlox profile <script> [--interval N | --memory]");
+            return EX_USAGE;
+        }
+    };
+    let source = match std::fs::read_to_string(path) {
+        Ok(source) => source,
+        Err(error) => {
+            eprintln!("Could not read '{}': {}", path, error);
+            return EX_IOERR;
+        }
+    };
+    let path = std::path::Path::new(path);
+    // A failing script still gets a profile of what ran, but fails the command
+    let run = |hook: &mut (dyn VmHook + Send)| match tester::run_test(&source, path, Some(hook)) {
+        Ok(()) => 0,
+        Err(error) => {
+            eprintln!("{}", error);
+            exit_code(&error)
+        }
+    };
+    let mut stdout = std::io::stdout();
+    let (code, written) = match interval {
+        Some(interval) => {
+            let mut profile = profile::Profile::new(interval);
+            let code = run(&mut profile);
+            (code, profile::write_folded(&mut stdout, &profile))
+        }
+        None => {
+            let mut profile = profile::AllocationProfile::default();
+            let code = run(&mut profile);
+            (code, profile::write_allocations(&mut stdout, &profile))
+        }
+    };
+    if let Err(error) = written {
+        eprintln!("Could not write profile: {}", error);
+        return EX_IOERR;
     }
+    return code;
 }
 
-fn run_file(path: &str) -> Result<(), Box<dyn Error>> {
+fn run_stdin(flags: Flags) -> u8 {
     use std::io::Read;
-    let mut file = std::fs::File::open(path)?;
     let mut contents = String::new();
-    let mut stdout = std::io::stdout();
-    file.read_to_string(&mut contents)?;
-    let mut gc = GC::new();
-    return match compile(&contents, &mut gc) {
-        Ok(chunk) => {
-            let mut vm = VM::new(
-                VMConfig {
-                    trace_instructions: true,
-                    trace_stack: true,
-                    trace_globals: true,
-                    stdout: &mut stdout,
-                },
-                &chunk,
-                &mut gc,
-            );
-            match vm.run() {
-                Ok(result) => {
-                    println!("{}", result);
-                    Ok(())
-                }
-                Err(e) => Err(Box::new(e)),
-            }
+    return match std::io::stdin().read_to_string(&mut contents) {
+        Ok(_) => run_source(&contents, None, flags),
+        Err(error) => {
+            eprintln!("Could not read script from stdin: {}", error);
+            EX_IOERR
         }
-        Err(e) => Err(Box::new(e)),
     };
 }
 
-fn main() -> Result<(), Box<dyn Error>> {
-    let argv: Vec<String> = env::args().collect();
-    return match argv.len() {
-        1 => repl(),
-        2 => run_file(&argv[1]),
+fn main() -> ExitCode {
+    let mut argv: Vec<String> = env::args().collect();
+    let trace_json = match argv.iter().position(|arg| arg == TRACE_JSON) {
+        Some(index) if index + 1 < argv.len() => {
+            argv.remove(index);
+            Some(argv.remove(index))
+        }
+        Some(_) => {
+            eprintln!("Usage: rlox {} <file> <script>", TRACE_JSON);
+            return ExitCode::from(EX_USAGE);
+        }
+        None => None,
+    };
+    let mut dialect = if argv.iter().any(|arg| arg == STRICT) {
+        Dialect::strict()
+    } else {
+        Dialect::extended()
+    };
+    dialect.case_insensitive_keywords = argv.iter().any(|arg| arg == CASE_INSENSITIVE_KEYWORDS);
+    let flags = Flags {
+        deny_warnings: argv.iter().any(|arg| arg == DENY_WARNINGS),
+        two_pass: argv.iter().any(|arg| arg == TWO_PASS),
+        trace_json: trace_json.as_deref(),
+        dialect,
+    };
+    let switches = [DENY_WARNINGS, TWO_PASS, STRICT, CASE_INSENSITIVE_KEYWORDS];
+    argv.retain(|arg| !switches.contains(&arg.as_str()));
+    let code = match argv.as_slice() {
+        [_] => match repl::repl() {
+            Ok(()) => 0,
+            Err(error) => {
+                eprintln!("{}", error);
+                EX_IOERR
+            }
+        },
+        [_, flag, source] if flag == "-e" => run_source(source, None, flags),
+        [_, command, path] if command == "debug" => run_debugger(path),
+        [_, command, path, args @ ..] if command == "bench" => run_bench(path, args),
+        [_, command, dir] if command == "test" => run_tests(dir),
+        [_, command, path, args @ ..] if command == "coverage" => run_coverage(path, args),
+        [_, command, path, args @ ..] if command == "profile" => run_profile(path, args),
+        [_, command, path] if command == "lint" => run_lint(path, flags),
+        [_, flag, path] if flag.starts_with(DUMP_TOKENS) => {
+            run_dump(flag, DUMP_TOKENS, path, run_dump_tokens)
+        }
+        [_, flag, path] if flag.starts_with(DUMP_AST) => {
+            run_dump(flag, DUMP_AST, path, run_dump_ast)
+        }
+        [_, path] if path == "-" => run_stdin(flags),
+        [_, path] if !path.starts_with('-') => run_file(path, flags),
         _ => {
-            use std::io::ErrorKind;
-            println!("Usage: rlox [script]");
-            Err(Box::new(std::io::Error::new(
-                ErrorKind::InvalidInput,
-                "invalid command line input",
-            )) as Box<dyn Error>)
+            eprintln!("Usage: rlox [--deny-warnings] [--two-pass] [--strict] [--case-insensitive-keywords] [--trace-json <file>] [script | - | -e <code> | debug <script> | bench <script> [--iterations N] | test <dir> | coverage <script | dir> [--lcov] | profile <script> [--interval N | --memory] | lint <script> | --dump-tokens[=json] <script> | --dump-ast[=json] <script>]");
+            EX_USAGE
         }
     };
+    return ExitCode::from(code);
 }
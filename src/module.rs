@@ -0,0 +1,122 @@
+//! Finding and loading the files that `import` refers to.
+
+use crate::chunk::{Chunk, SharedChunk};
+use crate::compiler::compile;
+use crate::gc::GC;
+use crate::vm::InterpreterError;
+use crate::vm::InterpreterError::RuntimeError;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// Environment variable with extra directories to look for modules in,
+/// separated like `PATH`.
+pub const SEARCH_PATH_VAR: &str = "RLOX_PATH";
+
+/// Extension of the compiled chunks cached next to module sources.
+const CACHE_EXTENSION: &str = "loxc";
+
+/// Directories listed in `RLOX_PATH`, to pass to `VM::set_search_path`.
+pub fn search_path_from_env() -> Vec<PathBuf> {
+    return match std::env::var_os(SEARCH_PATH_VAR) {
+        Some(paths) => std::env::split_paths(&paths).collect(),
+        None => Vec::new(),
+    };
+}
+
+/// Finds the module `path` imported from the file `importer`. A relative path
+/// is looked up next to the importer, or in the working directory if there
+/// is no importer, and then in each directory of `search_path`.
+pub fn resolve(path: &str, importer: Option<&Path>, search_path: &[PathBuf]) -> Option<PathBuf> {
+    let base = importer
+        .and_then(|importer| importer.parent())
+        .unwrap_or_else(|| Path::new(""));
+    return std::iter::once(base)
+        .chain(search_path.iter().map(|dir| dir.as_path()))
+        .map(|dir| dir.join(path))
+        .find(|candidate| candidate.exists() || cache_path(candidate).exists());
+}
+
+fn cache_path(source: &Path) -> PathBuf {
+    return source.with_extension(CACHE_EXTENSION);
+}
+
+fn modified(path: &Path) -> Option<std::time::SystemTime> {
+    return fs::metadata(path).and_then(|m| m.modified()).ok();
+}
+
+/// Loads the module at `path`. If a `.loxc` file next to it is at least as
+/// new as the source, or there is no source, the cached chunk is used
+/// instead of compiling. Otherwise the source is compiled, with its globals
+/// resolved to slots, and the cache written, if possible.
+pub fn load(path: &Path, gc: &mut GC) -> Result<Chunk, InterpreterError> {
+    let cache = cache_path(path);
+    let cache_is_fresh = match (modified(&cache), modified(path)) {
+        (Some(cached), Some(source)) => cached >= source,
+        (Some(_), None) => true,
+        _ => false,
+    };
+    if cache_is_fresh {
+        // An unreadable, outdated or corrupt cache falls back to the source
+        let cached = fs::read(&cache)
+            .map_err(|e| e.to_string())
+            .and_then(|bytes| SharedChunk::decode(&bytes));
+        if let Ok(shared) = cached {
+            let mut chunk = shared.instantiate(gc);
+            if chunk.verify().is_ok() {
+                let _ = chunk.record_max_stack();
+                return Ok(chunk);
+            }
+        }
+    }
+
+    let source = fs::read_to_string(path)
+        .map_err(|e| RuntimeError(format!("Could not import '{}': {}", path.display(), e)))?;
+    let (mut chunk, _) = compile(&source, gc)?;
+    chunk.resolve_global_slots().map_err(RuntimeError)?;
+    if let Ok(shared) = chunk.share() {
+        let _ = fs::write(&cache, shared.encode());
+    }
+    return Ok(chunk);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    #[cfg_attr(miri, ignore = "uses the file system")]
+    fn resolves_and_caches_modules() {
+        let dir = std::env::temp_dir().join(format!("rs-lox-module-{}", std::process::id()));
+        let lib = dir.join("lib");
+        fs::create_dir_all(&lib).unwrap();
+        fs::write(dir.join("main.lox"), "").unwrap();
+        fs::write(dir.join("local.lox"), "print 1;").unwrap();
+        fs::write(lib.join("shared.lox"), "print 2;").unwrap();
+
+        let main = dir.join("main.lox");
+        let search_path = [lib.clone()];
+        assert_eq!(
+            resolve("local.lox", Some(&main), &search_path),
+            Some(dir.join("local.lox"))
+        );
+        assert_eq!(
+            resolve("shared.lox", Some(&main), &search_path),
+            Some(lib.join("shared.lox"))
+        );
+        assert_eq!(resolve("shared.lox", Some(&main), &[]), None);
+
+        let mut gc = GC::new();
+        let compiled = load(&dir.join("local.lox"), &mut gc).unwrap();
+        assert!(dir.join("local.loxc").exists());
+        // Without the source, the cached chunk is still found and loaded
+        fs::remove_file(dir.join("local.lox")).unwrap();
+        assert_eq!(
+            resolve("local.lox", Some(&main), &[]),
+            Some(dir.join("local.lox"))
+        );
+        let cached = load(&dir.join("local.lox"), &mut gc).unwrap();
+        assert_eq!(cached.get_code(), compiled.get_code());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,171 @@
+use crate::sandbox::Capability;
+use crate::value::{FromLox, ToLox, Value};
+use crate::vm::VM;
+use std::fmt;
+
+/// Signature shared by all native functions. Arguments have already been
+/// checked against the declared arity. An `Err` becomes a runtime error.
+pub type NativeFn = Box<dyn Fn(&mut VM, &[Value]) -> Result<Value, String> + Send + Sync>;
+
+pub struct ObjNative {
+    name: String,
+    arity: u8,
+    variadic: bool,
+    capability: Option<Capability>,
+    function: NativeFn,
+}
+
+impl ObjNative {
+    pub fn new(name: String, arity: u8, function: NativeFn) -> ObjNative {
+        ObjNative {
+            name,
+            arity,
+            variadic: false,
+            capability: None,
+            function,
+        }
+    }
+
+    /// Lets the native take any number of arguments beyond its arity.
+    pub fn variadic(mut self) -> ObjNative {
+        self.variadic = true;
+        return self;
+    }
+
+    /// Marks the native as needing `capability`. The VM refuses to call it
+    /// if its sandbox doesn't allow that.
+    pub fn requiring(mut self, capability: Capability) -> ObjNative {
+        self.capability = Some(capability);
+        return self;
+    }
+
+    pub fn get_name(&self) -> &String {
+        &self.name
+    }
+
+    pub fn get_arity(&self) -> u8 {
+        self.arity
+    }
+
+    pub fn get_capability(&self) -> Option<Capability> {
+        self.capability
+    }
+
+    pub fn is_variadic(&self) -> bool {
+        self.variadic
+    }
+
+    /// Checks the number of arguments and runs the function.
+    pub fn call(&self, vm: &mut VM, args: &[Value]) -> Result<Value, String> {
+        if self.variadic && args.len() < self.arity as usize {
+            return Err(format!(
+                "{} expects at least {} arguments but got {}",
+                self.name,
+                self.arity,
+                args.len()
+            ));
+        }
+        if !self.variadic && args.len() != self.arity as usize {
+            return Err(format!(
+                "{} expects {} arguments but got {}",
+                self.name,
+                self.arity,
+                args.len()
+            ));
+        }
+        return (self.function)(vm, args);
+    }
+}
+
+impl fmt::Debug for ObjNative {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "ObjNative({}/{})", self.name, self.arity)
+    }
+}
+
+impl fmt::Display for ObjNative {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "<native fn {}>", self.name)
+    }
+}
+
+// Natives are only equal to themselves
+impl PartialEq for ObjNative {
+    fn eq(&self, other: &Self) -> bool {
+        std::ptr::eq(self, other)
+    }
+}
+
+/// Return types that a typed native function can produce: anything that
+/// converts to a `Value`, or a `Result` whose error becomes a runtime error.
+pub trait NativeReturn {
+    fn into_result(self, vm: &mut VM) -> Result<Value, String>;
+}
+
+impl<T: ToLox> NativeReturn for T {
+    fn into_result(self, vm: &mut VM) -> Result<Value, String> {
+        Ok(vm.with_gc(|gc| self.to_lox(gc)))
+    }
+}
+
+impl<T: ToLox> NativeReturn for Result<T, String> {
+    fn into_result(self, vm: &mut VM) -> Result<Value, String> {
+        let value = self?;
+        Ok(vm.with_gc(|gc| value.to_lox(gc)))
+    }
+}
+
+/// Returned by a typed native to suspend the VM until the host calls
+/// `VM::resume_with`. See `VM::suspend`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Pending;
+
+impl NativeReturn for Pending {
+    fn into_result(self, vm: &mut VM) -> Result<Value, String> {
+        vm.suspend();
+        Ok(Value::Nil)
+    }
+}
+
+/// Rust closures that can be registered as natives with `VM::register_fn`.
+/// Arity comes from the number of parameters, and every argument is
+/// converted with `FromLox`. `Args` is a tuple of the parameter types and
+/// only exists to tell the implementations apart.
+pub trait IntoNative<Args> {
+    const ARITY: u8;
+    fn into_native(self) -> NativeFn;
+}
+
+macro_rules! one {
+    ($arg:ident) => {
+        1
+    };
+}
+
+macro_rules! impl_into_native {
+    ($($arg:ident),*) => {
+        impl<F, R, $($arg),*> IntoNative<($($arg,)*)> for F
+        where
+            F: Fn($($arg),*) -> R + Send + Sync + 'static,
+            R: NativeReturn,
+            $($arg: FromLox,)*
+        {
+            const ARITY: u8 = 0 $(+ one!($arg))*;
+
+            #[allow(non_snake_case, unused_variables, unused_mut)]
+            fn into_native(self) -> NativeFn {
+                Box::new(move |vm, args| {
+                    let mut args = args.iter();
+                    $(let $arg = $arg::from_lox(*args.next().unwrap())?;)*
+                    (self)($($arg),*).into_result(vm)
+                })
+            }
+        }
+    };
+}
+
+impl_into_native!();
+impl_into_native!(A);
+impl_into_native!(A, B);
+impl_into_native!(A, B, C);
+impl_into_native!(A, B, C, D);
@@ -0,0 +1,174 @@
+use rs_lox::{Chunk, Frame, LineNumber, Obj, VmHook};
+use std::collections::BTreeMap;
+use std::io;
+use std::path::Path;
+
+/// Where a VM was every `interval` instructions, collected by installing it
+/// with `VM::set_hook`. Each sample is a stack of `file:line` frames: the
+/// script's own line, or the `import` that led to a module, then the line
+/// running in each module down to the innermost.
+#[derive(Debug)]
+pub struct Profile {
+    interval: u64,
+    /// How many samples found each stack, keyed by its folded form.
+    pub stacks: BTreeMap<String, u64>,
+}
+
+impl Profile {
+    pub fn new(interval: u64) -> Profile {
+        return Profile {
+            interval,
+            stacks: BTreeMap::new(),
+        };
+    }
+}
+
+/// Names the line `line` of `script` as `file:line`.
+fn site_name(script: Option<&Path>, line: Option<LineNumber>) -> String {
+    let script = match script {
+        Some(path) => path.display().to_string(),
+        None => String::from("script"),
+    };
+    return match line {
+        Some(line) => format!("{}:{}", script, line),
+        None => script,
+    };
+}
+
+/// Names a frame for a folded stack, where `;` separates frames.
+fn frame_name(frame: &Frame) -> String {
+    return site_name(frame.script, frame.line).replace(';', "_");
+}
+
+impl VmHook for Profile {
+    fn before_instruction(&mut self, _: Option<&Path>, _: &Chunk, _: usize) {}
+
+    fn sample_interval(&self) -> u64 {
+        return self.interval;
+    }
+
+    fn sample(&mut self, frames: &[Frame]) {
+        let stack = frames.iter().map(frame_name).collect::<Vec<_>>();
+        *self.stacks.entry(stack.join(";")).or_insert(0) += 1;
+    }
+}
+
+/// Writes `profile` as folded stacks, a line of `frame;frame count` per
+/// stack, for tools such as `inferno-flamegraph` or `flamegraph.pl`.
+pub fn write_folded(out: &mut dyn io::Write, profile: &Profile) -> io::Result<()> {
+    for (stack, count) in &profile.stacks {
+        writeln!(out, "{} {}", stack, count)?;
+    }
+    return Ok(());
+}
+
+/// Objects allocated by the instructions on one line.
+#[derive(Debug, Default, PartialEq)]
+pub struct LineAllocations {
+    pub count: usize,
+    pub bytes: usize,
+    /// Objects by kind, as `GC::heap_dump` groups them.
+    pub kinds: BTreeMap<String, usize>,
+}
+
+/// Every object a VM allocated while running, by the line that allocated
+/// it, collected by installing it with `VM::set_hook`.
+#[derive(Debug, Default)]
+pub struct AllocationProfile {
+    /// Keyed by `file:line`.
+    pub lines: BTreeMap<String, LineAllocations>,
+}
+
+impl VmHook for AllocationProfile {
+    fn before_instruction(&mut self, _: Option<&Path>, _: &Chunk, _: usize) {}
+
+    fn allocated(&mut self, script: Option<&Path>, line: Option<LineNumber>, object: &Obj) {
+        let site = self.lines.entry(site_name(script, line)).or_default();
+        site.count += 1;
+        site.bytes += object.size();
+        *site.kinds.entry(String::from(object.kind())).or_insert(0) += 1;
+    }
+}
+
+/// Writes the lines of `profile` that allocated the most bytes first, each
+/// with the number of objects of every kind, then the total.
+pub fn write_allocations(out: &mut dyn io::Write, profile: &AllocationProfile) -> io::Result<()> {
+    let mut lines = profile.lines.iter().collect::<Vec<_>>();
+    lines.sort_by_key(|(_, line)| std::cmp::Reverse(line.bytes));
+    writeln!(out, "{:>10} {:>8}  line", "bytes", "objects")?;
+    for (site, allocations) in lines {
+        let kinds = allocations
+            .kinds
+            .iter()
+            .map(|(kind, count)| format!("{} {}", kind, count))
+            .collect::<Vec<_>>();
+        writeln!(
+            out,
+            "{:>10} {:>8}  {} ({})",
+            allocations.bytes,
+            allocations.count,
+            site,
+            kinds.join(", ")
+        )?;
+    }
+    let bytes = profile.lines.values().map(|line| line.bytes).sum::<usize>();
+    let count = profile.lines.values().map(|line| line.count).sum::<usize>();
+    return writeln!(out, "{:>10} {:>8}  total", bytes, count);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rs_lox::GC;
+
+    #[test]
+    fn folds_samples_by_stack() {
+        let frame = |script: Option<&'static str>, line: Option<LineNumber>| Frame {
+            script: script.map(Path::new),
+            line,
+        };
+        let mut profile = Profile::new(10);
+        assert_eq!(profile.sample_interval(), 10);
+        profile.sample(&[frame(Some("main.lox"), Some(1))]);
+        profile.sample(&[
+            frame(Some("main.lox"), Some(2)),
+            frame(Some("lib;a.lox"), Some(5)),
+        ]);
+        profile.sample(&[frame(Some("main.lox"), Some(1))]);
+        profile.sample(&[frame(None, None)]);
+
+        let mut output = Vec::new();
+        write_folded(&mut output, &profile).unwrap();
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "main.lox:1 2\nmain.lox:2;lib_a.lox:5 1\nscript 1\n"
+        );
+    }
+
+    #[test]
+    fn lists_lines_allocating_most() {
+        let mut gc = GC::new();
+        let short = gc.alloc_string(String::from("a"));
+        let long = gc.alloc_string(String::from("a longer string"));
+        let mut profile = AllocationProfile::default();
+        profile.allocated(Some(Path::new("main.lox")), Some(1), &short);
+        profile.allocated(Some(Path::new("main.lox")), Some(2), &long);
+        profile.allocated(Some(Path::new("main.lox")), Some(1), &short);
+
+        let mut output = Vec::new();
+        write_allocations(&mut output, &profile).unwrap();
+        let (short, long) = (short.size(), long.size());
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            format!(
+                "     bytes  objects  line\n\
+                 {:>10}        2  main.lox:1 (string 2)\n\
+                 {:>10}        1  main.lox:2 (string 1)\n\
+                 {:>10}        3  total\n",
+                short * 2,
+                long,
+                short * 2 + long
+            )
+        );
+    }
+}
@@ -0,0 +1,371 @@
+use rs_lox::debug::{disassemble_chunk, dump_globals};
+use rs_lox::{
+    compile_with_result, is_incomplete, search_path_from_env, stdlib, InterpreterError, Sandbox,
+    VMConfig, Value, GC, VM,
+};
+use std::error::Error;
+use std::io::{self, BufRead, Write};
+
+const HELP: &str = "\
+Meta-commands:
+  :help                          Show this message
+  :quit                          Exit the REPL
+  :globals                       List global variables and their values
+  :dis                           Disassemble the last compiled chunk
+  :heap                          List live objects by kind, with their sizes
+  :trace [instructions|stack|globals] on|off
+                                 Toggle execution tracing (all flags by default)
+  :load <file>                   Run a script in the current session
+
+The value of an expression ending the input is shown and kept in `_`, and
+in `_1`, `_2` and so on in the order they were entered. Nil isn't kept.
+
+Input that ends too soon, such as a missing semicolon or an unterminated
+string, continues on the next line. An empty line ends it anyway.
+";
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+enum TraceFlag {
+    All,
+    Instructions,
+    Stack,
+    Globals,
+}
+
+#[derive(Debug, PartialEq)]
+enum Command<'a> {
+    Help,
+    Quit,
+    Globals,
+    Dis,
+    Heap,
+    Trace(TraceFlag, bool),
+    Load(&'a str),
+}
+
+fn parse_switch(word: Option<&str>) -> Result<bool, String> {
+    match word {
+        Some("on") => Ok(true),
+        Some("off") => Ok(false),
+        _ => Err(String::from("Expected 'on' or 'off'.")),
+    }
+}
+
+fn parse_trace(args: &str) -> Result<Command<'_>, String> {
+    let mut words = args.split_whitespace();
+    let first = words.next();
+    let (flag, switch) = match first {
+        Some("instructions") => (TraceFlag::Instructions, words.next()),
+        Some("stack") => (TraceFlag::Stack, words.next()),
+        Some("globals") => (TraceFlag::Globals, words.next()),
+        _ => (TraceFlag::All, first),
+    };
+    let on = parse_switch(switch)?;
+    if words.next().is_some() {
+        return Err(String::from("Too many arguments for :trace."));
+    }
+    Ok(Command::Trace(flag, on))
+}
+
+fn parse_command(line: &str) -> Result<Command<'_>, String> {
+    let line = line.trim();
+    let (name, args) = match line.find(char::is_whitespace) {
+        Some(index) => (&line[..index], line[index..].trim()),
+        None => (line, ""),
+    };
+    let command = match name {
+        ":help" => Command::Help,
+        ":quit" => Command::Quit,
+        ":globals" => Command::Globals,
+        ":dis" => Command::Dis,
+        ":heap" => Command::Heap,
+        ":trace" => return parse_trace(args),
+        ":load" if args.is_empty() => return Err(String::from("Usage: :load <file>")),
+        ":load" => return Ok(Command::Load(args)),
+        _ => {
+            return Err(format!(
+                "Unknown command '{}'. Type :help for a list of commands.",
+                name
+            ))
+        }
+    };
+    if !args.is_empty() {
+        return Err(format!("Command '{}' takes no arguments.", name));
+    }
+    Ok(command)
+}
+
+/// Binds a result to `_` and the next numbered history global, returning
+/// the name of the latter.
+fn record_result(vm: &mut VM, history: &mut usize, value: Value) -> String {
+    *history += 1;
+    let name = format!("_{}", history);
+    vm.define_global("_", value);
+    vm.define_global(&name, value);
+    return name;
+}
+
+/// Whether `source` only failed to compile because it ended too soon, so
+/// the REPL should read another line before running it.
+fn needs_more(vm: &mut VM, source: &String) -> bool {
+    return match vm.with_gc(|gc| compile_with_result(source, gc)) {
+        Err(InterpreterError::CompileError(diagnostics)) => is_incomplete(&diagnostics),
+        _ => false,
+    };
+}
+
+fn interpret(vm: &mut VM, history: &mut usize, source: &String) {
+    let chunk = match vm.with_gc(|gc| compile_with_result(source, gc)) {
+        Ok((chunk, warnings)) => {
+            for warning in &warnings {
+                eprintln!("{}", warning);
+            }
+            chunk
+        }
+        Err(error) => return eprintln!("{}", error),
+    };
+    match vm.interpret_chunk(chunk) {
+        Ok(Value::Nil) => {}
+        Ok(value) => {
+            let name = record_result(vm, history, value);
+            println!("{} = {}", name, value);
+        }
+        Err(error) => eprintln!("{}", error),
+    }
+}
+
+fn set_trace(config: &mut VMConfig, flag: TraceFlag, on: bool) {
+    match flag {
+        TraceFlag::All => {
+            config.trace_instructions = on;
+            config.trace_stack = on;
+            config.trace_globals = on;
+        }
+        TraceFlag::Instructions => config.trace_instructions = on,
+        TraceFlag::Stack => config.trace_stack = on,
+        TraceFlag::Globals => config.trace_globals = on,
+    }
+}
+
+fn run_command(vm: &mut VM, history: &mut usize, command: Command) {
+    match command {
+        Command::Help => print!("{}", HELP),
+        Command::Quit => {}
+        Command::Globals => print!("{}", dump_globals(vm.globals())),
+        Command::Dis => {
+            if vm.chunk().get_code().is_empty() {
+                println!("No chunk has been compiled yet.");
+            } else {
+                print!("{}", disassemble_chunk(vm.chunk(), "last chunk"));
+            }
+        }
+        Command::Heap => print!("{}", vm.with_gc(|gc| gc.heap_dump())),
+        Command::Trace(flag, on) => set_trace(vm.config_mut(), flag, on),
+        Command::Load(path) => match std::fs::read_to_string(path) {
+            Ok(source) => interpret(vm, history, &source),
+            Err(error) => eprintln!("Could not read '{}': {}", path, error),
+        },
+    }
+}
+
+pub fn repl() -> Result<(), Box<dyn Error>> {
+    println!("LOX interpreter. Type :help for a list of commands, :quit to exit.");
+    let mut stdout = io::stdout();
+    let mut gc = GC::new();
+    let mut script_input = io::BufReader::new(io::stdin());
+    let mut vm = VM::new(
+        VMConfig {
+            trace_instructions: false,
+            trace_stack: false,
+            trace_globals: false,
+            stdout: &mut stdout,
+            stdin: &mut script_input,
+            sandbox: Sandbox::default(),
+        },
+        &mut gc,
+    );
+    stdlib::install(&mut vm);
+    vm.set_search_path(search_path_from_env());
+    let stdin = io::stdin();
+    let mut input = String::new();
+    // Number of results kept so far
+    let mut history = 0;
+    loop {
+        print!("{}", if input.is_empty() { "> " } else { "... " });
+        io::stdout().flush()?;
+        let mut line = String::new();
+        if stdin.lock().read_line(&mut line)? == 0 {
+            // End of input (Ctrl+D)
+            println!();
+            return Ok(());
+        }
+        let continued = !input.is_empty();
+        input.push_str(&line);
+        if !continued && input.trim_start().starts_with(':') {
+            match parse_command(&input) {
+                Ok(Command::Quit) => return Ok(()),
+                Ok(command) => run_command(&mut vm, &mut history, command),
+                Err(message) => eprintln!("{}", message),
+            }
+            input.clear();
+            continue;
+        }
+        // An empty line runs what's there, reporting why it's incomplete
+        let ends_input = continued && line.trim().is_empty();
+        if !ends_input && needs_more(&mut vm, &input) {
+            continue;
+        }
+        interpret(&mut vm, &mut history, &input);
+        input.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_simple_commands() {
+        assert_eq!(parse_command(":help"), Ok(Command::Help));
+        assert_eq!(parse_command(":quit\n"), Ok(Command::Quit));
+        assert_eq!(parse_command("  :globals "), Ok(Command::Globals));
+        assert_eq!(parse_command(":dis"), Ok(Command::Dis));
+        assert_eq!(parse_command(":heap"), Ok(Command::Heap));
+    }
+
+    #[test]
+    fn rejects_arguments_for_simple_commands() {
+        assert!(parse_command(":quit now").is_err());
+    }
+
+    #[test]
+    fn rejects_unknown_command() {
+        assert!(parse_command(":frobnicate").is_err());
+    }
+
+    #[test]
+    fn parses_trace() {
+        assert_eq!(
+            parse_command(":trace on"),
+            Ok(Command::Trace(TraceFlag::All, true))
+        );
+        assert_eq!(
+            parse_command(":trace stack off"),
+            Ok(Command::Trace(TraceFlag::Stack, false))
+        );
+        assert_eq!(
+            parse_command(":trace instructions on"),
+            Ok(Command::Trace(TraceFlag::Instructions, true))
+        );
+        assert!(parse_command(":trace").is_err());
+        assert!(parse_command(":trace maybe").is_err());
+        assert!(parse_command(":trace globals on off").is_err());
+    }
+
+    #[test]
+    fn parses_load() {
+        assert_eq!(
+            parse_command(":load scripts/my file.lox\n"),
+            Ok(Command::Load("scripts/my file.lox"))
+        );
+        assert!(parse_command(":load").is_err());
+    }
+
+    #[test]
+    fn keeps_result_history() {
+        let mut output = Vec::new();
+        let mut input = io::empty();
+        let mut gc = GC::new();
+        let mut vm = VM::new(
+            VMConfig {
+                trace_instructions: false,
+                trace_stack: false,
+                trace_globals: false,
+                stdout: &mut output,
+                stdin: &mut input,
+                sandbox: Sandbox::default(),
+            },
+            &mut gc,
+        );
+        let mut history = 0;
+        interpret(&mut vm, &mut history, &String::from("1 + 2;"));
+        interpret(&mut vm, &mut history, &String::from("var a = 1;"));
+        interpret(&mut vm, &mut history, &String::from("_ * 2;"));
+        assert_eq!(history, 2);
+        let (chunk, _) = vm
+            .with_gc(|gc| compile_with_result(&String::from("_ + _1 * 10 + _2 * 100;"), gc))
+            .unwrap();
+        assert_eq!(vm.interpret_chunk(chunk), Ok(Value::Int(636)));
+    }
+
+    #[test]
+    fn reuses_constants_across_lines() {
+        let mut gc = GC::new();
+        let mut output = Vec::new();
+        let mut input = io::empty();
+        let mut vm = VM::new(
+            VMConfig {
+                trace_instructions: false,
+                trace_stack: false,
+                trace_globals: false,
+                stdout: &mut output,
+                stdin: &mut input,
+                sandbox: Sandbox::default(),
+            },
+            &mut gc,
+        );
+        let mut history = 0;
+        let line = String::from("var count = count + 1; var name = \"repl\";");
+        interpret(&mut vm, &mut history, &String::from("var count = 0;"));
+        interpret(&mut vm, &mut history, &line);
+        let allocations = vm.with_gc(|gc| gc.allocation_count());
+        for _ in 0..100 {
+            interpret(&mut vm, &mut history, &line);
+        }
+        // Strings are interned, so later lines find the ones already made
+        assert_eq!(vm.with_gc(|gc| gc.allocation_count()), allocations);
+        assert_eq!(history, 0);
+    }
+
+    #[test]
+    fn continues_incomplete_input() {
+        let mut output = Vec::new();
+        let mut input = io::empty();
+        let mut gc = GC::new();
+        let mut vm = VM::new(
+            VMConfig {
+                trace_instructions: false,
+                trace_stack: false,
+                trace_globals: false,
+                stdout: &mut output,
+                stdin: &mut input,
+                sandbox: Sandbox::default(),
+            },
+            &mut gc,
+        );
+        for source in ["print 1 +\n", "var a = 1\n", "print \"two\n", "a."] {
+            assert!(needs_more(&mut vm, &String::from(source)), "{}", source);
+        }
+        for source in ["print 1;\n", "\n", "print 1 + ;\n", "print ) +\n", "1 2"] {
+            assert!(!needs_more(&mut vm, &String::from(source)), "{}", source);
+        }
+    }
+
+    #[test]
+    fn toggles_trace_flags() {
+        let mut output = Vec::new();
+        let mut input = io::empty();
+        let mut config = VMConfig {
+            trace_instructions: false,
+            trace_stack: false,
+            trace_globals: false,
+            stdout: &mut output,
+            stdin: &mut input,
+            sandbox: Sandbox::default(),
+        };
+        set_trace(&mut config, TraceFlag::All, true);
+        assert!(config.trace_instructions && config.trace_stack && config.trace_globals);
+        set_trace(&mut config, TraceFlag::Stack, false);
+        assert!(config.trace_instructions && !config.trace_stack && config.trace_globals);
+    }
+}
@@ -2,16 +2,19 @@ use std::cmp::Eq;
 use std::fmt::Debug;
 use std::marker::Copy;
 
-#[derive(Debug)]
+/// A sequence stored as runs of equal values. Each run also records where
+/// it ends, so looking up an index is a binary search over the runs.
+#[derive(Debug, Clone)]
 pub struct Rle<T: Eq + Debug + Copy> {
     data: Vec<RleNode<T>>,
     last_value: Option<T>,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 struct RleNode<T> {
     pub value: T,
-    pub count: usize,
+    /// Number of values up to and including this run.
+    pub end: usize,
 }
 
 impl<T: Eq + Debug + Copy> Rle<T> {
@@ -23,29 +26,110 @@ impl<T: Eq + Debug + Copy> Rle<T> {
     }
 
     pub fn push(&mut self, value: T) {
+        self.push_n(value, 1);
+    }
+
+    /// Pushes `count` copies of `value`.
+    pub fn push_n(&mut self, value: T, count: usize) {
+        if count == 0 {
+            return;
+        }
         if let Some(last_value) = &self.last_value {
             if last_value == &value {
-                self.data.last_mut().unwrap().count += 1;
+                self.data.last_mut().unwrap().end += count;
                 return;
             }
         }
-        self.data.push(RleNode {
-            value: value,
-            count: 1,
-        });
+        let end = self.len() + count;
+        self.data.push(RleNode { value, end });
         self.last_value.replace(value);
     }
 
     pub fn get(&self, index: usize) -> Option<&T> {
-        let mut skipped = 0;
-        for node in &self.data {
-            if skipped + node.count > index {
-                return Some(&node.value);
+        let run = self.data.partition_point(|node| node.end <= index);
+        return self.data.get(run).map(|node| &node.value);
+    }
+
+    /// Drops every value from `len` on.
+    pub fn truncate(&mut self, len: usize) {
+        let kept = self.data.partition_point(|node| node.end <= len);
+        let start = kept.checked_sub(1).map_or(0, |run| self.data[run].end);
+        self.data.truncate(kept + 1);
+        if self.data.len() > kept {
+            if start < len {
+                self.data[kept].end = len;
             } else {
-                skipped += node.count;
+                self.data.pop();
+            }
+        }
+        self.last_value = self.data.last().map(|node| node.value);
+    }
+
+    /// Number of values, counting every value in each run.
+    pub fn len(&self) -> usize {
+        return self.data.last().map_or(0, |node| node.end);
+    }
+
+    /// Each value in order, repeated as many times as it was pushed.
+    pub fn iter(&self) -> impl Iterator<Item = &T> + '_ {
+        self.runs_with_refs()
+            .flat_map(|(value, count)| std::iter::repeat_n(value, count))
+    }
+
+    /// Each run of equal values with its length, in order.
+    pub fn iter_runs(&self) -> impl Iterator<Item = (T, usize)> + '_ {
+        self.runs_with_refs().map(|(value, count)| (*value, count))
+    }
+
+    fn runs_with_refs(&self) -> impl Iterator<Item = (&T, usize)> + '_ {
+        let starts = std::iter::once(0).chain(self.data.iter().map(|node| node.end));
+        self.data
+            .iter()
+            .zip(starts)
+            .map(|(node, start)| (&node.value, node.end - start))
+    }
+}
+
+/// Serialized as a sequence of `(value, count)` pairs, one per run.
+#[cfg(feature = "serde")]
+impl<T: Eq + Debug + Copy + serde::Serialize> serde::Serialize for Rle<T> {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        use serde::ser::SerializeSeq;
+        let mut seq = serializer.serialize_seq(Some(self.data.len()))?;
+        for run in self.iter_runs() {
+            seq.serialize_element(&run)?;
+        }
+        return seq.end();
+    }
+}
+
+#[cfg(feature = "serde")]
+impl<'de, T: Eq + Debug + Copy + serde::Deserialize<'de>> serde::Deserialize<'de> for Rle<T> {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct RunsVisitor<T>(std::marker::PhantomData<T>);
+
+        impl<'de, T: Eq + Debug + Copy + serde::Deserialize<'de>> serde::de::Visitor<'de>
+            for RunsVisitor<T>
+        {
+            type Value = Rle<T>;
+
+            fn expecting(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+                return f.write_str("a sequence of (value, count) runs");
+            }
+
+            fn visit_seq<A: serde::de::SeqAccess<'de>>(
+                self,
+                mut seq: A,
+            ) -> Result<Rle<T>, A::Error> {
+                let mut rle = Rle::new();
+                while let Some((value, count)) = seq.next_element::<(T, usize)>()? {
+                    rle.push_n(value, count);
+                }
+                return Ok(rle);
             }
         }
-        return None;
+
+        return deserializer.deserialize_seq(RunsVisitor(std::marker::PhantomData));
     }
 }
 
@@ -100,4 +184,76 @@ mod tests {
         rle.push(2);
         assert_eq!(rle.get(3), Some(&2));
     }
+
+    #[test]
+    fn len_and_iter() {
+        let mut rle: Rle<i32> = Rle::new();
+        assert_eq!(rle.len(), 0);
+        for value in [1, 1, 2, 3, 3, 3] {
+            rle.push(value);
+        }
+        assert_eq!(rle.len(), 6);
+        assert_eq!(rle.iter().copied().collect::<Vec<_>>(), [1, 1, 2, 3, 3, 3]);
+        assert_eq!(
+            rle.iter_runs().collect::<Vec<_>>(),
+            [(1, 2), (2, 1), (3, 3)]
+        );
+    }
+
+    #[test]
+    fn every_index() {
+        let mut rle: Rle<usize> = Rle::new();
+        let mut values = Vec::new();
+        for run in 0..100 {
+            for _ in 0..run % 7 + 1 {
+                rle.push(run);
+                values.push(run);
+            }
+        }
+        for (index, value) in values.iter().enumerate() {
+            assert_eq!(rle.get(index), Some(value));
+        }
+        assert_eq!(rle.get(values.len()), None);
+    }
+
+    #[test]
+    fn push_many() {
+        let mut rle: Rle<i32> = Rle::new();
+        rle.push_n(1, 3);
+        rle.push_n(2, 0);
+        rle.push_n(1, 2);
+        rle.push_n(2, 4);
+        assert_eq!(rle.len(), 9);
+        assert_eq!(rle.get(4), Some(&1));
+        assert_eq!(rle.get(5), Some(&2));
+        assert_eq!(rle.iter_runs().collect::<Vec<_>>(), [(1, 5), (2, 4)]);
+    }
+
+    #[test]
+    fn truncate() {
+        let mut rle: Rle<i32> = Rle::new();
+        rle.push_n(1, 3);
+        rle.push_n(2, 2);
+        rle.truncate(4);
+        assert_eq!(rle.iter_runs().collect::<Vec<_>>(), [(1, 3), (2, 1)]);
+        rle.truncate(3);
+        assert_eq!(rle.iter_runs().collect::<Vec<_>>(), [(1, 3)]);
+        rle.push(1);
+        assert_eq!(rle.iter_runs().collect::<Vec<_>>(), [(1, 4)]);
+        rle.truncate(0);
+        assert_eq!(rle.len(), 0);
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn bincode_round_trip() {
+        let mut rle: Rle<u16> = Rle::new();
+        rle.push_n(1, 1000);
+        rle.push_n(2, 3);
+        let bytes = bincode::serialize(&rle).unwrap();
+        // Length prefix, then a u16 and a u64 for each run
+        assert_eq!(bytes.len(), 8 + 2 * 10);
+        let decoded: Rle<u16> = bincode::deserialize(&bytes).unwrap();
+        assert_eq!(decoded.iter_runs().collect::<Vec<_>>(), [(1, 1000), (2, 3)]);
+    }
 }
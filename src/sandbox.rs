@@ -0,0 +1,81 @@
+use std::fmt;
+
+/// Something a native function can do beyond computing a value. Natives that
+/// need one are registered with `VM::register_native_requiring`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum Capability {
+    /// Reading and writing files, the console or the network.
+    Io,
+    /// Reading the current time.
+    Clock,
+    /// Calling into foreign code.
+    Ffi,
+}
+
+impl fmt::Display for Capability {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Capability::Io => write!(f, "I/O"),
+            Capability::Clock => write!(f, "clock"),
+            Capability::Ffi => write!(f, "FFI"),
+        }
+    }
+}
+
+/// What a script is allowed to do, set in `VMConfig`. The default allows
+/// everything; `Sandbox::untrusted` allows no capabilities, and limits can
+/// be added to either.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Sandbox {
+    pub allow_io: bool,
+    pub allow_clock: bool,
+    pub allow_ffi: bool,
+    /// Maximum bytes of heap objects in the GC, see `GC::bytes_allocated`.
+    pub max_memory: Option<usize>,
+    /// Maximum number of instructions the VM executes, see
+    /// `VM::instruction_count`.
+    pub max_instructions: Option<u64>,
+    /// Maximum number of nested imports, counting the script itself, as in
+    /// `VM::call_stack`. Each module runs on the host's stack, so unlike the
+    /// other limits this one is always set.
+    pub max_import_depth: usize,
+}
+
+/// Default for `Sandbox::max_import_depth`, low enough for the 2 MiB stack of
+/// a spawned thread in a debug build.
+pub const MAX_IMPORT_DEPTH: usize = 100;
+
+impl Default for Sandbox {
+    fn default() -> Self {
+        Sandbox {
+            allow_io: true,
+            allow_clock: true,
+            allow_ffi: true,
+            max_memory: None,
+            max_instructions: None,
+            max_import_depth: MAX_IMPORT_DEPTH,
+        }
+    }
+}
+
+impl Sandbox {
+    pub fn untrusted() -> Sandbox {
+        Sandbox {
+            allow_io: false,
+            allow_clock: false,
+            allow_ffi: false,
+            max_memory: None,
+            max_instructions: None,
+            max_import_depth: MAX_IMPORT_DEPTH,
+        }
+    }
+
+    pub fn allows(&self, capability: Capability) -> bool {
+        match capability {
+            Capability::Io => self.allow_io,
+            Capability::Clock => self.allow_clock,
+            Capability::Ffi => self.allow_ffi,
+        }
+    }
+}
@@ -1,6 +1,8 @@
-use crate::chunk::LineNumber;
+use crate::chunk::{ColumnNumber, LineNumber};
+use std::convert::TryFrom;
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
 pub enum TokenKind {
     // Single-character tokens
     LeftParen,
@@ -30,11 +32,13 @@ pub enum TokenKind {
     // Keywords
     And,
     Class,
+    Defer,
     Else,
     False,
     For,
     Fun,
     If,
+    Import,
     Nil,
     Or,
     Print,
@@ -49,65 +53,129 @@ pub enum TokenKind {
     Eof,
 }
 
+/// Message of the scanner error for a string still open at the end of input.
+pub const UNTERMINATED_STRING: &str = "Unterminated string.";
+
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub struct Token {
     pub kind: TokenKind,
     pub lexeme: String,
     pub line: LineNumber,
+    /// Column of the token's first character, starting from 1.
+    pub column: ColumnNumber,
 }
 
 impl Token {
-    pub fn new(kind: TokenKind, lexeme: &str, line: LineNumber) -> Token {
+    pub fn new(kind: TokenKind, lexeme: &str, line: LineNumber, column: ColumnNumber) -> Token {
         Token {
             kind,
             lexeme: String::from(lexeme),
             line,
+            column,
         }
     }
 }
 
+/// Which of this implementation's extensions to the Lox of Crafting
+/// Interpreters are enabled. A disabled keyword scans as an identifier, as
+/// it does in the book, so both compilers see the same language.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Dialect {
+    /// `import` statements.
+    pub imports: bool,
+    /// `defer` statements.
+    pub defer: bool,
+    /// Keywords in any case, such as `PRINT` or `Var`. Variable names are
+    /// still case sensitive.
+    pub case_insensitive_keywords: bool,
+}
+
+impl Dialect {
+    /// The language as the book defines it, for running its test suite.
+    pub fn strict() -> Dialect {
+        return Dialect {
+            imports: false,
+            defer: false,
+            case_insensitive_keywords: false,
+        };
+    }
+
+    /// Every extension, for everyday scripts. Keywords are still lowercase
+    /// only.
+    pub fn extended() -> Dialect {
+        return Dialect {
+            imports: true,
+            defer: true,
+            case_insensitive_keywords: false,
+        };
+    }
+}
+
+impl Default for Dialect {
+    fn default() -> Self {
+        Self::extended()
+    }
+}
+
 pub struct Scanner<'a> {
     input: &'a String,
+    dialect: Dialect,
+    /// Byte offset of the token being scanned.
     start: usize,
+    /// Byte offset of the next character.
     current: usize,
     line: LineNumber,
+    /// Byte offset where the current line starts.
+    line_start: usize,
+    start_column: ColumnNumber,
 }
 
 fn is_digit(c: char) -> bool {
-    c >= '0' && c <= '9'
+    c.is_ascii_digit()
 }
 
 fn is_alpha(c: char) -> bool {
-    (c >= 'a' && c <= 'z') || (c >= 'A' && c <= 'Z') || c == '_'
+    c.is_ascii_alphabetic() || c == '_'
 }
 
 impl<'a> Scanner<'a> {
-    pub fn new(input: &'a String) -> Scanner {
+    pub fn new(input: &'a String) -> Scanner<'a> {
+        Scanner::with_dialect(input, Dialect::default())
+    }
+    pub fn with_dialect(input: &'a String, dialect: Dialect) -> Scanner<'a> {
         Scanner {
             input,
+            dialect,
             start: 0,
             current: 0,
             line: 1,
+            line_start: 0,
+            start_column: 1,
         }
     }
     fn make_token(&self, kind: TokenKind) -> Token {
         let lexeme = &self.input[self.start..self.current];
-        Token::new(kind, lexeme, self.line)
+        Token::new(kind, lexeme, self.line, self.start_column)
     }
     fn error_token(&self, message: &'a str) -> Token {
-        Token::new(TokenKind::Error, message, self.line)
+        Token::new(TokenKind::Error, message, self.line, self.start_column)
     }
     fn peek(&self) -> Option<char> {
-        self.input.chars().nth(self.current)
+        self.input[self.current..].chars().next()
     }
     fn peek_next(&self) -> Option<char> {
-        self.input.chars().nth(self.current + 1)
+        self.input[self.current..].chars().nth(1)
     }
     fn advance(&mut self) -> Option<char> {
         let c = self.peek()?;
-        self.current += 1;
+        self.current += c.len_utf8();
         return Some(c);
     }
+    fn newline(&mut self) {
+        self.advance();
+        self.line += 1;
+        self.line_start = self.current;
+    }
     fn r#match(&mut self, expected: char) -> bool {
         let c = self.peek();
         if c == Some(expected) {
@@ -135,8 +203,7 @@ impl<'a> Scanner<'a> {
                 }
                 // Line break
                 '\n' => {
-                    self.line += 1;
-                    self.advance();
+                    self.newline();
                 }
                 // Comments
                 '/' => {
@@ -144,8 +211,7 @@ impl<'a> Scanner<'a> {
                         while let Some(c) = self.peek() {
                             match c {
                                 '\n' => {
-                                    self.line += 1;
-                                    self.advance();
+                                    self.newline();
                                     break;
                                 }
                                 _ => {
@@ -169,15 +235,14 @@ impl<'a> Scanner<'a> {
                     return self.make_token(TokenKind::Str);
                 }
                 '\n' => {
-                    self.line += 1;
-                    self.advance();
+                    self.newline();
                 }
                 _ => {
                     self.advance();
                 }
             }
         }
-        self.error_token("Unterminated string.")
+        self.error_token(UNTERMINATED_STRING)
     }
     fn number(&mut self) -> Token {
         self.match_while(is_digit);
@@ -195,41 +260,61 @@ impl<'a> Scanner<'a> {
         self.make_token(TokenKind::Number)
     }
     fn check_keyword(&self, start: usize, keyword: &str, kind: TokenKind) -> TokenKind {
-        if &self.input[self.start + start..self.current] == keyword {
+        let rest = &self.input[self.start + start..self.current];
+        let matches = if self.dialect.case_insensitive_keywords {
+            rest.eq_ignore_ascii_case(keyword)
+        } else {
+            rest == keyword
+        };
+        if matches {
             kind
         } else {
             TokenKind::Identifier
         }
     }
+    /// The character at byte `index`, lowercased if keywords can be in any
+    /// case. Identifiers are ASCII, so any index within one is a character.
+    fn keyword_char(&self, index: usize) -> char {
+        let c = self.input[index..].chars().next().unwrap();
+        if self.dialect.case_insensitive_keywords {
+            c.to_ascii_lowercase()
+        } else {
+            c
+        }
+    }
     fn identifier_type(&self) -> TokenKind {
-        match self.input.chars().nth(self.start).unwrap() {
+        return match self.keyword_type() {
+            TokenKind::Import if !self.dialect.imports => TokenKind::Identifier,
+            TokenKind::Defer if !self.dialect.defer => TokenKind::Identifier,
+            kind => kind,
+        };
+    }
+    fn keyword_type(&self) -> TokenKind {
+        match self.keyword_char(self.start) {
             'a' => return self.check_keyword(1, "nd", TokenKind::And),
             'c' => return self.check_keyword(1, "lass", TokenKind::Class),
+            'd' => return self.check_keyword(1, "efer", TokenKind::Defer),
             'e' => return self.check_keyword(1, "lse", TokenKind::Else),
-            'f' => {
-                if self.current - self.start > 1 {
-                    match self.input.chars().nth(self.start + 1).unwrap() {
-                        'a' => return self.check_keyword(2, "lse", TokenKind::False),
-                        'o' => return self.check_keyword(2, "r", TokenKind::For),
-                        'u' => return self.check_keyword(2, "n", TokenKind::Fun),
-                        _ => {}
-                    }
-                }
-            }
-            'i' => return self.check_keyword(1, "f", TokenKind::If),
+            'f' if self.current - self.start > 1 => match self.keyword_char(self.start + 1) {
+                'a' => return self.check_keyword(2, "lse", TokenKind::False),
+                'o' => return self.check_keyword(2, "r", TokenKind::For),
+                'u' => return self.check_keyword(2, "n", TokenKind::Fun),
+                _ => {}
+            },
+            'i' if self.current - self.start > 1 => match self.keyword_char(self.start + 1) {
+                'f' => return self.check_keyword(2, "", TokenKind::If),
+                'm' => return self.check_keyword(2, "port", TokenKind::Import),
+                _ => {}
+            },
             'n' => return self.check_keyword(1, "il", TokenKind::Nil),
             'o' => return self.check_keyword(1, "r", TokenKind::Or),
             'p' => return self.check_keyword(1, "rint", TokenKind::Print),
             'r' => return self.check_keyword(1, "eturn", TokenKind::Return),
-            't' => {
-                if self.current - self.start > 1 {
-                    match self.input.chars().nth(self.start + 1).unwrap() {
-                        'h' => return self.check_keyword(2, "is", TokenKind::This),
-                        'r' => return self.check_keyword(2, "ue", TokenKind::True),
-                        _ => {}
-                    }
-                }
-            }
+            't' if self.current - self.start > 1 => match self.keyword_char(self.start + 1) {
+                'h' => return self.check_keyword(2, "is", TokenKind::This),
+                'r' => return self.check_keyword(2, "ue", TokenKind::True),
+                _ => {}
+            },
             's' => return self.check_keyword(1, "uper", TokenKind::Super),
             'v' => return self.check_keyword(1, "ar", TokenKind::Var),
             'w' => return self.check_keyword(1, "hile", TokenKind::While),
@@ -244,6 +329,8 @@ impl<'a> Scanner<'a> {
     pub fn scan(&mut self) -> Token {
         self.skip_whitespace();
         self.start = self.current;
+        let column = self.input[self.line_start..self.start].chars().count() + 1;
+        self.start_column = ColumnNumber::try_from(column).unwrap_or(ColumnNumber::MAX);
         let next = self.advance();
         match next {
             None => self.make_token(TokenKind::Eof),
@@ -592,6 +679,16 @@ mod tests {
             assert_eq!(result.line, 1);
         }
 
+        #[test]
+        fn defer() {
+            let input = String::from("defer");
+            let mut scanner = Scanner::new(&input);
+            let result = scanner.scan();
+            assert_eq!(result.kind, TokenKind::Defer);
+            assert_eq!(result.lexeme, "defer");
+            assert_eq!(result.line, 1);
+        }
+
         #[test]
         fn r#else() {
             let input = String::from("else");
@@ -632,6 +729,16 @@ mod tests {
             assert_eq!(result.line, 1);
         }
 
+        #[test]
+        fn import() {
+            let input = String::from("import");
+            let mut scanner = Scanner::new(&input);
+            let result = scanner.scan();
+            assert_eq!(result.kind, TokenKind::Import);
+            assert_eq!(result.lexeme, "import");
+            assert_eq!(result.line, 1);
+        }
+
         #[test]
         fn r#if() {
             let input = String::from("if");
@@ -860,4 +967,92 @@ mod tests {
         assert_eq!(result.lexeme, "");
         assert_eq!(result.line, 2);
     }
+
+    #[test]
+    fn scans_non_ascii_text() {
+        let input = String::from("print \"héllo\";\nµ ok;");
+        let mut scanner = Scanner::new(&input);
+        let tokens = std::iter::from_fn(|| {
+            let token = scanner.scan();
+            if token.kind == TokenKind::Eof {
+                return None;
+            }
+            return Some((token.kind, token.lexeme, token.column));
+        })
+        .collect::<Vec<_>>();
+        assert_eq!(
+            tokens,
+            [
+                (TokenKind::Print, String::from("print"), 1),
+                (TokenKind::Str, String::from("\"héllo\""), 7),
+                (TokenKind::Semicolon, String::from(";"), 14),
+                (TokenKind::Error, String::from("Unexpected character."), 1),
+                (TokenKind::Identifier, String::from("ok"), 3),
+                (TokenKind::Semicolon, String::from(";"), 5),
+            ]
+        );
+    }
+
+    #[test]
+    fn tracks_columns() {
+        let input = String::from("var five = 5;\n  // note\n  print five;");
+        let mut scanner = Scanner::new(&input);
+        let columns = std::iter::from_fn(|| {
+            let token = scanner.scan();
+            if token.kind == TokenKind::Eof {
+                return None;
+            }
+            return Some((token.line, token.column));
+        })
+        .collect::<Vec<_>>();
+        assert_eq!(
+            columns,
+            [
+                (1, 1),
+                (1, 5),
+                (1, 10),
+                (1, 12),
+                (1, 13),
+                (3, 3),
+                (3, 9),
+                (3, 13)
+            ]
+        );
+    }
+
+    fn kinds(source: &str, dialect: Dialect) -> Vec<TokenKind> {
+        let input = String::from(source);
+        let mut scanner = Scanner::with_dialect(&input, dialect);
+        return std::iter::from_fn(|| {
+            let token = scanner.scan();
+            return (token.kind != TokenKind::Eof).then_some(token.kind);
+        })
+        .collect();
+    }
+
+    #[test]
+    fn scans_keywords_of_dialect() {
+        use TokenKind::*;
+        let source = "import defer print PRINT Var";
+        assert_eq!(
+            kinds(source, Dialect::extended()),
+            [Import, Defer, Print, Identifier, Identifier]
+        );
+        assert_eq!(
+            kinds(source, Dialect::strict()),
+            [Identifier, Identifier, Print, Identifier, Identifier]
+        );
+        let case_insensitive = Dialect {
+            case_insensitive_keywords: true,
+            ..Dialect::extended()
+        };
+        assert_eq!(
+            kinds(source, case_insensitive),
+            [Import, Defer, Print, Print, Var]
+        );
+        assert_eq!(
+            kinds("IMPORTS iF fOr nILL", case_insensitive),
+            [Identifier, If, For, Identifier]
+        );
+    }
 }
@@ -0,0 +1,49 @@
+use crate::sandbox::Capability;
+use crate::value::{FromLox, ToLox, Value};
+use crate::vm::VM;
+
+/// Installs `input(prompt)`, which prints `prompt` and reads a line from
+/// `VMConfig::stdin`, returning `nil` at the end of input. Needs the I/O
+/// capability.
+pub fn install(vm: &mut VM) {
+    vm.register_native_requiring(Capability::Io, "input", 1, |vm, args| {
+        let prompt = Option::<String>::from_lox(args[0])?;
+        let config = vm.config_mut();
+        if let Some(prompt) = prompt {
+            config
+                .stdout
+                .write_all(prompt.as_bytes())
+                .and_then(|_| config.stdout.flush())
+                .map_err(|_| String::from("Failed to write to stdout"))?;
+        }
+        let mut line = String::new();
+        let read = config
+            .stdin
+            .read_line(&mut line)
+            .map_err(|e| format!("Could not read input: {}", e))?;
+        if read == 0 {
+            return Ok(Value::Nil);
+        }
+        let line = line.strip_suffix('\n').unwrap_or(&line);
+        let line = line.strip_suffix('\r').unwrap_or(line);
+        Ok(vm.with_gc(|gc| line.to_lox(gc)))
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::lox::{Lox, LoxValue};
+    use std::io;
+
+    #[test]
+    fn reads_lines() {
+        let mut lox = Lox::new();
+        lox.set_output(Box::new(io::sink()));
+        lox.set_input(Box::new(io::Cursor::new("alice\r\nbob\n")));
+        assert_eq!(
+            lox.eval("input(\"Name: \") + \" and \" + input(nil);"),
+            Ok(LoxValue::String(String::from("alice and bob")))
+        );
+        assert_eq!(lox.eval("input(\"\");"), Ok(LoxValue::Nil));
+    }
+}
@@ -0,0 +1,34 @@
+use crate::userdata::UserDataType;
+use crate::vm::VM;
+use std::sync::Arc;
+
+/// Installs `trace()`, which returns a list of where the script is,
+/// innermost first, as `[line N] in path` for the current line and each
+/// `import` that led to it.
+pub fn install(vm: &mut VM, list_type: &Arc<UserDataType>) {
+    let list_type = Arc::clone(list_type);
+    vm.register_native("trace", 0, move |vm, _| {
+        let frames = vm.call_stack();
+        Ok(vm.new_userdata(&list_type, frames))
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::lox::{Lox, LoxValue};
+
+    #[test]
+    fn traces_the_current_line() {
+        let mut lox = Lox::new();
+        lox.eval("var frames = trace();").unwrap();
+        assert_eq!(lox.eval("frames.len();"), Ok(LoxValue::Int(1)));
+        assert_eq!(
+            lox.eval("frames.get(0);"),
+            Ok(LoxValue::String(String::from("[line 1] in script")))
+        );
+        assert_eq!(
+            lox.eval("deep_equals(frames, list(\"[line 1] in script\"));"),
+            Ok(LoxValue::Boolean(true))
+        );
+    }
+}
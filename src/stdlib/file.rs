@@ -0,0 +1,76 @@
+use crate::sandbox::Capability;
+use crate::vm::VM;
+use std::fs;
+use std::io::Write;
+
+/// Installs `read_file`, `write_file` and `append_file`, which need the I/O
+/// capability. Failures are runtime errors naming the path.
+pub fn install(vm: &mut VM) {
+    vm.register_fn_requiring(Capability::Io, "read_file", |path: String| {
+        fs::read_to_string(&path).map_err(|e| format!("Could not read '{}': {}", path, e))
+    });
+    vm.register_fn_requiring(
+        Capability::Io,
+        "write_file",
+        |path: String, contents: String| {
+            fs::write(&path, contents).map_err(|e| format!("Could not write '{}': {}", path, e))
+        },
+    );
+    vm.register_fn_requiring(
+        Capability::Io,
+        "append_file",
+        |path: String, contents: String| {
+            fs::OpenOptions::new()
+                .append(true)
+                .create(true)
+                .open(&path)
+                .and_then(|mut file| file.write_all(contents.as_bytes()))
+                .map_err(|e| format!("Could not write '{}': {}", path, e))
+        },
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::lox::{Lox, LoxError, LoxValue};
+    use crate::sandbox::Sandbox;
+
+    #[test]
+    #[cfg_attr(miri, ignore = "uses the file system")]
+    fn reads_and_writes_files() {
+        let dir = std::env::temp_dir().join(format!("rs-lox-file-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("notes.txt");
+        let mut lox = Lox::new();
+        lox.set_global("path", LoxValue::String(path.display().to_string()));
+        lox.eval("write_file(path, \"one\"); append_file(path, \" two\");")
+            .unwrap();
+        assert_eq!(
+            lox.eval("read_file(path);"),
+            Ok(LoxValue::String(String::from("one two")))
+        );
+        std::fs::remove_dir_all(&dir).unwrap();
+        match lox.eval("read_file(path);") {
+            Err(LoxError::RuntimeError(message)) => {
+                assert!(
+                    message.starts_with("[line 1] Could not read '"),
+                    "{}",
+                    message
+                )
+            }
+            result => panic!("Unexpected result {:?}", result),
+        }
+    }
+
+    #[test]
+    fn needs_io_capability() {
+        let mut lox = Lox::new();
+        lox.set_sandbox(Sandbox::untrusted());
+        assert_eq!(
+            lox.eval("write_file(\"x\", \"y\");"),
+            Err(LoxError::RuntimeError(String::from(
+                "[line 1] write_file needs I/O, which is not allowed here"
+            )))
+        );
+    }
+}
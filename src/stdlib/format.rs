@@ -0,0 +1,167 @@
+use crate::value::{
+    format_fixed, format_float, format_hex, format_precision, FromLox, ToLox, Value,
+};
+use crate::vm::VM;
+use std::convert::TryFrom;
+
+/// Appends `value` the way `print` shows it, except that strings are
+/// inserted without quotes.
+pub(super) fn push_value(result: &mut String, value: Value, vm: &VM) {
+    match value.as_string() {
+        Some(s) => result.push_str(s.get_value()),
+        None => result.push_str(&vm.display(value)),
+    }
+}
+
+/// Replaces `{}` placeholders in `template` with `args` in order. `{N}`
+/// refers to argument N, `{:.D}` formats a number with D decimals, and `{{`
+/// and `}}` are literal braces.
+fn format(template: &str, args: &[Value], vm: &VM) -> Result<String, String> {
+    let mut result = String::new();
+    let mut next = 0;
+    let mut chars = template.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '{' if chars.peek() == Some(&'{') => {
+                chars.next();
+                result.push('{');
+            }
+            '}' if chars.peek() == Some(&'}') => {
+                chars.next();
+                result.push('}');
+            }
+            '}' => return Err(String::from("Unmatched '}' in format string")),
+            '{' => {
+                let mut spec = String::new();
+                loop {
+                    match chars.next() {
+                        Some('}') => break,
+                        Some(c) => spec.push(c),
+                        None => return Err(String::from("Unclosed '{' in format string")),
+                    }
+                }
+                let (index, precision) = match spec.split_once(':') {
+                    Some((index, precision)) => (index, Some(precision)),
+                    None => (spec.as_str(), None),
+                };
+                let index = if index.is_empty() {
+                    next += 1;
+                    next - 1
+                } else {
+                    index
+                        .parse::<usize>()
+                        .map_err(|_| format!("Invalid placeholder '{{{}}}'", spec))?
+                };
+                let value = *args
+                    .get(index)
+                    .ok_or_else(|| format!("Missing argument {} for format string", index))?;
+                match precision {
+                    None => push_value(&mut result, value, vm),
+                    Some(precision) => {
+                        let digits = precision
+                            .strip_prefix('.')
+                            .and_then(|digits| digits.parse::<usize>().ok())
+                            .ok_or_else(|| format!("Invalid placeholder '{{{}}}'", spec))?;
+                        let n = f32::try_from(value)?;
+                        result.push_str(&format_fixed(n, digits));
+                    }
+                }
+            }
+            c => result.push(c),
+        }
+    }
+    return Ok(result);
+}
+
+/// Digits for `to_fixed` and `to_precision`, which must be whole numbers
+/// from 0 to 100.
+fn digits(n: f64) -> Result<usize, String> {
+    if n.fract() != 0.0 || !(0.0..=100.0).contains(&n) {
+        return Err(format!("Invalid number of digits {}", format_float(n)));
+    }
+    return Ok(n as usize);
+}
+
+pub fn install(vm: &mut VM) {
+    vm.register_fn("to_fixed", |n: f32, d: f64| Ok(format_fixed(n, digits(d)?)));
+    vm.register_fn("to_precision", |n: f32, d: f64| match digits(d)? {
+        0 => Err(String::from("Invalid number of digits 0")),
+        d => Ok(format_precision(n, d)),
+    });
+    vm.register_fn("to_hex", |n: f32| format_hex(n));
+    vm.register_variadic("format", 1, |vm, args| {
+        let template = String::from_lox(args[0])?;
+        let result = format(&template, &args[1..], vm)?;
+        Ok(vm.with_gc(|gc| result.to_lox(gc)))
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::lox::{Lox, LoxError, LoxValue};
+
+    fn eval(source: &str) -> Result<LoxValue, LoxError> {
+        return Lox::new().eval(source);
+    }
+
+    fn string(s: &str) -> Result<LoxValue, LoxError> {
+        return Ok(LoxValue::String(String::from(s)));
+    }
+
+    fn runtime_error(message: &str) -> Result<LoxValue, LoxError> {
+        return Err(LoxError::RuntimeError(String::from(message)));
+    }
+
+    #[test]
+    fn formats_values() {
+        assert_eq!(
+            eval("format(\"x = {}, y = {:.2}\", 1, 2.5);"),
+            string("x = 1, y = 2.50")
+        );
+        assert_eq!(
+            eval("format(\"{1} {0} {}\", \"a\", nil);"),
+            string("nil a a")
+        );
+        assert_eq!(eval("format(\"{{{}}}\", true);"), string("{true}"));
+        assert_eq!(eval("format(\"plain\");"), string("plain"));
+    }
+
+    #[test]
+    fn formats_numbers() {
+        assert_eq!(eval("to_fixed(1 / 3, 3);"), string("0.333"));
+        assert_eq!(eval("to_precision(pi, 4);"), string("3.142"));
+        assert_eq!(eval("to_hex(48879);"), string("beef"));
+        assert_eq!(
+            eval("to_fixed(1, -1);"),
+            runtime_error("[line 1] Invalid number of digits -1")
+        );
+        assert_eq!(
+            eval("to_precision(1, 0);"),
+            runtime_error("[line 1] Invalid number of digits 0")
+        );
+    }
+
+    #[test]
+    fn reports_bad_templates() {
+        assert_eq!(
+            eval("format(\"{} {}\", 1);"),
+            runtime_error("[line 1] Missing argument 1 for format string")
+        );
+        assert_eq!(
+            eval("format(\"{:x}\", 1);"),
+            runtime_error("[line 1] Invalid placeholder '{:x}'")
+        );
+        assert_eq!(
+            eval("format(\"{:.1}\", \"a\");"),
+            runtime_error("[line 1] Expected a number, got \"a\"")
+        );
+        assert_eq!(
+            eval("format(\"{\", 1);"),
+            runtime_error("[line 1] Unclosed '{' in format string")
+        );
+        assert_eq!(
+            eval("format();"),
+            runtime_error("[line 1] format expects at least 1 arguments but got 0")
+        );
+    }
+}
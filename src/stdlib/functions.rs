@@ -0,0 +1,63 @@
+use crate::native::ObjNative;
+use crate::value::{FromLox, Value};
+use crate::vm::VM;
+
+/// The native behind `value`, which is the only kind of function Lox has so
+/// far.
+fn as_function(value: &Value) -> Result<&ObjNative, String> {
+    return value
+        .as_native()
+        .ok_or_else(|| format!("Expected a function, got {}", value));
+}
+
+/// Installs `fn_name(f)`, `fn_arity(f)` and `has_method(value, name)`. The
+/// arity of a function that takes any number of arguments is the least it
+/// takes.
+pub fn install(vm: &mut VM) {
+    vm.register_fn("fn_name", |f: Value| {
+        as_function(&f).map(|native| native.get_name().clone())
+    });
+    vm.register_fn("fn_arity", |f: Value| {
+        as_function(&f).map(|native| f64::from(native.get_arity()))
+    });
+    vm.register_native("has_method", 2, |vm, args| {
+        let name = String::from_lox(args[1])?;
+        Ok(Value::from(vm.has_method(args[0], &name)))
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::lox::{Lox, LoxError, LoxValue};
+
+    fn eval(source: &str) -> Result<LoxValue, LoxError> {
+        return Lox::new().eval(source);
+    }
+
+    #[test]
+    fn describes_natives() {
+        assert_eq!(
+            eval("fn_name(upper);"),
+            Ok(LoxValue::String(String::from("upper")))
+        );
+        assert_eq!(eval("fn_arity(replace);"), Ok(LoxValue::Number(3.0)));
+        assert_eq!(eval("fn_arity(fn_arity);"), Ok(LoxValue::Number(1.0)));
+        assert_eq!(
+            eval("fn_name(1);"),
+            Err(LoxError::RuntimeError(String::from(
+                "[line 1] Expected a function, got 1"
+            )))
+        );
+    }
+
+    #[test]
+    fn checks_for_methods() {
+        let has = |source: &str| eval(source) == Ok(LoxValue::Boolean(true));
+        assert!(has("has_method(split(\"a,b\", \",\"), \"len\");"));
+        assert!(!has("has_method(split(\"a,b\", \",\"), \"append\");"));
+        assert!(has("has_method(\"text\", \"len\");"));
+        assert!(has("has_method(1, \"floor\");"));
+        assert!(!has("has_method(true, \"len\");"));
+        assert!(!has("has_method(nil, \"len\");"));
+    }
+}
@@ -0,0 +1,165 @@
+use crate::userdata::{with_userdata, UserDataType};
+use crate::value::{format_float, FromLox, Value};
+use crate::vm::{BuiltinType, VM};
+use std::sync::Arc;
+
+/// The numbers from `start` up to, but not including, `end`, `step` apart.
+/// A negative step counts down.
+struct Range {
+    start: f64,
+    end: f64,
+    step: f64,
+}
+
+impl Range {
+    fn len(&self) -> f64 {
+        return ((self.end - self.start) / self.step).ceil().max(0.0);
+    }
+
+    fn get(&self, index: f64) -> Option<f64> {
+        if index >= 0.0 && index.fract() == 0.0 && index < self.len() {
+            return Some(self.start + index * self.step);
+        }
+        return None;
+    }
+
+    fn contains(&self, x: f64) -> bool {
+        let index = (x - self.start) / self.step;
+        return self.get(index) == Some(x);
+    }
+}
+
+/// The values returned by `range(start, end, step)`. The step is optional
+/// and defaults to one. Until the language has loops they are read with
+/// `len()`, `get(index)` and `contains(x)`.
+fn range_type() -> UserDataType {
+    return UserDataType::new("Range")
+        .method_fn("len", |this: Value| {
+            with_userdata(this, |range: &mut Range| range.len())
+        })
+        .method_fn("get", |this: Value, index: f64| {
+            with_userdata(this, |range: &mut Range| {
+                range
+                    .get(index)
+                    .ok_or_else(|| format!("Range index {} out of range", index))
+            })?
+        })
+        .method_fn("contains", |this: Value, x: f64| {
+            with_userdata(this, |range: &mut Range| range.contains(x))
+        });
+}
+
+/// Installs the math functions, both as globals and as methods on numbers.
+pub fn install(vm: &mut VM) {
+    vm.define_global("pi", Value::from(std::f64::consts::PI));
+    vm.register_fn("abs", f64::abs);
+    vm.register_fn("floor", f64::floor);
+    vm.register_fn("ceil", f64::ceil);
+    vm.register_fn("sqrt", f64::sqrt);
+    vm.register_fn("pow", f64::powf);
+    vm.register_fn("min", f64::min);
+    vm.register_fn("max", f64::max);
+    vm.register_method_fn(BuiltinType::Number, "abs", f64::abs);
+    vm.register_method_fn(BuiltinType::Number, "floor", f64::floor);
+    vm.register_method_fn(BuiltinType::Number, "ceil", f64::ceil);
+    vm.register_method_fn(BuiltinType::Number, "sqrt", f64::sqrt);
+    vm.register_method_fn(BuiltinType::Number, "pow", f64::powf);
+    vm.register_method_fn(BuiltinType::Number, "min", f64::min);
+    vm.register_method_fn(BuiltinType::Number, "max", f64::max);
+    let range_type = Arc::new(range_type());
+    vm.register_variadic("range", 2, move |vm, args| {
+        let range = match args {
+            [start, end] => Range {
+                start: f64::from_lox(*start)?,
+                end: f64::from_lox(*end)?,
+                step: 1.0,
+            },
+            [start, end, step] => Range {
+                start: f64::from_lox(*start)?,
+                end: f64::from_lox(*end)?,
+                step: f64::from_lox(*step)?,
+            },
+            _ => {
+                return Err(format!(
+                    "range expects 2 or 3 arguments but got {}",
+                    args.len()
+                ))
+            }
+        };
+        if range.step == 0.0 || !range.step.is_finite() {
+            return Err(format!("Invalid range step {}", format_float(range.step)));
+        }
+        Ok(vm.new_userdata(&range_type, range))
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::lox::{Lox, LoxError, LoxValue};
+
+    fn eval(source: &str) -> Result<LoxValue, LoxError> {
+        return Lox::new().eval(source);
+    }
+
+    #[test]
+    fn computes() {
+        assert_eq!(eval("abs(-2.5);"), Ok(LoxValue::Number(2.5)));
+        assert_eq!(eval("floor(2.5) + ceil(2.5);"), Ok(LoxValue::Number(5.0)));
+        assert_eq!(
+            eval("sqrt(pow(3, 2) + pow(4, 2));"),
+            Ok(LoxValue::Number(5.0))
+        );
+        assert_eq!(eval("min(1, 2) - max(1, 2);"), Ok(LoxValue::Number(-1.0)));
+        assert_eq!(eval("pi;"), Ok(LoxValue::Number(std::f32::consts::PI)));
+    }
+
+    #[test]
+    fn calls_number_methods() {
+        assert_eq!(eval("(3.7).floor();"), Ok(LoxValue::Number(3.0)));
+        assert_eq!(eval("(-2).abs().pow(3);"), Ok(LoxValue::Number(8.0)));
+        assert_eq!(eval("var x = 2; x.max(5);"), Ok(LoxValue::Number(5.0)));
+    }
+
+    #[test]
+    fn makes_ranges() {
+        let mut lox = Lox::new();
+        lox.eval("var up = range(1, 10); var down = range(10, 0, -3);")
+            .unwrap();
+        assert_eq!(lox.eval("up.len();"), Ok(LoxValue::Number(9.0)));
+        assert_eq!(lox.eval("up.get(8);"), Ok(LoxValue::Number(9.0)));
+        assert_eq!(lox.eval("down.len();"), Ok(LoxValue::Number(4.0)));
+        assert_eq!(lox.eval("down.get(3);"), Ok(LoxValue::Number(1.0)));
+        assert_eq!(lox.eval("down.contains(4);"), Ok(LoxValue::Boolean(true)));
+        assert_eq!(lox.eval("down.contains(5);"), Ok(LoxValue::Boolean(false)));
+        assert_eq!(lox.eval("up.contains(10);"), Ok(LoxValue::Boolean(false)));
+        assert_eq!(lox.eval("range(2, 1).len();"), Ok(LoxValue::Number(0.0)));
+        assert_eq!(
+            lox.eval("up.get(9);"),
+            Err(LoxError::RuntimeError(String::from(
+                "[line 1] Range index 9 out of range"
+            )))
+        );
+        assert_eq!(
+            lox.eval("range(0, 1, 0);"),
+            Err(LoxError::RuntimeError(String::from(
+                "[line 1] Invalid range step 0"
+            )))
+        );
+    }
+
+    #[test]
+    fn checks_arguments() {
+        assert_eq!(
+            eval("sqrt(\"4\");"),
+            Err(LoxError::RuntimeError(String::from(
+                "[line 1] Expected a number, got \"4\""
+            )))
+        );
+        assert_eq!(
+            eval("min(1);"),
+            Err(LoxError::RuntimeError(String::from(
+                "[line 1] min expects 2 arguments but got 1"
+            )))
+        );
+    }
+}
@@ -0,0 +1,57 @@
+use crate::value::Value;
+use crate::vm::VM;
+
+/// Installs `heapdump()`, which prints every live object grouped by kind,
+/// with how many bytes each kind takes and, in debug builds, where the
+/// objects were allocated.
+pub fn install(vm: &mut VM) {
+    vm.register_native("heapdump", 0, |vm, _| {
+        let dump = vm.with_gc(|gc| gc.heap_dump());
+        vm.config_mut()
+            .stdout
+            .write_all(dump.to_string().as_bytes())
+            .map_err(|_| String::from("Failed to write to stdout"))?;
+        return Ok(Value::Nil);
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::compiler::compile;
+    use crate::gc::GC;
+    use crate::sandbox::Sandbox;
+    use crate::vm::{VMConfig, VM};
+    use std::io;
+
+    #[test]
+    fn prints_live_objects() {
+        let mut output = Vec::new();
+        let mut input = io::empty();
+        let mut gc = GC::new();
+        let mut vm = VM::new(
+            VMConfig {
+                trace_instructions: false,
+                trace_stack: false,
+                trace_globals: false,
+                stdout: &mut output,
+                stdin: &mut input,
+                sandbox: Sandbox::default(),
+            },
+            &mut gc,
+        );
+        super::install(&mut vm);
+        let (chunk, _) = vm
+            .with_gc(|gc| compile(&String::from("heapdump();"), gc))
+            .unwrap();
+        vm.interpret_chunk(chunk).unwrap();
+        drop(vm);
+
+        let output = String::from_utf8(output).unwrap();
+        let lines = output.lines().collect::<Vec<_>>();
+        assert!(lines[0].starts_with("kind"));
+        // The native itself and its name
+        assert!(lines.iter().any(|line| line.starts_with("native ")));
+        assert!(lines.iter().any(|line| line.starts_with("string ")));
+        assert!(lines.last().unwrap().starts_with("total"));
+    }
+}
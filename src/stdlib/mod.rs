@@ -0,0 +1,39 @@
+//! Natives that every script run by the CLI or a `Lox` session can use.
+//! A bare `VM` starts without them; call `install` to add them.
+
+mod console;
+mod debug;
+mod file;
+mod format;
+mod functions;
+mod math;
+mod memory;
+mod objects;
+mod random;
+mod strings;
+mod testing;
+mod time;
+mod types;
+
+use crate::vm::VM;
+use std::sync::Arc;
+
+/// Defines the standard library in the VM's globals. Natives that need a
+/// capability are skipped when the VM's sandbox denies it.
+pub fn install(vm: &mut VM) {
+    // Lists of strings, shared so that lists from any of them compare
+    let list_type = Arc::new(strings::list_type());
+    console::install(vm);
+    debug::install(vm, &list_type);
+    file::install(vm);
+    format::install(vm);
+    functions::install(vm);
+    math::install(vm);
+    memory::install(vm);
+    objects::install(vm);
+    random::install(vm);
+    strings::install(vm, &list_type);
+    testing::install(vm);
+    time::install(vm);
+    types::install(vm);
+}
@@ -0,0 +1,93 @@
+use crate::value::{are_equal, Value};
+use crate::vm::VM;
+
+/// Installs `freeze(value)`, which stops scripts from changing a list, map
+/// or other userdata and returns it, and `is_frozen(value)`. Every other
+/// value can't be changed anyway, so it counts as frozen.
+///
+/// Also installs `clone(value)`, which copies a list, map or buffer, and
+/// `deep_equals(a, b)`, which compares them by their contents. Lists and
+/// maps only hold strings, so a copy is as deep as it can be.
+pub fn install(vm: &mut VM) {
+    vm.register_fn("freeze", |value: Value| {
+        if let Some(userdata) = value.as_userdata() {
+            userdata.freeze();
+        }
+        value
+    });
+    vm.register_fn("is_frozen", |value: Value| match value.as_userdata() {
+        Some(userdata) => userdata.is_frozen(),
+        None => true,
+    });
+    vm.register_native("clone", 1, |vm, args| {
+        let userdata = match args[0].as_userdata() {
+            Some(userdata) => userdata,
+            None => return Ok(args[0]),
+        };
+        let data = userdata.clone_data()?;
+        let user_type = userdata.get_type();
+        Ok(Value::Object(
+            vm.with_gc(|gc| gc.alloc_userdata(user_type, data)),
+        ))
+    });
+    vm.register_fn("deep_equals", |a: Value, b: Value| {
+        match (a.as_userdata(), b.as_userdata()) {
+            (Some(a), Some(b)) => a.data_equals(b),
+            _ => Ok(are_equal(a, b)),
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::lox::{Lox, LoxError, LoxValue};
+
+    #[test]
+    fn freezes_userdata() {
+        let mut lox = Lox::new();
+        lox.eval("var xs = list(\"a\"); var m = freeze(map().set(\"k\", \"v\"));")
+            .unwrap();
+        assert_eq!(lox.eval("is_frozen(xs);"), Ok(LoxValue::Boolean(false)));
+        lox.eval("xs.push(\"b\"); freeze(xs);").unwrap();
+        assert_eq!(lox.eval("is_frozen(xs);"), Ok(LoxValue::Boolean(true)));
+        assert_eq!(
+            lox.eval("xs.get(1) + m.get(\"k\");"),
+            Ok(LoxValue::String(String::from("bv")))
+        );
+        assert_eq!(
+            lox.eval("xs.push(\"c\");"),
+            Err(LoxError::RuntimeError(String::from(
+                "[line 1] Can't call push on a frozen List"
+            )))
+        );
+        assert_eq!(
+            lox.eval("m.delete(\"k\");"),
+            Err(LoxError::RuntimeError(String::from(
+                "[line 1] Can't call delete on a frozen Map"
+            )))
+        );
+        assert_eq!(lox.eval("xs.len();"), Ok(LoxValue::Int(2)));
+        assert_eq!(lox.eval("is_frozen(1);"), Ok(LoxValue::Boolean(true)));
+    }
+
+    #[test]
+    fn clones_and_compares_contents() {
+        let mut lox = Lox::new();
+        lox.eval("var xs = freeze(list(\"a\", \"b\")); var ys = clone(xs);")
+            .unwrap();
+        let is_true = |lox: &mut Lox, source: &str| lox.eval(source) == Ok(LoxValue::Boolean(true));
+        assert!(is_true(&mut lox, "deep_equals(xs, ys);"));
+        assert!(is_true(&mut lox, "xs != ys;"));
+        assert!(is_true(&mut lox, "!is_frozen(ys);"));
+        lox.eval("ys.push(\"c\");").unwrap();
+        assert!(is_true(&mut lox, "!deep_equals(xs, ys);"));
+        assert!(is_true(&mut lox, "xs.len() == 2;"));
+        assert!(is_true(
+            &mut lox,
+            "deep_equals(map().set(\"k\", \"v\"), map().set(\"k\", \"v\"));"
+        ));
+        assert!(is_true(&mut lox, "!deep_equals(list(), map());"));
+        assert!(is_true(&mut lox, "deep_equals(\"s\", \"s\");"));
+        assert!(is_true(&mut lox, "clone(1) == 1;"));
+    }
+}
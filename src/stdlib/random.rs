@@ -0,0 +1,99 @@
+use crate::value::format_float;
+use crate::vm::VM;
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+use std::sync::{Arc, Mutex};
+
+/// SplitMix64, which is small, fast and good enough for games. Not suitable
+/// for anything security related.
+struct Rng {
+    state: u64,
+}
+
+impl Rng {
+    fn new(seed: u64) -> Rng {
+        Rng { state: seed }
+    }
+
+    fn next_u64(&mut self) -> u64 {
+        self.state = self.state.wrapping_add(0x9e3779b97f4a7c15);
+        let mut z = self.state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+        return z ^ (z >> 31);
+    }
+
+    /// Uniform in [0, 1). Uses 24 bits so the result stays below 1 when
+    /// stored as an `f32` number.
+    fn next_f32(&mut self) -> f32 {
+        return (self.next_u64() >> 40) as f32 / (1u32 << 24) as f32;
+    }
+}
+
+/// Installs `random`, `random_range` and `seed`. Each call creates a new
+/// generator shared by those natives, seeded from the OS until a script
+/// calls `seed`.
+pub fn install(vm: &mut VM) {
+    let seed = RandomState::new().build_hasher().finish();
+    let rng = Arc::new(Mutex::new(Rng::new(seed)));
+
+    let random = Arc::clone(&rng);
+    vm.register_fn("random", move || random.lock().unwrap().next_f32());
+
+    let range = Arc::clone(&rng);
+    vm.register_fn("random_range", move |low: f64, high: f64| {
+        if low.fract() != 0.0 || high.fract() != 0.0 || low > high {
+            return Err(format!(
+                "Invalid range {} to {}",
+                format_float(low),
+                format_float(high)
+            ));
+        }
+        let span = (high - low) as u64 + 1;
+        Ok(low + (range.lock().unwrap().next_u64() % span) as f64)
+    });
+
+    vm.register_fn("seed", move |seed: f64| {
+        *rng.lock().unwrap() = Rng::new(seed.to_bits());
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::lox::{Lox, LoxError, LoxValue};
+
+    #[test]
+    fn is_reproducible_when_seeded() {
+        let rolls = "seed(42); random() + random_range(1, 6) * 10;";
+        let first = Lox::new().eval(rolls).unwrap();
+        assert_eq!(Lox::new().eval(rolls), Ok(first));
+        assert_ne!(
+            Lox::new().eval("seed(1); random();"),
+            Lox::new().eval("seed(2); random();")
+        );
+    }
+
+    #[test]
+    fn stays_in_range() {
+        let mut lox = Lox::new();
+        for _ in 0..100 {
+            match lox.eval("random();") {
+                Ok(LoxValue::Number(n)) => assert!((0.0..1.0).contains(&n)),
+                result => panic!("Unexpected result {:?}", result),
+            }
+            match lox.eval("random_range(-2, 2);") {
+                Ok(LoxValue::Number(n)) => {
+                    assert!((-2.0..=2.0).contains(&n) && n.fract() == 0.0)
+                }
+                result => panic!("Unexpected result {:?}", result),
+            }
+        }
+        assert_eq!(lox.eval("random_range(3, 3);"), Ok(LoxValue::Number(3.0)));
+        assert_eq!(
+            lox.eval("random_range(2, 1);"),
+            Err(LoxError::RuntimeError(String::from(
+                "[line 1] Invalid range 2 to 1"
+            )))
+        );
+    }
+}
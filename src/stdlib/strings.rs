@@ -0,0 +1,476 @@
+use super::format::push_value;
+use crate::userdata::{with_userdata, UserDataType};
+use crate::value::{format_float, FromLox, Value};
+use crate::vm::{BuiltinType, VM};
+use std::collections::BTreeMap;
+use std::sync::Arc;
+
+/// Checks that `index` is a whole number no greater than `limit`.
+fn list_index(index: f64, limit: usize) -> Result<usize, String> {
+    if index >= 0.0 && index.fract() == 0.0 && index <= limit as f64 {
+        return Ok(index as usize);
+    }
+    return Err(format!("List index {} out of range", index));
+}
+
+/// The strings returned by `split` and `list`. Until the language has lists
+/// of its own they are userdata with these methods:
+///
+/// - `len()` and `get(index)`,
+/// - `push(s)`, which returns the list, and `pop()`,
+/// - `insert(index, s)`, which returns the list, and `remove(index)`,
+/// - `sort()`, which sorts in place and returns the list.
+pub(super) fn list_type() -> UserDataType {
+    return UserDataType::new("List")
+        .method_fn("len", |this: Value| {
+            with_userdata(this, |items: &mut Vec<String>| items.len() as i64)
+        })
+        .method_fn("get", |this: Value, index: f64| {
+            with_userdata(this, |items: &mut Vec<String>| {
+                let item = if index >= 0.0 && index.fract() == 0.0 {
+                    items.get(index as usize)
+                } else {
+                    None
+                };
+                item.cloned()
+                    .ok_or_else(|| format!("List index {} out of range", index))
+            })?
+        })
+        .method_fn("push", |this: Value, item: String| {
+            with_userdata(this, |items: &mut Vec<String>| items.push(item))?;
+            Ok(this)
+        })
+        .method_fn("pop", |this: Value| {
+            with_userdata(this, |items: &mut Vec<String>| {
+                items
+                    .pop()
+                    .ok_or_else(|| String::from("Can't pop from an empty list"))
+            })?
+        })
+        .method_fn("insert", |this: Value, index: f64, item: String| {
+            with_userdata(this, |items: &mut Vec<String>| {
+                list_index(index, items.len()).map(|index| items.insert(index, item))
+            })??;
+            Ok(this)
+        })
+        .method_fn("remove", |this: Value, index: f64| {
+            with_userdata(this, |items: &mut Vec<String>| {
+                let last = items.len().checked_sub(1);
+                let index = match last {
+                    Some(last) => list_index(index, last)?,
+                    None => return Err(format!("List index {} out of range", index)),
+                };
+                Ok(items.remove(index))
+            })?
+        })
+        .method_fn("sort", |this: Value| {
+            with_userdata(this, |items: &mut Vec<String>| items.sort())?;
+            Ok(this)
+        })
+        .mutating(&["push", "pop", "insert", "remove", "sort"])
+        .cloneable::<Vec<String>>()
+        .comparable::<Vec<String>>();
+}
+
+/// What a map holds: its entries, and the keys in the order they were first
+/// set, which `keys()` and `values()` follow so that printing them doesn't
+/// depend on anything but the script.
+#[derive(Debug, Clone, Default)]
+struct StringMap {
+    entries: BTreeMap<String, String>,
+    order: Vec<String>,
+}
+
+impl StringMap {
+    fn insert(&mut self, key: String, value: String) {
+        if self.entries.insert(key.clone(), value).is_none() {
+            self.order.push(key);
+        }
+    }
+
+    /// Returns whether the key was there.
+    fn remove(&mut self, key: &str) -> bool {
+        if self.entries.remove(key).is_none() {
+            return false;
+        }
+        self.order.retain(|k| k != key);
+        return true;
+    }
+
+    fn values(&self) -> Vec<String> {
+        return self
+            .order
+            .iter()
+            .map(|key| self.entries[key].clone())
+            .collect();
+    }
+}
+
+/// Maps with the same entries are equal, whatever order they were set in.
+impl PartialEq for StringMap {
+    fn eq(&self, other: &StringMap) -> bool {
+        return self.entries == other.entries;
+    }
+}
+
+/// The maps returned by `map()`, from strings to strings, with these methods:
+///
+/// - `set(key, value)`, which returns the map, and `get(key)`, which is nil
+///   for a missing key,
+/// - `has(key)`, `delete(key)`, which returns whether the key was there, and
+///   `size()`,
+/// - `keys()` and `values()`, which return lists in the order the keys were
+///   first set. Deleting a key and setting it again moves it to the end.
+fn map_type(list_type: &Arc<UserDataType>) -> UserDataType {
+    let keys_type = Arc::clone(list_type);
+    let values_type = Arc::clone(list_type);
+    return UserDataType::new("Map")
+        .method_fn("set", |this: Value, key: String, value: String| {
+            with_userdata(this, |map: &mut StringMap| map.insert(key, value))?;
+            Ok(this)
+        })
+        .method_fn("get", |this: Value, key: String| {
+            with_userdata(this, |map: &mut StringMap| map.entries.get(&key).cloned())
+        })
+        .method_fn("has", |this: Value, key: String| {
+            with_userdata(this, |map: &mut StringMap| map.entries.contains_key(&key))
+        })
+        .method_fn("delete", |this: Value, key: String| {
+            with_userdata(this, |map: &mut StringMap| map.remove(&key))
+        })
+        .method_fn("size", |this: Value| {
+            with_userdata(this, |map: &mut StringMap| map.entries.len() as i64)
+        })
+        .method("keys", 0, move |vm, args| {
+            let keys = with_userdata(args[0], |map: &mut StringMap| map.order.clone())?;
+            Ok(vm.new_userdata(&keys_type, keys))
+        })
+        .method("values", 0, move |vm, args| {
+            let values = with_userdata(args[0], |map: &mut StringMap| map.values())?;
+            Ok(vm.new_userdata(&values_type, values))
+        })
+        .mutating(&["set", "delete"])
+        .cloneable::<StringMap>()
+        .comparable::<StringMap>();
+}
+
+/// The buffers returned by `buffer()`, for building a long string without
+/// interning every intermediate one. `append(value)` adds a value the way
+/// `format` shows it and returns the buffer, `to_string()` returns the
+/// contents, and `len()` their length in characters.
+fn string_builder_type() -> UserDataType {
+    return UserDataType::new("StringBuilder")
+        .method("append", 1, |vm, args| {
+            let grown = with_userdata(args[0], |buffer: &mut String| {
+                let capacity = buffer.capacity();
+                push_value(buffer, args[1], vm);
+                buffer.capacity() - capacity
+            })?;
+            vm.with_gc(|gc| gc.add_external_bytes(grown));
+            Ok(args[0])
+        })
+        .method_fn("to_string", |this: Value| {
+            with_userdata(this, |buffer: &mut String| buffer.clone())
+        })
+        .method_fn("len", |this: Value| {
+            with_userdata(this, |buffer: &mut String| buffer.chars().count() as i64)
+        })
+        .mutating(&["append"])
+        .cloneable::<String>()
+        .comparable::<String>();
+}
+
+fn len(s: String) -> i64 {
+    return s.chars().count() as i64;
+}
+
+fn upper(s: String) -> String {
+    return s.to_uppercase();
+}
+
+fn lower(s: String) -> String {
+    return s.to_lowercase();
+}
+
+fn trim(s: String) -> String {
+    return String::from(s.trim());
+}
+
+fn contains(s: String, part: String) -> bool {
+    return s.contains(&part);
+}
+
+fn replace(s: String, from: String, to: String) -> Result<String, String> {
+    if from.is_empty() {
+        return Err(String::from("Can't replace an empty string"));
+    }
+    return Ok(s.replace(&from, &to));
+}
+
+fn ord(s: String) -> Result<i64, String> {
+    let mut chars = s.chars();
+    return match (chars.next(), chars.next()) {
+        (Some(c), None) => Ok(i64::from(u32::from(c))),
+        _ => Err(format!("Expected a single character, got \"{}\"", s)),
+    };
+}
+
+/// Installs the string functions, both as globals taking the string first
+/// and as methods on strings, and the lists of `list_type` and maps of
+/// strings.
+pub fn install(vm: &mut VM, list_type: &Arc<UserDataType>) {
+    vm.register_fn("len", len);
+    vm.register_fn("upper", upper);
+    vm.register_fn("lower", lower);
+    vm.register_fn("trim", trim);
+    vm.register_fn("contains", contains);
+    vm.register_fn("replace", replace);
+    vm.register_fn("ord", ord);
+    vm.register_method_fn(BuiltinType::String, "len", len);
+    vm.register_method_fn(BuiltinType::String, "upper", upper);
+    vm.register_method_fn(BuiltinType::String, "lower", lower);
+    vm.register_method_fn(BuiltinType::String, "trim", trim);
+    vm.register_method_fn(BuiltinType::String, "contains", contains);
+    vm.register_method_fn(BuiltinType::String, "replace", replace);
+    vm.register_method_fn(BuiltinType::String, "ord", ord);
+    vm.register_fn("chr", |code: f64| {
+        std::char::from_u32(code as u32)
+            .filter(|_| code >= 0.0 && code.fract() == 0.0)
+            .map(String::from)
+            .ok_or_else(|| format!("Invalid character code {}", format_float(code)))
+    });
+    let list = Arc::clone(list_type);
+    vm.register_variadic("list", 0, move |vm, args| {
+        let items = args
+            .iter()
+            .map(|item| String::from_lox(*item))
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(vm.new_userdata(&list, items))
+    });
+    let map_type = Arc::new(map_type(list_type));
+    vm.register_native("map", 0, move |vm, _| {
+        Ok(vm.new_userdata(&map_type, StringMap::default()))
+    });
+    let split_type = Arc::clone(list_type);
+    vm.register_native("split", 2, move |vm, args| {
+        let s = String::from_lox(args[0])?;
+        let separator = String::from_lox(args[1])?;
+        if separator.is_empty() {
+            return Err(String::from("Separator can't be empty"));
+        }
+        let items = s.split(&separator).map(String::from).collect::<Vec<_>>();
+        Ok(vm.new_userdata(&split_type, items))
+    });
+    let string_builder_type = Arc::new(string_builder_type());
+    vm.register_native("buffer", 0, move |vm, _| {
+        Ok(vm.new_userdata(&string_builder_type, String::new()))
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::lox::{Lox, LoxError, LoxValue};
+
+    fn eval(source: &str) -> Result<LoxValue, LoxError> {
+        return Lox::new().eval(source);
+    }
+
+    fn string(s: &str) -> Result<LoxValue, LoxError> {
+        return Ok(LoxValue::String(String::from(s)));
+    }
+
+    fn runtime_error(message: &str) -> Result<LoxValue, LoxError> {
+        return Err(LoxError::RuntimeError(String::from(message)));
+    }
+
+    #[test]
+    fn transforms_strings() {
+        assert_eq!(eval("len(\"hello\");"), Ok(LoxValue::Int(5)));
+        assert_eq!(eval("upper(\"abc\") + lower(\"DEF\");"), string("ABCdef"));
+        assert_eq!(eval("trim(\"  x \");"), string("x"));
+        assert_eq!(
+            eval("contains(\"haystack\", \"st\");"),
+            Ok(LoxValue::Boolean(true))
+        );
+        assert_eq!(eval("replace(\"a-b-c\", \"-\", \"+\");"), string("a+b+c"));
+        assert_eq!(eval("ord(\"a\");"), Ok(LoxValue::Int(97)));
+        assert_eq!(eval("chr(ord(\"a\") + 1);"), string("b"));
+        // Results are interned like any other string
+        assert_eq!(eval("upper(\"a\") == \"A\";"), Ok(LoxValue::Boolean(true)));
+    }
+
+    #[test]
+    fn splits_into_lists() {
+        let mut lox = Lox::new();
+        lox.eval("var parts = split(\"a,b,,c\", \",\");").unwrap();
+        assert_eq!(lox.eval("parts.len();"), Ok(LoxValue::Int(4)));
+        assert_eq!(lox.eval("parts.get(1) + parts.get(3);"), string("bc"));
+        assert_eq!(lox.eval("parts.get(2);"), string(""));
+        assert_eq!(
+            lox.eval("parts.get(4);"),
+            runtime_error("[line 1] List index 4 out of range")
+        );
+        assert_eq!(
+            lox.eval("split(\"abc\", \"\");"),
+            runtime_error("[line 1] Separator can't be empty")
+        );
+    }
+
+    #[test]
+    fn calls_string_methods() {
+        assert_eq!(eval("\"hello\".len();"), Ok(LoxValue::Int(5)));
+        assert_eq!(eval("\" Ab \".trim().upper();"), string("AB"));
+        assert_eq!(eval("\"a-b\".replace(\"-\", \"+\");"), string("a+b"));
+        assert_eq!(
+            eval("\"abc\".contains(\"bc\");"),
+            Ok(LoxValue::Boolean(true))
+        );
+        assert_eq!(
+            eval("\"abc\".reverse();"),
+            runtime_error("[line 1] Undefined method 'reverse' on string")
+        );
+        assert_eq!(
+            eval("\"abc\".upper(1);"),
+            runtime_error("[line 1] string.upper expects 0 arguments but got 1")
+        );
+    }
+
+    #[test]
+    fn changes_lists() {
+        let mut lox = Lox::new();
+        lox.eval("var xs = list(\"b\", \"d\");").unwrap();
+        lox.eval("xs.push(\"a\").insert(1, \"c\").sort();").unwrap();
+        assert_eq!(
+            lox.eval("xs.get(0) + xs.get(1) + xs.get(2) + xs.get(3);"),
+            string("abcd")
+        );
+        assert_eq!(lox.eval("xs.remove(1);"), string("b"));
+        assert_eq!(lox.eval("xs.pop();"), string("d"));
+        assert_eq!(lox.eval("xs.len();"), Ok(LoxValue::Int(2)));
+        assert_eq!(
+            lox.eval("xs.insert(3, \"x\");"),
+            runtime_error("[line 1] List index 3 out of range")
+        );
+        assert_eq!(
+            lox.eval("list().pop();"),
+            runtime_error("[line 1] Can't pop from an empty list")
+        );
+        assert_eq!(
+            lox.eval("list().remove(0);"),
+            runtime_error("[line 1] List index 0 out of range")
+        );
+        assert_eq!(
+            lox.eval("list(1);"),
+            runtime_error("[line 1] Expected a string, got 1")
+        );
+    }
+
+    #[test]
+    fn changes_maps() {
+        let mut lox = Lox::new();
+        lox.eval("var m = map().set(\"b\", \"2\").set(\"a\", \"1\");")
+            .unwrap();
+        lox.eval("m.set(\"c\", \"3\").set(\"b\", \"two\");")
+            .unwrap();
+        assert_eq!(lox.eval("m.get(\"b\");"), string("two"));
+        assert_eq!(lox.eval("m.get(\"z\");"), Ok(LoxValue::Nil));
+        assert_eq!(lox.eval("m.has(\"a\");"), Ok(LoxValue::Boolean(true)));
+        assert_eq!(lox.eval("m.delete(\"a\");"), Ok(LoxValue::Boolean(true)));
+        assert_eq!(lox.eval("m.delete(\"a\");"), Ok(LoxValue::Boolean(false)));
+        assert_eq!(lox.eval("m.size();"), Ok(LoxValue::Int(2)));
+        assert_eq!(
+            lox.eval("var k = m.keys(); k.get(0) + k.get(1);"),
+            string("bc")
+        );
+        assert_eq!(
+            lox.eval("var v = m.values(); v.get(0) + v.get(1);"),
+            string("two3")
+        );
+        // Keys keep the order they were set in, not their sort order
+        lox.eval("m.set(\"a\", \"one\");").unwrap();
+        assert_eq!(
+            lox.eval("var k = m.keys(); k.get(0) + k.get(1) + k.get(2);"),
+            string("bca")
+        );
+        assert_eq!(
+            lox.eval(
+                "deep_equals(m, map().set(\"c\", \"3\").set(\"a\", \"one\").set(\"b\", \"two\"));"
+            ),
+            Ok(LoxValue::Boolean(true))
+        );
+    }
+
+    #[test]
+    fn builds_strings() {
+        let mut lox = Lox::new();
+        lox.eval("var out = buffer();").unwrap();
+        lox.eval("out.append(\"n=\").append(1.5).append(nil);")
+            .unwrap();
+        lox.eval("out.append(true);").unwrap();
+        assert_eq!(lox.eval("out.to_string();"), string("n=1.5niltrue"));
+        assert_eq!(lox.eval("out.len();"), Ok(LoxValue::Int(12)));
+        assert_eq!(lox.eval("buffer().to_string();"), string(""));
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore = "evaluates hundreds of scripts")]
+    fn counts_buffers_towards_memory_limit() {
+        let mut lox = Lox::new();
+        lox.set_sandbox(crate::sandbox::Sandbox {
+            max_memory: Some(64 * 1024),
+            ..crate::sandbox::Sandbox::default()
+        });
+        lox.eval("var out = buffer(); var chunk = \"0123456789abcdef\";")
+            .unwrap();
+        let mut source = String::new();
+        for _ in 0..8 {
+            source.push_str("out.append(chunk);");
+        }
+        // A single string allocation for the chunk, however often it's
+        // appended, so only the buffer's growth can exceed the limit
+        let mut result = Ok(LoxValue::Nil);
+        for _ in 0..1024 {
+            result = lox.eval(&source);
+            if result.is_err() {
+                break;
+            }
+        }
+        assert_eq!(result, runtime_error("[line 1] Memory limit exceeded"));
+    }
+
+    #[test]
+    fn counts_long_appends_towards_memory_limit() {
+        // Few enough evals to run under Miri too
+        let mut lox = Lox::new();
+        lox.set_sandbox(crate::sandbox::Sandbox {
+            max_memory: Some(64 * 1024),
+            ..crate::sandbox::Sandbox::default()
+        });
+        lox.set_global("chunk", LoxValue::String("0123456789abcdef".repeat(256)));
+        lox.eval("var out = buffer();").unwrap();
+        let mut result = Ok(LoxValue::Nil);
+        for _ in 0..32 {
+            result = lox.eval("out.append(chunk);");
+            if result.is_err() {
+                break;
+            }
+        }
+        assert_eq!(result, runtime_error("[line 1] Memory limit exceeded"));
+    }
+
+    #[test]
+    fn checks_characters() {
+        assert_eq!(
+            eval("ord(\"ab\");"),
+            runtime_error("[line 1] Expected a single character, got \"ab\"")
+        );
+        assert_eq!(
+            eval("chr(-1);"),
+            runtime_error("[line 1] Invalid character code -1")
+        );
+        assert_eq!(
+            eval("chr(1.5);"),
+            runtime_error("[line 1] Invalid character code 1.5")
+        );
+    }
+}
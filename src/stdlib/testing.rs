@@ -0,0 +1,40 @@
+use crate::value::{is_falsey, FromLox, Value};
+use crate::vm::VM;
+
+/// Installs `assert(condition, message)`, which fails with `message` when
+/// `condition` is falsey. The VM adds the line of the call to the error.
+pub fn install(vm: &mut VM) {
+    vm.register_native("assert", 2, |_, args| {
+        if !is_falsey(args[0]) {
+            return Ok(Value::Nil);
+        }
+        let message = String::from_lox(args[1])?;
+        return Err(format!("Assertion failed: {}", message));
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::lox::{Lox, LoxError, LoxValue};
+
+    #[test]
+    fn fails_with_message_and_line() {
+        let mut lox = Lox::new();
+        assert_eq!(
+            lox.eval("assert(1 < 2, \"math works\");"),
+            Ok(LoxValue::Nil)
+        );
+        assert_eq!(
+            lox.eval("var a = 1;\nassert(a == 2, \"a should be 2\");"),
+            Err(LoxError::RuntimeError(String::from(
+                "[line 2] Assertion failed: a should be 2"
+            )))
+        );
+        assert_eq!(
+            lox.eval("assert(nil, 1);"),
+            Err(LoxError::RuntimeError(String::from(
+                "[line 1] Expected a string, got 1"
+            )))
+        );
+    }
+}
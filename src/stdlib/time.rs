@@ -0,0 +1,121 @@
+use crate::sandbox::Capability;
+use crate::vm::VM;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+/// Formats `seconds` since the Unix epoch as a UTC date. Supports `%Y`, `%m`,
+/// `%d`, `%H`, `%M`, `%S` and `%%`.
+fn format_date(seconds: u64, format: &str) -> Result<String, String> {
+    let days = (seconds / 86400) as i64;
+    let time = seconds % 86400;
+    // Civil date from days since the epoch, after Howard Hinnant's algorithm
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let day_of_era = z - era * 146097;
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let mp = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+
+    let mut result = String::new();
+    let mut chars = format.chars();
+    while let Some(c) = chars.next() {
+        if c != '%' {
+            result.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('Y') => result.push_str(&format!("{:04}", year)),
+            Some('m') => result.push_str(&format!("{:02}", month)),
+            Some('d') => result.push_str(&format!("{:02}", day)),
+            Some('H') => result.push_str(&format!("{:02}", time / 3600)),
+            Some('M') => result.push_str(&format!("{:02}", time / 60 % 60)),
+            Some('S') => result.push_str(&format!("{:02}", time % 60)),
+            Some('%') => result.push('%'),
+            Some(other) => return Err(format!("Unknown date format '%{}'", other)),
+            None => return Err(String::from("Date format ends with '%'")),
+        }
+    }
+    return Ok(result);
+}
+
+/// Installs `clock`, `time_millis`, `sleep` and `date`, which all need the
+/// clock capability. Numbers are 32-bit floats, so time is measured from
+/// when the natives were installed rather than from the Unix epoch.
+pub fn install(vm: &mut VM) {
+    let start = Instant::now();
+    vm.register_fn_requiring(Capability::Clock, "clock", move || {
+        start.elapsed().as_secs_f64()
+    });
+    vm.register_fn_requiring(Capability::Clock, "time_millis", move || {
+        start.elapsed().as_secs_f64() * 1000.0
+    });
+    vm.register_fn_requiring(Capability::Clock, "sleep", |seconds: f64| {
+        if !(seconds >= 0.0 && seconds.is_finite()) {
+            return Err(format!("Can't sleep for {} seconds", seconds));
+        }
+        std::thread::sleep(Duration::from_secs_f64(seconds));
+        Ok(())
+    });
+    vm.register_fn_requiring(Capability::Clock, "date", |format: String| {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_err(|_| String::from("System clock is before 1970"))?;
+        format_date(now.as_secs(), &format)
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lox::{Lox, LoxError, LoxValue};
+    use crate::sandbox::Sandbox;
+
+    #[test]
+    fn formats_dates() {
+        let format = "%Y-%m-%d %H:%M:%S";
+        assert_eq!(
+            format_date(0, format),
+            Ok(String::from("1970-01-01 00:00:00"))
+        );
+        assert_eq!(
+            format_date(951_827_696, format),
+            Ok(String::from("2000-02-29 12:34:56"))
+        );
+        assert_eq!(format_date(1_700_000_000, "%d%%"), Ok(String::from("14%")));
+        assert_eq!(
+            format_date(0, "%y"),
+            Err(String::from("Unknown date format '%y'"))
+        );
+    }
+
+    #[test]
+    fn measures_time() {
+        let mut lox = Lox::new();
+        lox.eval("var start = time_millis(); sleep(0.01);").unwrap();
+        match lox.eval("time_millis() - start;") {
+            Ok(LoxValue::Number(elapsed)) => assert!(elapsed >= 10.0),
+            result => panic!("Unexpected result {:?}", result),
+        }
+        assert_eq!(
+            lox.eval("sleep(-1);"),
+            Err(LoxError::RuntimeError(String::from(
+                "[line 1] Can't sleep for -1 seconds"
+            )))
+        );
+    }
+
+    #[test]
+    fn needs_clock_capability() {
+        let mut lox = Lox::new();
+        lox.set_sandbox(Sandbox::untrusted());
+        assert_eq!(
+            lox.eval("clock();"),
+            Err(LoxError::RuntimeError(String::from(
+                "[line 1] clock needs clock, which is not allowed here"
+            )))
+        );
+    }
+}
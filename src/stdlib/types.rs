@@ -0,0 +1,54 @@
+use crate::value::Value;
+use crate::vm::VM;
+
+fn is_userdata(value: &Value, type_name: &str) -> bool {
+    return match value.as_userdata() {
+        Some(userdata) => userdata.get_type().get_name() == type_name,
+        None => false,
+    };
+}
+
+/// Installs `is_number(value)`, `is_string`, `is_nil`, `is_bool`, `is_list`
+/// and `is_map`, so library code can check its arguments before using them.
+/// Integers and floats are both numbers.
+pub fn install(vm: &mut VM) {
+    vm.register_fn("is_number", |value: Value| {
+        matches!(value, Value::Number(_) | Value::Int(_))
+    });
+    vm.register_fn("is_string", |value: Value| value.as_string().is_some());
+    vm.register_fn("is_nil", |value: Value| matches!(value, Value::Nil));
+    vm.register_fn("is_bool", |value: Value| matches!(value, Value::Boolean(_)));
+    vm.register_fn("is_list", |value: Value| is_userdata(&value, "List"));
+    vm.register_fn("is_map", |value: Value| is_userdata(&value, "Map"));
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::lox::{Lox, LoxValue};
+
+    #[test]
+    fn tests_value_kinds() {
+        let mut lox = Lox::new();
+        let mut check = |source: &str, expected: bool| {
+            assert_eq!(
+                lox.eval(source),
+                Ok(LoxValue::Boolean(expected)),
+                "{}",
+                source
+            );
+        };
+        check("is_number(1);", true);
+        check("is_number(1.5);", true);
+        check("is_number(\"1\");", false);
+        check("is_string(\"\");", true);
+        check("is_string(nil);", false);
+        check("is_nil(nil);", true);
+        check("is_nil(false);", false);
+        check("is_bool(false);", true);
+        check("is_bool(0);", false);
+        check("is_list(list());", true);
+        check("is_list(map());", false);
+        check("is_map(map());", true);
+        check("is_map(buffer());", false);
+    }
+}
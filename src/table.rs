@@ -1,7 +1,9 @@
 use crate::gc::ObjString;
 use std::alloc::{self, dealloc, Layout};
+#[cfg(feature = "table-stats")]
+use std::cell::Cell;
 use std::fmt::Debug;
-use std::ptr::{self, null_mut};
+use std::ptr::null_mut;
 
 enum Entry<T> {
     Empty,
@@ -40,8 +42,47 @@ pub struct Table<T: Copy> {
     ptr: *mut Entry<T>,
     cap: usize,
     len: usize,
+    resizes: usize,
+    /// The keys in the order they were first set, for tables created with
+    /// `with_insertion_order`.
+    order: Option<Vec<*const ObjString>>,
+    #[cfg(feature = "table-stats")]
+    probes: ProbeCounters,
 }
 
+/// Counts how far lookups had to probe, only collected with the
+/// `table-stats` feature since it costs a little on every lookup.
+#[cfg(feature = "table-stats")]
+#[derive(Debug, Default)]
+struct ProbeCounters {
+    lookups: Cell<u64>,
+    probes: Cell<u64>,
+    collisions: Cell<u64>,
+    max_probe: Cell<usize>,
+}
+
+/// A snapshot of a table's size and, with the `table-stats` feature, of
+/// how lookups probed it. Without the feature the probe counters stay 0.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct TableStats {
+    /// Buckets holding a key or a tombstone.
+    pub len: usize,
+    pub capacity: usize,
+    /// Times the table grew.
+    pub resizes: usize,
+    pub lookups: u64,
+    /// Buckets looked at past the first one, over all lookups.
+    pub probes: u64,
+    /// Lookups whose first bucket was taken by another key.
+    pub collisions: u64,
+    /// Longest probe sequence of any lookup.
+    pub max_probe: usize,
+}
+
+// SAFETY: the table owns its entries. Keys point at strings owned by a GC,
+// which the owner of the table has to move along with it, see `ObjRef`.
+unsafe impl<T: Copy + Send> Send for Table<T> {}
+
 const TABLE_MAX_LOAD: f64 = 0.75;
 
 /// Finds an entry by the key, returns it and the kind of entry found:
@@ -50,42 +91,72 @@ const TABLE_MAX_LOAD: f64 = 0.75;
 /// * EmptyBucket — empty bucket
 /// * Tombstone — tombstone
 ///
+/// Also returns how many buckets past the first one it looked at.
+///
 /// Panics if cap is 0. Will not halt if the table is full.
-unsafe fn get_entry<T>(ptr: *mut Entry<T>, cap: usize, key: *const ObjString) -> *mut Entry<T> {
+unsafe fn get_entry<T>(
+    ptr: *mut Entry<T>,
+    cap: usize,
+    key: *const ObjString,
+) -> (*mut Entry<T>, usize) {
     let mut index = (*key).get_hash() as usize % cap;
     let mut tombstone: *mut Entry<T> = null_mut();
+    let mut probes = 0;
     loop {
-        let entry = ptr.offset(index as isize);
+        let entry = ptr.add(index);
         match *entry {
             // Empty bucket
             Entry::Empty => {
                 return if tombstone.is_null() {
-                    entry
+                    (entry, probes)
                 } else {
-                    tombstone
+                    (tombstone, probes)
                 }
             }
-            Entry::Tombstone { key: k } => {
+            Entry::Tombstone { .. } => {
                 // We found a tombstone, so we can reuse it.
-                if tombstone == null_mut() {
+                if tombstone.is_null() {
                     tombstone = entry;
                 }
             }
             Entry::Data { key: k, .. } => {
                 // We found the key
                 if k == key {
-                    return entry;
+                    return (entry, probes);
                 }
             }
         }
-        index = (index + 1) % cap;
+        probes += 1;
+        index = next_index(index, probes, cap);
     }
 }
 
+/// The bucket to look at after `index` on the `probe`th step of a lookup.
+///
+/// Probing is linear by default. With the `quadratic-probing` feature, the
+/// step grows by one each time, so clusters of neighbouring hashes spread
+/// out. The offsets are triangular numbers, which visit every bucket when
+/// `cap` is a power of two, as `grow_capacity` ensures.
+#[cfg(not(feature = "quadratic-probing"))]
+fn next_index(index: usize, _probe: usize, cap: usize) -> usize {
+    return (index + 1) % cap;
+}
+
+#[cfg(feature = "quadratic-probing")]
+fn next_index(index: usize, probe: usize, cap: usize) -> usize {
+    return (index + probe) % cap;
+}
+
+/// Frees entries allocated by `adjust_capacity`. A table that never grew
+/// has no allocation, so there is nothing to free.
 unsafe fn free_entries<T>(ptr: *mut Entry<T>, cap: usize) {
+    if cap == 0 {
+        return;
+    }
     dealloc(ptr as *mut u8, Layout::array::<Entry<T>>(cap).unwrap())
 }
 
+/// Always a power of two, see `next_index`.
 fn grow_capacity(cap: usize) -> usize {
     if cap < 8 {
         8
@@ -94,12 +165,13 @@ fn grow_capacity(cap: usize) -> usize {
     }
 }
 
+#[cfg(test)]
 impl<T: Copy + Debug> Table<T> {
     fn print(&self) {
         println!("Table: {{");
         for i in 0..self.cap {
             unsafe {
-                let entry = &*self.ptr.offset(i as isize);
+                let entry = &*self.ptr.add(i);
                 println!("  {:?}", entry);
             }
         }
@@ -107,15 +179,80 @@ impl<T: Copy + Debug> Table<T> {
     }
 }
 
+impl<T: Copy> Default for Table<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl<T: Copy> Table<T> {
     pub fn new() -> Self {
         Self {
             ptr: null_mut(),
             cap: 0,
             len: 0,
+            resizes: 0,
+            order: None,
+            #[cfg(feature = "table-stats")]
+            probes: ProbeCounters::default(),
         }
     }
 
+    /// Creates a table that `iter` walks in the order keys were first set,
+    /// rather than in bucket order, which depends on where the keys were
+    /// allocated. Keeping the order costs a vector of the keys, and
+    /// deleting a key has to search it.
+    pub fn with_insertion_order() -> Self {
+        let mut table = Self::new();
+        table.order = Some(Vec::new());
+        return table;
+    }
+
+    /// Whether the table was created with `with_insertion_order`.
+    pub fn preserves_order(&self) -> bool {
+        return self.order.is_some();
+    }
+
+    pub fn stats(&self) -> TableStats {
+        let stats = TableStats {
+            len: self.len,
+            capacity: self.cap,
+            resizes: self.resizes,
+            ..TableStats::default()
+        };
+        #[cfg(feature = "table-stats")]
+        let stats = TableStats {
+            lookups: self.probes.lookups.get(),
+            probes: self.probes.probes.get(),
+            collisions: self.probes.collisions.get(),
+            max_probe: self.probes.max_probe.get(),
+            ..stats
+        };
+        return stats;
+    }
+
+    /// Records a lookup that looked at `probes` buckets past the first.
+    #[cfg(feature = "table-stats")]
+    fn record_lookup(&self, probes: usize) {
+        let counters = &self.probes;
+        counters.lookups.set(counters.lookups.get() + 1);
+        counters.probes.set(counters.probes.get() + probes as u64);
+        if probes > 0 {
+            counters.collisions.set(counters.collisions.get() + 1);
+        }
+        counters.max_probe.set(counters.max_probe.get().max(probes));
+    }
+
+    #[cfg(not(feature = "table-stats"))]
+    fn record_lookup(&self, _probes: usize) {}
+
+    /// Finds the bucket for `key`, see `get_entry`, counting the lookup.
+    unsafe fn lookup(&self, key: *const ObjString) -> *mut Entry<T> {
+        let (entry, probes) = get_entry(self.ptr, self.cap, key);
+        self.record_lookup(probes);
+        return entry;
+    }
+
     unsafe fn adjust_capacity(&mut self, new_cap: usize) {
         assert!(
             new_cap > self.cap,
@@ -136,15 +273,15 @@ impl<T: Copy> Table<T> {
         }
         // Set new entries to null
         for i in 0..new_cap {
-            new_ptr.offset(i as isize).write(Entry::Empty);
+            new_ptr.add(i).write(Entry::Empty);
         }
 
         self.len = 0;
         // Copy old entries to new entries
         for i in 0..self.cap {
-            let entry = self.ptr.offset(i as isize);
+            let entry = self.ptr.add(i);
             if let Entry::Data { key, value } = &*entry {
-                let mut dest = get_entry(new_ptr, new_cap, *key);
+                let (dest, _) = get_entry(new_ptr, new_cap, *key);
                 (*dest) = Entry::Data {
                     key: *key,
                     value: *value,
@@ -159,6 +296,7 @@ impl<T: Copy> Table<T> {
         // Update table
         self.ptr = new_ptr;
         self.cap = new_cap;
+        self.resizes += 1;
     }
 
     /// Sets the value of the key in the table. Returns true if the key was
@@ -172,8 +310,8 @@ impl<T: Copy> Table<T> {
             }
         }
         unsafe {
-            let mut entry = get_entry(self.ptr, self.cap, key);
-            let mut result: bool = match *entry {
+            let entry = self.lookup(key);
+            let result: bool = match *entry {
                 Entry::Empty => {
                     // New entry
                     self.len += 1;
@@ -183,6 +321,9 @@ impl<T: Copy> Table<T> {
                 Entry::Data { key: _, value: _ } => false,
             };
             (*entry) = Entry::Data { key, value };
+            if let (true, Some(order)) = (result, &mut self.order) {
+                order.push(key);
+            }
             return result;
         }
     }
@@ -195,13 +336,63 @@ impl<T: Copy> Table<T> {
             return None;
         }
         unsafe {
-            let entry = get_entry(self.ptr, self.cap, key);
+            let entry = self.lookup(key);
             return match &*entry {
-                Entry::Data { key: _, value } => Some(&value),
+                Entry::Data { key: _, value } => Some(value),
                 _ => None,
             };
         }
     }
+    /// Returns the bucket holding the key, which `get_at` and `set_at` can
+    /// use later to skip hashing.
+    ///
+    /// Please note that keys are compared using **pointer equality**.
+    pub fn index_of(&self, key: &ObjString) -> Option<usize> {
+        if self.len == 0 {
+            return None;
+        }
+        unsafe {
+            let entry = self.lookup(key);
+            return match &*entry {
+                Entry::Data { .. } => Some(entry.offset_from(self.ptr) as usize),
+                _ => None,
+            };
+        }
+    }
+
+    /// Returns the value of the key if it's in the bucket at `index`. It
+    /// may have moved since `index_of` returned it, if the table has grown
+    /// or the key was deleted, and then this returns `None`.
+    pub fn get_at(&self, index: usize, key: &ObjString) -> Option<&T> {
+        if index >= self.cap {
+            return None;
+        }
+        unsafe {
+            return match &*self.ptr.add(index) {
+                Entry::Data { key: k, value } if std::ptr::eq(*k, key) => Some(value),
+                _ => None,
+            };
+        }
+    }
+
+    /// Replaces the value of the key if it's in the bucket at `index`, see
+    /// `get_at`. Returns true if it was.
+    pub fn set_at(&mut self, index: usize, key: &ObjString, value: T) -> bool {
+        if index >= self.cap {
+            return false;
+        }
+        unsafe {
+            let entry = self.ptr.add(index);
+            return match &mut *entry {
+                Entry::Data { key: k, value: v } if std::ptr::eq(*k, key) => {
+                    *v = value;
+                    true
+                }
+                _ => false,
+            };
+        }
+    }
+
     /// Returns the value of the key in the table.
     ///
     /// Please note that the keys are compared using **string equality**.
@@ -210,16 +401,19 @@ impl<T: Copy> Table<T> {
             return None;
         }
         let mut index = (*find_key).get_hash() as usize % self.cap;
+        let mut probes = 0;
         loop {
             unsafe {
-                let entry = self.ptr.offset(index as isize);
+                let entry = self.ptr.add(index);
                 match &*entry {
                     Entry::Data { key, value } => {
                         if **key == *find_key {
-                            return Some(&value);
+                            self.record_lookup(probes);
+                            return Some(value);
                         }
                     }
                     Entry::Empty => {
+                        self.record_lookup(probes);
                         return None;
                     }
                     Entry::Tombstone { .. } => {
@@ -227,7 +421,8 @@ impl<T: Copy> Table<T> {
                     }
                 }
             }
-            index = (index + 1) % self.cap;
+            probes += 1;
+            index = next_index(index, probes, self.cap);
         }
     }
 
@@ -235,22 +430,51 @@ impl<T: Copy> Table<T> {
     /// the table.
     ///
     /// Please note that keys are compared using **pointer equality**.
+    #[allow(dead_code)]
     pub fn delete(&mut self, key: &ObjString) -> bool {
         if self.len == 0 {
             return false;
         }
         unsafe {
             // Find the entry
-            let entry = get_entry(self.ptr, self.cap, key);
+            let entry = self.lookup(key);
             return if let Entry::Data { .. } = &*entry {
                 // Delete the entry
                 *entry = Entry::Tombstone { key };
+                if let Some(order) = &mut self.order {
+                    order.retain(|k| !std::ptr::eq(*k, key));
+                }
                 true
             } else {
                 false
             };
         }
     }
+
+    /// Iterates over all the keys and values present in the table, in the
+    /// order they were first set if the table was created with
+    /// `with_insertion_order`, or else in bucket order.
+    pub fn iter(&self) -> impl Iterator<Item = (&ObjString, &T)> + '_ {
+        let ordered = self.order.as_ref().map(|order| {
+            order.iter().map(move |key| unsafe {
+                match &*get_entry(self.ptr, self.cap, *key).0 {
+                    Entry::Data { key, value } => (&**key, value),
+                    _ => unreachable!("ordered key {:?} is not in the table", key),
+                }
+            })
+        });
+        let buckets = match self.order {
+            Some(_) => 0,
+            None => self.cap,
+        };
+        let unordered = (0..buckets).filter_map(move |i| unsafe {
+            match &*self.ptr.add(i) {
+                Entry::Data { key, value } => Some((&**key, value)),
+                _ => None,
+            }
+        });
+        return ordered.into_iter().flatten().chain(unordered);
+    }
 }
 
 impl<T: Copy> Drop for Table<T> {
@@ -280,6 +504,23 @@ mod tests {
         assert!(!table.set(&baz, ()));
     }
 
+    #[test]
+    fn test_index_access() {
+        let mut table = Table::new();
+        let foo = ObjString::new("foo".to_string());
+        let bar = ObjString::new("bar".to_string());
+        assert_eq!(table.index_of(&foo), None);
+        table.set(&foo, 1);
+        let index = table.index_of(&foo).unwrap();
+        assert_eq!(table.get_at(index, &foo), Some(&1));
+        assert_eq!(table.get_at(index, &bar), None);
+        assert!(table.set_at(index, &foo, 2));
+        assert!(!table.set_at(index, &bar, 3));
+        assert_eq!(table.get(&foo), Some(&2));
+        table.delete(&foo);
+        assert_eq!(table.get_at(index, &foo), None);
+    }
+
     #[test]
     fn test_setting_256_values() {
         let keys = (0..255)
@@ -337,11 +578,11 @@ mod tests {
         let mut table = Table::new();
         for (i, key) in &keys {
             println!("Setting key {:?} at address {:p}", key, key);
-            assert!(table.set(&key, *i));
-            assert!(!table.set(&key, *i));
+            assert!(table.set(key, *i));
+            assert!(!table.set(key, *i));
 
             println!("Getting key {:?} at address {:p}", key, key);
-            assert_eq!(table.get(&key), Some(i));
+            assert_eq!(table.get(key), Some(i));
         }
     }
 
@@ -478,6 +719,155 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_iter() {
+        let mut table = Table::new();
+        let foo = ObjString::new("foo".to_string());
+        let bar = ObjString::new("bar".to_string());
+        let baz = ObjString::new("baz".to_string());
+        assert!(table.set(&foo, 1));
+        assert!(table.set(&bar, 2));
+        assert!(table.set(&baz, 3));
+        assert!(table.delete(&bar));
+        let mut entries = table
+            .iter()
+            .map(|(k, v)| (k.get_value().clone(), *v))
+            .collect::<Vec<_>>();
+        entries.sort();
+        assert_eq!(
+            entries,
+            vec![("baz".to_string(), 3), ("foo".to_string(), 1)]
+        );
+    }
+
+    #[test]
+    fn test_growth_during_probing() {
+        // A few keys in the smallest table, some deleted, so that growing
+        // happens while probe sequences wrap around and pass tombstones
+        let mut table: Table<usize> = Table::new();
+        let keys = (0..64)
+            .map(|i| ObjString::new(format!("key_{}", i)))
+            .collect::<Vec<_>>();
+        for (i, key) in keys.iter().enumerate().take(6) {
+            assert!(table.set(key, i));
+        }
+        let old_buckets = keys
+            .iter()
+            .take(6)
+            .map(|key| table.index_of(key).unwrap())
+            .collect::<Vec<_>>();
+        for key in keys.iter().take(6).step_by(2) {
+            assert!(table.delete(key));
+        }
+        for (i, key) in keys.iter().enumerate() {
+            table.set(key, i);
+        }
+        for (i, key) in keys.iter().enumerate() {
+            assert_eq!(table.get(key), Some(&i));
+        }
+        assert_eq!(table.iter().count(), keys.len());
+        // Buckets from before growing either still hold their key or miss
+        for (i, (key, bucket)) in keys.iter().zip(old_buckets).enumerate() {
+            if let Some(value) = table.get_at(bucket, key) {
+                assert_eq!(*value, i);
+            }
+        }
+    }
+
+    #[test]
+    fn test_stats() {
+        let mut table: Table<usize> = Table::new();
+        assert_eq!(table.stats(), TableStats::default());
+        let keys = (0..20)
+            .map(|i| ObjString::new(format!("key_{}", i)))
+            .collect::<Vec<_>>();
+        for (i, key) in keys.iter().enumerate() {
+            table.set(key, i);
+        }
+        let stats = table.stats();
+        assert_eq!(stats.len, 20);
+        assert_eq!(stats.capacity, 32);
+        assert_eq!(stats.resizes, 3);
+    }
+
+    #[test]
+    #[cfg(feature = "table-stats")]
+    fn test_probe_stats() {
+        let mut table: Table<usize> = Table::new();
+        let keys = (0..20)
+            .map(|i| ObjString::new(format!("key_{}", i)))
+            .collect::<Vec<_>>();
+        for (i, key) in keys.iter().enumerate() {
+            table.set(key, i);
+        }
+        let before = table.stats();
+        assert_eq!(before.lookups, 20);
+        for key in &keys {
+            table.get(key);
+        }
+        let after = table.stats();
+        assert_eq!(after.lookups, 40);
+        assert!(after.collisions > before.collisions);
+        assert!(after.probes >= after.collisions);
+        assert!(after.max_probe > 0);
+    }
+
+    #[test]
+    fn test_probing_visits_every_bucket() {
+        for cap in [8, 16, 64, 1024] {
+            let mut seen = vec![false; cap];
+            let mut index = 5;
+            seen[index] = true;
+            for probe in 1..cap {
+                index = next_index(index, probe, cap);
+                seen[index] = true;
+            }
+            assert!(seen.iter().all(|seen| *seen), "cap {}", cap);
+        }
+    }
+
+    #[test]
+    fn test_drop_without_growing() {
+        // Nothing was allocated, so dropping must not free anything
+        let table: Table<()> = Table::new();
+        drop(table);
+    }
+
+    #[test]
+    fn test_empty_iter() {
+        let table: Table<()> = Table::new();
+        assert_eq!(table.iter().count(), 0);
+    }
+
+    #[test]
+    fn test_insertion_order() {
+        let keys = (0..40)
+            .rev()
+            .map(|i| ObjString::new(format!("key_{}", i)))
+            .collect::<Vec<_>>();
+        let mut table: Table<usize> = Table::with_insertion_order();
+        assert!(table.preserves_order());
+        assert!(!Table::<usize>::new().preserves_order());
+        for (i, key) in keys.iter().enumerate() {
+            table.set(key, i);
+        }
+        // Growing rehashes every key, but doesn't change the order
+        assert!(table.stats().resizes > 1);
+        table.set(&keys[0], 100);
+        table.delete(&keys[1]);
+        table.set(&keys[1], 101);
+        let order = table
+            .iter()
+            .map(|(key, value)| (key.get_value().clone(), *value))
+            .collect::<Vec<_>>();
+        let mut expected = (2..40)
+            .map(|i| (format!("key_{}", 39 - i), i))
+            .collect::<Vec<_>>();
+        expected.insert(0, (String::from("key_39"), 100));
+        expected.push((String::from("key_38"), 101));
+        assert_eq!(order, expected);
+    }
+
     #[test]
     fn test_set_find() {
         let mut table = Table::new();
@@ -489,7 +879,7 @@ mod tests {
 
     #[test]
     fn test_empty_find() {
-        let mut table: Table<()> = Table::new();
+        let table: Table<()> = Table::new();
         let foo = ObjString::new("foo".to_string());
         assert_eq!(table.find(&foo), None);
     }
@@ -0,0 +1,138 @@
+use rs_lox::{
+    compile, search_path_from_env, stdlib, InterpreterError, Sandbox, VMConfig, VmHook, GC, VM,
+};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+/// Test scripts are the files under a directory whose names end with this.
+const TEST_SUFFIX: &str = "_test.lox";
+
+#[derive(Debug, Default, PartialEq)]
+pub struct TestReport {
+    pub passed: usize,
+    pub failed: usize,
+}
+
+/// Finds test scripts under `dir`, recursively, in a stable order.
+pub fn find_tests(dir: &Path, tests: &mut Vec<PathBuf>) -> io::Result<()> {
+    let mut entries = fs::read_dir(dir)?
+        .map(|entry| entry.map(|e| e.path()))
+        .collect::<io::Result<Vec<_>>>()?;
+    entries.sort();
+    for path in entries {
+        if path.is_dir() {
+            find_tests(&path, tests)?;
+        } else if path
+            .file_name()
+            .and_then(|name| name.to_str())
+            .is_some_and(|name| name.ends_with(TEST_SUFFIX))
+        {
+            tests.push(path);
+        }
+    }
+    return Ok(());
+}
+
+/// Runs a test script with the standard library and its output discarded.
+/// A test passes if it runs to the end without an error. `hook`, if any,
+/// sees every instruction the script runs.
+pub fn run_test(
+    source: &String,
+    path: &Path,
+    hook: Option<&mut (dyn VmHook + Send + '_)>,
+) -> Result<(), InterpreterError> {
+    let mut output = io::sink();
+    let mut input = io::empty();
+    let mut gc = GC::new();
+    let mut vm = VM::new(
+        VMConfig {
+            trace_instructions: false,
+            trace_stack: false,
+            trace_globals: false,
+            stdout: &mut output,
+            stdin: &mut input,
+            sandbox: Sandbox::default(),
+        },
+        &mut gc,
+    );
+    stdlib::install(&mut vm);
+    vm.set_search_path(search_path_from_env());
+    vm.set_script_path(path);
+    if let Some(hook) = hook {
+        vm.set_hook(hook);
+    }
+    let (chunk, _) = vm.with_gc(|gc| compile(source, gc))?;
+    vm.interpret_chunk(chunk)?;
+    return Ok(());
+}
+
+/// Runs every `*_test.lox` script under `dir`, writing a line per script and
+/// a summary to `out`.
+pub fn run_tests(
+    dir: &Path,
+    out: &mut dyn io::Write,
+    mut hook: Option<&mut (dyn VmHook + Send)>,
+) -> io::Result<TestReport> {
+    let mut tests = Vec::new();
+    find_tests(dir, &mut tests)?;
+    let mut report = TestReport::default();
+    for path in tests {
+        let result = match fs::read_to_string(&path) {
+            Ok(source) => {
+                run_test(&source, &path, hook.as_deref_mut()).map_err(|error| error.to_string())
+            }
+            Err(error) => Err(format!("Could not read file: {}", error)),
+        };
+        match result {
+            Ok(()) => {
+                report.passed += 1;
+                writeln!(out, "PASS {}", path.display())?;
+            }
+            Err(error) => {
+                report.failed += 1;
+                writeln!(out, "FAIL {}\n     {}", path.display(), error)?;
+            }
+        }
+    }
+    writeln!(out, "\n{} passed, {} failed", report.passed, report.failed)?;
+    return Ok(report);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    #[cfg_attr(miri, ignore = "uses the file system")]
+    fn runs_test_scripts() {
+        let dir = std::env::temp_dir().join(format!("rs-lox-tester-{}", std::process::id()));
+        fs::create_dir_all(dir.join("nested")).unwrap();
+        fs::write(dir.join("math_test.lox"), "assert(1 + 1 == 2, \"add\");").unwrap();
+        fs::write(
+            dir.join("nested/strings_test.lox"),
+            "assert(\"a\" + \"b\" == \"ba\", \"concat\");",
+        )
+        .unwrap();
+        fs::write(dir.join("helper.lox"), "assert(false, \"not a test\");").unwrap();
+
+        let mut output = Vec::new();
+        let report = run_tests(&dir, &mut output, None).unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(
+            report,
+            TestReport {
+                passed: 1,
+                failed: 1
+            }
+        );
+        let output = String::from_utf8(output).unwrap();
+        let expected = format!(
+            "PASS {}\nFAIL {}\n     Runtime error: [line 1] Assertion failed: concat\n\n1 passed, 1 failed\n",
+            dir.join("math_test.lox").display(),
+            dir.join("nested/strings_test.lox").display()
+        );
+        assert_eq!(output, expected);
+    }
+}
@@ -0,0 +1,295 @@
+use crate::native::{IntoNative, NativeFn, ObjNative};
+use crate::value::Value;
+use crate::vm::VM;
+use std::any::Any;
+use std::cell::{Cell, RefCell};
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::sync::Arc;
+
+/// Describes a kind of host object: its name, shown when it is printed,
+/// and the methods scripts can call on it with `value.method(args)`.
+///
+/// Methods are natives whose first argument is the receiver. The arity given
+/// when registering a method does not include the receiver.
+pub struct UserDataType {
+    name: String,
+    methods: HashMap<String, ObjNative>,
+    /// Methods that change the object, which frozen objects refuse.
+    mutating: HashSet<String>,
+    /// Copies the host data, for `clone()`.
+    cloner: Option<Cloner>,
+    /// Compares the host data of two objects, for `deep_equals()`.
+    comparer: Option<Comparer>,
+}
+
+type Cloner = Box<dyn Fn(&dyn Any) -> Option<Box<dyn Any + Send>> + Send + Sync>;
+type Comparer = Box<dyn Fn(&dyn Any, &dyn Any) -> Option<bool> + Send + Sync>;
+
+impl UserDataType {
+    pub fn new(name: &str) -> UserDataType {
+        UserDataType {
+            name: String::from(name),
+            methods: HashMap::new(),
+            mutating: HashSet::new(),
+            cloner: None,
+            comparer: None,
+        }
+    }
+
+    pub fn get_name(&self) -> &String {
+        &self.name
+    }
+
+    pub fn get_method(&self, name: &str) -> Option<&ObjNative> {
+        self.methods.get(name)
+    }
+
+    /// Whether the method `name` changes the object.
+    pub fn is_mutating(&self, name: &str) -> bool {
+        self.mutating.contains(name)
+    }
+
+    /// Marks the methods `names` as changing the object, so that calling
+    /// them on a frozen object is a runtime error.
+    pub fn mutating(mut self, names: &[&str]) -> UserDataType {
+        self.mutating
+            .extend(names.iter().map(|name| String::from(*name)));
+        return self;
+    }
+
+    /// Lets `clone()` copy objects of this type, whose host data must be a
+    /// `T`.
+    pub fn cloneable<T: Any + Clone + Send>(mut self) -> UserDataType {
+        self.cloner = Some(Box::new(|data| {
+            let data = data.downcast_ref::<T>()?;
+            Some(Box::new(data.clone()))
+        }));
+        return self;
+    }
+
+    /// Lets `deep_equals()` compare objects of this type by their host data,
+    /// which must be a `T`, rather than by identity.
+    pub fn comparable<T: Any + PartialEq>(mut self) -> UserDataType {
+        self.comparer = Some(Box::new(|a, b| {
+            Some(a.downcast_ref::<T>()? == b.downcast_ref::<T>()?)
+        }));
+        return self;
+    }
+
+    /// Adds a method taking `arity` arguments besides the receiver.
+    pub fn method(
+        self,
+        name: &str,
+        arity: u8,
+        function: impl Fn(&mut VM, &[Value]) -> Result<Value, String> + Send + Sync + 'static,
+    ) -> UserDataType {
+        return self.add_method(name, arity + 1, Box::new(function));
+    }
+
+    /// Adds a method from a typed Rust closure. The closure's first parameter
+    /// receives the receiver, usually as a `Value` to pass to `with_userdata`.
+    pub fn method_fn<Args, F: IntoNative<Args>>(self, name: &str, function: F) -> UserDataType {
+        assert!(
+            F::ARITY > 0,
+            "Methods must take the receiver as an argument"
+        );
+        return self.add_method(name, F::ARITY, function.into_native());
+    }
+
+    fn add_method(mut self, name: &str, arity: u8, function: NativeFn) -> UserDataType {
+        let native = ObjNative::new(format!("{}.{}", self.name, name), arity, function);
+        self.methods.insert(String::from(name), native);
+        return self;
+    }
+}
+
+impl fmt::Debug for UserDataType {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut methods = self.methods.keys().collect::<Vec<_>>();
+        methods.sort();
+        f.debug_struct("UserDataType")
+            .field("name", &self.name)
+            .field("methods", &methods)
+            .finish()
+    }
+}
+
+pub struct ObjUserData {
+    data: RefCell<Box<dyn Any + Send>>,
+    user_type: Arc<UserDataType>,
+    frozen: Cell<bool>,
+}
+
+impl ObjUserData {
+    pub fn new(user_type: Arc<UserDataType>, data: Box<dyn Any + Send>) -> ObjUserData {
+        ObjUserData {
+            data: RefCell::new(data),
+            user_type,
+            frozen: Cell::new(false),
+        }
+    }
+
+    pub fn get_type(&self) -> &Arc<UserDataType> {
+        &self.user_type
+    }
+
+    /// Stops scripts from calling the type's mutating methods on this object
+    /// from now on. There is no way to unfreeze it.
+    pub fn freeze(&self) {
+        self.frozen.set(true);
+    }
+
+    pub fn is_frozen(&self) -> bool {
+        self.frozen.get()
+    }
+
+    /// A copy of the host data, if the type is cloneable.
+    pub(crate) fn clone_data(&self) -> Result<Box<dyn Any + Send>, String> {
+        let cloner = self
+            .user_type
+            .cloner
+            .as_ref()
+            .ok_or_else(|| format!("Can't clone {}", self.user_type.name))?;
+        let data = self
+            .data
+            .try_borrow()
+            .map_err(|_| format!("{} is already in use", self))?;
+        return cloner(data.as_ref()).ok_or_else(|| format!("Unexpected userdata type {}", self));
+    }
+
+    /// Whether the two objects hold equal host data. Objects of different
+    /// types, or of a type that isn't comparable, are only equal to
+    /// themselves.
+    pub(crate) fn data_equals(&self, other: &ObjUserData) -> Result<bool, String> {
+        if std::ptr::eq(self, other) {
+            return Ok(true);
+        }
+        let comparer = match &self.user_type.comparer {
+            Some(comparer) if Arc::ptr_eq(&self.user_type, &other.user_type) => comparer,
+            _ => return Ok(false),
+        };
+        let a = self
+            .data
+            .try_borrow()
+            .map_err(|_| format!("{} is already in use", self))?;
+        let b = other
+            .data
+            .try_borrow()
+            .map_err(|_| format!("{} is already in use", other))?;
+        return comparer(a.as_ref(), b.as_ref())
+            .ok_or_else(|| format!("Unexpected userdata type {}", self));
+    }
+}
+
+impl fmt::Debug for ObjUserData {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "ObjUserData({})", self.user_type.name)
+    }
+}
+
+impl fmt::Display for ObjUserData {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "<{}>", self.user_type.name)
+    }
+}
+
+// Userdata is only equal to itself
+impl PartialEq for ObjUserData {
+    fn eq(&self, other: &Self) -> bool {
+        std::ptr::eq(self, other)
+    }
+}
+
+/// Runs `f` on the host data inside a userdata value. Fails if `value` is not
+/// userdata holding a `T`, or if the data is already borrowed by a method
+/// further up the call stack.
+pub fn with_userdata<T: Any, R>(value: Value, f: impl FnOnce(&mut T) -> R) -> Result<R, String> {
+    let userdata = value
+        .as_userdata()
+        .ok_or_else(|| format!("Expected userdata, got {}", value))?;
+    let mut data = userdata
+        .data
+        .try_borrow_mut()
+        .map_err(|_| format!("{} is already in use", value))?;
+    return match data.downcast_mut::<T>() {
+        Some(data) => Ok(f(data)),
+        None => Err(format!("Unexpected userdata type {}", value)),
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lox::{Lox, LoxError, LoxValue};
+    use std::convert::TryFrom;
+
+    struct Counter {
+        count: f64,
+    }
+
+    fn counter_type() -> Arc<UserDataType> {
+        let user_type = UserDataType::new("Counter")
+            .method_fn("get", |this: Value| {
+                with_userdata(this, |counter: &mut Counter| counter.count)
+            })
+            .method("add", 1, |_, args| {
+                let amount = f64::try_from(args[1])?;
+                with_userdata(args[0], |counter: &mut Counter| counter.count += amount)?;
+                Ok(Value::Nil)
+            });
+        return Arc::new(user_type);
+    }
+
+    #[test]
+    fn calls_methods() {
+        let mut lox = Lox::new();
+        lox.set_userdata("counter", &counter_type(), Counter { count: 1.0 });
+        lox.eval("counter.add(2); counter.add(counter.get());")
+            .unwrap();
+        assert_eq!(lox.eval("counter.get();"), Ok(LoxValue::Number(6.0)));
+        assert_eq!(
+            lox.eval("counter;"),
+            Ok(LoxValue::Opaque(String::from("<Counter>")))
+        );
+    }
+
+    #[test]
+    fn reports_method_errors() {
+        let mut lox = Lox::new();
+        lox.set_userdata("counter", &counter_type(), Counter { count: 0.0 });
+        let runtime_error = |message: &str| Err(LoxError::RuntimeError(String::from(message)));
+        assert_eq!(
+            lox.eval("counter.reset();"),
+            runtime_error("[line 1] Undefined method 'reset' on Counter")
+        );
+        assert_eq!(
+            lox.eval("counter.add();"),
+            runtime_error("[line 1] Counter.add expects 1 arguments but got 0")
+        );
+        assert_eq!(
+            lox.eval("counter.add(\"one\");"),
+            runtime_error("[line 1] Expected a number, got \"one\"")
+        );
+        assert_eq!(
+            lox.eval("nil.get();"),
+            runtime_error("[line 1] Only userdata, strings and numbers have methods, got nil")
+        );
+        assert!(matches!(
+            lox.eval("counter.count;"),
+            Err(LoxError::CompileError(_))
+        ));
+    }
+
+    #[test]
+    fn checks_userdata_type() {
+        let mut lox = Lox::new();
+        lox.set_userdata("counter", &counter_type(), String::from("not a counter"));
+        assert_eq!(
+            lox.eval("counter.get();"),
+            Err(LoxError::RuntimeError(String::from(
+                "[line 1] Unexpected userdata type <Counter>"
+            )))
+        );
+    }
+}
@@ -1,11 +1,18 @@
-use crate::gc::ObjRef;
+use crate::gc::{ObjRef, ObjString, GC};
+use crate::native::ObjNative;
+use crate::userdata::ObjUserData;
+use std::convert::TryFrom;
 use std::fmt::{Display, Error, Formatter};
 use Value::*;
 
 #[derive(Copy, Clone, Debug, PartialEq, PartialOrd)]
+#[non_exhaustive]
 pub enum Value {
     Nil,
     Number(f32),
+    /// Integer literals and arithmetic on them. Mixing with a `Number` makes
+    /// a `Number`.
+    Int(i64),
     Boolean(bool),
     Object(ObjRef),
 }
@@ -14,31 +21,317 @@ impl Display for Value {
     fn fmt(&self, f: &mut Formatter) -> Result<(), Error> {
         match self {
             Value::Nil => write!(f, "nil"),
-            Value::Number(n) => n.fmt(f),
+            Value::Number(n) => write!(f, "{}", format_number(*n)),
+            Value::Int(n) => n.fmt(f),
             Value::Boolean(b) => b.fmt(f),
             Value::Object(o) => o.fmt(f),
         }
     }
 }
 
+impl Value {
+    pub fn as_string(&self) -> Option<&ObjString> {
+        match self {
+            Object(obj) => obj.as_string(),
+            _ => None,
+        }
+    }
+
+    pub fn as_native(&self) -> Option<&ObjNative> {
+        match self {
+            Object(obj) => obj.as_native(),
+            _ => None,
+        }
+    }
+
+    pub fn as_userdata(&self) -> Option<&ObjUserData> {
+        match self {
+            Object(obj) => obj.as_userdata(),
+            _ => None,
+        }
+    }
+
+    /// The value of a `Number` or an `Int`, as a float.
+    pub fn as_f64(&self) -> Option<f64> {
+        match self {
+            Number(n) => Some(*n as f64),
+            Int(n) => Some(*n as f64),
+            _ => None,
+        }
+    }
+}
+
+impl From<f64> for Value {
+    fn from(n: f64) -> Self {
+        Number(n as f32)
+    }
+}
+
+impl From<f32> for Value {
+    fn from(n: f32) -> Self {
+        Number(n)
+    }
+}
+
+impl From<i64> for Value {
+    fn from(n: i64) -> Self {
+        Int(n)
+    }
+}
+
+impl From<bool> for Value {
+    fn from(b: bool) -> Self {
+        Boolean(b)
+    }
+}
+
+impl From<()> for Value {
+    fn from(_: ()) -> Self {
+        Nil
+    }
+}
+
+fn type_error(expected: &str, value: Value) -> String {
+    return format!("Expected {}, got {}", expected, value);
+}
+
+impl TryFrom<Value> for f64 {
+    type Error = String;
+
+    fn try_from(value: Value) -> Result<Self, Self::Error> {
+        value.as_f64().ok_or_else(|| type_error("a number", value))
+    }
+}
+
+impl TryFrom<Value> for f32 {
+    type Error = String;
+
+    fn try_from(value: Value) -> Result<Self, Self::Error> {
+        match value {
+            Number(n) => Ok(n),
+            Int(n) => Ok(n as f32),
+            _ => Err(type_error("a number", value)),
+        }
+    }
+}
+
+/// Accepts an `Int`, or a `Number` with no fractional part that fits.
+impl TryFrom<Value> for i64 {
+    type Error = String;
+
+    fn try_from(value: Value) -> Result<Self, Self::Error> {
+        match value {
+            Int(n) => Ok(n),
+            // i64::MAX isn't a float, so the upper bound is exclusive
+            Number(n) if n.fract() == 0.0 && n >= i64::MIN as f32 && n < i64::MAX as f32 => {
+                Ok(n as i64)
+            }
+            _ => Err(type_error("an integer", value)),
+        }
+    }
+}
+
+impl TryFrom<Value> for bool {
+    type Error = String;
+
+    fn try_from(value: Value) -> Result<Self, Self::Error> {
+        match value {
+            Boolean(b) => Ok(b),
+            _ => Err(type_error("a boolean", value)),
+        }
+    }
+}
+
+impl TryFrom<Value> for String {
+    type Error = String;
+
+    fn try_from(value: Value) -> Result<Self, Self::Error> {
+        return match value.as_string() {
+            Some(s) => Ok(s.get_value().clone()),
+            None => Err(type_error("a string", value)),
+        };
+    }
+}
+
+/// Conversion from a Rust value into a `Value`. Unlike `From`, this has
+/// access to the GC, so it can allocate strings.
+pub trait ToLox {
+    fn to_lox(self, gc: &mut GC) -> Value;
+}
+
+/// Conversion from a `Value` into a Rust value. Errors are messages suitable
+/// for a runtime error.
+pub trait FromLox: Sized {
+    fn from_lox(value: Value) -> Result<Self, String>;
+}
+
+impl ToLox for Value {
+    fn to_lox(self, _: &mut GC) -> Value {
+        self
+    }
+}
+
+impl FromLox for Value {
+    fn from_lox(value: Value) -> Result<Self, String> {
+        Ok(value)
+    }
+}
+
+impl ToLox for &str {
+    #[track_caller]
+    fn to_lox(self, gc: &mut GC) -> Value {
+        Object(gc.alloc_string(String::from(self)))
+    }
+}
+
+impl ToLox for String {
+    #[track_caller]
+    fn to_lox(self, gc: &mut GC) -> Value {
+        Object(gc.alloc_string(self))
+    }
+}
+
+/// `None` converts to and from `nil`.
+impl<T: ToLox> ToLox for Option<T> {
+    fn to_lox(self, gc: &mut GC) -> Value {
+        match self {
+            Some(value) => value.to_lox(gc),
+            None => Nil,
+        }
+    }
+}
+
+impl<T: FromLox> FromLox for Option<T> {
+    fn from_lox(value: Value) -> Result<Self, String> {
+        match value {
+            Nil => Ok(None),
+            _ => T::from_lox(value).map(Some),
+        }
+    }
+}
+
+macro_rules! impl_to_lox {
+    ($($t:ty),*) => {
+        $(
+            impl ToLox for $t {
+                fn to_lox(self, _: &mut GC) -> Value {
+                    Value::from(self)
+                }
+            }
+        )*
+    };
+}
+
+impl_to_lox!(f64, f32, i64, bool, ());
+
+macro_rules! impl_from_lox {
+    ($($t:ty),*) => {
+        $(
+            impl FromLox for $t {
+                fn from_lox(value: Value) -> Result<Self, String> {
+                    <$t>::try_from(value)
+                }
+            }
+        )*
+    };
+}
+
+impl_from_lox!(f64, f32, i64, bool, String);
+
 pub type ValueArray = Vec<Value>;
 
 pub fn is_falsey(value: Value) -> bool {
-    return match value {
-        Nil | Value::Boolean(false) => true,
-        _ => false,
-    };
+    return matches!(value, Nil | Value::Boolean(false));
 }
 
 pub fn are_equal(a: Value, b: Value) -> bool {
     return match (a, b) {
         (Nil, Nil) => true,
         (Number(a), Number(b)) => a == b,
+        (Int(a), Int(b)) => a == b,
+        (Int(a), Number(b)) | (Number(b), Int(a)) => a as f64 == b as f64,
         (Boolean(a), Boolean(b)) => a == b,
+        // Strings are interned, so equal strings share the same object
+        (Object(a), Object(b)) => a == b,
         _ => false,
     };
 }
 
+/// The names of the special values, as clox prints them: `nan` whatever its
+/// sign, `inf` and `-inf`. `None` for a finite `n`.
+fn special_name(n: f64) -> Option<&'static str> {
+    if n.is_nan() {
+        return Some("nan");
+    }
+    if n.is_infinite() {
+        return Some(if n > 0.0 { "inf" } else { "-inf" });
+    }
+    return None;
+}
+
+/// How numbers are shown by `print` and string conversions: whole numbers
+/// without a decimal point, never in scientific notation, and `nan`, `inf`
+/// and `-inf` for the special values. Negative zero keeps its sign, `-0`,
+/// as it does with `%g` in clox, though it equals `0`.
+pub fn format_number(n: f32) -> String {
+    return match special_name(n as f64) {
+        Some(name) => String::from(name),
+        None => n.to_string(),
+    };
+}
+
+/// Like `format_number`, for the numbers natives take as `f64`, such as the
+/// arguments they mention in error messages.
+pub fn format_float(n: f64) -> String {
+    return match special_name(n) {
+        Some(name) => String::from(name),
+        None => n.to_string(),
+    };
+}
+
+/// How `print` shows `value`: floats with `precision` significant digits if
+/// it's set, otherwise like `Display`, in the shortest form that reads back
+/// as the same float.
+pub fn display_value(value: Value, precision: Option<usize>) -> String {
+    return match (value, precision) {
+        (Number(n), Some(digits)) => format_precision(n, digits),
+        _ => value.to_string(),
+    };
+}
+
+/// Formats `n` with exactly `digits` digits after the decimal point.
+pub fn format_fixed(n: f32, digits: usize) -> String {
+    if !n.is_finite() {
+        return format_number(n);
+    }
+    return format!("{:.*}", digits, n);
+}
+
+/// Formats `n` rounded to `digits` significant digits, padding with zeros
+/// rather than switching to scientific notation.
+pub fn format_precision(n: f32, digits: usize) -> String {
+    if !n.is_finite() || n == 0.0 {
+        return format_fixed(n, digits.saturating_sub(1));
+    }
+    let magnitude = (n.abs() as f64).log10().floor() as i32;
+    let decimals = digits as i32 - 1 - magnitude;
+    if decimals >= 0 {
+        return format_fixed(n, decimals as usize);
+    }
+    let scale = 10f64.powi(-decimals);
+    return format_number(((n as f64 / scale).round() * scale) as f32);
+}
+
+/// Formats a whole number in lowercase hexadecimal, with a leading `-` for
+/// negative numbers.
+pub fn format_hex(n: f32) -> Result<String, String> {
+    if !n.is_finite() || n.fract() != 0.0 {
+        return Err(format!("Expected a whole number, got {}", format_number(n)));
+    }
+    let sign = if n < 0.0 { "-" } else { "" };
+    return Ok(format!("{}{:x}", sign, n.abs() as u64));
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -51,6 +344,17 @@ mod tests {
         assert_eq!(is_falsey(Value::Boolean(true)), false);
     }
 
+    #[test]
+    fn formats_numbers_to_round_trip() {
+        for n in [0.1f32, 0.3, 1.0 / 3.0, 16777216.0, 1e-7, -2.5e9, f32::MAX] {
+            assert_eq!(format_number(n).parse::<f32>(), Ok(n));
+        }
+        assert_eq!(format_number(0.1 + 0.2), "0.3");
+        assert_eq!(display_value(Number(1.0 / 3.0), Some(3)), "0.333");
+        assert_eq!(display_value(Int(12345), Some(3)), "12345");
+        assert_eq!(display_value(Number(2.5), None), "2.5");
+    }
+
     #[test]
     fn test_are_equal() {
         assert_eq!(are_equal(Nil, Nil), true);
@@ -68,5 +372,77 @@ mod tests {
         assert_eq!(are_equal(Value::Boolean(true), Nil), false);
         assert_eq!(are_equal(Value::Boolean(false), Nil), false);
         assert_eq!(are_equal(Value::Number(0.0), Nil), false);
+        assert_eq!(are_equal(Value::Int(2), Value::Number(2.0)), true);
+        assert_eq!(are_equal(Value::Number(2.5), Value::Int(2)), false);
+    }
+
+    #[test]
+    fn test_are_equal_strings() {
+        let mut gc = crate::gc::GC::new();
+        let a = Value::Object(gc.alloc_string(String::from("a")));
+        let also_a = Value::Object(gc.alloc_string(String::from("a")));
+        let b = Value::Object(gc.alloc_string(String::from("b")));
+        assert_eq!(are_equal(a, also_a), true);
+        assert_eq!(are_equal(a, b), false);
+        assert_eq!(are_equal(a, Nil), false);
+    }
+
+    #[test]
+    fn test_conversions() {
+        assert_eq!(Value::from(1.5f64), Number(1.5));
+        assert_eq!(Value::from(true), Boolean(true));
+        assert_eq!(Value::from(()), Nil);
+        assert_eq!(f64::try_from(Number(2.0)), Ok(2.0));
+        assert_eq!(bool::try_from(Boolean(false)), Ok(false));
+        assert_eq!(
+            f64::try_from(Boolean(true)),
+            Err(String::from("Expected a number, got true"))
+        );
+        assert!(String::try_from(Number(1.0)).is_err());
+    }
+
+    #[test]
+    fn test_lox_traits() {
+        let mut gc = crate::gc::GC::new();
+        let s = "hello".to_lox(&mut gc);
+        assert_eq!(String::from_lox(s), Ok(String::from("hello")));
+        assert_eq!(3.0f64.to_lox(&mut gc), Number(3.0));
+        assert_eq!(None::<f64>.to_lox(&mut gc), Nil);
+        assert_eq!(Option::<bool>::from_lox(Nil), Ok(None));
+        assert_eq!(Option::<bool>::from_lox(Boolean(true)), Ok(Some(true)));
+        assert!(Option::<bool>::from_lox(Number(1.0)).is_err());
+    }
+
+    #[test]
+    fn test_format_numbers() {
+        assert_eq!(format_number(3.0), "3");
+        assert_eq!(format_number(-0.5), "-0.5");
+        assert_eq!(format_number(1e20), "100000000000000000000");
+        assert_eq!(format_number(f32::NAN), "nan");
+        assert_eq!(format_number(f32::NEG_INFINITY), "-inf");
+        assert_eq!(format_number(f32::INFINITY), "inf");
+        assert_eq!(format_number(-f32::NAN), "nan");
+        assert_eq!(format_number(-0.0), "-0");
+        assert_eq!(format_number(0.0), "0");
+        assert_eq!(format_float(f64::NAN), "nan");
+        assert_eq!(format_float(f64::NEG_INFINITY), "-inf");
+        assert_eq!(format_float(-0.0), "-0");
+        assert_eq!(format_float(0.25), "0.25");
+        assert_eq!(format_fixed(-0.0, 1), "-0.0");
+        assert_eq!(format_fixed(f32::NAN, 2), "nan");
+        assert_eq!(format_precision(-0.0, 2), "-0.0");
+        assert_eq!(format_precision(f32::NEG_INFINITY, 2), "-inf");
+        assert_eq!(format_fixed(2.0, 2), "2.00");
+        assert_eq!(format_fixed(1.005, 0), "1");
+        assert_eq!(format_precision(1.23456, 3), "1.23");
+        assert_eq!(format_precision(12345.0, 2), "12000");
+        assert_eq!(format_precision(0.000123456, 2), "0.00012");
+        assert_eq!(format_precision(0.0, 3), "0.00");
+        assert_eq!(format_hex(255.0), Ok(String::from("ff")));
+        assert_eq!(format_hex(-16.0), Ok(String::from("-10")));
+        assert_eq!(
+            format_hex(1.5),
+            Err(String::from("Expected a whole number, got 1.5"))
+        );
     }
 }
@@ -1,21 +1,33 @@
 use crate::chunk::*;
-use crate::compiler::ParserError;
+use crate::compiler::{format_diagnostics, Diagnostic};
 use crate::debug::*;
 use crate::gc::{Obj, GC};
+use crate::module;
+use crate::native::{IntoNative, ObjNative};
+use crate::sandbox::{Capability, Sandbox};
 use crate::table::Table;
-use crate::value::{are_equal, is_falsey, Value, Value::*};
+use crate::userdata::UserDataType;
+use crate::value::{are_equal, display_value, is_falsey, Value, Value::*};
 use crate::vm::OpCode::*;
 use crate::InterpreterError::*;
 use num_traits::FromPrimitive;
+use std::any::Any;
+use std::collections::{BTreeSet, HashMap};
 use std::fmt;
 use std::fmt::Formatter;
+use std::fs;
 use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
 
 pub struct VMConfig<'a> {
     pub trace_instructions: bool,
     pub trace_stack: bool,
     pub trace_globals: bool,
-    pub stdout: &'a mut dyn io::Write,
+    pub stdout: &'a mut (dyn io::Write + Send),
+    /// Where `input()` reads lines from.
+    pub stdin: &'a mut (dyn io::BufRead + Send),
+    pub sandbox: Sandbox,
 }
 
 impl std::fmt::Debug for VMConfig<'_> {
@@ -23,13 +35,15 @@ impl std::fmt::Debug for VMConfig<'_> {
         return f
             .debug_struct("VMConfig")
             .field("trace_execution", &self.trace_instructions)
+            .field("sandbox", &self.sandbox)
             .finish();
     }
 }
 
 #[derive(Debug, PartialEq, Eq)]
+#[non_exhaustive]
 pub enum InterpreterError {
-    CompileError(Vec<ParserError>),
+    CompileError(Vec<Diagnostic>),
     RuntimeError(String),
 }
 
@@ -37,25 +51,235 @@ impl fmt::Display for InterpreterError {
     fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), std::fmt::Error> {
         return match self {
             RuntimeError(s) => write!(f, "Runtime error: {}", s),
-            CompileError(_) => write!(f, "Compile error"),
+            CompileError(diagnostics) => write!(f, "{}", format_diagnostics(diagnostics)),
         };
     }
 }
 
 impl std::error::Error for InterpreterError {}
 
+// SAFETY: every object a VM can reach, through its chunk, stack, globals or
+// global slots, lives in the GC it borrows mutably, so the VM and the heap
+// move together.
+unsafe impl Send for VM<'_> {}
+
+/// Identifies a module file however it was imported.
+fn module_key(path: &Path) -> String {
+    return match fs::canonicalize(path) {
+        Ok(canonical) => canonical.to_string_lossy().into_owned(),
+        Err(_) => path.to_string_lossy().into_owned(),
+    };
+}
+
+/// Reported when a chunk names a global or method with a constant that isn't
+/// a string, which the compiler never emits.
+fn expected_name(value: Value) -> InterpreterError {
+    return RuntimeError(format!("Expected string as name, got {}", value));
+}
+
+/// The value of one of the chunk's global slots, see
+/// `Chunk::resolve_global_slots`.
+#[derive(Debug, Clone, Copy)]
+struct GlobalSlot {
+    /// `None` until the global is found or defined.
+    value: Option<Value>,
+    /// Whether the chunk defined it since `globals` was last synced.
+    dirty: bool,
+}
+
+/// Binds each of `chunk`'s global slots to the global of its name in
+/// `globals`, if there is one yet.
+fn bind_global_slots(
+    chunk: &Chunk,
+    globals: &Table<Value>,
+) -> Result<Vec<GlobalSlot>, InterpreterError> {
+    let mut slots = Vec::with_capacity(chunk.global_slots().len());
+    for index in chunk.global_slots() {
+        let name_val = chunk
+            .get_constant(*index)
+            .ok_or_else(|| RuntimeError(format!("Invalid constant reference: {}", index)))?;
+        let name = name_val
+            .as_string()
+            .ok_or_else(|| expected_name(name_val))?;
+        slots.push(GlobalSlot {
+            value: globals.get(name).copied(),
+            dirty: false,
+        });
+    }
+    return Ok(slots);
+}
+
+/// A module that has been run by `import`.
+struct Module {
+    /// The path the module was first imported with.
+    path: String,
+    exports: Table<Value>,
+}
+
+/// Observes a VM as it runs, for tools such as coverage that need to see
+/// every instruction. Installed with `VM::set_hook`.
+pub trait VmHook {
+    /// Called before the instruction at `offset` in `chunk` executes.
+    /// `script` is the file the chunk was compiled from, if it's known.
+    fn before_instruction(&mut self, script: Option<&Path>, chunk: &Chunk, offset: usize);
+
+    /// Called right after `before_instruction`, with the values on the stack
+    /// too, bottom first, for tools that show them.
+    fn before_instruction_with_stack(
+        &mut self,
+        _script: Option<&Path>,
+        _chunk: &Chunk,
+        _offset: usize,
+        _stack: &[Value],
+    ) {
+    }
+
+    /// How many instructions apart `sample` is called, or 0 to never call it.
+    fn sample_interval(&self) -> u64 {
+        return 0;
+    }
+
+    /// Called before every `sample_interval`th instruction, starting with
+    /// the first, with where execution is: the script first, then each
+    /// module imported on the way to the one running.
+    fn sample(&mut self, _frames: &[Frame]) {}
+
+    /// Called after each instruction for every object it allocated, newest
+    /// first, with the file and line of the instruction. An `import` counts
+    /// what compiling and binding the module allocated, and the module's own
+    /// instructions count the rest.
+    fn allocated(&mut self, _script: Option<&Path>, _line: Option<LineNumber>, _object: &Obj) {}
+}
+
+/// A script or module being run, as seen by `VmHook::sample`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Frame<'a> {
+    /// The file the code came from, if it's known.
+    pub script: Option<&'a Path>,
+    /// The line of the next instruction, or for an importer, of its `import`.
+    pub line: Option<LineNumber>,
+}
+
+/// The kinds of built-in values that can have methods, added with
+/// `VM::register_method_fn`. Userdata carries its own methods.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum BuiltinType {
+    Number,
+    String,
+}
+
+impl BuiltinType {
+    fn of(value: &Value) -> Option<BuiltinType> {
+        return match value {
+            Number(_) | Int(_) => Some(BuiltinType::Number),
+            Object(obj) => obj.as_string().map(|_| BuiltinType::String),
+            _ => None,
+        };
+    }
+}
+
+impl fmt::Display for BuiltinType {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        return match self {
+            BuiltinType::Number => write!(f, "number"),
+            BuiltinType::String => write!(f, "string"),
+        };
+    }
+}
+
+/// What integer arithmetic does when the result doesn't fit in an `i64`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum IntegerOverflow {
+    /// Fail with a runtime error.
+    #[default]
+    Error,
+    /// Wrap around, as two's complement arithmetic does.
+    Wrap,
+}
+
+/// The result of an arithmetic operator on two integers.
+fn int_result(
+    result: Option<i64>,
+    wrapped: i64,
+    overflow: IntegerOverflow,
+) -> Result<Value, InterpreterError> {
+    return match (result, overflow) {
+        (Some(n), _) => Ok(Int(n)),
+        (None, IntegerOverflow::Wrap) => Ok(Int(wrapped)),
+        (None, IntegerOverflow::Error) => Err(RuntimeError(String::from("Integer overflow"))),
+    };
+}
+
+/// Compares two numbers, exactly if both are integers. `None` if either
+/// isn't a number.
+fn compare_numbers(a: Value, b: Value) -> Option<Option<std::cmp::Ordering>> {
+    if let (Int(a), Int(b)) = (a, b) {
+        return Some(a.partial_cmp(&b));
+    }
+    return Some(a.as_f64()?.partial_cmp(&b.as_f64()?));
+}
+
+/// Methods of strings and numbers, by the type and name they're called with.
+pub(crate) type BuiltinMethods = HashMap<(BuiltinType, String), Arc<ObjNative>>;
+
 pub const STACK_MAX: usize = 256;
 
 pub struct VM<'a> {
-    chunk: &'a Chunk,
+    chunk: Chunk,
     ip: usize,
     config: VMConfig<'a>,
     stack: [Value; STACK_MAX],
     stack_top: usize,
+    /// Iterated in the order globals were defined, so what's copied into
+    /// and exported from modules doesn't depend on where names were interned.
     globals: Table<Value>,
+    /// Bucket of `globals` each name constant of the chunk was last found
+    /// in, so `Get` and `DefineGlobal` can skip hashing the name. Every site
+    /// has its own constant, so this is a cache per site.
+    global_cache: Vec<Option<usize>>,
+    /// Globals of a chunk that has them resolved to slots. Definitions go
+    /// here rather than to `globals`, which `sync_global_slots` catches up
+    /// when the chunk returns, fails, stops or imports a module.
+    global_slots: Vec<GlobalSlot>,
+    /// Slots defined since the last sync, in the order they were first
+    /// defined, so new globals keep that order in `globals`.
+    dirty_global_slots: Vec<usize>,
+    breakpoints: BTreeSet<LineNumber>,
+    instruction_count: u64,
+    suspended: bool,
+    /// Every module imported so far, by resolved path.
+    modules: HashMap<String, Module>,
+    /// Modules being loaded, outermost first, as their key and the path they
+    /// were imported with.
+    importing: Vec<(String, String)>,
+    /// File being run, which relative imports are resolved against.
+    script_path: Option<PathBuf>,
+    /// Scripts and modules waiting for an import to finish, outermost first,
+    /// with the line of their `import`.
+    importers: Vec<(Option<PathBuf>, Option<LineNumber>)>,
+    /// Directories to look for imported modules in, after the importing
+    /// file's own directory.
+    search_path: Vec<PathBuf>,
+    hook: Option<&'a mut (dyn VmHook + Send)>,
+    /// Allocations so far that the hook was told about, or that happened
+    /// outside of any instruction.
+    allocations_seen: usize,
+    builtin_methods: BuiltinMethods,
+    integer_overflow: IntegerOverflow,
+    /// Significant digits `print` and traces show floats with, if not the
+    /// shortest form that round-trips.
+    number_precision: Option<usize>,
     gc: &'a mut GC,
 }
 
+#[derive(Debug, PartialEq)]
+pub enum RunOutcome {
+    Finished(Value),
+    Breakpoint(LineNumber),
+    /// A native called `VM::suspend`. Continue with `resume_with`.
+    Suspended,
+}
+
 macro_rules! vm_print {
     ($dst:expr, $($arg:tt)*) => (
         $dst
@@ -64,21 +288,37 @@ macro_rules! vm_print {
             .write_fmt(std::format_args!($($arg)*))
             .map_err(|_| {
                 RuntimeError(
-                    format!("Failed to write to stdout")
+                    String::from("Failed to write to stdout")
                 )
             })?
     );
 }
 
 impl<'a> VM<'a> {
-    pub fn new(config: VMConfig<'a>, chunk: &'a Chunk, gc: &'a mut GC) -> Self {
+    pub fn new(config: VMConfig<'a>, gc: &'a mut GC) -> Self {
         VM {
-            chunk,
+            chunk: Chunk::new(),
             ip: 0,
             config,
             stack: [Value::Nil; STACK_MAX],
             stack_top: 0,
-            globals: Table::new(),
+            globals: Table::with_insertion_order(),
+            global_cache: Vec::new(),
+            global_slots: Vec::new(),
+            dirty_global_slots: Vec::new(),
+            breakpoints: BTreeSet::new(),
+            instruction_count: 0,
+            suspended: false,
+            modules: HashMap::new(),
+            importing: Vec::new(),
+            script_path: None,
+            importers: Vec::new(),
+            search_path: Vec::new(),
+            hook: None,
+            allocations_seen: 0,
+            builtin_methods: HashMap::new(),
+            integer_overflow: IntegerOverflow::default(),
+            number_precision: None,
             gc,
         }
     }
@@ -87,68 +327,762 @@ impl<'a> VM<'a> {
         f(self.gc)
     }
 
-    fn stack_push(&mut self, value: Value) -> Result<(), InterpreterError> {
-        if self.stack_top == STACK_MAX {
-            return Err(RuntimeError(format!("Stack overflow")));
+    /// The chunk that was most recently passed to `interpret_chunk`.
+    pub fn chunk(&self) -> &Chunk {
+        &self.chunk
+    }
+
+    pub fn config_mut(&mut self) -> &mut VMConfig<'a> {
+        &mut self.config
+    }
+
+    /// While a chunk with global slots runs, the globals it defines only
+    /// show up here once it returns, fails or stops.
+    pub fn globals(&self) -> &Table<Value> {
+        &self.globals
+    }
+
+    pub fn globals_mut(&mut self) -> &mut Table<Value> {
+        &mut self.globals
+    }
+
+    pub(crate) fn builtin_methods_mut(&mut self) -> &mut BuiltinMethods {
+        &mut self.builtin_methods
+    }
+
+    /// Defines a global `name` holding a native function that takes exactly
+    /// `arity` arguments.
+    pub fn register_native(
+        &mut self,
+        name: &str,
+        arity: u8,
+        function: impl Fn(&mut VM, &[Value]) -> Result<Value, String> + Send + Sync + 'static,
+    ) {
+        let native = ObjNative::new(String::from(name), arity, Box::new(function));
+        self.define_native(native);
+    }
+
+    /// Like `register_native`, for a native that takes `min_arity` or more
+    /// arguments.
+    pub fn register_variadic(
+        &mut self,
+        name: &str,
+        min_arity: u8,
+        function: impl Fn(&mut VM, &[Value]) -> Result<Value, String> + Send + Sync + 'static,
+    ) {
+        let native = ObjNative::new(String::from(name), min_arity, Box::new(function));
+        self.define_native(native.variadic());
+    }
+
+    /// Like `register_native`, for a native that needs `capability`. Does
+    /// nothing and returns `false` if the sandbox doesn't allow it, so the
+    /// name stays undefined for scripts.
+    pub fn register_native_requiring(
+        &mut self,
+        capability: Capability,
+        name: &str,
+        arity: u8,
+        function: impl Fn(&mut VM, &[Value]) -> Result<Value, String> + Send + Sync + 'static,
+    ) -> bool {
+        if !self.config.sandbox.allows(capability) {
+            return false;
+        }
+        let native = ObjNative::new(String::from(name), arity, Box::new(function));
+        self.define_native(native.requiring(capability));
+        return true;
+    }
+
+    /// Like `register_native`, but takes a plain Rust closure. Arguments and
+    /// the return value are converted with `FromLox` and `ToLox`.
+    pub fn register_fn<Args, F: IntoNative<Args>>(&mut self, name: &str, function: F) {
+        let native = ObjNative::new(String::from(name), F::ARITY, function.into_native());
+        self.define_native(native);
+    }
+
+    /// Like `register_fn`, for a native that needs `capability`. See
+    /// `register_native_requiring`.
+    pub fn register_fn_requiring<Args, F: IntoNative<Args>>(
+        &mut self,
+        capability: Capability,
+        name: &str,
+        function: F,
+    ) -> bool {
+        if !self.config.sandbox.allows(capability) {
+            return false;
+        }
+        let native = ObjNative::new(String::from(name), F::ARITY, function.into_native());
+        self.define_native(native.requiring(capability));
+        return true;
+    }
+
+    /// Adds a method to every value of type `kind`, so that scripts can call
+    /// `value.name(args)`. As with `UserDataType::method_fn`, the function's
+    /// first parameter receives the receiver.
+    pub fn register_method_fn<Args, F: IntoNative<Args>>(
+        &mut self,
+        kind: BuiltinType,
+        name: &str,
+        function: F,
+    ) {
+        assert!(
+            F::ARITY > 0,
+            "Methods must take the receiver as an argument"
+        );
+        let native = ObjNative::new(
+            format!("{}.{}", kind, name),
+            F::ARITY,
+            function.into_native(),
+        );
+        self.builtin_methods
+            .insert((kind, String::from(name)), Arc::new(native));
+    }
+
+    /// Whether `value.name()` would find a method.
+    pub fn has_method(&self, value: Value, name: &str) -> bool {
+        if let Some(userdata) = value.as_userdata() {
+            return userdata.get_type().get_method(name).is_some();
+        }
+        return match BuiltinType::of(&value) {
+            Some(kind) => self
+                .builtin_methods
+                .contains_key(&(kind, String::from(name))),
+            None => false,
+        };
+    }
+
+    fn define_native(&mut self, native: ObjNative) {
+        let name = native.get_name().clone();
+        let native = self.gc.alloc_native(native);
+        self.define_global(&name, Value::Object(native));
+    }
+
+    /// Sets the file the loaded chunk was compiled from, so that it can
+    /// import modules relative to itself. Without one, relative imports are
+    /// resolved against the working directory.
+    pub fn set_script_path(&mut self, path: impl Into<PathBuf>) {
+        self.script_path = Some(path.into());
+    }
+
+    /// Sets the directories to look for modules in when they aren't found
+    /// next to the importing file, such as `search_path_from_env()`.
+    pub fn set_search_path(&mut self, search_path: Vec<PathBuf>) {
+        self.search_path = search_path;
+    }
+
+    /// Sets what integer arithmetic does on overflow. It's a runtime error
+    /// by default.
+    pub fn set_integer_overflow(&mut self, overflow: IntegerOverflow) {
+        self.integer_overflow = overflow;
+    }
+
+    /// Makes `print`, traces and `format` show floats with `digits`
+    /// significant digits. With `None`, the default, they show the shortest
+    /// form that reads back as the same float.
+    pub fn set_number_precision(&mut self, digits: Option<usize>) {
+        self.number_precision = digits;
+    }
+
+    /// Shows `value` the way `print` does.
+    pub fn display(&self, value: Value) -> String {
+        return display_value(value, self.number_precision);
+    }
+
+    /// Calls `hook` before every instruction, including those of imported
+    /// modules.
+    pub fn set_hook(&mut self, hook: &'a mut (dyn VmHook + Send)) {
+        self.hook = Some(hook);
+        self.allocations_seen = self.gc.allocation_count();
+    }
+
+    /// Defines or replaces the global `name`.
+    pub fn define_global(&mut self, name: &str, value: Value) {
+        let name = self.gc.alloc_string(String::from(name));
+        self.globals.set(name.unwrap_string(), value);
+    }
+
+    fn check_capability(&self, native: &ObjNative) -> Result<(), InterpreterError> {
+        return match native.get_capability() {
+            Some(capability) if !self.config.sandbox.allows(capability) => {
+                Err(RuntimeError(format!(
+                    "{} needs {}, which is not allowed here",
+                    native.get_name(),
+                    capability
+                )))
+            }
+            _ => Ok(()),
+        };
+    }
+
+    fn check_limits(&self) -> Result<(), InterpreterError> {
+        let sandbox = &self.config.sandbox;
+        if let Some(limit) = sandbox.max_instructions {
+            if self.instruction_count > limit {
+                return Err(RuntimeError(String::from("Instruction limit exceeded")));
+            }
+        }
+        if let Some(limit) = sandbox.max_memory {
+            if self.gc.bytes_allocated() > limit {
+                return Err(RuntimeError(String::from("Memory limit exceeded")));
+            }
+        }
+        return Ok(());
+    }
+
+    /// Fails if `chunk` needs more stack than is left, so it overflows
+    /// before running rather than partway through. Pushes rely on this.
+    fn check_stack_space(&self, chunk: &Chunk) -> Result<(), InterpreterError> {
+        let needed = chunk.max_stack().unwrap_or_else(|| chunk.stack_bound());
+        if self.stack_top + needed > STACK_MAX {
+            return Err(RuntimeError(String::from("Stack overflow")));
         }
+        return Ok(());
+    }
+
+    fn stack_push(&mut self, value: Value) {
+        // `check_stack_space` made room for everything the chunk pushes
+        debug_assert!(self.stack_top < STACK_MAX, "Stack overflow");
         self.stack[self.stack_top] = value;
         self.stack_top += 1;
-        return Ok(());
     }
 
     fn stack_pop(&mut self) -> Result<Value, InterpreterError> {
         if self.stack_top == 0 {
-            return Err(RuntimeError(format!("Stack underflow")));
+            return Err(RuntimeError(String::from("Stack underflow")));
         }
         self.stack_top -= 1;
         return Ok(self.stack[self.stack_top]);
     }
 
+    fn stack_peek(&self) -> Result<Value, InterpreterError> {
+        if self.stack_top == 0 {
+            return Err(RuntimeError(String::from("Stack underflow")));
+        }
+        return Ok(self.stack[self.stack_top - 1]);
+    }
+
     fn stack_pop_binary(&mut self) -> Result<(Value, Value), InterpreterError> {
         let b = self.stack_pop()?;
         let a = self.stack_pop()?;
         Ok((a, b))
     }
-    pub fn interpret_chunk(&mut self, chunk: &'a Chunk) -> Result<Value, InterpreterError> {
+
+    pub fn interpret_chunk(&mut self, chunk: Chunk) -> Result<Value, InterpreterError> {
+        self.load_chunk(chunk)?;
+        return self.run();
+    }
+
+    /// Prepares `chunk` for execution without running it, so it can be driven
+    /// with `step` and `resume`. Fails if the chunk needs more stack than the
+    /// VM has.
+    pub fn load_chunk(&mut self, chunk: Chunk) -> Result<(), InterpreterError> {
+        self.global_slots = bind_global_slots(&chunk, &self.globals)?;
+        self.dirty_global_slots.clear();
+        self.global_cache = vec![None; chunk.constant_count()];
         self.chunk = chunk;
         self.ip = 0;
-        return self.run();
+        self.stack_top = 0;
+        self.suspended = false;
+        self.allocations_seen = self.gc.allocation_count();
+        return self.check_stack_space(&self.chunk);
     }
 
-    fn read_byte(&mut self) -> Result<u8, InterpreterError> {
-        let b = self
+    /// Offset of the next instruction to execute.
+    pub fn ip(&self) -> usize {
+        self.ip
+    }
+
+    /// Source line of the next instruction, or `None` past the end of the chunk.
+    pub fn current_line(&self) -> Option<LineNumber> {
+        self.chunk.get_line(self.ip).copied()
+    }
+
+    /// Source line of the instruction being executed, such as the call that
+    /// is running a native.
+    pub fn executing_line(&self) -> Option<LineNumber> {
+        self.chunk.get_line(self.ip.checked_sub(1)?).copied()
+    }
+
+    /// Number of instructions executed since the VM was created.
+    pub fn instruction_count(&self) -> u64 {
+        self.instruction_count
+    }
+
+    pub fn stack(&self) -> &[Value] {
+        &self.stack[..self.stack_top]
+    }
+
+    pub fn set_breakpoint(&mut self, line: LineNumber) {
+        self.breakpoints.insert(line);
+    }
+
+    pub fn clear_breakpoint(&mut self, line: LineNumber) -> bool {
+        self.breakpoints.remove(&line)
+    }
+
+    pub fn breakpoints(&self) -> &BTreeSet<LineNumber> {
+        &self.breakpoints
+    }
+
+    /// Called by a native to pause the VM once it returns, so the host can
+    /// wait for a result without blocking the interpreter. The value the
+    /// native returns is a placeholder: the call evaluates to whatever is
+    /// passed to `resume_with`. Only `resume` can be suspended; `run` fails.
+    pub fn suspend(&mut self) {
+        self.suspended = true;
+    }
+
+    pub fn is_suspended(&self) -> bool {
+        self.suspended
+    }
+
+    fn call_value(&mut self, arg_count: usize) -> Result<(), InterpreterError> {
+        if self.stack_top < arg_count + 1 {
+            return Err(RuntimeError(String::from("Stack underflow")));
+        }
+        let callee_index = self.stack_top - arg_count - 1;
+        let callee = self.stack[callee_index];
+        let native = callee
+            .as_native()
+            .ok_or_else(|| RuntimeError(format!("Can only call functions, got {}", callee)))?;
+        self.check_capability(native)?;
+        let args = self.stack[callee_index + 1..self.stack_top].to_vec();
+        let result = native.call(self, &args).map_err(|error| {
+            // A failed call has no result to resume with
+            self.suspended = false;
+            RuntimeError(error)
+        })?;
+        self.stack_top = callee_index;
+        self.stack_push(result);
+        return Ok(());
+    }
+
+    fn invoke(&mut self, name: Value, arg_count: usize) -> Result<(), InterpreterError> {
+        if self.stack_top < arg_count + 1 {
+            return Err(RuntimeError(String::from("Stack underflow")));
+        }
+        let name = name
+            .as_string()
+            .ok_or_else(|| expected_name(name))?
+            .get_value();
+        let receiver_index = self.stack_top - arg_count - 1;
+        let receiver = self.stack[receiver_index];
+        let userdata = receiver.as_userdata();
+        let builtin;
+        let method = match userdata {
+            Some(userdata) => {
+                let user_type = userdata.get_type();
+                let method = user_type.get_method(name).ok_or_else(|| {
+                    RuntimeError(format!(
+                        "Undefined method '{}' on {}",
+                        name,
+                        user_type.get_name()
+                    ))
+                })?;
+                if userdata.is_frozen() && user_type.is_mutating(name) {
+                    return Err(RuntimeError(format!(
+                        "Can't call {} on a frozen {}",
+                        name,
+                        user_type.get_name()
+                    )));
+                }
+                method
+            }
+            None => {
+                let kind = BuiltinType::of(&receiver).ok_or_else(|| {
+                    RuntimeError(format!(
+                        "Only userdata, strings and numbers have methods, got {}",
+                        receiver
+                    ))
+                })?;
+                builtin = self
+                    .builtin_methods
+                    .get(&(kind, name.clone()))
+                    .cloned()
+                    .ok_or_else(|| {
+                        RuntimeError(format!("Undefined method '{}' on {}", name, kind))
+                    })?;
+                &*builtin
+            }
+        };
+        let arity = method.get_arity() as usize - 1;
+        if arg_count != arity {
+            return Err(RuntimeError(format!(
+                "{} expects {} arguments but got {}",
+                method.get_name(),
+                arity,
+                arg_count
+            )));
+        }
+        self.check_capability(method)?;
+        // The receiver is passed to the method as its first argument
+        let args = self.stack[receiver_index..self.stack_top].to_vec();
+        let result = method.call(self, &args).map_err(RuntimeError)?;
+        self.stack_top = receiver_index;
+        self.stack_push(result);
+        return Ok(());
+    }
+
+    /// Wraps `data` in a userdata object of type `user_type`.
+    #[track_caller]
+    pub fn new_userdata(&mut self, user_type: &Arc<UserDataType>, data: impl Any + Send) -> Value {
+        return Value::Object(self.gc.alloc_userdata(user_type, Box::new(data)));
+    }
+
+    /// Where execution is, innermost first: the line running in the current
+    /// script or module, then the `import` of each importer that led to it.
+    pub fn call_stack(&self) -> Vec<String> {
+        let frame = |frame: &Frame| {
+            let name = match frame.script {
+                Some(path) => path.display().to_string(),
+                None => String::from("script"),
+            };
+            match frame.line {
+                Some(line) => format!("[line {}] in {}", line, name),
+                None => format!("in {}", name),
+            }
+        };
+        let frames = self.frames(self.executing_line());
+        return frames.iter().rev().map(frame).collect();
+    }
+
+    /// The importers of the code running, outermost first, followed by that
+    /// code itself at `line`.
+    fn frames(&self, line: Option<LineNumber>) -> Vec<Frame<'_>> {
+        let mut frames = self
+            .importers
+            .iter()
+            .map(|(path, line)| Frame {
+                script: path.as_deref(),
+                line: *line,
+            })
+            .collect::<Vec<_>>();
+        frames.push(Frame {
+            script: self.script_path.as_deref(),
+            line,
+        });
+        return frames;
+    }
+
+    /// Runs the module at `path` unless it's already loaded, and returns the
+    /// key its exports are registered under.
+    fn import_module(&mut self, path: &str) -> Result<String, InterpreterError> {
+        if !self.config.sandbox.allows(Capability::Io) {
+            return Err(RuntimeError(format!(
+                "import needs {}, which is not allowed here",
+                Capability::Io
+            )));
+        }
+        let resolved = module::resolve(path, self.script_path.as_deref(), &self.search_path)
+            .ok_or_else(|| RuntimeError(format!("Could not find module '{}'", path)))?;
+        let key = module_key(&resolved);
+        if self.modules.contains_key(&key) {
+            return Ok(key);
+        }
+        // The script and its importers, then the module
+        let depth = self.importers.len() + 2;
+        if depth > self.config.sandbox.max_import_depth {
+            return Err(RuntimeError(format!(
+                "Import nesting exceeds {}",
+                self.config.sandbox.max_import_depth
+            )));
+        }
+
+        // The script that started the chain of imports can be part of a
+        // cycle too, so it goes first
+        let is_root = self.importing.is_empty();
+        if let (true, Some(script)) = (is_root, &self.script_path) {
+            let root = (module_key(script), script.display().to_string());
+            self.importing.push(root);
+        }
+        if let Some(start) = self.importing.iter().position(|(k, _)| *k == key) {
+            let mut cycle = self.importing[start..]
+                .iter()
+                .map(|(_, path)| path.as_str())
+                .collect::<Vec<_>>();
+            cycle.push(path);
+            let message = format!("Import cycle: {}", cycle.join(" -> "));
+            if is_root {
+                self.importing.clear();
+            }
+            return Err(RuntimeError(message));
+        }
+
+        self.importing.push((key.clone(), String::from(path)));
+        let exports = self.load_module(path, resolved);
+        self.importing.pop();
+        if is_root {
+            self.importing.clear();
+        }
+        let module = Module {
+            path: String::from(path),
+            exports: exports?,
+        };
+        self.modules.insert(key.clone(), module);
+        return Ok(key);
+    }
+
+    /// Compiles and runs a module in its own chunk and globals. The globals
+    /// start as a copy of the importer's, so the module sees the standard
+    /// library and whatever the host defined; every global the module adds
+    /// or changes is exported.
+    fn load_module(
+        &mut self,
+        path: &str,
+        resolved: PathBuf,
+    ) -> Result<Table<Value>, InterpreterError> {
+        let chunk = module::load(&resolved, self.gc).map_err(|error| match error {
+            CompileError(_) => {
+                RuntimeError(format!("Could not compile module '{}': {}", path, error))
+            }
+            error => error,
+        })?;
+        self.check_stack_space(&chunk)?;
+
+        // The module sees what the importer defined so far
+        self.sync_global_slots();
+        let mut module_globals = Table::with_insertion_order();
+        for (name, value) in self.globals.iter() {
+            module_globals.set(name, *value);
+        }
+        let module_slots = bind_global_slots(&chunk, &module_globals)?;
+
+        // Compiling the module counts towards the `import`
+        self.report_allocations(self.executing_line());
+        let importer = (self.script_path.clone(), self.executing_line());
+        self.importers.push(importer);
+        let global_cache = vec![None; chunk.constant_count()];
+        let global_cache = std::mem::replace(&mut self.global_cache, global_cache);
+        let global_slots = std::mem::replace(&mut self.global_slots, module_slots);
+        let chunk = std::mem::replace(&mut self.chunk, chunk);
+        let ip = std::mem::replace(&mut self.ip, 0);
+        let globals = std::mem::replace(&mut self.globals, module_globals);
+        let script_path = self.script_path.replace(resolved);
+        let stack_top = self.stack_top;
+        let mut result = Ok(None);
+        while let Ok(None) = result {
+            result = self.step();
+        }
+        self.chunk = chunk;
+        self.global_cache = global_cache;
+        // The module synced its slots into its globals when it returned or
+        // failed
+        self.global_slots = global_slots;
+        self.ip = ip;
+        self.script_path = script_path;
+        self.importers.pop();
+        let module_globals = std::mem::replace(&mut self.globals, globals);
+        self.stack_top = stack_top;
+        if let Err(RuntimeError(message)) = result {
+            return Err(RuntimeError(format!("In module '{}': {}", path, message)));
+        }
+        result?;
+
+        let mut exports = Table::with_insertion_order();
+        for (name, value) in module_globals.iter() {
+            let unchanged = matches!(self.globals.get(name), Some(v) if are_equal(*v, *value));
+            if !unchanged {
+                exports.set(name, *value);
+            }
+        }
+        return Ok(exports);
+    }
+
+    /// Takes the module left on the stack by `Import`. It must be put back
+    /// with `self.modules.insert`.
+    fn take_module(&mut self) -> Result<(String, Module), InterpreterError> {
+        let key = self
+            .stack_peek()?
+            .as_string()
+            .map(|key| key.get_value().clone());
+        return key
+            .and_then(|key| self.modules.remove(&key).map(|module| (key, module)))
+            .ok_or_else(|| RuntimeError(String::from("Expected a module on the stack")));
+    }
+
+    fn import_name(&mut self, name: Value) -> Result<(), InterpreterError> {
+        let name = name.as_string().ok_or_else(|| expected_name(name))?;
+        let (key, module) = self.take_module()?;
+        let value = module.exports.get(name).copied();
+        let result = match value {
+            Some(value) => {
+                self.globals.set(name, value);
+                Ok(())
+            }
+            None => Err(RuntimeError(format!(
+                "Module '{}' has no export '{}'",
+                module.path,
+                name.get_value()
+            ))),
+        };
+        self.modules.insert(key, module);
+        return result;
+    }
+
+    /// Pushes the global named by constant `index`, using the inline cache.
+    fn get_global(&mut self, index: usize) -> Result<(), InterpreterError> {
+        let name_val = self.constant(index)?;
+        let name = name_val
+            .as_string()
+            .ok_or_else(|| expected_name(name_val))?;
+        let cached = self.global_cache[index].and_then(|i| self.globals.get_at(i, name));
+        if let Some(value) = cached {
+            let value = *value;
+            self.stack_push(value);
+            return Ok(());
+        }
+        let bucket = self.globals.index_of(name);
+        let value = bucket
+            .and_then(|i| self.globals.get_at(i, name))
+            .copied()
+            .ok_or_else(|| RuntimeError(format!("Undefined variable '{}'", name.get_value())))?;
+        self.global_cache[index] = bucket;
+        self.stack_push(value);
+        return Ok(());
+    }
+
+    /// Pops a value into the global named by constant `index`, using the
+    /// inline cache.
+    fn define_global_at(&mut self, index: usize) -> Result<(), InterpreterError> {
+        let name_val = self.constant(index)?;
+        let name = name_val
+            .as_string()
+            .ok_or_else(|| expected_name(name_val))?;
+        let value = self.stack_pop()?;
+        let cached = self.global_cache[index];
+        if !cached.is_some_and(|i| self.globals.set_at(i, name, value)) {
+            self.globals.set(name, value);
+            self.global_cache[index] = self.globals.index_of(name);
+        }
+        return Ok(());
+    }
+
+    /// Pushes the global in `slot`. A slot that isn't bound yet is looked up
+    /// by name, in case the global was defined some other way since the
+    /// chunk was loaded, such as by a native.
+    fn get_global_slot(&mut self, slot: usize) -> Result<(), InterpreterError> {
+        if let Some(GlobalSlot {
+            value: Some(value), ..
+        }) = self.global_slots.get(slot)
+        {
+            let value = *value;
+            self.stack_push(value);
+            return Ok(());
+        }
+        let name_val = self
             .chunk
-            .read_byte(self.ip)
-            .ok_or(RuntimeError(format!("Read byte out of bounds")));
-        if b.is_ok() {
-            self.ip += 1;
+            .global_slot_name(slot)
+            .ok_or_else(|| RuntimeError(format!("Invalid global slot: {}", slot)))?;
+        let name = name_val
+            .as_string()
+            .ok_or_else(|| expected_name(name_val))?;
+        let value = *self
+            .globals
+            .get(name)
+            .ok_or_else(|| RuntimeError(format!("Undefined variable '{}'", name.get_value())))?;
+        self.global_slots[slot].value = Some(value);
+        self.stack_push(value);
+        return Ok(());
+    }
+
+    /// Pops a value into the global in `slot`.
+    fn define_global_slot(&mut self, slot: usize) -> Result<(), InterpreterError> {
+        let value = self.stack_pop()?;
+        let entry = self
+            .global_slots
+            .get_mut(slot)
+            .ok_or_else(|| RuntimeError(format!("Invalid global slot: {}", slot)))?;
+        entry.value = Some(value);
+        if !entry.dirty {
+            entry.dirty = true;
+            self.dirty_global_slots.push(slot);
         }
-        return b;
+        return Ok(());
     }
 
+    /// Copies the globals the chunk defined in its slots into `globals`.
+    fn sync_global_slots(&mut self) {
+        for slot in std::mem::take(&mut self.dirty_global_slots) {
+            let entry = &mut self.global_slots[slot];
+            entry.dirty = false;
+            // Loading the chunk checked that every slot has a name
+            let name = self.chunk.global_slot_name(slot);
+            if let (Some(name), Some(value)) =
+                (name.as_ref().and_then(Value::as_string), entry.value)
+            {
+                self.globals.set(name, value);
+            }
+        }
+    }
+
+    /// Syncs the global slots, then binds them again, for when `globals` may
+    /// have changed outside the chunk.
+    fn refresh_global_slots(&mut self) -> Result<(), InterpreterError> {
+        self.sync_global_slots();
+        self.global_slots = bind_global_slots(&self.chunk, &self.globals)?;
+        return Ok(());
+    }
+
+    // These run for every instruction, so they index the code and constant
+    // slices directly and only build an error message when one is needed.
+
+    #[inline]
+    fn read_byte(&mut self) -> Result<u8, InterpreterError> {
+        let b = *self
+            .chunk
+            .get_code()
+            .get(self.ip)
+            .ok_or_else(|| RuntimeError(String::from("Read byte out of bounds")))?;
+        self.ip += 1;
+        return Ok(b);
+    }
+
+    #[inline]
     fn read_short(&mut self) -> Result<u16, InterpreterError> {
-        let s = self
+        let bytes = self
             .chunk
-            .read_short(self.ip)
-            .ok_or(RuntimeError(format!("Read short out of bounds")));
-        if s.is_ok() {
-            self.ip += 2;
-        }
-        return s;
+            .get_code()
+            .get(self.ip..self.ip + 2)
+            .ok_or_else(|| RuntimeError(String::from("Read short out of bounds")))?;
+        let s = u16::from_be_bytes([bytes[0], bytes[1]]);
+        self.ip += 2;
+        return Ok(s);
+    }
+
+    #[inline]
+    fn constant(&self, index: usize) -> Result<Value, InterpreterError> {
+        return self
+            .chunk
+            .get_constants()
+            .get(index)
+            .copied()
+            .ok_or_else(|| RuntimeError(format!("Invalid constant reference: {}", index)));
     }
 
-    fn read_constant(&mut self) -> Result<Value, InterpreterError> {
-        let b = self.read_byte()?;
-        return Ok(self.chunk.get_constant(b as usize));
+    /// Reads the constant index operand of `opcode`, in whichever width
+    /// the opcode uses.
+    fn read_constant_index(&mut self, opcode: OpCode) -> Result<usize, InterpreterError> {
+        return match ConstOp::from_opcode(opcode) {
+            Some((_, OperandWidth::Short)) => self.read_short().map(usize::from),
+            _ => self.read_byte().map(usize::from),
+        };
+    }
+
+    /// Reads the global slot operand of `opcode`, in whichever width the
+    /// opcode uses.
+    fn read_slot(&mut self, opcode: OpCode) -> Result<usize, InterpreterError> {
+        return match SlotOp::from_opcode(opcode) {
+            Some((_, OperandWidth::Short)) => self.read_short().map(usize::from),
+            _ => self.read_byte().map(usize::from),
+        };
     }
 
-    fn read_constant_long(&mut self) -> Result<Value, InterpreterError> {
-        let s = self.read_short()?;
-        return Ok(self.chunk.get_constant(s as usize));
+    fn read_constant(&mut self, opcode: OpCode) -> Result<Value, InterpreterError> {
+        let index = self.read_constant_index(opcode)?;
+        return self.constant(index);
     }
 
     fn trace_instruction(&mut self) -> Result<(), InterpreterError> {
-        if let Some((_, decription)) = disassemble_instruction(self.chunk, self.ip) {
+        if let Some((_, decription)) = disassemble_instruction(&self.chunk, self.ip) {
             vm_print!(self, "{}\n", decription);
         } else {
             vm_print!(self, "[END OF CHUNK]\n");
@@ -159,13 +1093,15 @@ impl<'a> VM<'a> {
     fn trace_stack(&mut self) -> Result<(), InterpreterError> {
         vm_print!(self, "Stack:");
         for i in 0..self.stack_top {
-            vm_print!(self, "[{}]", self.stack[i]);
+            let value = self.display(self.stack[i]);
+            vm_print!(self, "[{}]", value);
         }
         vm_print!(self, "\n");
         return Ok(());
     }
 
     fn trace_globals(&mut self) -> Result<(), InterpreterError> {
+        self.sync_global_slots();
         vm_print!(self, "Globals: {:?}\n", self.globals);
         return Ok(());
     }
@@ -176,188 +1112,319 @@ impl<'a> VM<'a> {
             vm_print!(self, "Offs Line Instruction\n");
         }
         loop {
-            if self.config.trace_stack {
-                self.trace_stack()?;
-            }
-            if self.config.trace_globals {
-                self.trace_globals()?;
-            }
-            if self.config.trace_instructions {
-                self.trace_instruction()?;
-            }
-            let byte = self.read_byte()?;
-            let instruction = FromPrimitive::from_u8(byte)
-                .ok_or(RuntimeError(format!("Unknown opcode: {}", byte)))?;
-            match instruction {
-                Return => match self.stack_pop() {
-                    Ok(value) => return Ok(value),
-                    Err(_) => return Ok(Value::Nil),
-                },
-                Constant => {
-                    let constant = self.read_constant()?;
-                    self.stack_push(constant)?;
-                }
-                ConstantLong => {
-                    let constant = self.read_constant_long()?;
-                    self.stack_push(constant)?;
-                }
-                OpCode::Nil => {
-                    self.stack_push(Value::Nil)?;
-                }
-                True => {
-                    self.stack_push(Boolean(true))?;
-                }
-                False => {
-                    self.stack_push(Boolean(false))?;
-                }
-                Pop => {
-                    self.stack_pop()?;
-                }
-                Get => {
-                    let name_val = self.read_constant()?;
-                    if let Object(name_obj) = name_val {
-                        let Obj::String(name_string) = &*name_obj;
-                        let value = self.globals.get(name_string);
-                        match value {
-                            Some(value) => self.stack_push(value.clone())?,
-                            None => {
-                                return Err(RuntimeError(format!(
-                                    "Undefined variable: {}",
-                                    name_string
-                                )))
-                            }
-                        }
-                    } else {
-                        panic!("Expected string as name, got {:?}", name_val);
-                    }
-                }
-                GetLong => {
-                    let name_val = self.read_constant_long()?;
-                    if let Object(name_obj) = name_val {
-                        let Obj::String(name_string) = &*name_obj;
-                        let value = self.globals.get(name_string);
-                        match value {
-                            Some(value) => self.stack_push(value.clone())?,
-                            None => {
-                                return Err(RuntimeError(format!(
-                                    "Undefined variable: {}",
-                                    name_string
-                                )))
-                            }
-                        }
-                    } else {
-                        panic!("Expected string as name, got {:?}", name_val);
-                    }
-                }
-                DefineGlobal => {
-                    let name_val = self.read_constant()?;
-                    if let Object(name_obj) = name_val {
-                        let value = self.stack_pop()?;
-                        let Obj::String(name_string) = &*name_obj;
-                        self.globals.set(name_string, value);
-                    } else {
-                        panic!("Expected string as name, got {:?}", name_val);
-                    }
-                }
-                DefineGlobalLong => {
-                    let name_val = self.read_constant_long()?;
-                    if let Object(name_obj) = name_val {
-                        let value = self.stack_pop()?;
-                        let Obj::String(name_string) = &*name_obj;
-                        self.globals.set(name_string, value);
-                    } else {
-                        panic!("Expected string as name, got {:?}", name_val);
-                    }
-                }
-                Equal => {
-                    let (a, b) = self.stack_pop_binary()?;
-                    self.stack_push(Boolean(are_equal(a, b)))?;
-                }
-                Less => {
-                    let (a, b) = self.stack_pop_binary()?;
-                    self.stack_push(Boolean(a < b))?;
-                }
-                Greater => {
-                    let (a, b) = self.stack_pop_binary()?;
-                    self.stack_push(Boolean(a > b))?;
-                }
-                Add => {
-                    let (a, b) = self.stack_pop_binary()?;
-                    if let (Number(a_num), Number(b_num)) = (a, b) {
-                        self.stack_push(Number(a_num + b_num))?;
-                    } else if let (Object(a_obj), Object(b_obj)) = (a, b) {
-                        let (Obj::String(a_string), Obj::String(b_string)) = (&*a_obj, &*b_obj);
-                        let result = self.gc.alloc_string(format!(
-                            "{}{}",
-                            a_string.get_value(),
-                            b_string.get_value()
-                        ));
-                        self.stack_push(Value::Object(result))?;
-                    } else {
-                        return Err(RuntimeError(format!(
-                            "Invalid type for addition: {} {}",
-                            a, b
-                        )));
-                    }
+            if let Some(value) = self.step()? {
+                return Ok(value);
+            }
+            if self.suspended {
+                self.sync_global_slots();
+                return Err(RuntimeError(String::from(
+                    "Execution can only be suspended with resume",
+                )));
+            }
+        }
+    }
+
+    /// Runs until the chunk returns, or until execution reaches the first
+    /// instruction of a line with a breakpoint. Always executes at least one
+    /// instruction, so calling it again after a breakpoint makes progress.
+    ///
+    /// Also stops when a native suspends the VM. Resuming without a value
+    /// leaves the native's placeholder as the result of its call.
+    pub fn resume(&mut self) -> Result<RunOutcome, InterpreterError> {
+        self.suspended = false;
+        // The host may have changed globals while the VM was stopped
+        self.refresh_global_slots()?;
+        loop {
+            let line = self.current_line();
+            if let Some(value) = self.step()? {
+                return Ok(RunOutcome::Finished(value));
+            }
+            if self.suspended {
+                self.sync_global_slots();
+                return Ok(RunOutcome::Suspended);
+            }
+            match self.current_line() {
+                Some(next_line)
+                    if Some(next_line) != line && self.breakpoints.contains(&next_line) =>
+                {
+                    self.sync_global_slots();
+                    return Ok(RunOutcome::Breakpoint(next_line));
                 }
-                Subtract => {
-                    let (a, b) = self.stack_pop_binary()?;
-                    if let (Number(a), Number(b)) = (a, b) {
-                        self.stack_push(Number(a - b))?;
-                    } else {
-                        return Err(RuntimeError(format!(
-                            "Invalid type for subtraction: {} {}",
-                            a, b
-                        )));
-                    }
+                _ => {}
+            }
+        }
+    }
+
+    /// Continues a suspended VM, with `value` as the result of the native call
+    /// that suspended it.
+    pub fn resume_with(&mut self, value: Value) -> Result<RunOutcome, InterpreterError> {
+        if !self.suspended {
+            return Err(RuntimeError(String::from("VM is not suspended")));
+        }
+        // The placeholder returned by the native is on top of the stack
+        self.stack_pop()?;
+        self.stack_push(value);
+        return self.resume();
+    }
+
+    /// Executes a single instruction. Returns the result of the chunk if the
+    /// instruction was a `Return`. Runtime errors start with the line of the
+    /// instruction, as `[line N] message`.
+    pub fn step(&mut self) -> Result<Option<Value>, InterpreterError> {
+        // Without a hook, nothing is spent on reporting allocations
+        let line = self.hook.as_ref().and_then(|_| self.current_line());
+        let result = self.execute_instruction();
+        if self.hook.is_some() {
+            self.report_allocations(line);
+        }
+        if result.is_err() {
+            self.sync_global_slots();
+        }
+        return result.map_err(|error| self.locate(error));
+    }
+
+    /// Tells the hook about every object allocated since it was last told,
+    /// as allocated at `line` of the current script.
+    fn report_allocations(&mut self, line: Option<LineNumber>) {
+        let hook = match self.hook.take() {
+            Some(hook) => hook,
+            None => return,
+        };
+        let count = self.gc.allocation_count();
+        for object in self.gc.objects().take(count - self.allocations_seen) {
+            hook.allocated(self.script_path.as_deref(), line, object);
+        }
+        self.allocations_seen = count;
+        self.hook = Some(hook);
+    }
+
+    /// Prefixes a runtime error with the line of the instruction being
+    /// executed, so no error site has to look it up.
+    fn locate(&self, error: InterpreterError) -> InterpreterError {
+        return match (error, self.executing_line()) {
+            (RuntimeError(message), Some(line)) => {
+                RuntimeError(format!("[line {}] {}", line, message))
+            }
+            (error, _) => error,
+        };
+    }
+
+    fn execute_instruction(&mut self) -> Result<Option<Value>, InterpreterError> {
+        if self.config.trace_stack {
+            self.trace_stack()?;
+        }
+        if self.config.trace_globals {
+            self.trace_globals()?;
+        }
+        if self.config.trace_instructions {
+            self.trace_instruction()?;
+        }
+        if let Some(hook) = self.hook.take() {
+            hook.before_instruction(self.script_path.as_deref(), &self.chunk, self.ip);
+            hook.before_instruction_with_stack(
+                self.script_path.as_deref(),
+                &self.chunk,
+                self.ip,
+                self.stack(),
+            );
+            let interval = hook.sample_interval();
+            if interval > 0 && self.instruction_count.is_multiple_of(interval) {
+                hook.sample(&self.frames(self.current_line()));
+            }
+            self.hook = Some(hook);
+        }
+        let byte = self.read_byte()?;
+        self.instruction_count += 1;
+        self.check_limits()?;
+        let instruction = FromPrimitive::from_u8(byte)
+            .ok_or_else(|| RuntimeError(format!("Unknown opcode: {}", byte)))?;
+        match instruction {
+            Return => {
+                self.sync_global_slots();
+                return match self.stack_pop() {
+                    Ok(value) => Ok(Some(value)),
+                    Err(_) => Ok(Some(Value::Nil)),
+                };
+            }
+            Constant | ConstantLong => {
+                let constant = self.read_constant(instruction)?;
+                self.stack_push(constant);
+            }
+            OpCode::Nil => {
+                self.stack_push(Value::Nil);
+            }
+            True => {
+                self.stack_push(Boolean(true));
+            }
+            False => {
+                self.stack_push(Boolean(false));
+            }
+            Pop => {
+                self.stack_pop()?;
+            }
+            Get | GetLong => {
+                let index = self.read_constant_index(instruction)?;
+                self.get_global(index)?;
+            }
+            DefineGlobal | DefineGlobalLong => {
+                let index = self.read_constant_index(instruction)?;
+                self.define_global_at(index)?;
+            }
+            GetSlot | GetSlotLong => {
+                let slot = self.read_slot(instruction)?;
+                self.get_global_slot(slot)?;
+            }
+            DefineSlot | DefineSlotLong => {
+                let slot = self.read_slot(instruction)?;
+                self.define_global_slot(slot)?;
+            }
+            Equal => {
+                let (a, b) = self.stack_pop_binary()?;
+                self.stack_push(Boolean(are_equal(a, b)));
+            }
+            Less => {
+                let (a, b) = self.stack_pop_binary()?;
+                let less = match compare_numbers(a, b) {
+                    Some(ordering) => ordering == Some(std::cmp::Ordering::Less),
+                    None => a < b,
+                };
+                self.stack_push(Boolean(less));
+            }
+            Greater => {
+                let (a, b) = self.stack_pop_binary()?;
+                let greater = match compare_numbers(a, b) {
+                    Some(ordering) => ordering == Some(std::cmp::Ordering::Greater),
+                    None => a > b,
+                };
+                self.stack_push(Boolean(greater));
+            }
+            Add => {
+                let (a, b) = self.stack_pop_binary()?;
+                if let (Int(a), Int(b)) = (a, b) {
+                    let sum =
+                        int_result(a.checked_add(b), a.wrapping_add(b), self.integer_overflow)?;
+                    self.stack_push(sum);
+                } else if let (Some(a_num), Some(b_num)) = (a.as_f64(), b.as_f64()) {
+                    self.stack_push(Number(a_num as f32 + b_num as f32));
+                } else if let (Some(a_string), Some(b_string)) = (a.as_string(), b.as_string()) {
+                    let result = self.gc.alloc_string(format!(
+                        "{}{}",
+                        a_string.get_value(),
+                        b_string.get_value()
+                    ));
+                    self.stack_push(Value::Object(result));
+                } else {
+                    return Err(RuntimeError(format!(
+                        "Invalid type for addition: {} {}",
+                        a, b
+                    )));
                 }
-                Multiply => {
-                    let (a, b) = self.stack_pop_binary()?;
-                    if let (Number(a), Number(b)) = (a, b) {
-                        self.stack_push(Number(a * b))?;
-                    } else {
-                        return Err(RuntimeError(format!(
-                            "Invalid type for multiplication: {} {}",
-                            a, b
-                        )));
-                    }
+            }
+            Subtract => {
+                let (a, b) = self.stack_pop_binary()?;
+                if let (Int(a), Int(b)) = (a, b) {
+                    let difference =
+                        int_result(a.checked_sub(b), a.wrapping_sub(b), self.integer_overflow)?;
+                    self.stack_push(difference);
+                } else if let (Some(a), Some(b)) = (a.as_f64(), b.as_f64()) {
+                    self.stack_push(Number(a as f32 - b as f32));
+                } else {
+                    return Err(RuntimeError(format!(
+                        "Invalid type for subtraction: {} {}",
+                        a, b
+                    )));
                 }
-                Divide => {
-                    let (a, b) = self.stack_pop_binary()?;
-                    if let (Number(a), Number(b)) = (a, b) {
-                        self.stack_push(Number(a / b))?;
-                    } else {
-                        return Err(RuntimeError(format!(
-                            "Invalid type for division: {} {}",
-                            a, b
-                        )));
-                    }
+            }
+            Multiply => {
+                let (a, b) = self.stack_pop_binary()?;
+                if let (Int(a), Int(b)) = (a, b) {
+                    let product =
+                        int_result(a.checked_mul(b), a.wrapping_mul(b), self.integer_overflow)?;
+                    self.stack_push(product);
+                } else if let (Some(a), Some(b)) = (a.as_f64(), b.as_f64()) {
+                    self.stack_push(Number(a as f32 * b as f32));
+                } else {
+                    return Err(RuntimeError(format!(
+                        "Invalid type for multiplication: {} {}",
+                        a, b
+                    )));
                 }
-                Negate => {
-                    let value = self.stack_pop()?;
-                    if let Number(n) = value {
-                        self.stack_push(Number(-n))?;
-                    } else {
-                        return Err(RuntimeError(format!(
-                            "Invalid type for negation: {}",
-                            value
-                        )));
-                    }
+            }
+            Divide => {
+                let (a, b) = self.stack_pop_binary()?;
+                // Division always gives a float, even of two integers
+                if let (Some(a), Some(b)) = (a.as_f64(), b.as_f64()) {
+                    self.stack_push(Number(a as f32 / b as f32));
+                } else {
+                    return Err(RuntimeError(format!(
+                        "Invalid type for division: {} {}",
+                        a, b
+                    )));
                 }
-                Not => {
-                    let value = self.stack_pop()?;
-                    self.stack_push(Value::Boolean(is_falsey(value)))?;
+            }
+            Negate => {
+                let value = self.stack_pop()?;
+                if let Int(n) = value {
+                    let negated =
+                        int_result(n.checked_neg(), n.wrapping_neg(), self.integer_overflow)?;
+                    self.stack_push(negated);
+                } else if let Number(n) = value {
+                    self.stack_push(Number(-n));
+                } else {
+                    return Err(RuntimeError(format!(
+                        "Invalid type for negation: {}",
+                        value
+                    )));
                 }
-                Print => {
-                    let value = self.stack_pop()?;
-                    vm_print!(self, "{}\n", value);
+            }
+            Not => {
+                let value = self.stack_pop()?;
+                self.stack_push(Value::Boolean(is_falsey(value)));
+            }
+            Print => {
+                let value = self.stack_pop()?;
+                let shown = self.display(value);
+                vm_print!(self, "{}\n", shown);
+            }
+            Call => {
+                let arg_count = self.read_byte()? as usize;
+                self.call_value(arg_count)?;
+            }
+            Invoke | InvokeLong => {
+                let name = self.read_constant(instruction)?;
+                let arg_count = self.read_byte()? as usize;
+                self.invoke(name, arg_count)?;
+            }
+            Import => {
+                let path = self.stack_pop()?;
+                let path = path
+                    .as_string()
+                    .ok_or_else(|| RuntimeError(format!("Invalid module path: {}", path)))?
+                    .get_value()
+                    .clone();
+                let key = self.import_module(&path)?;
+                let key = self.gc.alloc_string(key);
+                self.stack_push(Value::Object(key));
+            }
+            ImportAll => {
+                let (key, module) = self.take_module()?;
+                for (name, value) in module.exports.iter() {
+                    self.globals.set(name, *value);
                 }
+                self.modules.insert(key, module);
+                self.refresh_global_slots()?;
+            }
+            ImportName | ImportNameLong => {
+                let name = self.read_constant(instruction)?;
+                self.import_name(name)?;
+                self.refresh_global_slots()?;
             }
-            self.config
-                .stdout
-                .flush()
-                .map_err(|_| RuntimeError(format!("Failed to write to stdout")))?;
         }
+        self.config
+            .stdout
+            .flush()
+            .map_err(|_| RuntimeError(String::from("Failed to write to stdout")))?;
+        return Ok(None);
     }
 }
 
@@ -366,6 +1433,7 @@ mod tests {
 
     use super::*;
     use crate::assert_eq_str;
+    use crate::value::Value::Nil;
     use std::str;
 
     struct StdoutAdapter<'a> {
@@ -392,17 +1460,19 @@ mod tests {
         ($chunk: expr, $gc: expr) => {{
             let mut output = String::new();
             let mut adapter = StdoutAdapter { f: &mut output };
+            let mut input = io::empty();
             let mut vm = VM::new(
                 VMConfig {
                     trace_instructions: false,
                     trace_stack: false,
                     trace_globals: false,
                     stdout: &mut adapter,
+                    stdin: &mut input,
+                    sandbox: Sandbox::default(),
                 },
-                &$chunk,
                 &mut $gc,
             );
-            let result = vm.interpret_chunk(&$chunk);
+            let result = vm.interpret_chunk($chunk);
             (result, output)
         }};
     }
@@ -414,6 +1484,196 @@ mod tests {
         }};
     }
 
+    #[test]
+    #[cfg_attr(miri, ignore = "scans long sources")]
+    fn overflows_before_running() {
+        // Each `1 + (` leaves a value on the stack until the innermost one
+        let source = format!(
+            "print 1;\n{}1{};",
+            "1 + (".repeat(STACK_MAX),
+            ")".repeat(STACK_MAX)
+        );
+        let mut gc = GC::new();
+        let (chunk, _) = crate::compiler::compile(&source, &mut gc).unwrap();
+        assert_eq!(chunk.max_stack(), Some(STACK_MAX + 1));
+        let (result, output) = run_chunk_with_gc!(chunk, gc);
+        assert_eq!(result, Err(RuntimeError(String::from("Stack overflow"))));
+        assert_eq!(output, "");
+    }
+
+    #[test]
+    fn calls_hook_before_each_instruction() {
+        struct Offsets(Vec<(Option<PathBuf>, usize)>, Vec<usize>);
+
+        impl VmHook for Offsets {
+            fn before_instruction(&mut self, script: Option<&Path>, _: &Chunk, offset: usize) {
+                self.0.push((script.map(Path::to_path_buf), offset));
+            }
+
+            fn before_instruction_with_stack(
+                &mut self,
+                _: Option<&Path>,
+                _: &Chunk,
+                _: usize,
+                stack: &[Value],
+            ) {
+                self.1.push(stack.len());
+            }
+        }
+
+        let mut gc = GC::new();
+        let (chunk, _) = crate::compiler::compile(&String::from("print 1;"), &mut gc).unwrap();
+        let mut offsets = Offsets(Vec::new(), Vec::new());
+        let mut output = io::sink();
+        let mut input = io::empty();
+        let mut vm = VM::new(
+            VMConfig {
+                trace_instructions: false,
+                trace_stack: false,
+                trace_globals: false,
+                stdout: &mut output,
+                stdin: &mut input,
+                sandbox: Sandbox::default(),
+            },
+            &mut gc,
+        );
+        vm.set_script_path("main.lox");
+        vm.set_hook(&mut offsets);
+        assert_eq!(vm.interpret_chunk(chunk), Ok(Nil));
+        drop(vm);
+        // Constant, Print, Return
+        let script = Some(PathBuf::from("main.lox"));
+        assert_eq!(
+            offsets.0,
+            vec![(script.clone(), 0), (script.clone(), 2), (script, 3)]
+        );
+        assert_eq!(offsets.1, vec![0, 1, 0]);
+    }
+
+    #[test]
+    fn samples_every_interval() {
+        struct Samples(Vec<Vec<(Option<PathBuf>, Option<LineNumber>)>>);
+
+        impl VmHook for Samples {
+            fn before_instruction(&mut self, _: Option<&Path>, _: &Chunk, _: usize) {}
+
+            fn sample_interval(&self) -> u64 {
+                return 2;
+            }
+
+            fn sample(&mut self, frames: &[Frame]) {
+                let frames = frames
+                    .iter()
+                    .map(|frame| (frame.script.map(Path::to_path_buf), frame.line))
+                    .collect();
+                self.0.push(frames);
+            }
+        }
+
+        let mut gc = GC::new();
+        let source = String::from("print 1;\nprint 2;");
+        let (chunk, _) = crate::compiler::compile(&source, &mut gc).unwrap();
+        let mut samples = Samples(Vec::new());
+        let mut output = io::sink();
+        let mut input = io::empty();
+        let mut vm = VM::new(
+            VMConfig {
+                trace_instructions: false,
+                trace_stack: false,
+                trace_globals: false,
+                stdout: &mut output,
+                stdin: &mut input,
+                sandbox: Sandbox::default(),
+            },
+            &mut gc,
+        );
+        vm.set_script_path("main.lox");
+        vm.set_hook(&mut samples);
+        assert_eq!(vm.interpret_chunk(chunk), Ok(Nil));
+        drop(vm);
+        // Constant, Print, Constant, Print, Return
+        let script = Some(PathBuf::from("main.lox"));
+        assert_eq!(
+            samples.0,
+            vec![
+                vec![(script.clone(), Some(1))],
+                vec![(script.clone(), Some(2))],
+                vec![(script, Some(2))],
+            ]
+        );
+    }
+
+    #[test]
+    fn reports_allocations_by_line() {
+        struct Allocations(Vec<(Option<LineNumber>, Option<String>)>);
+
+        impl VmHook for Allocations {
+            fn before_instruction(&mut self, _: Option<&Path>, _: &Chunk, _: usize) {}
+
+            fn allocated(&mut self, _: Option<&Path>, line: Option<LineNumber>, object: &Obj) {
+                let string = object.as_string().map(|s| s.get_value().clone());
+                self.0.push((line, string));
+            }
+        }
+
+        let mut gc = GC::new();
+        let source = String::from("var a = \"x\" + \"y\";\nprint a;\nprint a + \"!\";");
+        let (chunk, _) = crate::compiler::compile(&source, &mut gc).unwrap();
+        let mut allocations = Allocations(Vec::new());
+        let mut output = io::sink();
+        let mut input = io::empty();
+        let mut vm = VM::new(
+            VMConfig {
+                trace_instructions: false,
+                trace_stack: false,
+                trace_globals: false,
+                stdout: &mut output,
+                stdin: &mut input,
+                sandbox: Sandbox::default(),
+            },
+            &mut gc,
+        );
+        vm.set_hook(&mut allocations);
+        // Made before the chunk runs, so not reported
+        vm.define_global("b", Nil);
+        assert_eq!(vm.interpret_chunk(chunk), Ok(Nil));
+        drop(vm);
+        assert_eq!(
+            allocations.0,
+            vec![
+                (Some(1), Some(String::from("xy"))),
+                (Some(3), Some(String::from("xy!")))
+            ]
+        );
+    }
+
+    #[test]
+    fn invalid_constants() {
+        let mut chunk = Chunk::new();
+        chunk.write_opcode(Constant, 1);
+        chunk.write_byte(0, 1);
+        chunk.write_opcode(Return, 1);
+        let (result, _) = run_chunk!(chunk);
+        assert_eq!(
+            result,
+            Err(RuntimeError(String::from(
+                "[line 1] Invalid constant reference: 0"
+            )))
+        );
+
+        let mut chunk = Chunk::new();
+        let const_ref = chunk.add_const(Number(1.0));
+        chunk.ref_const(const_ref, ConstOp::Get, 1);
+        chunk.write_opcode(Return, 1);
+        let (result, _) = run_chunk!(chunk);
+        assert_eq!(
+            result,
+            Err(RuntimeError(String::from(
+                "[line 1] Expected string as name, got 1"
+            )))
+        );
+    }
+
     #[test]
     fn return_wo_constant() {
         let mut chunk = Chunk::new();
@@ -427,11 +1687,13 @@ mod tests {
     fn constant_wo_return() {
         let mut chunk = Chunk::new();
         let const_ref = chunk.add_const(Number(1.2));
-        chunk.ref_const(const_ref, OpCode::Constant, OpCode::ConstantLong, 1);
+        chunk.ref_const(const_ref, ConstOp::Constant, 1);
         let (result, _) = run_chunk!(chunk);
         assert_eq!(
             result,
-            Err(RuntimeError(String::from("Read byte out of bounds")))
+            Err(RuntimeError(String::from(
+                "[line 1] Read byte out of bounds"
+            )))
         );
     }
 
@@ -440,17 +1702,18 @@ mod tests {
         let mut chunk = Chunk::new();
         for i in 0..257 {
             let const_ref = chunk.add_const(Number(i as f32));
-            chunk.ref_const(const_ref, OpCode::Constant, OpCode::ConstantLong, i);
+            chunk.ref_const(const_ref, ConstOp::Constant, i);
         }
-        let (result, _) = run_chunk!(chunk);
+        let (result, output) = run_chunk!(chunk);
         assert_eq!(result, Err(RuntimeError(String::from("Stack overflow"))));
+        assert_eq!(output, "");
     }
 
     #[test]
     fn return_w_number_constant() {
         let mut chunk = Chunk::new();
         let const_ref = chunk.add_const(Number(1.2));
-        chunk.ref_const(const_ref, OpCode::Constant, OpCode::ConstantLong, 1);
+        chunk.ref_const(const_ref, ConstOp::Constant, 1);
         chunk.write_opcode(Return, 2);
         let (result, _) = run_chunk!(chunk);
         assert_eq!(result, Ok(Number(1.2)));
@@ -479,7 +1742,7 @@ mod tests {
         let mut gc = GC::new();
         let mut chunk = Chunk::new();
         let const_ref = chunk.add_const(Value::Object(gc.alloc_string("hello world".to_string())));
-        chunk.ref_const(const_ref, Constant, ConstantLong, 1);
+        chunk.ref_const(const_ref, ConstOp::Constant, 1);
         chunk.write_opcode(Return, 2);
         let (result, _) = run_chunk_with_gc!(chunk, gc);
         match result {
@@ -493,7 +1756,7 @@ mod tests {
         let mut chunk = Chunk::new();
         for i in 0..256 {
             let const_ref = chunk.add_const(Number(i as f32));
-            chunk.ref_const(const_ref, OpCode::Constant, OpCode::ConstantLong, i);
+            chunk.ref_const(const_ref, ConstOp::Constant, i);
         }
         chunk.write_opcode(Return, 256);
         let (result, _) = run_chunk!(chunk);
@@ -504,9 +1767,9 @@ mod tests {
     fn add_numbers() {
         let mut chunk = Chunk::new();
         let const_ref = chunk.add_const(Number(1.0));
-        chunk.ref_const(const_ref, OpCode::Constant, OpCode::ConstantLong, 1);
+        chunk.ref_const(const_ref, ConstOp::Constant, 1);
         let const_ref = chunk.add_const(Number(1.0));
-        chunk.ref_const(const_ref, OpCode::Constant, OpCode::ConstantLong, 2);
+        chunk.ref_const(const_ref, ConstOp::Constant, 2);
         chunk.write_opcode(Add, 3);
         chunk.write_opcode(Return, 4);
         let (result, _) = run_chunk!(chunk);
@@ -517,16 +1780,16 @@ mod tests {
     fn add_number_nil() {
         let mut chunk = Chunk::new();
         let const_ref = chunk.add_const(Nil);
-        chunk.ref_const(const_ref, OpCode::Constant, OpCode::ConstantLong, 1);
+        chunk.ref_const(const_ref, ConstOp::Constant, 1);
         let const_ref = chunk.add_const(Number(1.0));
-        chunk.ref_const(const_ref, OpCode::Constant, OpCode::ConstantLong, 2);
+        chunk.ref_const(const_ref, ConstOp::Constant, 2);
         chunk.write_opcode(Add, 3);
         chunk.write_opcode(Return, 4);
         let (result, _) = run_chunk!(chunk);
         assert_eq!(
             result,
             Err(RuntimeError(String::from(
-                "Invalid type for addition: nil 1"
+                "[line 3] Invalid type for addition: nil 1"
             )))
         );
     }
@@ -535,9 +1798,9 @@ mod tests {
         let mut gc = GC::new();
         let mut chunk = Chunk::new();
         let const_ref = chunk.add_const(Value::Object(gc.alloc_string("hello".to_string())));
-        chunk.ref_const(const_ref, Constant, ConstantLong, 1);
+        chunk.ref_const(const_ref, ConstOp::Constant, 1);
         let const_ref = chunk.add_const(Value::Object(gc.alloc_string("world".to_string())));
-        chunk.ref_const(const_ref, Constant, ConstantLong, 2);
+        chunk.ref_const(const_ref, ConstOp::Constant, 2);
         chunk.write_opcode(Add, 3);
         chunk.write_opcode(Return, 4);
         println!("Running");
@@ -553,9 +1816,9 @@ mod tests {
     fn subtract_numbers() {
         let mut chunk = Chunk::new();
         let const_ref = chunk.add_const(Number(1.2));
-        chunk.ref_const(const_ref, OpCode::Constant, OpCode::ConstantLong, 1);
+        chunk.ref_const(const_ref, ConstOp::Constant, 1);
         let const_ref = chunk.add_const(Number(3.4));
-        chunk.ref_const(const_ref, OpCode::Constant, OpCode::ConstantLong, 2);
+        chunk.ref_const(const_ref, ConstOp::Constant, 2);
         chunk.write_opcode(Subtract, 3);
         chunk.write_opcode(Return, 4);
         let (result, _) = run_chunk!(chunk);
@@ -566,16 +1829,16 @@ mod tests {
     fn subtract_number_nil() {
         let mut chunk = Chunk::new();
         let const_ref = chunk.add_const(Nil);
-        chunk.ref_const(const_ref, OpCode::Constant, OpCode::ConstantLong, 1);
+        chunk.ref_const(const_ref, ConstOp::Constant, 1);
         let const_ref = chunk.add_const(Number(1.0));
-        chunk.ref_const(const_ref, OpCode::Constant, OpCode::ConstantLong, 2);
+        chunk.ref_const(const_ref, ConstOp::Constant, 2);
         chunk.write_opcode(Subtract, 3);
         chunk.write_opcode(Return, 4);
         let (result, _) = run_chunk!(chunk);
         assert_eq!(
             result,
             Err(RuntimeError(String::from(
-                "Invalid type for subtraction: nil 1"
+                "[line 3] Invalid type for subtraction: nil 1"
             )))
         );
     }
@@ -584,9 +1847,9 @@ mod tests {
     fn multiply_numbers() {
         let mut chunk = Chunk::new();
         let const_ref = chunk.add_const(Number(3.0));
-        chunk.ref_const(const_ref, OpCode::Constant, OpCode::ConstantLong, 1);
+        chunk.ref_const(const_ref, ConstOp::Constant, 1);
         let const_ref = chunk.add_const(Number(-0.5));
-        chunk.ref_const(const_ref, OpCode::Constant, OpCode::ConstantLong, 2);
+        chunk.ref_const(const_ref, ConstOp::Constant, 2);
         chunk.write_opcode(Multiply, 3);
         chunk.write_opcode(Return, 4);
         let (result, _) = run_chunk!(chunk);
@@ -597,16 +1860,16 @@ mod tests {
     fn multiply_number_nil() {
         let mut chunk = Chunk::new();
         let const_ref = chunk.add_const(Nil);
-        chunk.ref_const(const_ref, OpCode::Constant, OpCode::ConstantLong, 1);
+        chunk.ref_const(const_ref, ConstOp::Constant, 1);
         let const_ref = chunk.add_const(Number(1.0));
-        chunk.ref_const(const_ref, OpCode::Constant, OpCode::ConstantLong, 2);
+        chunk.ref_const(const_ref, ConstOp::Constant, 2);
         chunk.write_opcode(Multiply, 3);
         chunk.write_opcode(Return, 4);
         let (result, _) = run_chunk!(chunk);
         assert_eq!(
             result,
             Err(RuntimeError(String::from(
-                "Invalid type for multiplication: nil 1"
+                "[line 3] Invalid type for multiplication: nil 1"
             )))
         );
     }
@@ -615,9 +1878,9 @@ mod tests {
     fn divide_numbers() {
         let mut chunk = Chunk::new();
         let const_ref = chunk.add_const(Number(10.0));
-        chunk.ref_const(const_ref, OpCode::Constant, OpCode::ConstantLong, 1);
+        chunk.ref_const(const_ref, ConstOp::Constant, 1);
         let const_ref = chunk.add_const(Number(2.0));
-        chunk.ref_const(const_ref, OpCode::Constant, OpCode::ConstantLong, 2);
+        chunk.ref_const(const_ref, ConstOp::Constant, 2);
         chunk.write_opcode(Divide, 3);
         chunk.write_opcode(Return, 4);
         let (result, _) = run_chunk!(chunk);
@@ -628,16 +1891,16 @@ mod tests {
     fn divide_number_nil() {
         let mut chunk = Chunk::new();
         let const_ref = chunk.add_const(Nil);
-        chunk.ref_const(const_ref, OpCode::Constant, OpCode::ConstantLong, 1);
+        chunk.ref_const(const_ref, ConstOp::Constant, 1);
         let const_ref = chunk.add_const(Number(1.0));
-        chunk.ref_const(const_ref, OpCode::Constant, OpCode::ConstantLong, 2);
+        chunk.ref_const(const_ref, ConstOp::Constant, 2);
         chunk.write_opcode(Divide, 3);
         chunk.write_opcode(Return, 4);
         let (result, _) = run_chunk!(chunk);
         assert_eq!(
             result,
             Err(RuntimeError(String::from(
-                "Invalid type for division: nil 1"
+                "[line 3] Invalid type for division: nil 1"
             )))
         );
     }
@@ -646,7 +1909,7 @@ mod tests {
     fn negate_number() {
         let mut chunk = Chunk::new();
         let const_ref = chunk.add_const(Number(1.2));
-        chunk.ref_const(const_ref, OpCode::Constant, OpCode::ConstantLong, 1);
+        chunk.ref_const(const_ref, ConstOp::Constant, 1);
         chunk.write_opcode(Negate, 2);
         chunk.write_opcode(Return, 3);
         let (result, output) = run_chunk!(chunk);
@@ -658,13 +1921,15 @@ mod tests {
     fn negate_nil() {
         let mut chunk = Chunk::new();
         let const_ref = chunk.add_const(Nil);
-        chunk.ref_const(const_ref, OpCode::Constant, OpCode::ConstantLong, 1);
+        chunk.ref_const(const_ref, ConstOp::Constant, 1);
         chunk.write_opcode(Negate, 2);
         chunk.write_opcode(Return, 3);
         let (result, _) = run_chunk!(chunk);
         assert_eq!(
             result,
-            Err(RuntimeError(String::from("Invalid type for negation: nil")))
+            Err(RuntimeError(String::from(
+                "[line 2] Invalid type for negation: nil"
+            )))
         );
     }
 
@@ -672,7 +1937,7 @@ mod tests {
     fn not_true() {
         let mut chunk = Chunk::new();
         let const_ref = chunk.add_const(Boolean(true));
-        chunk.ref_const(const_ref, OpCode::Constant, OpCode::ConstantLong, 1);
+        chunk.ref_const(const_ref, ConstOp::Constant, 1);
         chunk.write_opcode(Not, 2);
         chunk.write_opcode(Return, 3);
         let (result, output) = run_chunk!(chunk);
@@ -684,7 +1949,7 @@ mod tests {
     fn not_nil() {
         let mut chunk = Chunk::new();
         let const_ref = chunk.add_const(Nil);
-        chunk.ref_const(const_ref, OpCode::Constant, OpCode::ConstantLong, 1);
+        chunk.ref_const(const_ref, ConstOp::Constant, 1);
         chunk.write_opcode(Not, 2);
         chunk.write_opcode(Return, 3);
         let (result, output) = run_chunk!(chunk);
@@ -696,7 +1961,7 @@ mod tests {
     fn not_zero() {
         let mut chunk = Chunk::new();
         let const_ref = chunk.add_const(Number(0.0));
-        chunk.ref_const(const_ref, OpCode::Constant, OpCode::ConstantLong, 1);
+        chunk.ref_const(const_ref, ConstOp::Constant, 1);
         chunk.write_opcode(Not, 2);
         chunk.write_opcode(Return, 3);
         let (result, output) = run_chunk!(chunk);
@@ -708,7 +1973,7 @@ mod tests {
     fn not_one() {
         let mut chunk = Chunk::new();
         let const_ref = chunk.add_const(Number(1.0));
-        chunk.ref_const(const_ref, OpCode::Constant, OpCode::ConstantLong, 1);
+        chunk.ref_const(const_ref, ConstOp::Constant, 1);
         chunk.write_opcode(Not, 2);
         chunk.write_opcode(Return, 3);
         let (result, output) = run_chunk!(chunk);
@@ -720,9 +1985,9 @@ mod tests {
     fn equal_true() {
         let mut chunk = Chunk::new();
         let const_ref = chunk.add_const(Number(1.0));
-        chunk.ref_const(const_ref, OpCode::Constant, OpCode::ConstantLong, 1);
+        chunk.ref_const(const_ref, ConstOp::Constant, 1);
         let const_ref = chunk.add_const(Number(1.0));
-        chunk.ref_const(const_ref, OpCode::Constant, OpCode::ConstantLong, 2);
+        chunk.ref_const(const_ref, ConstOp::Constant, 2);
         chunk.write_opcode(Equal, 3);
         chunk.write_opcode(Return, 4);
         let (result, output) = run_chunk!(chunk);
@@ -734,9 +1999,9 @@ mod tests {
     fn equal_false() {
         let mut chunk = Chunk::new();
         let const_ref = chunk.add_const(Number(1.0));
-        chunk.ref_const(const_ref, OpCode::Constant, OpCode::ConstantLong, 1);
+        chunk.ref_const(const_ref, ConstOp::Constant, 1);
         let const_ref = chunk.add_const(Number(2.0));
-        chunk.ref_const(const_ref, OpCode::Constant, OpCode::ConstantLong, 2);
+        chunk.ref_const(const_ref, ConstOp::Constant, 2);
         chunk.write_opcode(Equal, 3);
         chunk.write_opcode(Return, 4);
         let (result, output) = run_chunk!(chunk);
@@ -748,9 +2013,9 @@ mod tests {
     fn greater_true() {
         let mut chunk = Chunk::new();
         let const_ref = chunk.add_const(Number(2.0));
-        chunk.ref_const(const_ref, OpCode::Constant, OpCode::ConstantLong, 1);
+        chunk.ref_const(const_ref, ConstOp::Constant, 1);
         let const_ref = chunk.add_const(Number(1.0));
-        chunk.ref_const(const_ref, OpCode::Constant, OpCode::ConstantLong, 2);
+        chunk.ref_const(const_ref, ConstOp::Constant, 2);
         chunk.write_opcode(Greater, 3);
         chunk.write_opcode(Return, 4);
         let (result, output) = run_chunk!(chunk);
@@ -762,9 +2027,9 @@ mod tests {
     fn greater_false() {
         let mut chunk = Chunk::new();
         let const_ref = chunk.add_const(Number(1.0));
-        chunk.ref_const(const_ref, OpCode::Constant, OpCode::ConstantLong, 1);
+        chunk.ref_const(const_ref, ConstOp::Constant, 1);
         let const_ref = chunk.add_const(Number(1.0));
-        chunk.ref_const(const_ref, OpCode::Constant, OpCode::ConstantLong, 2);
+        chunk.ref_const(const_ref, ConstOp::Constant, 2);
         chunk.write_opcode(Greater, 3);
         chunk.write_opcode(Return, 4);
         let (result, output) = run_chunk!(chunk);
@@ -776,9 +2041,9 @@ mod tests {
     fn less_true() {
         let mut chunk = Chunk::new();
         let const_ref = chunk.add_const(Number(1.0));
-        chunk.ref_const(const_ref, OpCode::Constant, OpCode::ConstantLong, 1);
+        chunk.ref_const(const_ref, ConstOp::Constant, 1);
         let const_ref = chunk.add_const(Number(2.0));
-        chunk.ref_const(const_ref, OpCode::Constant, OpCode::ConstantLong, 2);
+        chunk.ref_const(const_ref, ConstOp::Constant, 2);
         chunk.write_opcode(Less, 3);
         chunk.write_opcode(Return, 4);
         let (result, output) = run_chunk!(chunk);
@@ -790,9 +2055,9 @@ mod tests {
     fn less_false() {
         let mut chunk = Chunk::new();
         let const_ref = chunk.add_const(Number(1.0));
-        chunk.ref_const(const_ref, OpCode::Constant, OpCode::ConstantLong, 1);
+        chunk.ref_const(const_ref, ConstOp::Constant, 1);
         let const_ref = chunk.add_const(Number(1.0));
-        chunk.ref_const(const_ref, OpCode::Constant, OpCode::ConstantLong, 2);
+        chunk.ref_const(const_ref, ConstOp::Constant, 2);
         chunk.write_opcode(Less, 3);
         chunk.write_opcode(Return, 4);
         let (result, output) = run_chunk!(chunk);
@@ -804,12 +2069,12 @@ mod tests {
     fn simple_operations() {
         let mut chunk = Chunk::new();
         let const_ref = chunk.add_const(Number(1.2));
-        chunk.ref_const(const_ref, OpCode::Constant, OpCode::ConstantLong, 1);
+        chunk.ref_const(const_ref, ConstOp::Constant, 1);
         let const_ref = chunk.add_const(Number(3.4));
-        chunk.ref_const(const_ref, OpCode::Constant, OpCode::ConstantLong, 2);
+        chunk.ref_const(const_ref, ConstOp::Constant, 2);
         chunk.write_opcode(Add, 1);
         let const_ref = chunk.add_const(Number(5.6));
-        chunk.ref_const(const_ref, OpCode::Constant, OpCode::ConstantLong, 3);
+        chunk.ref_const(const_ref, ConstOp::Constant, 3);
         chunk.write_opcode(Divide, 1);
         chunk.write_opcode(Negate, 1);
         chunk.write_opcode(Return, 1);
@@ -821,7 +2086,7 @@ mod tests {
     fn simple_print_number() {
         let mut chunk = Chunk::new();
         let const_ref = chunk.add_const(Number(1.2));
-        chunk.ref_const(const_ref, OpCode::Constant, OpCode::ConstantLong, 1);
+        chunk.ref_const(const_ref, ConstOp::Constant, 1);
         chunk.write_opcode(Print, 1);
         chunk.write_opcode(Return, 2);
         let (result, output) = run_chunk!(chunk);
@@ -834,7 +2099,7 @@ mod tests {
         let mut gc = GC::new();
         let mut chunk = Chunk::new();
         let const_ref = chunk.add_const(Value::Object(gc.alloc_string("hello world".to_string())));
-        chunk.ref_const(const_ref, OpCode::Constant, OpCode::ConstantLong, 1);
+        chunk.ref_const(const_ref, ConstOp::Constant, 1);
         chunk.write_opcode(Print, 1);
         chunk.write_opcode(Return, 2);
         let (result, output) = run_chunk_with_gc!(chunk, gc);
@@ -846,9 +2111,9 @@ mod tests {
     fn simple_expression_statement() {
         let mut chunk = Chunk::new();
         let const_ref = chunk.add_const(Number(1.2));
-        chunk.ref_const(const_ref, OpCode::Constant, OpCode::ConstantLong, 1);
+        chunk.ref_const(const_ref, ConstOp::Constant, 1);
         let const_ref = chunk.add_const(Number(3.4));
-        chunk.ref_const(const_ref, OpCode::Constant, OpCode::ConstantLong, 2);
+        chunk.ref_const(const_ref, ConstOp::Constant, 2);
         chunk.write_opcode(Add, 1);
         chunk.write_opcode(Pop, 2);
         chunk.write_opcode(Return, 3);
@@ -861,9 +2126,9 @@ mod tests {
         let mut gc = GC::new();
         let mut chunk = Chunk::new();
         let const_ref = chunk.add_const(Number(1.2));
-        chunk.ref_const(const_ref, OpCode::Constant, OpCode::ConstantLong, 1);
+        chunk.ref_const(const_ref, ConstOp::Constant, 1);
         let const_ref = chunk.add_const(Value::Object(gc.alloc_string("x".to_string())));
-        chunk.ref_const(const_ref, OpCode::DefineGlobal, OpCode::DefineGlobalLong, 1);
+        chunk.ref_const(const_ref, ConstOp::DefineGlobal, 1);
         chunk.write_opcode(Return, 2);
         let (result, _) = run_chunk_with_gc!(chunk, gc);
         assert_eq!(result, Ok(Nil));
@@ -874,13 +2139,578 @@ mod tests {
         let mut gc = GC::new();
         let mut chunk = Chunk::new();
         let const_ref = chunk.add_const(Number(1.2));
-        chunk.ref_const(const_ref, OpCode::Constant, OpCode::ConstantLong, 1);
+        chunk.ref_const(const_ref, ConstOp::Constant, 1);
         let const_ref = chunk.add_const(Value::Object(gc.alloc_string("x".to_string())));
-        chunk.ref_const(const_ref, OpCode::DefineGlobal, OpCode::DefineGlobalLong, 1);
+        chunk.ref_const(const_ref, ConstOp::DefineGlobal, 1);
         let const_ref = chunk.add_const(Value::Object(gc.alloc_string("x".to_string())));
-        chunk.ref_const(const_ref, OpCode::Get, OpCode::GetLong, 1);
+        chunk.ref_const(const_ref, ConstOp::Get, 1);
         chunk.write_opcode(Return, 2);
         let (result, _) = run_chunk_with_gc!(chunk, gc);
         assert_eq!(result, Ok(Number(1.2)));
     }
+
+    /// Runs `source` with its globals resolved to slots, after calling
+    /// `before` on the loaded VM. Returns the globals that weren't there
+    /// before, such as the standard library's.
+    fn run_with_slots(
+        source: &str,
+        before: impl FnOnce(&mut VM),
+    ) -> (
+        Result<Value, InterpreterError>,
+        String,
+        Vec<(String, Value)>,
+    ) {
+        let mut gc = GC::new();
+        let mut output = String::new();
+        let mut adapter = StdoutAdapter { f: &mut output };
+        let mut input = io::empty();
+        let mut vm = VM::new(
+            VMConfig {
+                trace_instructions: false,
+                trace_stack: false,
+                trace_globals: false,
+                stdout: &mut adapter,
+                stdin: &mut input,
+                sandbox: Sandbox::default(),
+            },
+            &mut gc,
+        );
+        crate::stdlib::install(&mut vm);
+        let predefined = vm.globals().iter().count();
+        let source = String::from(source);
+        let (mut chunk, _) = vm
+            .with_gc(|gc| crate::compiler::compile(&source, gc))
+            .unwrap();
+        chunk.resolve_global_slots().unwrap();
+        let result = vm.load_chunk(chunk).and_then(|_| {
+            before(&mut vm);
+            vm.run()
+        });
+        let globals = vm
+            .globals()
+            .iter()
+            .skip(predefined)
+            .map(|(name, value)| (name.get_value().clone(), *value))
+            .collect();
+        drop(vm);
+        return (result, output, globals);
+    }
+
+    #[test]
+    fn runs_globals_in_slots() {
+        let (result, output, globals) = run_with_slots(
+            "var b = 1; var a = b + len(\"xy\"); var b = a; print b;",
+            |_| {},
+        );
+        assert_eq!(result, Ok(Nil));
+        assert_eq!(output, "3\n");
+        // Defined in the order the script defined them
+        assert_eq!(
+            globals,
+            vec![(String::from("b"), Int(3)), (String::from("a"), Int(3))]
+        );
+    }
+
+    #[test]
+    fn binds_global_slots_late() {
+        // Defined after the chunk was loaded, so its slot starts out empty
+        let (result, output, _) =
+            run_with_slots("print late;", |vm| vm.define_global("late", Int(1)));
+        assert_eq!(result, Ok(Nil));
+        assert_eq!(output, "1\n");
+    }
+
+    #[test]
+    fn syncs_global_slots_on_errors() {
+        let (result, _, globals) = run_with_slots("var a = 1;\nprint missing;", |_| {});
+        assert_eq!(
+            result,
+            Err(RuntimeError(String::from(
+                "[line 2] Undefined variable 'missing'"
+            )))
+        );
+        assert_eq!(globals, vec![(String::from("a"), Int(1))]);
+    }
+
+    #[test]
+    fn step_and_breakpoints() {
+        let mut chunk = Chunk::new();
+        for line in 1..4 {
+            let const_ref = chunk.add_const(Number(line as f32));
+            chunk.ref_const(const_ref, ConstOp::Constant, line);
+            chunk.write_opcode(Pop, line);
+        }
+        chunk.write_opcode(Return, 4);
+        let mut gc = GC::new();
+        let mut output = Vec::new();
+        let mut input = io::empty();
+        let mut vm = VM::new(
+            VMConfig {
+                trace_instructions: false,
+                trace_stack: false,
+                trace_globals: false,
+                stdout: &mut output,
+                stdin: &mut input,
+                sandbox: Sandbox::default(),
+            },
+            &mut gc,
+        );
+        vm.load_chunk(chunk).unwrap();
+        assert_eq!(vm.current_line(), Some(1));
+        assert_eq!(vm.step(), Ok(None));
+        assert_eq!(vm.stack(), &[Number(1.0)]);
+        assert_eq!(vm.instruction_count(), 1);
+        vm.set_breakpoint(3);
+        assert_eq!(vm.resume(), Ok(RunOutcome::Breakpoint(3)));
+        assert_eq!(vm.ip(), 6);
+        assert_eq!(vm.stack(), &[]);
+        assert_eq!(vm.resume(), Ok(RunOutcome::Finished(Nil)));
+    }
+
+    #[test]
+    fn call_native() {
+        let mut gc = GC::new();
+        let chunk = crate::compiler::compile(
+            &String::from("print add(1, 2); print add(\"a\", 2);"),
+            &mut gc,
+        )
+        .unwrap()
+        .0;
+        let mut output = String::new();
+        let mut adapter = StdoutAdapter { f: &mut output };
+        let mut input = io::empty();
+        let mut vm = VM::new(
+            VMConfig {
+                trace_instructions: false,
+                trace_stack: false,
+                trace_globals: false,
+                stdout: &mut adapter,
+                stdin: &mut input,
+                sandbox: Sandbox::default(),
+            },
+            &mut gc,
+        );
+        vm.register_fn("add", |a: f64, b: f64| a + b);
+        let result = vm.interpret_chunk(chunk);
+        assert_eq!(
+            result,
+            Err(RuntimeError(String::from(
+                "[line 1] Expected a number, got \"a\""
+            )))
+        );
+        assert_eq!(output, "3\n");
+    }
+
+    #[test]
+    fn call_errors() {
+        let mut chunk = Chunk::new();
+        let const_ref = chunk.add_const(Number(1.0));
+        chunk.ref_const(const_ref, ConstOp::Constant, 1);
+        chunk.write_opcode(Call, 1);
+        chunk.write_byte(0, 1);
+        let (result, _) = run_chunk!(chunk);
+        assert_eq!(
+            result,
+            Err(RuntimeError(String::from(
+                "[line 1] Can only call functions, got 1"
+            )))
+        );
+
+        let mut gc = GC::new();
+        let mut output = Vec::new();
+        let mut input = io::empty();
+        let mut vm = VM::new(
+            VMConfig {
+                trace_instructions: false,
+                trace_stack: false,
+                trace_globals: false,
+                stdout: &mut output,
+                stdin: &mut input,
+                sandbox: Sandbox::default(),
+            },
+            &mut gc,
+        );
+        vm.register_native("zero", 0, |_, _| Ok(Nil));
+        let chunk = vm
+            .with_gc(|gc| crate::compiler::compile(&String::from("zero(1);"), gc))
+            .unwrap()
+            .0;
+        assert_eq!(
+            vm.interpret_chunk(chunk),
+            Err(RuntimeError(String::from(
+                "[line 1] zero expects 0 arguments but got 1"
+            )))
+        );
+    }
+
+    #[test]
+    fn runs_on_another_thread() {
+        let mut gc = GC::new();
+        let mut output = Vec::new();
+        let chunk = crate::compiler::compile(&String::from("print \"moved\";"), &mut gc)
+            .unwrap()
+            .0;
+        let mut input = io::empty();
+        let mut vm = VM::new(
+            VMConfig {
+                trace_instructions: false,
+                trace_stack: false,
+                trace_globals: false,
+                stdout: &mut output,
+                stdin: &mut input,
+                sandbox: Sandbox::default(),
+            },
+            &mut gc,
+        );
+        // Values can't cross threads on their own, so the chunk is loaded
+        // first and the result is converted before it is sent back.
+        vm.load_chunk(chunk).unwrap();
+        let result = std::thread::scope(|scope| {
+            scope
+                .spawn(move || vm.run().map(|value| value.to_string()))
+                .join()
+                .unwrap()
+        });
+        assert_eq!(result, Ok(String::from("nil")));
+        assert_eq!(output, b"\"moved\"\n");
+    }
+
+    #[test]
+    fn suspends_and_resumes() {
+        use crate::native::Pending;
+        use std::sync::Mutex;
+
+        let requests = Arc::new(Mutex::new(Vec::new()));
+        let mut gc = GC::new();
+        let mut output = String::new();
+        let mut adapter = StdoutAdapter { f: &mut output };
+        let mut input = io::empty();
+        let mut vm = VM::new(
+            VMConfig {
+                trace_instructions: false,
+                trace_stack: false,
+                trace_globals: false,
+                stdout: &mut adapter,
+                stdin: &mut input,
+                sandbox: Sandbox::default(),
+            },
+            &mut gc,
+        );
+        let pending = Arc::clone(&requests);
+        vm.register_fn("fetch", move |url: String| {
+            pending.lock().unwrap().push(url);
+            Pending
+        });
+        let chunk = vm
+            .with_gc(|gc| {
+                crate::compiler::compile(&String::from("print fetch(\"a\") + fetch(\"b\");"), gc)
+            })
+            .unwrap()
+            .0;
+        vm.load_chunk(chunk).unwrap();
+        assert_eq!(vm.resume(), Ok(RunOutcome::Suspended));
+        assert!(vm.is_suspended());
+        let a = vm.with_gc(|gc| Value::Object(gc.alloc_string(String::from("1"))));
+        assert_eq!(vm.resume_with(a), Ok(RunOutcome::Suspended));
+        let b = vm.with_gc(|gc| Value::Object(gc.alloc_string(String::from("2"))));
+        assert_eq!(vm.resume_with(b), Ok(RunOutcome::Finished(Nil)));
+        assert_eq!(
+            vm.resume_with(Nil),
+            Err(RuntimeError(String::from("VM is not suspended")))
+        );
+        assert_eq!(*requests.lock().unwrap(), vec!["a", "b"]);
+
+        let chunk = vm
+            .with_gc(|gc| crate::compiler::compile(&String::from("fetch(\"c\");"), gc))
+            .unwrap()
+            .0;
+        assert_eq!(
+            vm.interpret_chunk(chunk),
+            Err(RuntimeError(String::from(
+                "Execution can only be suspended with resume"
+            )))
+        );
+        drop(vm);
+        assert_eq!(output, "\"12\"\n");
+    }
+
+    #[test]
+    fn failed_suspend() {
+        let mut gc = GC::new();
+        let mut output = String::new();
+        let mut adapter = StdoutAdapter { f: &mut output };
+        let mut input = io::empty();
+        let mut vm = VM::new(
+            VMConfig {
+                trace_instructions: false,
+                trace_stack: false,
+                trace_globals: false,
+                stdout: &mut adapter,
+                stdin: &mut input,
+                sandbox: Sandbox::default(),
+            },
+            &mut gc,
+        );
+        vm.register_native("fail", 0, |vm, _| {
+            vm.suspend();
+            Err(String::from("failed"))
+        });
+        let chunk = vm
+            .with_gc(|gc| crate::compiler::compile(&String::from("fail();"), gc))
+            .unwrap()
+            .0;
+        vm.load_chunk(chunk).unwrap();
+        assert_eq!(
+            vm.resume(),
+            Err(RuntimeError(String::from("[line 1] failed")))
+        );
+        assert!(!vm.is_suspended());
+        assert_eq!(
+            vm.resume_with(Nil),
+            Err(RuntimeError(String::from("VM is not suspended")))
+        );
+    }
+
+    #[test]
+    fn sandbox_capabilities() {
+        let mut gc = GC::new();
+        let mut output = Vec::new();
+        let mut input = io::empty();
+        let mut vm = VM::new(
+            VMConfig {
+                trace_instructions: false,
+                trace_stack: false,
+                trace_globals: false,
+                stdout: &mut output,
+                stdin: &mut input,
+                sandbox: Sandbox::default(),
+            },
+            &mut gc,
+        );
+        assert!(vm.register_native_requiring(Capability::Clock, "clock", 0, |_, _| Ok(Number(1.0))));
+        vm.config_mut().sandbox = Sandbox::untrusted();
+        assert!(!vm.register_native_requiring(Capability::Io, "read", 0, |_, _| Ok(Nil)));
+        let chunk = vm
+            .with_gc(|gc| crate::compiler::compile(&String::from("clock();"), gc))
+            .unwrap()
+            .0;
+        assert_eq!(
+            vm.interpret_chunk(chunk),
+            Err(RuntimeError(String::from(
+                "[line 1] clock needs clock, which is not allowed here"
+            )))
+        );
+        let chunk = vm
+            .with_gc(|gc| crate::compiler::compile(&String::from("read();"), gc))
+            .unwrap()
+            .0;
+        assert_eq!(
+            vm.interpret_chunk(chunk),
+            Err(RuntimeError(String::from(
+                "[line 1] Undefined variable 'read'"
+            )))
+        );
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore = "uses the file system")]
+    fn imports_modules() {
+        let dir = std::env::temp_dir().join(format!("rs-lox-modules-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let module = dir.join("counter.lox");
+        let broken = dir.join("broken.lox");
+        fs::write(&module, "print \"loaded\"; var start = base + 1;").unwrap();
+        fs::write(&broken, "var = 1;").unwrap();
+        let source = format!(
+            "var base = 10; import \"{0}\"; import {{ start }} from \"{0}\"; print start;",
+            module.display()
+        );
+
+        let mut gc = GC::new();
+        let chunk = crate::compiler::compile(&source, &mut gc).unwrap().0;
+        let (result, output) = run_chunk_with_gc!(chunk, gc);
+        assert_eq!(result, Ok(Nil));
+        assert_eq!(output, "\"loaded\"\n11\n");
+
+        let source = format!("import \"{}\";", broken.display());
+        let chunk = crate::compiler::compile(&source, &mut gc).unwrap().0;
+        let (result, _) = run_chunk_with_gc!(chunk, gc);
+        assert_eq!(
+            result,
+            Err(RuntimeError(format!(
+                "[line 1] Could not compile module '{}': [line 1] Error at '=': Expected variable name.",
+                broken.display()
+            )))
+        );
+        fs::remove_dir_all(&dir).unwrap();
+
+        let mut output = Vec::new();
+        let mut input = io::empty();
+        let mut vm = VM::new(
+            VMConfig {
+                trace_instructions: false,
+                trace_stack: false,
+                trace_globals: false,
+                stdout: &mut output,
+                stdin: &mut input,
+                sandbox: Sandbox::untrusted(),
+            },
+            &mut gc,
+        );
+        let chunk = vm
+            .with_gc(|gc| crate::compiler::compile(&String::from("import \"a.lox\";"), gc))
+            .unwrap()
+            .0;
+        assert_eq!(
+            vm.interpret_chunk(chunk),
+            Err(RuntimeError(String::from(
+                "[line 1] import needs I/O, which is not allowed here"
+            )))
+        );
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore = "uses the file system")]
+    fn limits_import_depth() {
+        let dir = std::env::temp_dir().join(format!("rs-lox-depth-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        // A chain of modules each importing the next, deeper than the limit
+        let depth = crate::sandbox::MAX_IMPORT_DEPTH;
+        for index in 0..depth {
+            let source = format!("import \"{}.lox\";", index + 1);
+            fs::write(dir.join(format!("{}.lox", index)), source).unwrap();
+        }
+        fs::write(dir.join(format!("{}.lox", depth)), "var last = true;").unwrap();
+
+        let mut gc = GC::new();
+        let mut output = Vec::new();
+        let mut input = io::empty();
+        let mut vm = VM::new(
+            VMConfig {
+                trace_instructions: false,
+                trace_stack: false,
+                trace_globals: false,
+                stdout: &mut output,
+                stdin: &mut input,
+                sandbox: Sandbox {
+                    max_import_depth: 3,
+                    ..Sandbox::default()
+                },
+            },
+            &mut gc,
+        );
+        vm.set_script_path(dir.join("main.lox"));
+        let source = String::from("import \"0.lox\";");
+        let chunk = vm
+            .with_gc(|gc| crate::compiler::compile(&source, gc))
+            .unwrap()
+            .0;
+        assert_eq!(
+            vm.interpret_chunk(chunk),
+            Err(RuntimeError(String::from(
+                "[line 1] In module '0.lox': [line 1] In module '1.lox': [line 1] Import nesting exceeds 3"
+            )))
+        );
+
+        // The default fits on the stack of a test thread, and the script
+        // itself is one of the frames
+        vm.config_mut().sandbox = Sandbox::default();
+        let chunk = vm
+            .with_gc(|gc| crate::compiler::compile(&source, gc))
+            .unwrap()
+            .0;
+        let error = vm.interpret_chunk(chunk).unwrap_err().to_string();
+        assert!(
+            error.ends_with(&format!("Import nesting exceeds {}", depth)),
+            "{}",
+            error
+        );
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore = "uses the file system")]
+    fn detects_import_cycles() {
+        let dir = std::env::temp_dir().join(format!("rs-lox-cycle-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("main.lox"), "import \"a.lox\";").unwrap();
+        fs::write(dir.join("a.lox"), "import \"b.lox\";").unwrap();
+        fs::write(dir.join("b.lox"), "import \"main.lox\";").unwrap();
+
+        let mut gc = GC::new();
+        let mut output = Vec::new();
+        let mut input = io::empty();
+        let mut vm = VM::new(
+            VMConfig {
+                trace_instructions: false,
+                trace_stack: false,
+                trace_globals: false,
+                stdout: &mut output,
+                stdin: &mut input,
+                sandbox: Sandbox::default(),
+            },
+            &mut gc,
+        );
+        vm.set_script_path(dir.join("main.lox"));
+        let chunk = vm
+            .with_gc(|gc| crate::compiler::compile(&String::from("import \"a.lox\";"), gc))
+            .unwrap()
+            .0;
+        assert_eq!(
+            vm.interpret_chunk(chunk),
+            Err(RuntimeError(format!(
+                "[line 1] In module 'a.lox': [line 1] In module 'b.lox': [line 1] Import cycle: {} -> a.lox -> b.lox -> main.lox",
+                dir.join("main.lox").display()
+            )))
+        );
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn sandbox_limits() {
+        let mut gc = GC::new();
+        let mut output = Vec::new();
+        let mut input = io::empty();
+        let mut vm = VM::new(
+            VMConfig {
+                trace_instructions: false,
+                trace_stack: false,
+                trace_globals: false,
+                stdout: &mut output,
+                stdin: &mut input,
+                sandbox: Sandbox {
+                    max_instructions: Some(5),
+                    ..Sandbox::untrusted()
+                },
+            },
+            &mut gc,
+        );
+        let chunk = vm
+            .with_gc(|gc| crate::compiler::compile(&String::from("1; 2; 3;"), gc))
+            .unwrap()
+            .0;
+        assert_eq!(
+            vm.interpret_chunk(chunk),
+            Err(RuntimeError(String::from(
+                "[line 1] Instruction limit exceeded"
+            )))
+        );
+
+        vm.config_mut().sandbox = Sandbox {
+            max_memory: Some(vm.with_gc(|gc| gc.bytes_allocated()) + 200),
+            ..Sandbox::untrusted()
+        };
+        let chunk = vm
+            .with_gc(|gc| {
+                crate::compiler::compile(
+                    &String::from("var a = \"a\"; var a = a + a; var a = a + a; var a = a + a; var a = a + a; var a = a + a; var a = a + a; var a = a + a; var a = a + a;"),
+                    gc,
+                )
+            })
+            .unwrap().0;
+        assert_eq!(
+            vm.interpret_chunk(chunk),
+            Err(RuntimeError(String::from("[line 1] Memory limit exceeded")))
+        );
+    }
 }
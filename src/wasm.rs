@@ -0,0 +1,108 @@
+//! JavaScript bindings for running the interpreter in a browser, enabled with
+//! the `wasm` feature. Build with
+//! `cargo build --lib --target wasm32-unknown-unknown --features wasm` and
+//! generate the JS glue with `wasm-bindgen`.
+
+use crate::lox::Lox;
+use std::io;
+use std::sync::{Arc, Mutex};
+use wasm_bindgen::prelude::*;
+
+#[derive(Clone, Default)]
+struct Output(Arc<Mutex<Vec<u8>>>);
+
+impl Output {
+    fn take(&self) -> String {
+        let bytes = std::mem::take(&mut *self.0.lock().unwrap());
+        return String::from_utf8_lossy(&bytes).into_owned();
+    }
+}
+
+impl io::Write for Output {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.lock().unwrap().write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// An interpreter session whose globals persist between calls to `eval`.
+#[wasm_bindgen]
+pub struct LoxSession {
+    lox: Lox,
+    output: Output,
+}
+
+impl Default for LoxSession {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[wasm_bindgen]
+impl LoxSession {
+    #[wasm_bindgen(constructor)]
+    pub fn new() -> LoxSession {
+        let output = Output::default();
+        let mut lox = Lox::new();
+        lox.set_output(Box::new(output.clone()));
+        LoxSession { lox, output }
+    }
+
+    /// Runs `source` and returns the value of its trailing expression, or the
+    /// errors it produced. Printed output is collected for `take_output`.
+    pub fn eval(&mut self, source: &str) -> Result<String, String> {
+        return self
+            .lox
+            .eval(source)
+            .map(|value| value.to_string())
+            .map_err(|error| format!("{}\n", error));
+    }
+
+    /// Returns everything printed since the last call.
+    pub fn take_output(&mut self) -> String {
+        return self.output.take();
+    }
+}
+
+/// Runs `source` in a fresh session and returns everything it printed,
+/// followed by any errors.
+#[wasm_bindgen]
+pub fn lox_eval(source: &str) -> String {
+    let mut session = LoxSession::new();
+    let result = session.eval(source);
+    let mut output = session.take_output();
+    if let Err(error) = result {
+        output.push_str(&error);
+    }
+    return output;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn captures_output() {
+        assert_eq!(lox_eval("print 1; print \"a\" + \"b\";"), "1\n\"ab\"\n");
+        assert_eq!(
+            lox_eval("print 1; -nil;"),
+            "1\nRuntime error: [line 1] Invalid type for negation: nil\n"
+        );
+        assert_eq!(
+            lox_eval("print;"),
+            "[line 1] Error at ';': Expected expression.\n"
+        );
+    }
+
+    #[test]
+    fn keeps_session_state() {
+        let mut session = LoxSession::new();
+        assert_eq!(session.eval("var a = 2; print a;"), Ok(String::from("nil")));
+        assert_eq!(session.eval("a * 3;"), Ok(String::from("6")));
+        assert_eq!(session.take_output(), "2\n");
+        assert_eq!(session.take_output(), "");
+    }
+}
@@ -0,0 +1,219 @@
+#![allow(clippy::needless_return)]
+
+//! Runs the test suite of Crafting Interpreters, if it's vendored under
+//! `tests/craftinginterpreters` (the `test` directory of
+//! <https://github.com/munificent/craftinginterpreters>), and reports how
+//! many scripts of each chapter pass. Lox here is still missing most of the
+//! language, so the suite isn't expected to pass; instead, the number of
+//! passing scripts per chapter is kept in `tests/conformance.txt`, and the
+//! test fails if a chapter passes fewer than that. Run it with
+//! `UPDATE_CONFORMANCE=1` to record new numbers as features land.
+//!
+//! Scripts run with `--strict`, so the extensions' keywords are plain names
+//! as in the book, and are checked the way the suite's own runner checks
+//! clox: output against `// expect:` comments, compile errors against
+//! `// [line N] Error` and `// Error` comments, and runtime errors by
+//! message and line.
+
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+const EXPECT_OUTPUT: &str = "// expect: ";
+const EXPECT_RUNTIME_ERROR: &str = "// expect runtime error: ";
+
+const EX_DATAERR: i32 = 65;
+const EX_SOFTWARE: i32 = 70;
+
+#[derive(Debug, Default, PartialEq)]
+struct Expectations {
+    output: Vec<String>,
+    /// Compile errors as the CLI prints them, `[line N] Error...`.
+    compile_errors: Vec<String>,
+    /// The message and the line it's reported at.
+    runtime_error: Option<(usize, String)>,
+}
+
+/// Reads a compile error comment, `// [line N] Error...` or `// Error...`
+/// for an error on the comment's own line. Errors only jlox reports, marked
+/// `[java line N]`, are skipped.
+fn parse_compile_error(line: &str, number: usize) -> Option<String> {
+    let comment = &line[line.find("// ")? + 3..];
+    if comment.starts_with("Error") {
+        return Some(format!("[line {}] {}", number, comment));
+    }
+    let comment = comment
+        .strip_prefix("[c line ")
+        .or_else(|| comment.strip_prefix("[line "))?;
+    let (line, error) = comment.split_once("] ")?;
+    if !error.starts_with("Error") {
+        return None;
+    }
+    return Some(format!("[line {}] {}", line, error));
+}
+
+fn parse_expectations(source: &str) -> Expectations {
+    let mut expectations = Expectations::default();
+    for (index, line) in source.lines().enumerate() {
+        if let Some(start) = line.find(EXPECT_OUTPUT) {
+            let output = &line[start + EXPECT_OUTPUT.len()..];
+            expectations.output.push(String::from(output));
+        } else if let Some(start) = line.find(EXPECT_RUNTIME_ERROR) {
+            let message = &line[start + EXPECT_RUNTIME_ERROR.len()..];
+            expectations.runtime_error = Some((index + 1, String::from(message)));
+        } else if let Some(error) = parse_compile_error(line, index + 1) {
+            expectations.compile_errors.push(error);
+        }
+    }
+    return expectations;
+}
+
+fn collect_scripts(dir: &Path, scripts: &mut Vec<PathBuf>) {
+    let entries = std::fs::read_dir(dir)
+        .unwrap_or_else(|error| panic!("Could not read '{}': {}", dir.display(), error));
+    for entry in entries {
+        let path = entry.unwrap().path();
+        if path.is_dir() {
+            collect_scripts(&path, scripts);
+        } else if path.extension().is_some_and(|ext| ext == "lox") {
+            scripts.push(path);
+        }
+    }
+}
+
+/// Whether the script at `path` behaves as its comments expect.
+fn passes(path: &Path) -> bool {
+    let source = std::fs::read_to_string(path).unwrap();
+    let expectations = parse_expectations(&source);
+    let output = Command::new(env!("CARGO_BIN_EXE_rs-lox"))
+        .arg("--strict")
+        .arg(path)
+        .output()
+        .expect("Failed to run rlox");
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    if stdout.lines().collect::<Vec<_>>() != expectations.output {
+        return false;
+    }
+    if !expectations.compile_errors.is_empty() {
+        let errors = stderr
+            .lines()
+            .filter(|line| line.contains("] Error"))
+            .collect::<Vec<_>>();
+        return output.status.code() == Some(EX_DATAERR) && errors == expectations.compile_errors;
+    }
+    return match &expectations.runtime_error {
+        Some((line, message)) => {
+            let expected = format!("[line {}] {}", line, message);
+            output.status.code() == Some(EX_SOFTWARE) && stderr.contains(&expected)
+        }
+        None => output.status.success(),
+    };
+}
+
+/// Passing and total scripts per chapter, the directory directly under the
+/// suite's root, or `-` for scripts at the root.
+fn conformance(root: &Path) -> BTreeMap<String, (usize, usize)> {
+    let mut scripts = Vec::new();
+    collect_scripts(root, &mut scripts);
+    let mut matrix = BTreeMap::new();
+    for script in &scripts {
+        let relative = script.strip_prefix(root).unwrap();
+        let chapter = match relative.parent().and_then(|parent| parent.iter().next()) {
+            Some(chapter) => chapter.to_string_lossy().into_owned(),
+            None => String::from("-"),
+        };
+        let (passed, total) = matrix.entry(chapter).or_insert((0, 0));
+        *total += 1;
+        if passes(script) {
+            *passed += 1;
+        }
+    }
+    return matrix;
+}
+
+/// Reads `chapter passed` lines.
+fn parse_baseline(baseline: &str) -> BTreeMap<String, usize> {
+    return baseline
+        .lines()
+        .filter_map(|line| {
+            let (chapter, passed) = line.split_once(' ')?;
+            return Some((String::from(chapter), passed.trim().parse().ok()?));
+        })
+        .collect();
+}
+
+#[test]
+#[cfg_attr(miri, ignore = "runs the interpreter binary")]
+fn crafting_interpreters_suite() {
+    let tests = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests");
+    let root = tests.join("craftinginterpreters");
+    if !root.is_dir() {
+        eprintln!("Skipped: no test suite in {}", root.display());
+        return;
+    }
+    let matrix = conformance(&root);
+    let (mut passed, mut total) = (0, 0);
+    for (chapter, (chapter_passed, chapter_total)) in &matrix {
+        eprintln!("{:<24} {:>4}/{:<4}", chapter, chapter_passed, chapter_total);
+        passed += chapter_passed;
+        total += chapter_total;
+    }
+    eprintln!("{:<24} {:>4}/{:<4}", "total", passed, total);
+
+    let baseline_path = tests.join("conformance.txt");
+    if std::env::var_os("UPDATE_CONFORMANCE").is_some() {
+        let baseline = matrix
+            .iter()
+            .map(|(chapter, (passed, _))| format!("{} {}\n", chapter, passed))
+            .collect::<String>();
+        std::fs::write(&baseline_path, baseline).unwrap();
+        return;
+    }
+    let baseline = std::fs::read_to_string(&baseline_path).unwrap_or_default();
+    let regressions = parse_baseline(&baseline)
+        .into_iter()
+        .filter_map(|(chapter, expected)| {
+            let passed = matrix.get(&chapter).map_or(0, |(passed, _)| *passed);
+            return (passed < expected)
+                .then(|| format!("{}: {} passed, {} before", chapter, passed, expected));
+        })
+        .collect::<Vec<_>>();
+    assert!(
+        regressions.is_empty(),
+        "Fewer scripts pass than before:\n{}",
+        regressions.join("\n")
+    );
+}
+
+#[test]
+fn parses_suite_expectations() {
+    let expectations = parse_expectations(
+        "print \"a\"; // expect: a\n\
+         var a = ; // Error at ';': Expect expression.\n\
+         // [line 4] Error at end: Expect '}' after block.\n\
+         // [java line 5] Error at 'b': Only in jlox.\n\
+         // [c line 6] Error at 'c': Only in clox.\n\
+         a(); // expect runtime error: Can only call functions and classes.\n",
+    );
+    assert_eq!(
+        expectations,
+        Expectations {
+            output: vec![String::from("a")],
+            compile_errors: vec![
+                String::from("[line 2] Error at ';': Expect expression."),
+                String::from("[line 4] Error at end: Expect '}' after block."),
+                String::from("[line 6] Error at 'c': Only in clox."),
+            ],
+            runtime_error: Some((6, String::from("Can only call functions and classes."))),
+        }
+    );
+}
+
+#[test]
+fn parses_baseline() {
+    assert_eq!(
+        parse_baseline("assignment 3\nbool 0\nbroken\n"),
+        BTreeMap::from([(String::from("assignment"), 3), (String::from("bool"), 0)])
+    );
+}
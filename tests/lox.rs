@@ -0,0 +1,155 @@
+#![allow(clippy::needless_return)]
+
+//! Runs every `tests/lox/**/*.lox` script through the interpreter and checks
+//! its behaviour against comments in the script, following the format of the
+//! Crafting Interpreters test suite:
+//!
+//! - `// expect: <output>` for each line the script prints, in order.
+//! - `// expect runtime error: <message>` if the script fails at runtime on
+//!   the line of the comment.
+//! - `// expect compile error` if the script fails to compile.
+//!
+//! Every script runs twice, once with the default single-pass compiler and
+//! once with `--two-pass`, and must behave the same both times.
+
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+const EXPECT_OUTPUT: &str = "// expect: ";
+const EXPECT_RUNTIME_ERROR: &str = "// expect runtime error: ";
+const EXPECT_COMPILE_ERROR: &str = "// expect compile error";
+
+const EX_DATAERR: i32 = 65;
+const EX_SOFTWARE: i32 = 70;
+
+#[derive(Debug, Default)]
+struct Expectations {
+    output: Vec<String>,
+    /// The message and the line it's reported at.
+    runtime_error: Option<(usize, String)>,
+    compile_error: bool,
+}
+
+fn parse_expectations(source: &str) -> Expectations {
+    let mut expectations = Expectations::default();
+    for (number, line) in source.lines().enumerate() {
+        if let Some(index) = line.find(EXPECT_OUTPUT) {
+            let output = &line[index + EXPECT_OUTPUT.len()..];
+            expectations.output.push(String::from(output));
+        } else if let Some(index) = line.find(EXPECT_RUNTIME_ERROR) {
+            let message = &line[index + EXPECT_RUNTIME_ERROR.len()..];
+            expectations.runtime_error = Some((number + 1, String::from(message)));
+        } else if line.contains(EXPECT_COMPILE_ERROR) {
+            expectations.compile_error = true;
+        }
+    }
+    return expectations;
+}
+
+fn collect_scripts(dir: &Path, scripts: &mut Vec<PathBuf>) {
+    let entries = std::fs::read_dir(dir)
+        .unwrap_or_else(|error| panic!("Could not read '{}': {}", dir.display(), error));
+    for entry in entries {
+        let path = entry.unwrap().path();
+        if path.is_dir() {
+            collect_scripts(&path, scripts);
+        } else if path.extension().is_some_and(|ext| ext == "lox") {
+            scripts.push(path);
+        }
+    }
+}
+
+/// Runs a single script with `flags`, returning a description of every
+/// mismatch.
+fn check_script(path: &Path, flags: &[&str]) -> Vec<String> {
+    let source = std::fs::read_to_string(path).unwrap();
+    let expectations = parse_expectations(&source);
+    let output = Command::new(env!("CARGO_BIN_EXE_rs-lox"))
+        .args(flags)
+        .arg(path)
+        .output()
+        .expect("Failed to run rlox");
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    let mut failures = Vec::new();
+
+    let actual_lines = stdout.lines().collect::<Vec<_>>();
+    if actual_lines != expectations.output {
+        failures.push(format!(
+            "expected output {:?}, got {:?}",
+            expectations.output, actual_lines
+        ));
+    }
+
+    let expected_code = if expectations.compile_error {
+        EX_DATAERR
+    } else if expectations.runtime_error.is_some() {
+        EX_SOFTWARE
+    } else {
+        0
+    };
+    if output.status.code() != Some(expected_code) {
+        failures.push(format!(
+            "expected exit code {}, got {:?} (stderr: {:?})",
+            expected_code,
+            output.status.code(),
+            stderr
+        ));
+    }
+
+    if let Some((line, message)) = &expectations.runtime_error {
+        let expected = format!("Runtime error: [line {}] {}", line, message);
+        if !stderr.lines().any(|line| line == expected) {
+            failures.push(format!(
+                "expected {:?} on stderr, got {:?}",
+                expected, stderr
+            ));
+        }
+    }
+    return failures;
+}
+
+#[test]
+#[cfg_attr(miri, ignore = "runs the interpreter binary")]
+fn lox_scripts() {
+    let root = Path::new(env!("CARGO_MANIFEST_DIR"))
+        .join("tests")
+        .join("lox");
+    let mut scripts = Vec::new();
+    collect_scripts(&root, &mut scripts);
+    scripts.sort();
+    assert!(
+        !scripts.is_empty(),
+        "No scripts found in {}",
+        root.display()
+    );
+
+    let mut report = String::new();
+    for script in &scripts {
+        for flags in [&[][..], &["--two-pass"][..]] {
+            for failure in check_script(script, flags) {
+                let name = script.strip_prefix(&root).unwrap_or(script);
+                let mode = flags
+                    .first()
+                    .map_or(String::new(), |flag| format!(" {}", flag));
+                report.push_str(&format!("{}{}: {}\n", name.display(), mode, failure));
+            }
+        }
+    }
+    assert!(report.is_empty(), "Some Lox scripts failed:\n{}", report);
+}
+
+#[test]
+fn parses_expectations() {
+    let expectations = parse_expectations(
+        "print 1; // expect: 1\n\
+         print x; // expect runtime error: Undefined variable 'x'\n\
+         // expect compile error\n",
+    );
+    assert_eq!(expectations.output, vec![String::from("1")]);
+    assert_eq!(
+        expectations.runtime_error,
+        Some((2, String::from("Undefined variable 'x'")))
+    );
+    assert!(expectations.compile_error);
+}
@@ -0,0 +1,314 @@
+#![allow(clippy::needless_return)]
+
+//! Checks the compiler and VM against a small tree-walking evaluator on
+//! randomly generated expressions. Both must agree on the value of every
+//! expression, or both must fail at runtime.
+
+use proptest::prelude::*;
+use rs_lox::{Lox, LoxError, LoxValue};
+use std::fmt;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum UnaryOp {
+    Negate,
+    Not,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum BinaryOp {
+    Equal,
+    NotEqual,
+    Less,
+    LessEqual,
+    Greater,
+    GreaterEqual,
+    Add,
+    Subtract,
+    Multiply,
+    Divide,
+}
+
+impl BinaryOp {
+    fn symbol(self) -> &'static str {
+        return match self {
+            BinaryOp::Equal => "==",
+            BinaryOp::NotEqual => "!=",
+            BinaryOp::Less => "<",
+            BinaryOp::LessEqual => "<=",
+            BinaryOp::Greater => ">",
+            BinaryOp::GreaterEqual => ">=",
+            BinaryOp::Add => "+",
+            BinaryOp::Subtract => "-",
+            BinaryOp::Multiply => "*",
+            BinaryOp::Divide => "/",
+        };
+    }
+
+    /// Binding power, higher binds tighter. All binary operators are left
+    /// associative.
+    fn precedence(self) -> u8 {
+        return match self {
+            BinaryOp::Equal | BinaryOp::NotEqual => 1,
+            BinaryOp::Less | BinaryOp::LessEqual | BinaryOp::Greater | BinaryOp::GreaterEqual => 2,
+            BinaryOp::Add | BinaryOp::Subtract => 3,
+            BinaryOp::Multiply | BinaryOp::Divide => 4,
+        };
+    }
+}
+
+const UNARY_PRECEDENCE: u8 = 5;
+
+#[derive(Debug, Clone)]
+enum Expr {
+    Nil,
+    Boolean(bool),
+    Number(f32),
+    Str(String),
+    Unary(UnaryOp, Box<Expr>),
+    Binary(Box<Expr>, BinaryOp, Box<Expr>),
+}
+
+impl Expr {
+    fn precedence(&self) -> u8 {
+        return match self {
+            Expr::Binary(_, op, _) => op.precedence(),
+            Expr::Unary(_, _) => UNARY_PRECEDENCE,
+            _ => UNARY_PRECEDENCE + 1,
+        };
+    }
+
+    /// Writes `self`, in parentheses if it binds looser than `min`.
+    fn fmt_operand(&self, f: &mut fmt::Formatter, min: u8) -> fmt::Result {
+        if self.precedence() < min {
+            return write!(f, "({})", self);
+        }
+        return write!(f, "{}", self);
+    }
+}
+
+/// Source code for the expression, with only the parentheses precedence
+/// requires, so the parser's precedence handling is tested too.
+impl fmt::Display for Expr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Expr::Nil => write!(f, "nil"),
+            Expr::Boolean(b) => write!(f, "{}", b),
+            Expr::Number(n) => write!(f, "{}", n),
+            Expr::Str(s) => write!(f, "\"{}\"", s),
+            Expr::Unary(op, operand) => {
+                let symbol = if *op == UnaryOp::Negate { "-" } else { "!" };
+                write!(f, "{}", symbol)?;
+                operand.fmt_operand(f, UNARY_PRECEDENCE)
+            }
+            Expr::Binary(left, op, right) => {
+                left.fmt_operand(f, op.precedence())?;
+                write!(f, " {} ", op.symbol())?;
+                right.fmt_operand(f, op.precedence() + 1)
+            }
+        }
+    }
+}
+
+fn is_falsey(value: &LoxValue) -> bool {
+    return matches!(value, LoxValue::Nil | LoxValue::Boolean(false));
+}
+
+/// Evaluates `expr` directly, with `Err(())` for a runtime error.
+fn evaluate(expr: &Expr) -> Result<LoxValue, ()> {
+    return match expr {
+        Expr::Nil => Ok(LoxValue::Nil),
+        Expr::Boolean(b) => Ok(LoxValue::Boolean(*b)),
+        // Whole numbers are written without a decimal point, so they're
+        // integer literals
+        Expr::Number(n) if n.fract() == 0.0 => Ok(LoxValue::Int(*n as i64)),
+        Expr::Number(n) => Ok(LoxValue::Number(*n)),
+        Expr::Str(s) => Ok(LoxValue::String(s.clone())),
+        Expr::Unary(UnaryOp::Negate, operand) => match evaluate(operand)? {
+            LoxValue::Number(n) => Ok(LoxValue::Number(-n)),
+            LoxValue::Int(n) => n.checked_neg().map(LoxValue::Int).ok_or(()),
+            _ => Err(()),
+        },
+        Expr::Unary(UnaryOp::Not, operand) => Ok(LoxValue::Boolean(is_falsey(&evaluate(operand)?))),
+        Expr::Binary(left, op, right) => {
+            let (a, b) = (evaluate(left)?, evaluate(right)?);
+            binary(a, *op, b)
+        }
+    };
+}
+
+fn as_float(value: &LoxValue) -> Option<f32> {
+    return match value {
+        LoxValue::Number(n) => Some(*n),
+        LoxValue::Int(n) => Some(*n as f64 as f32),
+        _ => None,
+    };
+}
+
+/// Arithmetic on two integers, which stays exact and fails on overflow.
+fn int_binary(a: i64, op: BinaryOp, b: i64) -> Option<Result<LoxValue, ()>> {
+    use LoxValue::{Boolean, Int};
+    let checked = |result: Option<i64>| Some(result.map(Int).ok_or(()));
+    return match op {
+        BinaryOp::Less => Some(Ok(Boolean(a < b))),
+        BinaryOp::LessEqual => Some(Ok(Boolean(a <= b))),
+        BinaryOp::Greater => Some(Ok(Boolean(a > b))),
+        BinaryOp::GreaterEqual => Some(Ok(Boolean(a >= b))),
+        BinaryOp::Add => checked(a.checked_add(b)),
+        BinaryOp::Subtract => checked(a.checked_sub(b)),
+        BinaryOp::Multiply => checked(a.checked_mul(b)),
+        // Division always gives a float
+        _ => None,
+    };
+}
+
+#[allow(clippy::neg_cmp_op_on_partial_ord)]
+fn binary(a: LoxValue, op: BinaryOp, b: LoxValue) -> Result<LoxValue, ()> {
+    use LoxValue::{Boolean, Number};
+    if let (LoxValue::Int(a), LoxValue::Int(b)) = (&a, &b) {
+        if let Some(result) = int_binary(*a, op, *b) {
+            return result;
+        }
+    }
+    return match (op, as_float(&a), as_float(&b)) {
+        (BinaryOp::Equal, _, _) => Ok(Boolean(equal(&a, &b))),
+        (BinaryOp::NotEqual, _, _) => Ok(Boolean(!equal(&a, &b))),
+        (op, Some(a), Some(b)) => Ok(match op {
+            BinaryOp::Less => Boolean(a < b),
+            // As in clox, `<=` is `!(a > b)` and `>=` is `!(a < b)`, which
+            // differs from IEEE comparison for NaN
+            BinaryOp::LessEqual => Boolean(!(a > b)),
+            BinaryOp::Greater => Boolean(a > b),
+            BinaryOp::GreaterEqual => Boolean(!(a < b)),
+            BinaryOp::Add => Number(a + b),
+            BinaryOp::Subtract => Number(a - b),
+            BinaryOp::Multiply => Number(a * b),
+            BinaryOp::Divide => Number(a / b),
+            BinaryOp::Equal | BinaryOp::NotEqual => unreachable!(),
+        }),
+        (BinaryOp::Add, _, _) => match (a, b) {
+            (LoxValue::String(a), LoxValue::String(b)) => Ok(LoxValue::String(a + &b)),
+            _ => Err(()),
+        },
+        _ => Err(()),
+    };
+}
+
+/// Lox's `==`, where integers and floats compare by value.
+fn equal(a: &LoxValue, b: &LoxValue) -> bool {
+    return match (a, b) {
+        (LoxValue::Int(a), LoxValue::Number(b)) | (LoxValue::Number(b), LoxValue::Int(a)) => {
+            *a as f64 == *b as f64
+        }
+        _ => a == b,
+    };
+}
+
+fn same_value(a: &LoxValue, b: &LoxValue) -> bool {
+    return match (a, b) {
+        (LoxValue::Number(a), LoxValue::Number(b)) => a == b || (a.is_nan() && b.is_nan()),
+        _ => a == b,
+    };
+}
+
+fn arb_number() -> impl Strategy<Value = f32> {
+    // Quarters are exact in an f32 and print back as the same literal
+    return (0u16..400).prop_map(|n| f32::from(n) / 4.0);
+}
+
+/// Expressions that always evaluate to a number.
+fn arb_numeric() -> impl Strategy<Value = Expr> {
+    let leaf = arb_number().prop_map(Expr::Number);
+    return leaf.prop_recursive(4, 16, 2, |inner| {
+        prop_oneof![
+            inner
+                .clone()
+                .prop_map(|e| Expr::Unary(UnaryOp::Negate, Box::new(e))),
+            (
+                inner.clone(),
+                prop_oneof![
+                    Just(BinaryOp::Add),
+                    Just(BinaryOp::Subtract),
+                    Just(BinaryOp::Multiply),
+                    Just(BinaryOp::Divide),
+                ],
+                inner
+            )
+                .prop_map(|(a, op, b)| Expr::Binary(Box::new(a), op, Box::new(b))),
+        ]
+    });
+}
+
+/// Any expression, including ones that fail at runtime.
+fn arb_expr() -> impl Strategy<Value = Expr> {
+    let leaf = prop_oneof![
+        Just(Expr::Nil),
+        any::<bool>().prop_map(Expr::Boolean),
+        arb_number().prop_map(Expr::Number),
+        "[a-c]{0,2}".prop_map(Expr::Str),
+    ];
+    return leaf.prop_recursive(4, 24, 2, |inner| {
+        prop_oneof![
+            (
+                prop_oneof![Just(UnaryOp::Negate), Just(UnaryOp::Not)],
+                inner.clone()
+            )
+                .prop_map(|(op, e)| Expr::Unary(op, Box::new(e))),
+            (
+                inner.clone(),
+                prop_oneof![
+                    Just(BinaryOp::Equal),
+                    Just(BinaryOp::NotEqual),
+                    Just(BinaryOp::Add),
+                    Just(BinaryOp::Subtract),
+                    Just(BinaryOp::Multiply),
+                    Just(BinaryOp::Divide),
+                ],
+                inner
+            )
+                .prop_map(|(a, op, b)| Expr::Binary(Box::new(a), op, Box::new(b))),
+            // The VM orders values other than numbers by their internal
+            // representation, which the reference doesn't model, so
+            // comparisons only get numbers
+            (
+                arb_numeric(),
+                prop_oneof![
+                    Just(BinaryOp::Less),
+                    Just(BinaryOp::LessEqual),
+                    Just(BinaryOp::Greater),
+                    Just(BinaryOp::GreaterEqual),
+                ],
+                arb_numeric()
+            )
+                .prop_map(|(a, op, b)| Expr::Binary(Box::new(a), op, Box::new(b))),
+        ]
+    });
+}
+
+proptest! {
+    // Miri is far slower, so it only checks a few cases
+    #![proptest_config(ProptestConfig::with_cases(if cfg!(miri) { 8 } else { 256 }))]
+
+    #[test]
+    fn vm_agrees_with_reference(expr in arb_expr()) {
+        let source = format!("{};", expr);
+        let expected = evaluate(&expr);
+        let actual = Lox::new().eval(&source);
+        match (&actual, &expected) {
+            (Ok(actual), Ok(expected)) => prop_assert!(
+                same_value(actual, expected),
+                "{} gave {:?}, expected {:?}",
+                source,
+                actual,
+                expected
+            ),
+            (Err(LoxError::RuntimeError(_)), Err(())) => {}
+            _ => prop_assert!(
+                false,
+                "{} gave {:?}, expected {:?}",
+                source,
+                actual,
+                expected
+            ),
+        }
+    }
+}